@@ -1,7 +1,11 @@
 use actix_cors::Cors;
+use actix_web::dev::Service;
 use actix_web::{web, App, HttpResponse, HttpServer, Responder};
-use serde::Deserialize;
+use iot_sound_wire::scale::TimeWeighting;
+use iot_sound_wire::Scale;
+use serde::{Deserialize, Serialize};
 use std::env;
+use tracing::{error, info, Instrument};
 
 /// Api endpoint index
 /// Shows all available endpoints
@@ -13,13 +17,26 @@ async fn index() -> impl Responder {
 
     end_points.push_str(&get_link_string(base_url, "sound"));
     end_points.push_str(&get_link_string(base_url, "sensors"));
+    end_points.push_str(&get_link_string(base_url, "sensors/pending"));
+    end_points.push_str(&get_link_string(base_url, "sensors/status"));
+    end_points.push_str("sensors/{id}/config<br>");
     end_points.push_str(&get_link_string(base_url, "sound/sorted"));
     end_points.push_str(&get_link_string(
         base_url,
         "sound/sorted/limit?limit_amount=10",
     ));
+    end_points.push_str(&get_link_string(
+        base_url,
+        "sound/aggregate?limit_amount=10",
+    ));
+    end_points.push_str("sound/spectrum/{id}?limit_amount=10<br>");
     end_points.push_str(&get_link_string(base_url, "logs"));
     end_points.push_str(&get_link_string(base_url, "logs/limit?limit_amount=10"));
+    end_points.push_str("POST <br>");
+    end_points.push_str("sensors/pending/{id}/approve<br>");
+    end_points.push_str("sensors/pending/{id}/reject<br>");
+    end_points.push_str("PUT <br>");
+    end_points.push_str("sensors/{id}/config<br>");
 
     end_points.push_str("</div>");
 
@@ -68,7 +85,7 @@ async fn get_sound(pool: web::Data<iot_sound_database::Pool>) -> impl Responder
     let returned = match returned {
         Ok(data) => data,
         Err(e) => {
-            println!("Error: {}", e);
+            error!("Error: {}", e);
             return HttpResponse::InternalServerError().body("Internal Server Error");
         }
     };
@@ -118,7 +135,7 @@ async fn get_sound_sorted_by_sensor_limited(
     let sensors = match sensors {
         Ok(data) => data,
         Err(e) => {
-            println!("Error: {}", e);
+            error!("Error: {}", e);
             return HttpResponse::InternalServerError().body("Internal Server Error");
         }
     };
@@ -130,7 +147,7 @@ async fn get_sound_sorted_by_sensor_limited(
         let returned = match returned {
             Ok(data) => data,
             Err(e) => {
-                println!("Error: {}", e);
+                error!("Error: {}", e);
                 return HttpResponse::InternalServerError().body("Internal Server Error");
             }
         };
@@ -198,7 +215,7 @@ async fn get_sound_sorted_by_sensor(pool: web::Data<iot_sound_database::Pool>) -
     let sensors = match sensors {
         Ok(data) => data,
         Err(e) => {
-            println!("Error: {}", e);
+            error!("Error: {}", e);
             return HttpResponse::InternalServerError().body("Internal Server Error");
         }
     };
@@ -207,7 +224,7 @@ async fn get_sound_sorted_by_sensor(pool: web::Data<iot_sound_database::Pool>) -
     let data = match data {
         Ok(data) => data,
         Err(e) => {
-            println!("Error: {}", e);
+            error!("Error: {}", e);
             return HttpResponse::InternalServerError().body("Internal Server Error");
         }
     };
@@ -241,6 +258,197 @@ async fn get_sound_sorted_by_sensor(pool: web::Data<iot_sound_database::Pool>) -
     }
 }
 
+#[derive(Deserialize)]
+struct AggregateInfo {
+    limit_amount: i64,
+    /// Time weighting to convert the levels to, only `leq` converts
+    convert: Option<String>,
+}
+
+/// Levels of many readings summed up
+#[derive(Debug, Serialize)]
+struct Aggregate {
+    weighting: &'static str,
+    time_weighting: &'static str,
+    reference: &'static str,
+    count: usize,
+    /// Energy average of the levels
+    leq: f32,
+    min: f32,
+    max: f32,
+}
+
+/// Aggregate readings, which must all be on one scale unless they convert to `convert`
+/// # Arguments
+/// * `data` - The readings, at least one
+/// * `convert` - The time weighting to convert the levels to
+/// # Returns
+/// * `Result<Aggregate, Vec<Scale>>` - The aggregate, or the scales that do not fit together
+fn aggregate(
+    data: &[iot_sound_database::Data],
+    convert: Option<TimeWeighting>,
+) -> Result<Aggregate, Vec<Scale>> {
+    let mut scales: Vec<Scale> = Vec::new();
+    let mut levels = Vec::new();
+    for row in data {
+        // rows are written by the backend, which stores numbers on known scales
+        if let (Some(level), Some(scale)) = (row.get_level(), row.get_scale()) {
+            if !scales.contains(&scale) {
+                scales.push(scale);
+            }
+            levels.push(level);
+        }
+    }
+    let first = *scales.first().ok_or_else(Vec::new)?;
+    let target = Scale {
+        time_weighting: convert.unwrap_or(first.time_weighting),
+        ..first
+    };
+    if !scales.iter().all(|scale| scale.converts_to(&target)) {
+        return Err(scales);
+    }
+
+    let energy: f64 = levels
+        .iter()
+        .map(|level| 10f64.powf(f64::from(*level) / 10.0))
+        .sum::<f64>()
+        / levels.len() as f64;
+    Ok(Aggregate {
+        weighting: target.weighting.as_str(),
+        time_weighting: target.time_weighting.as_str(),
+        reference: target.reference.as_str(),
+        count: levels.len(),
+        leq: (10.0 * energy.log10()) as f32,
+        min: levels.iter().copied().fold(f32::INFINITY, f32::min),
+        max: levels.iter().copied().fold(f32::NEG_INFINITY, f32::max),
+    })
+}
+
+/// Aggregates the latest readings of every sensor into one level
+///
+/// Levels on different scales, e.g. dBA and dBC or SPL and dBFS, are not comparable and the
+/// request is refused. Fast and slow levels are aggregated as an Leq with `convert=leq`,
+/// the other parts of a scale never convert.
+/// # Arguments
+/// * `pool` - The database pool
+/// * `info` - The limit amount per sensor and the conversion
+/// # Returns
+/// * `impl Responder` - The response
+/// # Errors
+/// * `InternalServerError` - If there is an error with the database
+/// * `NotFound` - If there is no data in the database
+/// * `BadRequest` - If the conversion is not a time weighting
+/// * `Conflict` - If the readings are on scales that do not convert
+/// # Example call
+/// ```bash
+/// curl -X GET "http://localhost:8081/sound/aggregate?limit_amount=10&convert=leq" -H "accept: application/json"
+/// ```
+/// # Example response
+/// ```json
+/// {
+///  "weighting": "A",
+///  "time_weighting": "leq",
+///  "reference": "spl",
+///  "count": 20,
+///  "leq": 52.3,
+///  "min": 41.0,
+///  "max": 60.5
+/// }
+/// ```
+async fn get_sound_aggregate(
+    pool: web::Data<iot_sound_database::Pool>,
+    info: web::Query<AggregateInfo>,
+) -> impl Responder {
+    let convert = match info.convert.as_deref().map(str::parse::<TimeWeighting>) {
+        Some(Ok(convert)) => Some(convert),
+        Some(Err(_)) => {
+            return HttpResponse::BadRequest().body("convert must be fast, slow or leq");
+        }
+        None => None,
+    };
+    let sensors = match pool.get_sensor_ids().await {
+        Ok(data) => data,
+        Err(e) => {
+            error!("Error: {}", e);
+            return HttpResponse::InternalServerError().body("Internal Server Error");
+        }
+    };
+
+    let mut data = Vec::new();
+    for sensor in sensors {
+        match pool.get_loudness_limited(&sensor, info.limit_amount).await {
+            Ok(returned) => data.extend(returned),
+            Err(e) => {
+                error!("Error: {}", e);
+                return HttpResponse::InternalServerError().body("Internal Server Error");
+            }
+        }
+    }
+
+    match aggregate(&data, convert) {
+        Ok(aggregate) => HttpResponse::Ok().json(aggregate),
+        Err(scales) if scales.is_empty() => HttpResponse::NotFound().body("No data found"),
+        Err(scales) => {
+            let scales: Vec<String> = scales.iter().map(Scale::to_string).collect();
+            HttpResponse::Conflict().body(format!(
+                "Readings on different scales can not be aggregated: {}",
+                scales.join(", ")
+            ))
+        }
+    }
+}
+
+/// Returns the band levels of the latest spectrum readings of a sensor, oldest first
+/// # Arguments
+/// * `pool` - The database pool
+/// * `sensor_id` - The id of the sensor
+/// * `info` - The limit amount
+/// # Returns
+/// * `impl Responder` - The response
+/// # Errors
+/// * `InternalServerError` - If there is an error with the database
+/// * `NotFound` - If the sensor has no spectrum readings
+/// # Example call
+/// ```bash
+/// curl -X GET "http://localhost:8081/sound/spectrum/sensor1?limit_amount=1" -H "accept: application/json"
+/// ```
+/// # Example response
+/// ```json
+/// [
+///  {
+///   "sensor_name": "sensor1",
+///   "time": { "secs_since_epoch": 1669026612, "nanos_since_epoch": 0 },
+///   "bandwidth": "octave",
+///   "frequencies": [63.0, 125.0, 250.0, 500.0],
+///   "levels": [58.2, 51.0, 44.5, 40.1],
+///   "weighting": "Z",
+///   "time_weighting": "leq",
+///   "reference": "spl"
+///  }
+/// ]
+/// ```
+async fn get_spectrum(
+    pool: web::Data<iot_sound_database::Pool>,
+    sensor_id: web::Path<String>,
+    info: web::Query<Info>,
+) -> impl Responder {
+    let returned = match pool
+        .get_spectrum_limited(&sensor_id, info.limit_amount)
+        .await
+    {
+        Ok(data) => data,
+        Err(e) => {
+            error!("Error: {}", e);
+            return HttpResponse::InternalServerError().body("Internal Server Error");
+        }
+    };
+    if returned.is_empty() {
+        HttpResponse::NotFound().body("No data found")
+    } else {
+        HttpResponse::Ok().json(returned)
+    }
+}
+
 /// the api call that returns all sensors stored in the database
 /// # Arguments
 /// * `pool` - the database pool
@@ -251,7 +459,46 @@ async fn get_sensors(pool: web::Data<iot_sound_database::Pool>) -> impl Responde
     let returned = match returned {
         Ok(data) => data,
         Err(e) => {
-            println!("Error: {}", e);
+            error!("Error: {}", e);
+            return HttpResponse::InternalServerError().body("Internal Server Error");
+        }
+    };
+    if returned.is_empty() {
+        HttpResponse::NotFound().body("No data found")
+    } else {
+        HttpResponse::Ok().json(returned)
+    }
+}
+
+/// the api call that returns whether each sensor is online, late or offline,
+/// when it was last seen and how often it is expected to report,
+/// with the connection status the sensor announced over MQTT
+/// # Arguments
+/// * `pool` - the database pool
+/// # Returns
+/// * `impl Responder` - the response
+/// # Example response
+/// ```json
+/// [
+///  {
+///   "sensor_id": "sensor1",
+///   "state": "online",
+///   "last_seen": { "secs_since_epoch": 1669026612, "nanos_since_epoch": 0 },
+///   "expected_interval_secs": 2.0,
+///   "changed_at": { "secs_since_epoch": 1669026000, "nanos_since_epoch": 0 },
+///   "connection": "online",
+///   "firmware_version": "0.1.0",
+///   "booted_at": { "secs_since_epoch": 1669020000, "nanos_since_epoch": 0 },
+///   "config_hash": "3f2a9c1d0b7e4a65"
+///  }
+/// ]
+/// ```
+async fn get_sensor_statuses(pool: web::Data<iot_sound_database::Pool>) -> impl Responder {
+    let returned = pool.get_sensor_statuses().await;
+    let returned = match returned {
+        Ok(data) => data,
+        Err(e) => {
+            error!("Error: {}", e);
             return HttpResponse::InternalServerError().body("Internal Server Error");
         }
     };
@@ -262,6 +509,165 @@ async fn get_sensors(pool: web::Data<iot_sound_database::Pool>) -> impl Responde
     }
 }
 
+/// the api call that returns all sensors waiting for approval,
+/// with the number of readings held for each
+/// # Arguments
+/// * `pool` - the database pool
+/// # Returns
+/// * `impl Responder` - the response
+async fn get_pending_sensors(pool: web::Data<iot_sound_database::Pool>) -> impl Responder {
+    let returned = pool.get_pending_sensors().await;
+    let returned = match returned {
+        Ok(data) => data,
+        Err(e) => {
+            error!("Error: {}", e);
+            return HttpResponse::InternalServerError().body("Internal Server Error");
+        }
+    };
+    if returned.is_empty() {
+        HttpResponse::NotFound().body("No data found")
+    } else {
+        HttpResponse::Ok().json(returned)
+    }
+}
+
+/// the api call that approves a sensor waiting for approval,
+/// its held readings are stored with the rest of the data
+/// # Arguments
+/// * `pool` - the database pool
+/// * `sensor_id` - the id of the sensor
+/// # Returns
+/// * `impl Responder` - the response
+/// # Errors
+/// * `NotFound` - If the sensor is not waiting for approval
+/// # Example call
+/// ```bash
+/// curl -X POST "http://localhost:8081/sensors/pending/sensor1/approve"
+/// ```
+async fn approve_pending_sensor(
+    pool: web::Data<iot_sound_database::Pool>,
+    sensor_id: web::Path<String>,
+) -> impl Responder {
+    match pool.approve_pending_sensor(&sensor_id).await {
+        Ok(true) => HttpResponse::Ok().body("Sensor approved"),
+        Ok(false) => HttpResponse::NotFound().body("Sensor is not waiting for approval"),
+        Err(e) => {
+            error!("Error: {}", e);
+            HttpResponse::InternalServerError().body("Internal Server Error")
+        }
+    }
+}
+
+/// the api call that rejects a sensor waiting for approval,
+/// its held readings are deleted and later readings are dropped
+/// # Arguments
+/// * `pool` - the database pool
+/// * `sensor_id` - the id of the sensor
+/// # Returns
+/// * `impl Responder` - the response
+/// # Errors
+/// * `NotFound` - If the sensor is not waiting for approval
+/// # Example call
+/// ```bash
+/// curl -X POST "http://localhost:8081/sensors/pending/sensor1/reject"
+/// ```
+async fn reject_pending_sensor(
+    pool: web::Data<iot_sound_database::Pool>,
+    sensor_id: web::Path<String>,
+) -> impl Responder {
+    match pool.reject_pending_sensor(&sensor_id).await {
+        Ok(true) => HttpResponse::Ok().body("Sensor rejected"),
+        Ok(false) => HttpResponse::NotFound().body("Sensor is not waiting for approval"),
+        Err(e) => {
+            error!("Error: {}", e);
+            HttpResponse::InternalServerError().body("Internal Server Error")
+        }
+    }
+}
+
+/// the api call that returns the config an operator wants a sensor to have,
+/// and the config the sensor last reported it is running with
+/// # Arguments
+/// * `pool` - the database pool
+/// * `sensor_id` - the id of the sensor
+/// # Returns
+/// * `impl Responder` - the response
+/// # Errors
+/// * `NotFound` - If the sensor has neither a desired nor a reported config
+/// # Example response
+/// ```json
+/// {
+///  "sensor_id": "sensor1",
+///  "desired": { "version": 2, "sample_interval_secs": 10, "reporting_mode": "on_change", ... },
+///  "reported": { "version": 1, "sample_interval_secs": 2, "reporting_mode": "periodic", ... },
+///  "reported_at": { "secs_since_epoch": 1669026612, "nanos_since_epoch": 0 },
+///  "in_sync": false
+/// }
+/// ```
+async fn get_sensor_config(
+    pool: web::Data<iot_sound_database::Pool>,
+    sensor_id: web::Path<String>,
+) -> impl Responder {
+    sensor_config_response(&pool, &sensor_id).await
+}
+
+/// Returns the desired and reported config of a sensor as the response
+/// # Arguments
+/// * `pool` - the database pool
+/// * `sensor_id` - the id of the sensor
+async fn sensor_config_response(pool: &iot_sound_database::Pool, sensor_id: &str) -> HttpResponse {
+    match pool.get_sensor_config_state(sensor_id).await {
+        Ok(Some(state)) => HttpResponse::Ok().json(state),
+        Ok(None) => HttpResponse::NotFound().body("No config found"),
+        Err(e) => {
+            error!("Error: {}", e);
+            HttpResponse::InternalServerError().body("Internal Server Error")
+        }
+    }
+}
+
+/// the api call that sets the config a sensor should have,
+/// the backend publishes it to the sensor
+/// # Arguments
+/// * `pool` - the database pool
+/// * `sensor_id` - the id of the sensor
+/// * `config` - the desired config, its version is set by the database
+/// # Returns
+/// * `impl Responder` - the desired and reported config of the sensor
+/// # Errors
+/// * `NotFound` - If the sensor is not registered
+/// * `BadRequest` - If the sensor can not apply the config
+/// # Example call
+/// ```bash
+/// curl -X PUT "http://localhost:8081/sensors/sensor1/config" -H "content-type: application/json" \
+///   -d '{"sample_interval_secs": 10, "reporting_mode": "on_change", "change_threshold_db": 3.0,
+///        "alert_threshold_db": 70.0, "max_report_interval_secs": 60}'
+/// ```
+async fn set_sensor_config(
+    pool: web::Data<iot_sound_database::Pool>,
+    sensor_id: web::Path<String>,
+    config: web::Json<iot_sound_database::SensorConfig>,
+) -> impl Responder {
+    if let Err(e) = config.validate() {
+        return HttpResponse::BadRequest().body(e);
+    }
+    match pool.get_sensor_ids().await {
+        Ok(sensors) if !sensors.contains(&sensor_id) => {
+            return HttpResponse::NotFound().body("Sensor not found")
+        }
+        Ok(_) => {}
+        Err(e) => {
+            error!("Error: {}", e);
+            return HttpResponse::InternalServerError().body("Internal Server Error");
+        }
+    }
+    if let Err(e) = pool.set_desired_sensor_config(&sensor_id, &config).await {
+        error!("Error: {}", e);
+        return HttpResponse::InternalServerError().body("Internal Server Error");
+    }
+    sensor_config_response(&pool, &sensor_id).await
+}
+
 /// This is the api call to get logged errors from the database
 /// # Arguments
 /// * `pool` - The database pool
@@ -272,7 +678,7 @@ async fn get_logs(pool: web::Data<iot_sound_database::Pool>) -> impl Responder {
     let returned = match returned {
         Ok(data) => data,
         Err(e) => {
-            println!("Error: {}", e);
+            error!("Error: {}", e);
             return HttpResponse::InternalServerError().body("Internal Server Error");
         }
     };
@@ -297,7 +703,7 @@ async fn get_logs_limited(
     let returned = match returned {
         Ok(data) => data,
         Err(e) => {
-            println!("Error: {}", e);
+            error!("Error: {}", e);
             return HttpResponse::InternalServerError().body("Internal Server Error");
         }
     };
@@ -321,6 +727,7 @@ async fn main() -> std::io::Result<()> {
         println!("Environment variables not set. Loading .env file");
         dotenv::dotenv().ok();
     }
+    iot_sound_telemetry::init();
 
     let pool = iot_sound_database::Pool::new(
         Some(env::var("DB_HOST").unwrap()),
@@ -336,18 +743,48 @@ async fn main() -> std::io::Result<()> {
         Err(e) => panic!("Error creating database pool: {}", e),
     };
 
-    println!("Starting API");
+    info!("Starting API");
     HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(pool.clone()))
+            .wrap_fn(|request, service| {
+                let span = tracing::info_span!(
+                    "request",
+                    method = %request.method(),
+                    path = %request.path()
+                );
+                let response = service.call(request);
+                async move {
+                    let response = response.await;
+                    if let Ok(response) = &response {
+                        info!(status = response.status().as_u16(), "Request handled");
+                    }
+                    response
+                }
+                .instrument(span)
+            })
             .route("/", web::get().to(index))
             .route("/sound", web::get().to(get_sound))
             .route("/sensors", web::get().to(get_sensors))
+            .route("/sensors/pending", web::get().to(get_pending_sensors))
+            .route("/sensors/status", web::get().to(get_sensor_statuses))
+            .route("/sensors/{id}/config", web::get().to(get_sensor_config))
+            .route("/sensors/{id}/config", web::put().to(set_sensor_config))
+            .route(
+                "/sensors/pending/{id}/approve",
+                web::post().to(approve_pending_sensor),
+            )
+            .route(
+                "/sensors/pending/{id}/reject",
+                web::post().to(reject_pending_sensor),
+            )
             .route("/sound/sorted", web::get().to(get_sound_sorted_by_sensor))
             .route(
                 "/sound/sorted/limit",
                 web::get().to(get_sound_sorted_by_sensor_limited),
             )
+            .route("/sound/aggregate", web::get().to(get_sound_aggregate))
+            .route("/sound/spectrum/{id}", web::get().to(get_spectrum))
             .route("/logs", web::get().to(get_logs))
             .route("/logs/limit", web::get().to(get_logs_limited))
             .wrap(Cors::permissive())
@@ -356,3 +793,156 @@ async fn main() -> std::io::Result<()> {
     .run()
     .await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iot_sound_wire::scale::{Reference, Weighting};
+    use std::time::SystemTime;
+
+    fn scale(weighting: Weighting, time_weighting: TimeWeighting) -> Scale {
+        Scale {
+            weighting,
+            time_weighting,
+            reference: Reference::Spl,
+        }
+    }
+
+    fn row(level: &str, scale: Scale) -> iot_sound_database::Data {
+        iot_sound_database::Data::new(
+            1,
+            level.to_string(),
+            "sensor-1".to_string(),
+            SystemTime::now(),
+            Vec::new(),
+            scale,
+        )
+    }
+
+    #[test]
+    fn aggregate_is_the_energy_average() {
+        let fast = scale(Weighting::A, TimeWeighting::Fast);
+        let aggregate = aggregate(&[row("50", fast), row("60", fast)], None).unwrap();
+        assert_eq!(
+            (
+                aggregate.weighting,
+                aggregate.time_weighting,
+                aggregate.reference
+            ),
+            ("A", "fast", "spl")
+        );
+        assert_eq!(aggregate.count, 2);
+        assert!((aggregate.leq - 57.4036).abs() < 0.001, "{}", aggregate.leq);
+        assert_eq!((aggregate.min, aggregate.max), (50.0, 60.0));
+    }
+
+    #[test]
+    fn aggregate_refuses_different_scales() {
+        let fast = scale(Weighting::A, TimeWeighting::Fast);
+        let slow = scale(Weighting::A, TimeWeighting::Slow);
+        let dbc = scale(Weighting::C, TimeWeighting::Fast);
+        let data = [row("50", fast), row("60", slow)];
+        assert_eq!(aggregate(&data, None).unwrap_err(), [fast, slow]);
+        let data = [row("50", fast), row("60", dbc)];
+        assert_eq!(
+            aggregate(&data, Some(TimeWeighting::Leq)).unwrap_err(),
+            [fast, dbc]
+        );
+    }
+
+    #[test]
+    fn aggregate_converts_fast_and_slow_to_leq() {
+        let data = [
+            row("50", scale(Weighting::A, TimeWeighting::Fast)),
+            row("60", scale(Weighting::A, TimeWeighting::Slow)),
+            row("55", scale(Weighting::A, TimeWeighting::Leq)),
+        ];
+        let leq = aggregate(&data, Some(TimeWeighting::Leq)).unwrap();
+        assert_eq!(leq.time_weighting, "leq");
+        assert_eq!(leq.count, 3);
+        // a level can not be converted to fast or slow
+        let leq = [row("55", scale(Weighting::A, TimeWeighting::Leq))];
+        assert!(aggregate(&leq, Some(TimeWeighting::Fast)).is_err());
+    }
+
+    #[test]
+    fn aggregate_of_nothing_has_no_scales() {
+        assert_eq!(aggregate(&[], None).unwrap_err(), []);
+        // rows that are not numbers are skipped
+        let fast = scale(Weighting::A, TimeWeighting::Fast);
+        assert_eq!(aggregate(&[row("loud", fast)], None).unwrap_err(), []);
+    }
+
+    /// A pool on the database in `TEST_DB_NAME`, which the tests write to, `None` if it is not set
+    async fn test_pool() -> Option<iot_sound_database::Pool> {
+        let dbname = env::var("TEST_DB_NAME").ok()?;
+        let var =
+            |name: &str, default: &str| env::var(name).unwrap_or_else(|_| default.to_string());
+        let pool = iot_sound_database::Pool::new(
+            Some(var("TEST_DB_HOST", "localhost")),
+            Some(var("TEST_DB_PORT", "5432").parse().unwrap()),
+            Some(var("TEST_DB_USER", "postgres")),
+            Some(var("TEST_DB_PASSWORD", "postgres")),
+            Some(dbname),
+        )
+        .await
+        .unwrap();
+        pool.create_sensor_table().await.unwrap();
+        pool.create_loudness_table().await.unwrap();
+        Some(pool)
+    }
+
+    #[actix_web::test]
+    async fn a_spectrum_does_not_count_twice_in_the_aggregate() {
+        let Some(pool) = test_pool().await else {
+            eprintln!("TEST_DB_NAME is not set, skipping");
+            return;
+        };
+        let sensor_id = format!("aggregate-{}", std::process::id());
+        pool.insert_new_sensor(&sensor_id, "loudness", "test")
+            .await
+            .unwrap();
+        let secs = SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let time = std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs);
+        let timestamp = iot_sound_wire::Timestamp::from_unix_secs(secs).unwrap();
+        let spectrum = iot_sound_wire::Spectrum::new(
+            iot_sound_wire::spectrum::Bandwidth::Octave,
+            1000.0,
+            &[70.0],
+            timestamp,
+        )
+        .unwrap();
+        let reading = |level: &str, spectrum| iot_sound_database::NewReading {
+            level: level.to_string(),
+            time,
+            flags: Vec::new(),
+            scale: Scale::default(),
+            spectrum,
+            fields: Default::default(),
+        };
+        let readings = [reading("50", None), reading("70", Some(spectrum))];
+        assert!(pool
+            .insert_loudness_data_batch(&sensor_id, &readings, None)
+            .await
+            .unwrap());
+
+        // both are stored, the spectrum is only returned as a spectrum
+        assert_eq!(
+            pool.get_spectrum_limited(&sensor_id, 10)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+        let data = pool.get_loudness_limited(&sensor_id, 10).await.unwrap();
+        let aggregate = aggregate(&data, None).unwrap();
+        assert_eq!(aggregate.count, 1);
+        assert_eq!((aggregate.leq, aggregate.max), (50.0, 50.0));
+        let all = pool.get_loudness().await.unwrap();
+        let stored = all.iter().filter(|row| row.get_sensor_name() == sensor_id);
+        assert_eq!(stored.count(), 1);
+    }
+}
@@ -0,0 +1,110 @@
+use iot_sound_backend::auth::{self, KeyStore, SensorKey};
+use std::env;
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+
+const USAGE: &str = "Usage:
+    iot_sound_keys provision <sensor id>          Create the first key of a sensor
+    iot_sound_keys rotate <sensor id>             Add a new key, the old keys stay valid
+    iot_sound_keys retire <sensor id> <key id>    Remove a key once the sensor uses a newer one
+    iot_sound_keys list                           List the key ids of every sensor
+
+The keys file is read from SENSOR_KEYS_FILE.";
+
+/// Manages the keys file the backend uses to authenticate sensors
+///
+/// A sensor is rotated to a new key without losing readings by running `rotate`,
+/// configuring the sensor with the printed key, and running `retire` for the old key.
+/// The backend picks up changes to the keys file without a restart.
+fn main() {
+    if let Err(e) = run(env::args().skip(1).collect()) {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run(args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    dotenv::dotenv().ok();
+    let path = env::var("SENSOR_KEYS_FILE").map_err(|_| "SENSOR_KEYS_FILE must be set")?;
+    let mut keys: KeyStore = if fs::metadata(&path).is_ok() {
+        auth::load_keys(&path)?
+    } else {
+        KeyStore::new()
+    };
+
+    let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+    match args.as_slice() {
+        ["provision", sensor_id] => {
+            if keys.contains_key(*sensor_id) {
+                return Err(format!("Sensor {} already has a key, use rotate", sensor_id).into());
+            }
+            let key = SensorKey::generate(1);
+            print_sensor_config(&key);
+            keys.insert(sensor_id.to_string(), vec![key]);
+        }
+        ["rotate", sensor_id] => {
+            let sensor_keys = keys
+                .get_mut(*sensor_id)
+                .ok_or_else(|| format!("Sensor {} has no key, use provision", sensor_id))?;
+            let id = sensor_keys.iter().map(|key| key.id).max().unwrap_or(0) + 1;
+            let key = SensorKey::generate(id);
+            print_sensor_config(&key);
+            sensor_keys.push(key);
+        }
+        ["retire", sensor_id, key_id] => {
+            let key_id = key_id.parse::<u32>()?;
+            let sensor_keys = keys
+                .get_mut(*sensor_id)
+                .ok_or_else(|| format!("Sensor {} has no key", sensor_id))?;
+            if !sensor_keys.iter().any(|key| key.id == key_id) {
+                return Err(format!("Sensor {} has no key {}", sensor_id, key_id).into());
+            }
+            if sensor_keys.len() == 1 {
+                return Err(format!(
+                    "Key {} is the only key of sensor {}, rotate it first",
+                    key_id, sensor_id
+                )
+                .into());
+            }
+            sensor_keys.retain(|key| key.id != key_id);
+            println!("Retired key {} of sensor {}", key_id, sensor_id);
+        }
+        ["list"] => {
+            let mut sensor_ids: Vec<&String> = keys.keys().collect();
+            sensor_ids.sort();
+            for sensor_id in sensor_ids {
+                let ids: Vec<String> = keys[sensor_id].iter().map(|k| k.id.to_string()).collect();
+                println!("{}: {}", sensor_id, ids.join(", "));
+            }
+            return Ok(());
+        }
+        _ => return Err(USAGE.into()),
+    }
+    save_keys(&path, &keys)
+}
+
+/// Print the env variables the sensor needs to use the key
+fn print_sensor_config(key: &SensorKey) {
+    println!("Add the following to the env of the sensor:");
+    println!("SENSOR_KEY_ID={}", key.id);
+    println!("SENSOR_KEY={}", key.key);
+}
+
+/// Write the keys file, only readable by the owner, replacing it in one step
+/// so the backend never reads a partially written file
+fn save_keys(path: &str, keys: &KeyStore) -> Result<(), Box<dyn Error>> {
+    let tmp_path = format!("{}.tmp", path);
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&tmp_path)?;
+    file.write_all(serde_json::to_string_pretty(keys)?.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
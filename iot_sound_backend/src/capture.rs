@@ -0,0 +1,90 @@
+use crate::spool::SpooledMessage;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use tracing::warn;
+
+/// Records every message received from the broker, so the traffic can be
+/// replayed later with `iot_sound_backend replay`.
+///
+/// Messages are appended to the file as json lines, in the same format as the spool,
+/// so a spool file can be replayed as well.
+pub struct Capture {
+    file: File,
+}
+
+impl Capture {
+    /// Open the capture file, appending to it if it exists
+    /// # Arguments
+    /// * `path` - Path to the capture file
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Capture { file })
+    }
+
+    /// Append a message to the capture file
+    /// # Arguments
+    /// * `message` - The message as received
+    pub fn record(&mut self, message: &SpooledMessage) -> io::Result<()> {
+        let mut line = serde_json::to_string(message)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())
+    }
+}
+
+/// The messages read from a capture or spool file
+pub struct Captured {
+    /// The messages with their line in the file, starting at 1
+    pub messages: Vec<(usize, SpooledMessage)>,
+    /// The number of lines that could not be read
+    pub skipped: usize,
+}
+
+/// Read the messages in a capture or spool file, skipping lines that are not
+/// a message, such as a partially written last line from a crash
+/// # Arguments
+/// * `path` - Path to the file
+pub fn read(path: impl AsRef<Path>) -> io::Result<Captured> {
+    let file = File::open(path)?;
+    let mut captured = Captured {
+        messages: Vec::new(),
+        skipped: 0,
+    };
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        match serde_json::from_str::<SpooledMessage>(&line?) {
+            Ok(message) => captured.messages.push((index + 1, message)),
+            Err(e) => {
+                warn!("Skipping unreadable line {}: {}", index + 1, e);
+                captured.skipped += 1;
+            }
+        }
+    }
+    Ok(captured)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn unreadable_lines_are_skipped_and_counted() {
+        let dir = std::env::temp_dir().join(format!("capture-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("capture.jsonl");
+
+        let mut capture = Capture::open(&path).unwrap();
+        let message = SpooledMessage::new("topic".to_string(), vec![1], UNIX_EPOCH);
+        capture.record(&message).unwrap();
+        capture.file.write_all(b"not json\n").unwrap();
+        capture.record(&message).unwrap();
+        capture.file.write_all(b"{\"topic\":\"par").unwrap();
+
+        let captured = read(&path).unwrap();
+        let lines: Vec<usize> = captured.messages.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, [1, 3]);
+        assert_eq!(captured.skipped, 2);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
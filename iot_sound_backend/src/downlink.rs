@@ -0,0 +1,124 @@
+use iot_sound_backend::auth::{Channel, KeysFile};
+use iot_sound_backend::sensor_config::{self, SensorConfig};
+use iot_sound_database::{Pool, Sensor};
+use rumqttc::{AsyncClient, QoS};
+use std::error::Error;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Topic sensors report the config they applied on, see `sensor_config::reported_topic`
+pub const REPORTED_CONFIG_TOPIC: &str = "ntnu/+/+/+/group06/+/config/reported";
+
+/// How often the database is checked for changed configs
+const PUBLISH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Publishes the desired config of a sensor every time it is changed through the API
+///
+/// Configs are published retained, so a sensor that is offline gets its
+/// latest config as soon as it subscribes. Configs for sensors with a key are signed
+/// with the version as counter, so the sensor only applies configs from the backend
+/// and never goes back to an older version.
+///
+/// # Arguments
+/// * `db_pool` - The database pool
+/// * `client` - The MQTT client
+/// * `keys_file` - The keys to sign configs with
+pub async fn publish_desired_configs(db_pool: Pool, client: AsyncClient, keys_file: KeysFile) {
+    let mut interval = tokio::time::interval(PUBLISH_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = publish_changed(&db_pool, &client, &keys_file).await {
+            warn!("Error publishing sensor configs: {}", e);
+        }
+    }
+}
+
+/// Publish every desired config that has not been published yet
+async fn publish_changed(
+    db_pool: &Pool,
+    client: &AsyncClient,
+    keys_file: &KeysFile,
+) -> Result<(), Box<dyn Error>> {
+    for (sensor, config) in db_pool.get_unpublished_sensor_configs().await? {
+        let topic = sensor_config::config_topic(&sensor_topic(&sensor));
+        let payload = keys_file.sign(
+            Channel::Config,
+            sensor.get_id(),
+            config.version as u64,
+            &serde_json::to_string(&config)?,
+        )?;
+        client
+            .publish(&topic, QoS::AtLeastOnce, true, payload)
+            .await?;
+        db_pool
+            .mark_sensor_config_published(sensor.get_id(), config.version)
+            .await?;
+        info!(
+            db_log = true,
+            "Published config version {} to sensor {}",
+            config.version,
+            sensor.get_id()
+        );
+    }
+    Ok(())
+}
+
+/// Returns the topic a registered sensor publishes its readings to
+fn sensor_topic(sensor: &Sensor) -> String {
+    format!(
+        "{}/{}/group06/{}",
+        sensor.get_location(),
+        sensor.get_type(),
+        sensor.get_id()
+    )
+}
+
+/// Store the config a sensor reported it applied
+///
+/// Reports from sensors with a key must be signed by the sensor, and are only stored
+/// if their counter is higher than the one of the stored report.
+///
+/// # Arguments
+/// * `db_pool` - The database pool
+/// * `keys_file` - The keys to verify the report with
+/// * `topic` - The reported config topic the message was received on
+/// * `payload` - The config as json
+pub async fn record_reported_config(
+    db_pool: &Pool,
+    keys_file: &KeysFile,
+    topic: &str,
+    payload: &[u8],
+) -> Result<(), Box<dyn Error>> {
+    let sensor_id = sensor_config::sensor_topic_of_report(topic)
+        .and_then(|sensor_topic| sensor_topic.rsplit('/').next())
+        .ok_or_else(|| format!("Not a reported config topic: {}", topic))?;
+    let (message, counter) = keys_file
+        .verify(Channel::ReportedConfig, sensor_id, payload)
+        .map_err(|e| {
+            format!(
+                "Unauthenticated config report from sensor {}: {}",
+                sensor_id, e
+            )
+        })?;
+    let config: SensorConfig = serde_json::from_str(message)
+        .map_err(|e| format!("Invalid config reported by sensor {}: {}", sensor_id, e))?;
+    let counter = counter
+        .map(i64::try_from)
+        .transpose()
+        .map_err(|_| format!("Invalid counter in config report from sensor {}", sensor_id))?;
+    if !db_pool
+        .update_reported_sensor_config(sensor_id, &config, counter)
+        .await?
+    {
+        warn!(
+            "Ignoring config report from sensor {}: a newer report is stored",
+            sensor_id
+        );
+        return Ok(());
+    }
+    info!(
+        db_log = true,
+        "Sensor {} is running config version {}", sensor_id, config.version
+    );
+    Ok(())
+}
@@ -0,0 +1,660 @@
+use crate::metrics::Metrics;
+use iot_sound_database::{Pool, ReportingMode, SensorConfig, SensorStatus};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// How often the monitor checks the sensors
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// A sensor is late when no reading has arrived for this many expected intervals
+const LATE_AFTER_INTERVALS: f64 = 3.0;
+/// A sensor is offline when no reading has arrived for this many expected intervals
+const OFFLINE_AFTER_INTERVALS: f64 = 10.0;
+/// Expected interval used until enough readings have been seen to infer one
+const DEFAULT_INTERVAL_SECS: f64 = 10.0;
+/// Number of intervals that have to be seen before the inferred interval is used
+const MIN_SAMPLES: u32 = 3;
+/// Weight of a new interval sample in the moving average
+const SMOOTHING: f64 = 0.1;
+/// How long a backend keeps deciding the sensor states without renewing its lease
+const LEASE: Duration = Duration::from_secs(15);
+/// How old a last seen time written by another backend can be when it is read,
+/// it is written and read once per check
+const SYNC_LAG: Duration = Duration::from_secs(10);
+
+/// Liveness state of a sensor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SensorState {
+    Online,
+    Late,
+    Offline,
+}
+
+impl SensorState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SensorState::Online => "online",
+            SensorState::Late => "late",
+            SensorState::Offline => "offline",
+        }
+    }
+
+    fn from_str(state: &str) -> Self {
+        match state {
+            "online" => SensorState::Online,
+            "late" => SensorState::Late,
+            _ => SensorState::Offline,
+        }
+    }
+}
+
+impl fmt::Display for SensorState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// What the monitor knows about a single sensor
+struct Tracked {
+    state: SensorState,
+    /// Time the last reading was received
+    last_seen: SystemTime,
+    /// Timestamp of the last reading, used to infer the interval
+    last_timestamp: Option<SystemTime>,
+    /// Moving average of the time between readings, in seconds
+    interval_secs: f64,
+    samples: u32,
+    /// Whether the sensor announced that it is offline, through its last will
+    disconnected: bool,
+    /// Whether `last_seen` was read from the database, written by another backend
+    remote: bool,
+    /// Longest time between reports of a sensor that does not publish every sample,
+    /// from the config it reported
+    report_interval_secs: Option<f64>,
+    /// Whether there is something new to write to the database
+    dirty: bool,
+}
+
+impl Tracked {
+    fn new(last_seen: SystemTime) -> Self {
+        Tracked {
+            state: SensorState::Online,
+            last_seen,
+            last_timestamp: None,
+            interval_secs: DEFAULT_INTERVAL_SECS,
+            samples: 0,
+            disconnected: false,
+            remote: false,
+            report_interval_secs: None,
+            dirty: true,
+        }
+    }
+
+    /// Returns how often the sensor is expected to report, in seconds
+    /// # Arguments
+    /// * `configured` - Expected interval for all sensors, inferred from readings if `None`
+    fn expected_interval(&self, configured: Option<Duration>) -> f64 {
+        match (self.report_interval_secs, configured) {
+            (Some(interval), _) => interval,
+            (None, Some(interval)) => interval.as_secs_f64(),
+            (None, None) if self.samples >= MIN_SAMPLES => self.interval_secs,
+            (None, None) => DEFAULT_INTERVAL_SECS,
+        }
+    }
+}
+
+/// Tracks the last reading time of every sensor and decides whether it is
+/// online, late or offline based on how often it normally reports.
+/// A sensor that announced it is offline is offline until it is back.
+///
+/// Backends sharing a subscription each see some of the readings, so they merge
+/// what the others wrote to the database, and only the one holding the lease
+/// decides the states.
+pub struct Liveness {
+    sensors: HashMap<String, Tracked>,
+    configured_interval: Option<Duration>,
+}
+
+impl Liveness {
+    /// Create a new Liveness tracker
+    /// # Arguments
+    /// * `configured_interval` - Expected interval for all sensors, inferred from readings if `None`
+    pub fn new(configured_interval: Option<Duration>) -> Self {
+        Liveness {
+            sensors: HashMap::new(),
+            configured_interval,
+        }
+    }
+
+    /// Load the state stored by a previous run, so a restart does not reset the interval
+    /// # Arguments
+    /// * `db_pool` - The database pool
+    pub async fn load(&mut self, db_pool: &Pool) -> Result<(), Box<dyn Error>> {
+        for status in db_pool.get_sensor_statuses().await? {
+            self.sensors.insert(
+                status.get_sensor_id().to_string(),
+                Tracked {
+                    state: SensorState::from_str(status.get_state()),
+                    last_seen: status.get_last_seen(),
+                    last_timestamp: None,
+                    interval_secs: status.get_expected_interval_secs(),
+                    samples: MIN_SAMPLES,
+                    disconnected: status.get_connection() == Some("offline"),
+                    remote: false,
+                    report_interval_secs: None,
+                    dirty: false,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    /// Merge the statuses in the database, which other backends sharing the subscription
+    /// may have updated. Last seen times only move forward, and a sensor is disconnected
+    /// if its last announcement was offline and nothing was seen from it since.
+    /// # Arguments
+    /// * `statuses` - The statuses in the database
+    /// * `decides` - Whether this backend decides the states, if not they are taken as stored
+    fn merge(&mut self, statuses: &[SensorStatus], decides: bool) {
+        for status in statuses {
+            let tracked = self
+                .sensors
+                .entry(status.get_sensor_id().to_string())
+                .or_insert_with(|| Tracked {
+                    state: SensorState::from_str(status.get_state()),
+                    last_seen: status.get_last_seen(),
+                    last_timestamp: None,
+                    interval_secs: status.get_expected_interval_secs(),
+                    samples: MIN_SAMPLES,
+                    disconnected: false,
+                    remote: true,
+                    report_interval_secs: None,
+                    dirty: false,
+                });
+            if status.get_last_seen() > tracked.last_seen {
+                tracked.last_seen = status.get_last_seen();
+                tracked.remote = true;
+            }
+            if let Some(changed_at) = status.get_connection_changed_at() {
+                if changed_at >= tracked.last_seen {
+                    tracked.disconnected = status.get_connection() == Some("offline");
+                }
+            }
+            if !decides {
+                tracked.state = SensorState::from_str(status.get_state());
+            }
+        }
+    }
+
+    /// Take the reporting of sensors from the configs they reported. A sensor that only
+    /// publishes changes or levels above a threshold is expected every
+    /// `max_report_interval_secs`, a sensor that publishes every sample as often as
+    /// it is configured or seen to.
+    /// # Arguments
+    /// * `configs` - The configs the sensors reported they applied
+    fn set_reporting(&mut self, configs: &[(String, SensorConfig)]) {
+        for (sensor_id, config) in configs {
+            if let Some(tracked) = self.sensors.get_mut(sensor_id) {
+                let report_interval_secs = match config.reporting_mode {
+                    ReportingMode::Periodic => None,
+                    ReportingMode::OnChange | ReportingMode::Threshold => {
+                        Some(config.max_report_interval_secs as f64)
+                    }
+                };
+                if tracked.report_interval_secs != report_interval_secs {
+                    tracked.report_interval_secs = report_interval_secs;
+                    tracked.dirty = true;
+                }
+            }
+        }
+    }
+
+    /// Record a reading from a sensor
+    /// # Arguments
+    /// * `sensor_id` - The id of the sensor
+    /// * `timestamp` - The timestamp of the reading
+    /// * `received` - The time the reading was received
+    pub fn record(&mut self, sensor_id: &str, timestamp: SystemTime, received: SystemTime) {
+        let tracked = self
+            .sensors
+            .entry(sensor_id.to_string())
+            .or_insert_with(|| Tracked::new(received));
+        tracked.disconnected = false;
+        tracked.remote = false;
+
+        if let Some(last_timestamp) = tracked.last_timestamp {
+            if let Ok(delta) = timestamp.duration_since(last_timestamp) {
+                let delta = delta.as_secs_f64();
+                // gaps where the sensor was down are not part of its normal interval
+                let is_gap = tracked.samples >= MIN_SAMPLES
+                    && delta > tracked.interval_secs * LATE_AFTER_INTERVALS;
+                if delta > 0.0 && !is_gap {
+                    tracked.interval_secs = if tracked.samples == 0 {
+                        delta
+                    } else {
+                        tracked.interval_secs * (1.0 - SMOOTHING) + delta * SMOOTHING
+                    };
+                    tracked.samples += 1;
+                }
+            }
+        }
+        match tracked.last_timestamp {
+            Some(last) if last >= timestamp => {}
+            _ => tracked.last_timestamp = Some(timestamp),
+        }
+        if received > tracked.last_seen {
+            tracked.last_seen = received;
+        }
+        tracked.dirty = true;
+    }
+
+    /// Record the connection status a sensor announced
+    /// # Arguments
+    /// * `sensor_id` - The id of the sensor
+    /// * `online` - Whether the sensor connected or lost its connection
+    /// * `received` - The time the status was received
+    pub fn record_connection(&mut self, sensor_id: &str, online: bool, received: SystemTime) {
+        let tracked = self
+            .sensors
+            .entry(sensor_id.to_string())
+            .or_insert_with(|| Tracked::new(received));
+        tracked.disconnected = !online;
+        if online && received > tracked.last_seen {
+            tracked.last_seen = received;
+            tracked.remote = false;
+        }
+        tracked.dirty = true;
+    }
+
+    /// Update the state of every sensor
+    /// Returns the sensors whose state changed, with their old state
+    /// # Arguments
+    /// * `now` - The current time
+    fn check(&mut self, now: SystemTime) -> Vec<(String, SensorState, SensorState)> {
+        let mut transitions = Vec::new();
+        for (sensor_id, tracked) in self.sensors.iter_mut() {
+            let interval = tracked.expected_interval(self.configured_interval);
+            let mut silent = now.duration_since(tracked.last_seen).unwrap_or_default();
+            if tracked.remote {
+                silent = silent.saturating_sub(SYNC_LAG);
+            }
+            let silent = silent.as_secs_f64();
+            let state = if tracked.disconnected || silent > interval * OFFLINE_AFTER_INTERVALS {
+                SensorState::Offline
+            } else if silent > interval * LATE_AFTER_INTERVALS {
+                SensorState::Late
+            } else {
+                SensorState::Online
+            };
+            if state != tracked.state {
+                transitions.push((sensor_id.clone(), tracked.state, state));
+                tracked.state = state;
+                tracked.dirty = true;
+            }
+        }
+        transitions
+    }
+
+    /// Returns the number of sensors that are online
+    fn online(&self) -> usize {
+        self.sensors
+            .values()
+            .filter(|tracked| tracked.state == SensorState::Online)
+            .count()
+    }
+
+    /// Returns the sensors that changed since the last call, as
+    /// (sensor id, state, last seen, expected interval in seconds)
+    fn take_dirty(&mut self) -> Vec<(String, SensorState, SystemTime, f64)> {
+        let configured = self.configured_interval;
+        self.sensors
+            .iter_mut()
+            .filter(|(_, tracked)| tracked.dirty)
+            .map(|(sensor_id, tracked)| {
+                tracked.dirty = false;
+                (
+                    sensor_id.clone(),
+                    tracked.state,
+                    tracked.last_seen,
+                    tracked.expected_interval(configured),
+                )
+            })
+            .collect()
+    }
+}
+
+/// Periodically checks the liveness of all sensors, logs state transitions
+/// and stores the current status of each sensor in the database.
+///
+/// With several backends only the one holding the lease decides the states and
+/// logs the transitions, the others write when they last saw their sensors.
+///
+/// # Arguments
+/// * `db_pool` - The database pool
+/// * `liveness` - The liveness tracker shared with the ingestion path
+/// * `metrics` - Metrics of the ingestion path
+pub async fn monitor(db_pool: Pool, liveness: Arc<Mutex<Liveness>>, metrics: Metrics) {
+    let backend_id = Uuid::new_v4().to_string();
+    let mut decides = false;
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let decided = decides;
+        match db_pool
+            .claim_liveness_lease(&backend_id, LEASE.as_secs_f64())
+            .await
+        {
+            Ok(claimed) if claimed != decides => {
+                decides = claimed;
+                if decides {
+                    info!("This backend now decides the liveness state of the sensors");
+                }
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Error claiming the liveness lease: {}", e),
+        }
+        let statuses = match db_pool.get_sensor_statuses().await {
+            Ok(statuses) => statuses,
+            Err(e) => {
+                warn!("Error reading sensor statuses from database: {}", e);
+                Vec::new()
+            }
+        };
+
+        let configs = match db_pool.get_reported_sensor_configs().await {
+            Ok(configs) => configs,
+            Err(e) => {
+                warn!("Error reading sensor configs from database: {}", e);
+                Vec::new()
+            }
+        };
+        let (transitions, dirty) = {
+            let mut liveness = liveness.lock().expect("Liveness lock poisoned");
+            // a backend that just took over starts from the states the last one decided
+            liveness.merge(&statuses, decides && decided);
+            liveness.set_reporting(&configs);
+            let transitions = if decides {
+                liveness.check(SystemTime::now())
+            } else {
+                Vec::new()
+            };
+            metrics.sensors_online.set(liveness.online() as i64);
+            (transitions, liveness.take_dirty())
+        };
+
+        for (sensor_id, from, to) in transitions {
+            info!(
+                db_log = true,
+                "Sensor {} changed from {} to {}", sensor_id, from, to
+            );
+        }
+        for (sensor_id, state, last_seen, interval_secs) in dirty {
+            let result = if decides {
+                db_pool
+                    .upsert_sensor_status(&sensor_id, state.as_str(), last_seen, interval_secs)
+                    .await
+            } else {
+                db_pool
+                    .upsert_sensor_last_seen(&sensor_id, last_seen, interval_secs)
+                    .await
+            };
+            if let Err(e) = result {
+                warn!("Error updating sensor status in database: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const START: Duration = Duration::from_secs(1_700_000_000);
+
+    fn at(secs: f64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + START + Duration::from_secs_f64(secs)
+    }
+
+    /// Records a reading that is received at the time it was taken
+    fn record(liveness: &mut Liveness, secs: f64) {
+        liveness.record("sensor", at(secs), at(secs));
+    }
+
+    fn state(liveness: &Liveness) -> SensorState {
+        liveness.sensors["sensor"].state
+    }
+
+    #[test]
+    fn silent_sensors_go_late_then_offline() {
+        let mut liveness = Liveness::new(None);
+        for secs in [0.0, 10.0, 20.0, 30.0] {
+            record(&mut liveness, secs);
+        }
+        assert!(liveness.check(at(60.0)).is_empty());
+        assert_eq!(state(&liveness), SensorState::Online);
+
+        assert_eq!(
+            liveness.check(at(61.0)),
+            vec![("sensor".to_string(), SensorState::Online, SensorState::Late)]
+        );
+        assert!(liveness.check(at(130.0)).is_empty());
+        assert_eq!(
+            liveness.check(at(131.0)),
+            vec![(
+                "sensor".to_string(),
+                SensorState::Late,
+                SensorState::Offline
+            )]
+        );
+
+        record(&mut liveness, 200.0);
+        assert_eq!(
+            liveness.check(at(200.0)),
+            vec![(
+                "sensor".to_string(),
+                SensorState::Offline,
+                SensorState::Online
+            )]
+        );
+        assert_eq!(liveness.online(), 1);
+    }
+
+    #[test]
+    fn the_interval_is_a_moving_average() {
+        let mut liveness = Liveness::new(None);
+        for secs in [0.0, 10.0, 20.0, 30.0] {
+            record(&mut liveness, secs);
+        }
+        let tracked = &liveness.sensors["sensor"];
+        assert_eq!(tracked.samples, MIN_SAMPLES);
+        assert_eq!(tracked.interval_secs, 10.0);
+
+        record(&mut liveness, 32.0);
+        let interval = liveness.sensors["sensor"].interval_secs;
+        assert!((interval - 9.2).abs() < 1e-9, "{}", interval);
+
+        // a gap while the sensor was down and a reading out of order are no samples
+        record(&mut liveness, 132.0);
+        record(&mut liveness, 131.0);
+        let tracked = &liveness.sensors["sensor"];
+        assert!((tracked.interval_secs - 9.2).abs() < 1e-9);
+        assert_eq!(tracked.samples, MIN_SAMPLES + 1);
+    }
+
+    #[test]
+    fn the_default_interval_is_used_until_enough_samples() {
+        let mut liveness = Liveness::new(None);
+        for secs in [0.0, 1.0] {
+            record(&mut liveness, secs);
+        }
+        // one sample of 1 s, the sensor is judged by the default interval of 10 s
+        liveness.check(at(1.0 + 3.0 * DEFAULT_INTERVAL_SECS));
+        assert_eq!(state(&liveness), SensorState::Online);
+        liveness.check(at(2.0 + 3.0 * DEFAULT_INTERVAL_SECS));
+        assert_eq!(state(&liveness), SensorState::Late);
+    }
+
+    /// Returns a status as stored by another backend
+    fn status(state: &str, last_seen: f64, connection: Option<(&str, f64)>) -> SensorStatus {
+        let time = |secs: f64| {
+            let since_epoch = at(secs).duration_since(SystemTime::UNIX_EPOCH).unwrap();
+            serde_json::json!({
+                "secs_since_epoch": since_epoch.as_secs(),
+                "nanos_since_epoch": since_epoch.subsec_nanos(),
+            })
+        };
+        serde_json::from_value(serde_json::json!({
+            "sensor_id": "sensor",
+            "state": state,
+            "last_seen": time(last_seen),
+            "expected_interval_secs": 10.0,
+            "changed_at": time(0.0),
+            "connection": connection.map(|(connection, _)| connection),
+            "connection_changed_at": connection.map(|(_, secs)| time(secs)),
+            "firmware_version": null,
+            "booted_at": null,
+            "config_hash": null,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn readings_seen_by_other_backends_keep_a_sensor_online() {
+        let mut liveness = Liveness::new(None);
+        record(&mut liveness, 0.0);
+        liveness.merge(&[status("online", 100.0, None)], true);
+        // silent for 40s, but the last seen time may be up to 10s old when it is read
+        liveness.check(at(140.0));
+        assert_eq!(state(&liveness), SensorState::Online);
+        liveness.check(at(141.0));
+        assert_eq!(state(&liveness), SensorState::Late);
+
+        // an older time from the database does not move the last seen time back
+        record(&mut liveness, 150.0);
+        liveness.merge(&[status("late", 100.0, None)], true);
+        liveness.check(at(150.0));
+        assert_eq!(state(&liveness), SensorState::Online);
+    }
+
+    #[test]
+    fn announcements_are_merged_unless_the_sensor_was_seen_since() {
+        let mut liveness = Liveness::new(None);
+        record(&mut liveness, 0.0);
+        liveness.merge(&[status("online", 0.0, Some(("offline", 5.0)))], true);
+        liveness.check(at(6.0));
+        assert_eq!(state(&liveness), SensorState::Offline);
+
+        record(&mut liveness, 10.0);
+        liveness.merge(&[status("offline", 0.0, Some(("offline", 5.0)))], true);
+        liveness.check(at(10.0));
+        assert_eq!(state(&liveness), SensorState::Online);
+    }
+
+    #[test]
+    fn backends_that_do_not_decide_take_the_stored_state() {
+        let mut liveness = Liveness::new(None);
+        record(&mut liveness, 0.0);
+        liveness.merge(&[status("late", 0.0, None)], false);
+        assert_eq!(state(&liveness), SensorState::Late);
+        assert_eq!(liveness.online(), 0);
+    }
+
+    #[test]
+    fn a_configured_interval_overrides_the_inferred_one() {
+        let mut liveness = Liveness::new(Some(Duration::from_secs(60)));
+        for secs in [0.0, 10.0, 20.0, 30.0] {
+            record(&mut liveness, secs);
+        }
+        liveness.check(at(200.0));
+        assert_eq!(state(&liveness), SensorState::Online);
+        liveness.check(at(211.0));
+        assert_eq!(state(&liveness), SensorState::Late);
+
+        let dirty = liveness.take_dirty();
+        assert_eq!(dirty.len(), 1);
+        assert_eq!(dirty[0].3, 60.0);
+        assert!(liveness.take_dirty().is_empty());
+    }
+
+    #[test]
+    fn sensors_reporting_changes_are_expected_every_max_report_interval() {
+        let mut liveness = Liveness::new(Some(Duration::from_secs(10)));
+        record(&mut liveness, 0.0);
+        let config = |reporting_mode| {
+            vec![(
+                "sensor".to_string(),
+                SensorConfig {
+                    reporting_mode,
+                    max_report_interval_secs: 300,
+                    ..SensorConfig::default()
+                },
+            )]
+        };
+        liveness.set_reporting(&config(ReportingMode::OnChange));
+        liveness.check(at(900.0));
+        assert_eq!(state(&liveness), SensorState::Online);
+        liveness.check(at(901.0));
+        assert_eq!(state(&liveness), SensorState::Late);
+        assert_eq!(liveness.take_dirty()[0].3, 300.0);
+
+        liveness.set_reporting(&config(ReportingMode::Periodic));
+        liveness.check(at(901.0));
+        assert_eq!(state(&liveness), SensorState::Offline);
+        assert_eq!(liveness.take_dirty()[0].3, 10.0);
+    }
+
+    #[test]
+    fn a_last_will_takes_a_sensor_offline_until_it_is_back() {
+        let mut liveness = Liveness::new(None);
+        for secs in [0.0, 10.0, 20.0, 30.0] {
+            record(&mut liveness, secs);
+        }
+        liveness.record_connection("sensor", false, at(35.0));
+        assert_eq!(
+            liveness.check(at(36.0)),
+            vec![(
+                "sensor".to_string(),
+                SensorState::Online,
+                SensorState::Offline
+            )]
+        );
+
+        // a birth message counts as being seen
+        liveness.record_connection("sensor", true, at(300.0));
+        assert_eq!(liveness.sensors["sensor"].last_seen, at(300.0));
+        assert_eq!(
+            liveness.check(at(301.0)),
+            vec![(
+                "sensor".to_string(),
+                SensorState::Offline,
+                SensorState::Online
+            )]
+        );
+
+        // so does a reading after a last will
+        liveness.record_connection("sensor", false, at(310.0));
+        liveness.check(at(311.0));
+        assert_eq!(state(&liveness), SensorState::Offline);
+        record(&mut liveness, 320.0);
+        liveness.check(at(321.0));
+        assert_eq!(state(&liveness), SensorState::Online);
+    }
+
+    #[test]
+    fn a_birth_message_from_an_unknown_sensor_tracks_it() {
+        let mut liveness = Liveness::new(None);
+        liveness.record_connection("sensor", true, at(0.0));
+        assert!(liveness.check(at(1.0)).is_empty());
+        assert_eq!(state(&liveness), SensorState::Online);
+        assert_eq!(liveness.take_dirty().len(), 1);
+
+        // a late birth message does not move the last seen time back
+        liveness.record_connection("sensor", true, at(50.0));
+        liveness.record_connection("sensor", true, at(40.0));
+        assert_eq!(liveness.sensors["sensor"].last_seen, at(50.0));
+    }
+}
@@ -1,14 +1,46 @@
-use bytes::Bytes;
-use iot_sound_backend::loudness_data::LoudnessData;
+mod capture;
+mod downlink;
+mod liveness;
+mod metrics;
+mod mqtt;
+mod pipeline;
+mod presence;
+mod registration;
+mod replay;
+mod spool;
+mod stages;
+mod telemetry;
+mod validation;
+
+use capture::Capture;
+use iot_sound_backend::auth::KeysFile;
+use iot_sound_backend::{sensor_config, status};
 use iot_sound_database::{self, Pool};
-use rumqttc::{AsyncClient, ClientError, MqttOptions, QoS};
+use liveness::Liveness;
+use metrics::Metrics;
+use mqtt::{Acker, Backoff};
+use pipeline::Pipeline;
+use registration::RegistrationPolicy;
+use rumqttc::{AsyncClient, Publish, QoS};
+use spool::{OverflowPolicy, Spool, SpooledMessage};
+use stages::StageContext;
+use std::collections::HashMap;
 use std::env::{self};
 use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, SystemTime};
-use tokio::sync::mpsc::{channel, Receiver, Sender};
-use uuid::Uuid;
+use tokio::sync::mpsc::{channel, Receiver};
+use tracing::{error, info, warn};
 
-const MQTT_TOPIC: &str = "ntnu/+/+/+/group06/+";
+/// Capacity of the channel between the MQTT listener and the database writer
+pub const CHANNEL_CAPACITY: usize = 100;
+/// How often to check if the database is back while messages are spooled
+const SPOOL_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+/// How often the health of the backend is printed
+const HEALTH_INTERVAL: Duration = Duration::from_secs(30);
+/// How often acks that did not fit in the request queue of the MQTT client are retried
+const ACK_RETRY_INTERVAL: Duration = Duration::from_millis(50);
 
 #[tokio::main]
 async fn main() {
@@ -16,44 +48,232 @@ async fn main() {
         Ok(env_vars) => env_vars,
         Err(e) => panic!("Error getting env variables: {}", e),
     };
+    let db_log = telemetry::init();
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("replay") {
+        if let Err(e) = run_replay(&args[1..], env_vars, db_log).await {
+            error!("Error replaying: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let db_pool = create_pool(&env_vars).await;
+    create_tables(&db_pool).await;
+    tokio::spawn(telemetry::write_db_log(db_pool.clone(), db_log));
+
+    let mut liveness = Liveness::new(env_vars.expected_interval);
+    if let Err(e) = liveness.load(&db_pool).await {
+        warn!("Error loading sensor statuses: {}", e);
+    }
+    let liveness = Arc::new(Mutex::new(liveness));
+
+    let spool = match Spool::open(
+        &env_vars.spool_path,
+        env_vars.spool_max_messages,
+        env_vars.spool_overflow,
+    ) {
+        Ok(spool) => spool,
+        Err(e) => panic!("Error opening spool file {}: {}", env_vars.spool_path, e),
+    };
+    if !spool.is_empty() {
+        info!("Replaying {} messages left in the spool", spool.len());
+    }
+
+    let persistent_session = env_vars.mqtt_client_id.is_some();
+    let share_group = env_vars.mqtt_share_group.as_deref();
+    let topics = vec![
+        mqtt::subscription_topic(share_group, mqtt::MQTT_TOPIC),
+        mqtt::subscription_topic(share_group, downlink::REPORTED_CONFIG_TOPIC),
+        mqtt::subscription_topic(share_group, presence::STATUS_TOPIC),
+    ];
+    let (mqtt_client, eventloop) = mqtt::setup_mqtt_client(
+        env_vars.mqtt_address.clone(),
+        env_vars.mqtt_port,
+        env_vars.mqtt_client_id.clone(),
+    );
+
+    let metrics = Metrics::new();
+    if let Some(address) = env_vars.metrics_address {
+        tokio::spawn(metrics::serve(address, metrics.clone()));
+    }
+
+    let context = stage_context(
+        &env_vars,
+        &db_pool,
+        &metrics,
+        &liveness,
+        Some(mqtt_client.clone()),
+        load_sensor_counters(&db_pool).await,
+    );
+    let pipeline = match stages::build(&env_vars.ingestion_pipeline, &context) {
+        Ok(stages) => Pipeline::new(db_pool.clone(), stages),
+        Err(e) => panic!("Error building ingestion pipeline: {}", e),
+    };
+    info!(
+        "Ingestion pipeline: {}",
+        pipeline.stage_names().join(" -> ")
+    );
+    let capture = match &env_vars.capture_path {
+        Some(path) => match Capture::open(path) {
+            Ok(capture) => {
+                info!("Capturing received messages to {}", path);
+                Some(capture)
+            }
+            Err(e) => panic!("Error opening capture file {}: {}", path, e),
+        },
+        None => None,
+    };
+    let keys_file = KeysFile {
+        path: env_vars.sensor_keys_file.clone(),
+        required: env_vars.sensor_keys_required,
+    };
+    let ingestion = Ingestion {
+        db_pool: db_pool.clone(),
+        pipeline,
+        metrics: metrics.clone(),
+        liveness: liveness.clone(),
+        capture,
+        keys_file: keys_file.clone(),
+    };
+
+    let (tx, rx) = channel::<Publish>(CHANNEL_CAPACITY);
+
+    info!("Backend started...");
+    info!("Listening to MQTT topics: {}", topics.join(", "));
+    tokio::join!(
+        mqtt::listen_for_messages(
+            eventloop,
+            mqtt_client.clone(),
+            topics,
+            env_vars.mqtt_qos,
+            metrics.clone(),
+            tx,
+            persistent_session
+        ),
+        insert_into_database(ingestion, Acker::new(mqtt_client.clone()), spool, rx),
+        downlink::publish_desired_configs(db_pool.clone(), mqtt_client, keys_file),
+        liveness::monitor(db_pool.clone(), liveness, metrics)
+    );
+}
 
-    let db_pool = match iot_sound_database::Pool::new(
-        Some(env_vars.db_host),
+/// Create the database pool, panics if the configuration is invalid
+async fn create_pool(env_vars: &EnvVars) -> Pool {
+    match iot_sound_database::Pool::new(
+        Some(env_vars.db_host.clone()),
         Some(env_vars.db_port),
-        Some(env_vars.db_user),
-        Some(env_vars.db_password),
-        Some(env_vars.db_name),
+        Some(env_vars.db_user.clone()),
+        Some(env_vars.db_password.clone()),
+        Some(env_vars.db_name.clone()),
     )
     .await
     {
         Ok(pool) => pool,
         Err(e) => panic!("Error creating database pool: {}", e),
-    };
-
-    if let Err(e) = db_pool.create_sensor_table().await {
-        panic!("Error creating sensor table: {}", e);
-    }
-    if let Err(e) = db_pool.create_loudness_table().await {
-        panic!("Error creating loudness table: {}", e);
     }
-    if let Err(e) = db_pool.create_log_table().await {
-        panic!("Error creating log table: {}", e);
+}
+
+/// Create the tables used by the backend, retrying with backoff until the database is reachable
+async fn create_tables(db_pool: &Pool) {
+    let mut backoff = Backoff::new();
+    while let Err(e) = try_create_tables(db_pool).await {
+        let delay = backoff.next_delay();
+        warn!("Error creating tables, retrying in {:?}: {}", delay, e);
+        tokio::time::sleep(delay).await;
     }
+}
 
-    let (_mqtt_client, eventloop) =
-        match setup_mqtt_client(env_vars.mqtt_address, env_vars.mqtt_port).await {
-            Ok((client, eventloop)) => (client, eventloop),
-            Err(e) => panic!("MQTT setup error: {}", e),
-        };
+/// Create the tables used by the backend, stopping at the first that fails
+async fn try_create_tables(db_pool: &Pool) -> Result<(), Box<dyn Error>> {
+    db_pool.create_sensor_table().await?;
+    db_pool.create_loudness_table().await?;
+    db_pool.create_log_table().await?;
+    db_pool.create_pending_sensor_tables().await?;
+    db_pool.create_sensor_status_table().await?;
+    db_pool.create_sensor_connection_table().await?;
+    db_pool.create_sensor_config_table().await?;
+    db_pool.create_sensor_counter_table().await?;
+    Ok(())
+}
 
-    let (tx, rx) = channel::<(String, Bytes)>(100);
+/// Collect what the pipeline stages need
+/// # Arguments
+/// * `env_vars` - The env variables
+/// * `db_pool` - The database pool
+/// * `metrics` - Metrics of the ingestion path
+/// * `liveness` - The liveness tracker
+/// * `mqtt_client` - The MQTT client, used by the forward stage
+/// * `sensor_counters` - The highest counter stored per sensor, used by the authenticate stage
+fn stage_context(
+    env_vars: &EnvVars,
+    db_pool: &Pool,
+    metrics: &Metrics,
+    liveness: &Arc<Mutex<Liveness>>,
+    mqtt_client: Option<AsyncClient>,
+    sensor_counters: HashMap<String, u64>,
+) -> StageContext {
+    StageContext {
+        db_pool: db_pool.clone(),
+        metrics: metrics.clone(),
+        liveness: liveness.clone(),
+        validation_rules_file: env_vars.validation_rules_file.clone(),
+        registration_policy: env_vars.registration_policy.clone(),
+        calibration_file: env_vars.calibration_file.clone(),
+        mqtt_client,
+        forward_topic: env_vars.forward_topic.clone(),
+        sensor_keys_file: env_vars.sensor_keys_file.clone(),
+        sensor_keys_required: env_vars.sensor_keys_required,
+        encryption_required: env_vars.encryption_required,
+        sensor_counters,
+        known_firmware: env_vars.known_firmware.clone(),
+    }
+}
 
-    println!("Backend started...");
-    println!("Listening to MQTT topic: {}", MQTT_TOPIC);
-    tokio::join!(
-        listen_for_messages(eventloop, db_pool.clone(), tx),
-        insert_into_database(db_pool.clone(), rx)
+/// Load the highest counter stored per sensor, so payloads accepted before
+/// a restart are not accepted again
+async fn load_sensor_counters(db_pool: &Pool) -> HashMap<String, u64> {
+    match db_pool.get_sensor_counters().await {
+        Ok(counters) => counters
+            .into_iter()
+            .map(|(sensor_id, counter)| (sensor_id, counter as u64))
+            .collect(),
+        Err(e) => {
+            // the store stage still checks the counters in the database
+            warn!("Error loading sensor counters: {}", e);
+            HashMap::new()
+        }
+    }
+}
+
+/// Runs the `replay` command, feeding a capture file through the pipeline
+/// # Arguments
+/// * `args` - The arguments after `replay`
+/// * `env_vars` - The env variables
+/// * `db_log` - Messages for the database log, only written when not a dry run
+async fn run_replay(
+    args: &[String],
+    env_vars: EnvVars,
+    db_log: telemetry::DbLog,
+) -> Result<(), Box<dyn Error>> {
+    let options = replay::Options::parse(args)?;
+    let db_pool = create_pool(&env_vars).await;
+    if !options.dry_run {
+        create_tables(&db_pool).await;
+        tokio::spawn(telemetry::write_db_log(db_pool.clone(), db_log));
+    }
+    let liveness = Arc::new(Mutex::new(Liveness::new(env_vars.expected_interval)));
+    // a replay looks at old messages again, so it starts without counters
+    let context = stage_context(
+        &env_vars,
+        &db_pool,
+        &Metrics::new(),
+        &liveness,
+        None,
+        HashMap::new(),
     );
+    let stages = stages::build(&options.stages(&env_vars.ingestion_pipeline), &context)?;
+    replay::replay(&options, &mut Pipeline::new(db_pool, stages)).await
 }
 
 struct EnvVars {
@@ -64,10 +284,35 @@ struct EnvVars {
     db_user: String,
     db_password: String,
     db_name: String,
+    mqtt_client_id: Option<String>,
+    mqtt_qos: QoS,
+    mqtt_share_group: Option<String>,
+    ingestion_pipeline: String,
+    calibration_file: Option<String>,
+    forward_topic: Option<String>,
+    sensor_keys_file: Option<String>,
+    sensor_keys_required: bool,
+    encryption_required: bool,
+    validation_rules_file: Option<String>,
+    registration_policy: RegistrationPolicy,
+    expected_interval: Option<Duration>,
+    spool_path: String,
+    spool_max_messages: usize,
+    spool_overflow: OverflowPolicy,
+    metrics_address: Option<SocketAddr>,
+    capture_path: Option<String>,
+    known_firmware: Vec<String>,
 }
 
 /// Get the environment variables
 /// MQTT_ADDRESS, MQTT_PORT, DB_CONNECTION_STRING
+///
+/// Optional: BACKEND_MQTT_CLIENT_ID, BACKEND_MQTT_QOS, BACKEND_MQTT_SHARE_GROUP,
+/// INGESTION_PIPELINE, CALIBRATION_FILE, FORWARD_TOPIC, SENSOR_KEYS_FILE,
+/// SENSOR_KEYS_REQUIRED, SENSOR_ENCRYPTION_REQUIRED, VALIDATION_RULES_FILE,
+/// SENSOR_REGISTRATION_POLICY, SENSOR_ALLOWLIST, SENSOR_EXPECTED_INTERVAL_SECS,
+/// SPOOL_PATH, SPOOL_MAX_MESSAGES, SPOOL_OVERFLOW, METRICS_ADDRESS, CAPTURE_PATH,
+/// KNOWN_FIRMWARE_VERSIONS
 fn get_env_variables() -> Result<EnvVars, Box<dyn Error>> {
     // check if env are set already
     if env::var("MQTT_ADDRESS").is_err()
@@ -92,6 +337,65 @@ fn get_env_variables() -> Result<EnvVars, Box<dyn Error>> {
 
     let mqtt_port = mqtt_port.parse::<u16>()?;
     let db_port = db_port.parse::<u16>()?;
+
+    // optional, a stable client id lets the broker queue messages while we are down
+    let mqtt_client_id = env::var("BACKEND_MQTT_CLIENT_ID").ok();
+    let mqtt_qos = match env::var("BACKEND_MQTT_QOS") {
+        Ok(qos) => mqtt::parse_qos(&qos)?,
+        Err(_) => QoS::AtLeastOnce,
+    };
+    // optional, instances in the same share group split the messages between them
+    let mqtt_share_group = env::var("BACKEND_MQTT_SHARE_GROUP").ok();
+    let sensor_keys_file = env::var("SENSOR_KEYS_FILE").ok();
+    let sensor_keys_required = match env::var("SENSOR_KEYS_REQUIRED") {
+        Ok(required) => required.parse::<bool>()?,
+        Err(_) => false,
+    };
+    let encryption_required = match env::var("SENSOR_ENCRYPTION_REQUIRED") {
+        Ok(required) => required.parse::<bool>()?,
+        Err(_) => false,
+    };
+    // payloads are authenticated before anything else looks at them
+    let ingestion_pipeline = match env::var("INGESTION_PIPELINE") {
+        Ok(stages) => stages,
+        Err(_) if sensor_keys_file.is_some() => {
+            format!("authenticate,{}", pipeline::DEFAULT_STAGES)
+        }
+        Err(_) => pipeline::DEFAULT_STAGES.to_string(),
+    };
+    let calibration_file = env::var("CALIBRATION_FILE").ok();
+    let forward_topic = env::var("FORWARD_TOPIC").ok();
+    let validation_rules_file = env::var("VALIDATION_RULES_FILE").ok();
+    let registration_policy = match env::var("SENSOR_REGISTRATION_POLICY") {
+        Ok(policy) => policy.parse::<RegistrationPolicy>()?,
+        Err(_) => RegistrationPolicy::AutoAccept,
+    };
+    let expected_interval = match env::var("SENSOR_EXPECTED_INTERVAL_SECS") {
+        Ok(secs) => Some(Duration::from_secs(secs.parse::<u64>()?)),
+        Err(_) => None,
+    };
+    let spool_path = env::var("SPOOL_PATH").unwrap_or_else(|_| "backend_spool.jsonl".to_string());
+    let spool_max_messages = match env::var("SPOOL_MAX_MESSAGES") {
+        Ok(max) => max.parse::<usize>()?,
+        Err(_) => 100_000,
+    };
+    let spool_overflow = match env::var("SPOOL_OVERFLOW") {
+        Ok(policy) => policy.parse::<OverflowPolicy>()?,
+        Err(_) => OverflowPolicy::DropOldest,
+    };
+    let metrics_address = match env::var("METRICS_ADDRESS") {
+        Ok(address) => Some(address.parse::<SocketAddr>()?),
+        Err(_) => None,
+    };
+    let capture_path = env::var("CAPTURE_PATH").ok();
+    // the sensors are built from this workspace, so its version is known by default
+    let known_firmware = env::var("KNOWN_FIRMWARE_VERSIONS")
+        .unwrap_or_else(|_| env!("CARGO_PKG_VERSION").to_string())
+        .split(',')
+        .map(|version| version.trim().to_string())
+        .filter(|version| !version.is_empty())
+        .collect();
+
     Ok(EnvVars {
         mqtt_address,
         mqtt_port,
@@ -100,188 +404,196 @@ fn get_env_variables() -> Result<EnvVars, Box<dyn Error>> {
         db_user,
         db_password,
         db_name,
+        mqtt_client_id,
+        mqtt_qos,
+        mqtt_share_group,
+        ingestion_pipeline,
+        calibration_file,
+        forward_topic,
+        sensor_keys_file,
+        sensor_keys_required,
+        encryption_required,
+        validation_rules_file,
+        registration_policy,
+        expected_interval,
+        spool_path,
+        spool_max_messages,
+        spool_overflow,
+        metrics_address,
+        capture_path,
+        known_firmware,
     })
 }
 
-/// Setup the MQTT client
-/// Returns a Result with the client and eventloop
-/// # Arguments
-/// * `mqtt_address` - The address of the MQTT broker
-/// * `mqtt_port` - The port of the MQTT broker
-/// # Returns
-/// * `Result<(AsyncClient, EventLoop), ClientError>` - The client and eventloop
-async fn setup_mqtt_client(
-    mqtt_adress: String,
-    mqtt_port: u16,
-) -> Result<(AsyncClient, rumqttc::EventLoop), ClientError> {
-    let mut mqtt_options =
-        MqttOptions::new(Uuid::new_v4().to_string().as_str(), mqtt_adress, mqtt_port);
-    mqtt_options.set_keep_alive(Duration::from_secs(5));
-    let (mqtt_client, eventloop) = AsyncClient::new(mqtt_options, 10);
-
-    mqtt_client.subscribe(MQTT_TOPIC, QoS::AtLeastOnce).await?;
-    Ok((mqtt_client, eventloop))
-}
-
-/// Function that listens for messages from the MQTT broker
-/// 
-/// # Arguments
-/// mut `eventloop` - The eventloop that listens for messages
-/// `db_pool` - The database pool for logging purposes
-/// `channel` - The channel to send the messages to
-async fn listen_for_messages(
-    mut eventloop: rumqttc::EventLoop,
+/// State shared by every message on the ingestion path
+struct Ingestion {
     db_pool: Pool,
-    channel: Sender<(String, Bytes)>,
-) {
-    loop {
-        match eventloop.poll().await {
-            Ok(rumqttc::Event::Incoming(incoming)) => {
-                if let rumqttc::Incoming::Publish(publish) = incoming {
-                    if let Err(e) = channel.send((publish.topic, publish.payload)).await {
-                        println!("Error sending message to channel: {}", e);
-                        if let Err(e) = db_pool
-                            .insert_log(
-                                &format!("Error sending recieved message to db writer: {:?}", e),
-                                SystemTime::now(),
-                            )
-                            .await
-                        {
-                            eprintln!("Error inserting log into database: {}", e);
-                            continue;
-                        }
-                    };
-                }
-            }
-            Ok(_) => {}
-            Err(e) => {
-                eprintln!("MQTT Connection error: {:?}", &e);
-                if let Err(e) = db_pool
-                    .insert_log(
-                        &format!("Mqtt Connection error: {:?}", e),
-                        SystemTime::now(),
-                    )
-                    .await
-                {
-                    eprintln!("Error inserting log into database: {}", e);
-                    continue;
-                }
-            }
-        }
-    }
+    pipeline: Pipeline,
+    metrics: Metrics,
+    liveness: Arc<Mutex<Liveness>>,
+    capture: Option<Capture>,
+    /// Verifies messages from sensors other than readings
+    keys_file: KeysFile,
 }
 
 /// Function that inserts the messages into the database
-/// 
+///
+/// Every message is acked to the broker once it has been handled, so messages
+/// still in the channel when the backend stops are redelivered on the next start.
+/// Redelivered readings that were already stored are ignored by the database.
+///
+/// While the database is unavailable messages are written to the spool instead,
+/// and replayed in order once it is reachable again.
+///
 /// # Arguments
-/// * `db_pool` - The database pool
+/// * `ingestion` - The state of the ingestion path
+/// * `acker` - Acks handled messages without blocking the event loop
+/// * `spool` - Holds messages while the database is unavailable
 /// * `channel` - The channel to listen for messages on
-async fn insert_into_database(db_pool: Pool, mut channel: Receiver<(String, Bytes)>) {
-    let mut sensors_cache = match db_pool.get_sensor_ids().await {
-        Ok(sensors) => sensors,
-        Err(e) => panic!("Error getting sensor ids from db: {}", e),
-    };
-
-    while let Some(data) = channel.recv().await {
-        let topic_split: Vec<&str> = data.0.split('/').collect();
-        let sensor_id = topic_split.last().expect("Subscribed topic is valid");
+async fn insert_into_database(
+    mut ingestion: Ingestion,
+    mut acker: Acker,
+    mut spool: Spool,
+    mut channel: Receiver<Publish>,
+) {
+    let mut retry = tokio::time::interval(SPOOL_RETRY_INTERVAL);
+    let mut ack_retry = tokio::time::interval(ACK_RETRY_INTERVAL);
+    let mut health = tokio::time::interval(HEALTH_INTERVAL);
 
-        let data: &str = match std::str::from_utf8(&data.1) {
-            Ok(data) => data,
-            Err(e) => {
-                eprintln!("Error converting bytes to string: {}", e);
-                if let Err(e) = db_pool
-                    .insert_log(
-                        &format!("Error converting bytes to string: {:?}", e),
-                        SystemTime::now(),
-                    )
-                    .await
-                {
-                    eprintln!("Error inserting log into database: {}", e);
+    loop {
+        tokio::select! {
+            publish = channel.recv() => {
+                let publish = match publish {
+                    Some(publish) => publish,
+                    None => break,
+                };
+                ingestion.metrics.channel_occupancy.dec();
+                let received = SystemTime::now();
+                // status and reported configs are not readings, they skip the capture and the pipeline
+                if let Some(result) = handle_sensor_message(&ingestion, &publish, received).await {
+                    if let Err(e) = result {
+                        warn!("Error handling message on {}: {}", publish.topic, e);
+                    }
+                    acker.ack(&publish);
+                    continue;
                 }
-                continue;
-            }
-        };
-
-        let payload = match LoudnessData::parse_csv(data) {
-            Ok(payload) => payload,
-            Err(e) => {
-                eprintln!("Error parsing payload: {}", e);
-                if let Err(e) = db_pool
-                    .insert_log(
-                        &format!("Error parsing payload: {:?}", e),
-                        SystemTime::now(),
-                    )
-                    .await
-                {
-                    eprintln!("Error inserting log into database: {}", e);
+                if let Some(capture) = &mut ingestion.capture {
+                    let message =
+                        SpooledMessage::new(publish.topic.clone(), publish.payload.to_vec(), received);
+                    if let Err(e) = capture.record(&message) {
+                        error!("Error writing message to capture file: {}", e);
+                    }
+                }
+                // once something is spooled, everything after it is too, to keep the order
+                let attempted = spool.is_empty();
+                let result = if attempted {
+                    ingestion.pipeline.run(&publish.topic, &publish.payload, received, false).await
+                } else {
+                    Err("Spool is not empty".into())
+                };
+                if result.is_err() {
+                    let mut message =
+                        SpooledMessage::new(publish.topic.clone(), publish.payload.to_vec(), received);
+                    message.attempted = attempted;
+                    if let Err(e) = spool.push(message) {
+                        // not acked, so the broker redelivers it after a restart
+                        error!("Error writing message to spool: {}", e);
+                        continue;
+                    }
                 }
-                continue;
+                ingestion.metrics.spool_depth.set(spool.len() as i64);
+                acker.ack(&publish);
             }
-        };
-
-        if !sensors_cache.contains(&sensor_id.to_string()) {
-            println!("Sensor {} not found in database", sensor_id);
-            if let Err(e) = db_pool
-                .insert_log(
-                    &format!("Sensor {} not found in database, adding...", sensor_id),
-                    SystemTime::now(),
-                )
-                .await
-            {
-                eprintln!("Error inserting log into database: {}", e);
-                continue;
+            _ = ack_retry.tick(), if acker.pending() > 0 => acker.flush(),
+            _ = retry.tick(), if !spool.is_empty() => {
+                drain_spool(&mut ingestion, &mut spool).await;
+                ingestion.metrics.spool_depth.set(spool.len() as i64);
+            }
+            _ = health.tick() => {
+                let database = if ingestion.db_pool.is_available().await { "up" } else { "down" };
+                info!(
+                    "Health: database {}, spool depth {}, spool dropped {}",
+                    database,
+                    spool.len(),
+                    spool.dropped()
+                );
             }
-            if let Err(e) = add_new_sensor(&db_pool, &topic_split).await {
-                eprintln!("Error adding new sensor: {}", e);
-                if let Err(e) = db_pool
-                    .insert_log(
-                        &format!("Error adding new sensor: {:?}", e),
-                        SystemTime::now(),
-                    )
-                    .await
-                {
-                    eprintln!("Error inserting log into database: {}", e);
-                }
-                continue;
-            };
-            sensors_cache = match db_pool.get_sensor_ids().await {
-                Ok(sensors) => sensors,
-                Err(e) => {
-                    eprintln!("Error getting sensor ids from db: {}", e);
-                    continue;
-                }
-            };
         }
+    }
+}
 
-        println!("Sensorid: {} Message: {}", sensor_id, payload.db_level());
-        if let Err(e) = db_pool
-            .insert_loudness_data(
-                sensor_id,
-                &format!("{}", payload.db_level()),
-                payload.timestamp(),
+/// Handle a message about a sensor rather than a reading from it
+/// # Arguments
+/// * `ingestion` - The state of the ingestion path
+/// * `publish` - The message
+/// * `received` - The time the message was received
+/// # Returns
+/// * `Option<Result<(), Box<dyn Error>>>` - `None` if the message is a reading
+async fn handle_sensor_message(
+    ingestion: &Ingestion,
+    publish: &Publish,
+    received: SystemTime,
+) -> Option<Result<(), Box<dyn Error>>> {
+    if sensor_config::sensor_topic_of_report(&publish.topic).is_some() {
+        Some(
+            downlink::record_reported_config(
+                &ingestion.db_pool,
+                &ingestion.keys_file,
+                &publish.topic,
+                &publish.payload,
             )
-            .await
-        {
-            eprintln!("Error inserting loudness data into database: {}", e);
-        }
+            .await,
+        )
+    } else if status::sensor_topic_of_status(&publish.topic).is_some() {
+        Some(
+            presence::record_status(
+                &ingestion.db_pool,
+                &ingestion.keys_file,
+                &ingestion.liveness,
+                &publish.topic,
+                &publish.payload,
+                publish.retain,
+                received,
+            )
+            .await,
+        )
+    } else {
+        None
     }
 }
 
-async fn add_new_sensor(db_pool: &Pool, topic_split: &[&str]) -> Result<(), Box<dyn Error>> {
-    let sensor_id = topic_split.last().unwrap();
-    let sensor_type = topic_split[3];
-    let sensor_location = format!("{}/{}/{}", topic_split[0], topic_split[1], topic_split[2]);
-
-    if sensor_type == "loudness" {
-        if let Err(e) = db_pool
-            .insert_new_sensor(sensor_id, sensor_type, &sensor_location)
+/// Replays spooled messages in order until the spool is empty
+/// or the database becomes unavailable again
+///
+/// # Arguments
+/// * `ingestion` - The state of the ingestion path
+/// * `spool` - The spool to drain
+async fn drain_spool(ingestion: &mut Ingestion, spool: &mut Spool) {
+    if !ingestion.db_pool.is_available().await {
+        return;
+    }
+    let depth = spool.len();
+    while let Some(message) = spool.front() {
+        let message = message.clone();
+        if let Err(e) = ingestion
+            .pipeline
+            .run(
+                &message.topic,
+                &message.payload,
+                message.received(),
+                message.attempted,
+            )
             .await
         {
-            eprintln!("Error inserting new sensor into database: {}", e);
+            warn!("Database unavailable while replaying spool: {}", e);
+            spool.mark_front_attempted();
+            return;
         }
-        Ok(())
-    } else {
-        Err((format!("Sensor type {} not supported", sensor_type)).into())
+        spool.pop_front();
     }
+
+    info!(
+        db_log = true,
+        "Database available again, replayed {} spooled messages", depth
+    );
 }
@@ -0,0 +1,167 @@
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use tracing::{error, info};
+
+/// Prometheus metrics of the ingestion path.
+/// Cloning is cheap, all clones update the same metrics.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    pub messages_received: IntCounterVec,
+    pub parse_failures: IntCounterVec,
+    pub payloads_decoded: IntCounterVec,
+    pub auth_failures: IntCounterVec,
+    pub db_insert_seconds: Histogram,
+    pub channel_occupancy: IntGauge,
+    pub spool_depth: IntGauge,
+    pub sensors_online: IntGauge,
+    pub mqtt_reconnects: IntCounter,
+}
+
+impl Metrics {
+    /// Create and register all metrics
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("iot_sound_backend".to_string()), None)
+            .expect("Metric prefix is valid");
+        let messages_received = IntCounterVec::new(
+            Opts::new(
+                "messages_received_total",
+                "MQTT messages received per subscribed topic filter",
+            ),
+            &["filter"],
+        )
+        .expect("Metric is valid");
+        let parse_failures = IntCounterVec::new(
+            Opts::new(
+                "parse_failures_total",
+                "Payloads that could not be parsed, by kind",
+            ),
+            &["kind"],
+        )
+        .expect("Metric is valid");
+        let payloads_decoded = IntCounterVec::new(
+            Opts::new(
+                "payloads_decoded_total",
+                "Payloads decoded, by format version and firmware version of the sensor",
+            ),
+            &["version", "firmware"],
+        )
+        .expect("Metric is valid");
+        let auth_failures = IntCounterVec::new(
+            Opts::new(
+                "auth_failures_total",
+                "Payloads that failed authentication, by reason",
+            ),
+            &["reason"],
+        )
+        .expect("Metric is valid");
+        let db_insert_seconds = Histogram::with_opts(HistogramOpts::new(
+            "db_insert_duration_seconds",
+            "Time taken to insert a reading into the database",
+        ))
+        .expect("Metric is valid");
+        let channel_occupancy = IntGauge::new(
+            "channel_occupancy",
+            "Messages waiting in the channel between the MQTT listener and the database writer",
+        )
+        .expect("Metric is valid");
+        let spool_depth = IntGauge::new(
+            "spool_depth",
+            "Messages spooled to disk while the database is unavailable",
+        )
+        .expect("Metric is valid");
+        let sensors_online =
+            IntGauge::new("sensors_online", "Sensors currently online").expect("Metric is valid");
+        let mqtt_reconnects = IntCounter::new(
+            "mqtt_reconnects_total",
+            "Times the connection to the MQTT broker was re-established",
+        )
+        .expect("Metric is valid");
+
+        for collector in [
+            Box::new(messages_received.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(parse_failures.clone()),
+            Box::new(payloads_decoded.clone()),
+            Box::new(auth_failures.clone()),
+            Box::new(db_insert_seconds.clone()),
+            Box::new(channel_occupancy.clone()),
+            Box::new(spool_depth.clone()),
+            Box::new(sensors_online.clone()),
+            Box::new(mqtt_reconnects.clone()),
+        ] {
+            registry
+                .register(collector)
+                .expect("Metric is registered once");
+        }
+
+        Metrics {
+            registry,
+            messages_received,
+            parse_failures,
+            payloads_decoded,
+            auth_failures,
+            db_insert_seconds,
+            channel_occupancy,
+            spool_depth,
+            sensors_online,
+            mqtt_reconnects,
+        }
+    }
+
+    /// Returns all metrics in the Prometheus text format
+    fn render(&self) -> Result<Vec<u8>, prometheus::Error> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(buffer)
+    }
+}
+
+/// Serves the metrics over HTTP at `/metrics`
+///
+/// # Arguments
+/// * `address` - The address to listen on
+/// * `metrics` - The metrics to serve
+pub async fn serve(address: SocketAddr, metrics: Metrics) {
+    let make_service = make_service_fn(move |_| {
+        let metrics = metrics.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                let metrics = metrics.clone();
+                async move { Ok::<_, Infallible>(respond(&metrics, request)) }
+            }))
+        }
+    });
+
+    info!("Serving metrics on http://{}/metrics", address);
+    if let Err(e) = Server::bind(&address).serve(make_service).await {
+        error!("Metrics server error: {}", e);
+    }
+}
+
+fn respond(metrics: &Metrics, request: Request<Body>) -> Response<Body> {
+    if request.method() != Method::GET || request.uri().path() != "/metrics" {
+        return Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from("Not found"))
+            .expect("Response is valid");
+    }
+    match metrics.render() {
+        Ok(body) => Response::builder()
+            .header("Content-Type", TextEncoder::new().format_type())
+            .body(Body::from(body))
+            .expect("Response is valid"),
+        Err(e) => {
+            error!("Error encoding metrics: {}", e);
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::from("Internal Server Error"))
+                .expect("Response is valid")
+        }
+    }
+}
@@ -0,0 +1,406 @@
+use crate::metrics::Metrics;
+use rand::Rng;
+use rumqttc::{
+    AsyncClient, ConnectionError, Event, EventLoop, Incoming, MqttOptions, Publish, QoS,
+};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc::Sender;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+pub const MQTT_TOPIC: &str = "ntnu/+/+/+/group06/+";
+
+/// Delay before the first reconnection attempt
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+/// Longest delay between reconnection attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Parse a MQTT quality of service level from its numeric value
+/// # Arguments
+/// * `qos` - "0", "1" or "2"
+pub fn parse_qos(qos: &str) -> Result<QoS, Box<dyn Error>> {
+    match qos.trim() {
+        "0" => Ok(QoS::AtMostOnce),
+        "1" => Ok(QoS::AtLeastOnce),
+        "2" => Ok(QoS::ExactlyOnce),
+        other => Err(format!("Invalid MQTT QoS: {}, expected 0, 1 or 2", other).into()),
+    }
+}
+
+/// Returns the subscription for a topic
+///
+/// Without a share group every backend receives every message. With one the
+/// subscription is shared (`$share/<group>/...`), the broker delivers each message
+/// to only one of the backends in the group, so several backends can split the load.
+/// # Arguments
+/// * `share_group` - The name of the share group, if any
+/// * `topic` - The topic to subscribe to
+pub fn subscription_topic(share_group: Option<&str>, topic: &str) -> String {
+    match share_group {
+        Some(group) => format!("$share/{}/{}", group, topic),
+        None => topic.to_string(),
+    }
+}
+
+/// Setup the MQTT client
+/// Returns the client and eventloop, the subscription is made by
+/// `listen_for_messages` every time the client connects.
+///
+/// With a configured client id the session is persistent (`clean_session = false`),
+/// so the broker queues messages for us while the backend is down and replays them
+/// when we reconnect. Publishes are acked manually, only after they have been
+/// processed, so a message that was received but not yet stored is redelivered.
+/// # Arguments
+/// * `mqtt_address` - The address of the MQTT broker
+/// * `mqtt_port` - The port of the MQTT broker
+/// * `mqtt_client_id` - Stable client id, a random one is used if `None`
+/// # Returns
+/// * `(AsyncClient, EventLoop)` - The client and eventloop
+pub fn setup_mqtt_client(
+    mqtt_adress: String,
+    mqtt_port: u16,
+    mqtt_client_id: Option<String>,
+) -> (AsyncClient, EventLoop) {
+    let persistent = mqtt_client_id.is_some();
+    let client_id = mqtt_client_id.unwrap_or_else(|| Uuid::new_v4().to_string());
+    let mut mqtt_options = MqttOptions::new(client_id, mqtt_adress, mqtt_port);
+    mqtt_options.set_keep_alive(Duration::from_secs(5));
+    mqtt_options.set_clean_session(!persistent);
+    mqtt_options.set_manual_acks(true);
+    AsyncClient::new(mqtt_options, 10)
+}
+
+/// Acks handled messages without waiting for room in the request queue of the client
+///
+/// The event loop empties the request queue, but it may itself be waiting for room in
+/// the channel to the database writer, so a writer waiting for room to ack would
+/// deadlock with it. Acks that do not fit are kept and sent by `flush`, in the order
+/// the messages were received, as MQTT requires.
+pub struct Acker {
+    client: AsyncClient,
+    pending: VecDeque<Publish>,
+}
+
+impl Acker {
+    pub fn new(client: AsyncClient) -> Self {
+        Acker {
+            client,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Ack a message, or keep the ack for `flush` if the request queue is full
+    /// # Arguments
+    /// * `publish` - The handled message
+    pub fn ack(&mut self, publish: &Publish) {
+        self.pending.push_back(publish.clone());
+        self.flush();
+    }
+
+    /// Send the kept acks, in order, until the request queue is full
+    pub fn flush(&mut self) {
+        while let Some(publish) = self.pending.front() {
+            if let Err(e) = self.client.try_ack(publish) {
+                debug!("Request queue is full, acking later: {}", e);
+                return;
+            }
+            self.pending.pop_front();
+        }
+    }
+
+    /// Returns the number of acks waiting for room in the request queue
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+/// Exponential backoff with jitter between reconnection attempts,
+/// also used between attempts to reach the database at startup
+pub struct Backoff {
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new() -> Self {
+        Backoff {
+            current: MIN_BACKOFF,
+        }
+    }
+
+    /// Returns the delay before the next attempt and doubles the delay after it
+    pub fn next_delay(&mut self) -> Duration {
+        let jitter_ms = rand::thread_rng().gen_range(0..=self.current.as_millis() as u64 / 2);
+        let delay = self.current + Duration::from_millis(jitter_ms);
+        self.current = (self.current * 2).min(MAX_BACKOFF);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.current = MIN_BACKOFF;
+    }
+}
+
+/// State of the connection to the broker
+enum ConnectionState {
+    Connected,
+    Disconnected {
+        since: SystemTime,
+        failed_attempts: u32,
+    },
+}
+
+/// Function that listens for messages from the MQTT broker
+///
+/// When the connection is lost it retries with exponential backoff.
+/// An outage is written to the log once when it starts and once when the
+/// connection is back, instead of once per failed attempt.
+///
+/// # Arguments
+/// mut `eventloop` - The eventloop that listens for messages
+/// `client` - The client, used to subscribe after every connect
+/// `topics` - The topics to subscribe to
+/// `qos` - The QoS to subscribe with
+/// `metrics` - Metrics of the ingestion path
+/// `channel` - The channel to send the messages to
+/// `persistent` - Whether the client uses a persistent session
+pub async fn listen_for_messages(
+    mut eventloop: EventLoop,
+    client: AsyncClient,
+    topics: Vec<String>,
+    qos: QoS,
+    metrics: Metrics,
+    channel: Sender<Publish>,
+    persistent: bool,
+) {
+    let mut backoff = Backoff::new();
+    let mut has_connected = false;
+    let mut state = ConnectionState::Disconnected {
+        since: SystemTime::now(),
+        failed_attempts: 0,
+    };
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Incoming::ConnAck(connack))) => {
+                backoff.reset();
+                if has_connected {
+                    metrics.mqtt_reconnects.inc();
+                }
+                has_connected = true;
+                on_connected(&state);
+                state = ConnectionState::Connected;
+                log_session_state(persistent, connack.session_present);
+                // a clean session loses its subscriptions on every reconnect
+                for topic in &topics {
+                    if let Err(e) = client.try_subscribe(topic, qos) {
+                        warn!("Error subscribing to {}: {}", topic, e);
+                    }
+                }
+            }
+            Ok(Event::Incoming(Incoming::Publish(publish))) => {
+                metrics
+                    .messages_received
+                    .with_label_values(&[topic_filter(&topics, &publish.topic)])
+                    .inc();
+                // the database writer decrements it when it takes the message
+                metrics.channel_occupancy.inc();
+                if let Err(e) = channel.send(publish).await {
+                    metrics.channel_occupancy.dec();
+                    warn!("Error sending recieved message to db writer: {:?}", e);
+                };
+            }
+            Ok(_) => {}
+            Err(e) => {
+                state = on_connection_error(state, &e);
+                let delay = backoff.next_delay();
+                info!("Reconnecting to MQTT broker in {:.1}s", delay.as_secs_f32());
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+}
+
+/// Returns the subscribed topic filter a topic matches, to label metrics
+/// with a bounded set of values, or `other` if it matches none
+/// # Arguments
+/// * `filters` - The subscribed topic filters, shared ones with their `$share/<group>/` prefix
+/// * `topic` - The topic of a received message
+fn topic_filter<'a>(filters: &'a [String], topic: &str) -> &'a str {
+    filters
+        .iter()
+        .map(|filter| match filter.strip_prefix("$share/") {
+            Some(shared) => shared.split_once('/').map_or(shared, |(_, filter)| filter),
+            None => filter,
+        })
+        .find(|filter| matches_filter(filter, topic))
+        .unwrap_or("other")
+}
+
+/// Returns whether a topic matches a topic filter with `+` and `#` wildcards
+fn matches_filter(filter: &str, topic: &str) -> bool {
+    let mut levels = topic.split('/');
+    for pattern in filter.split('/') {
+        match (pattern, levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (pattern, Some(level)) if pattern == level => {}
+            _ => return false,
+        }
+    }
+    levels.next().is_none()
+}
+
+/// Logs the transition to connected
+/// # Arguments
+/// * `state` - The state before the connection was made
+fn on_connected(state: &ConnectionState) {
+    let message = match state {
+        ConnectionState::Connected => return,
+        ConnectionState::Disconnected {
+            failed_attempts: 0, ..
+        } => "MQTT connected".to_string(),
+        ConnectionState::Disconnected {
+            since,
+            failed_attempts,
+        } => format!(
+            "MQTT connected again after {}s and {} failed attempts",
+            since.elapsed().unwrap_or_default().as_secs(),
+            failed_attempts
+        ),
+    };
+    info!(db_log = true, "{}", message);
+}
+
+/// Logs the transition to disconnected on the first error of an outage,
+/// later errors of the same outage are only counted
+/// # Arguments
+/// * `state` - The state when the error happened
+/// * `error` - The connection error
+/// # Returns
+/// * `ConnectionState` - The new state
+fn on_connection_error(state: ConnectionState, error: &ConnectionError) -> ConnectionState {
+    match state {
+        ConnectionState::Connected => {
+            warn!("MQTT disconnected: {:?}", error);
+            ConnectionState::Disconnected {
+                since: SystemTime::now(),
+                failed_attempts: 1,
+            }
+        }
+        ConnectionState::Disconnected {
+            since,
+            failed_attempts,
+        } => {
+            if failed_attempts == 0 {
+                warn!("MQTT could not connect: {:?}", error);
+            } else {
+                debug!("MQTT Connection error: {:?}", error);
+            }
+            ConnectionState::Disconnected {
+                since,
+                failed_attempts: failed_attempts + 1,
+            }
+        }
+    }
+}
+
+/// Logs whether the broker resumed our session on connect.
+/// A persistent session that was not resumed means the broker lost the messages
+/// queued while we were disconnected, which shows up as a gap in the charts.
+///
+/// # Arguments
+/// * `persistent` - Whether we asked for a persistent session
+/// * `session_present` - The session present flag from the broker's ConnAck
+fn log_session_state(persistent: bool, session_present: bool) {
+    match (persistent, session_present) {
+        (true, true) => info!(
+            db_log = true,
+            "MQTT session resumed, replaying messages queued by the broker"
+        ),
+        (true, false) => warn!(
+            "MQTT session not present on broker, messages sent while the backend was down may be lost"
+        ),
+        (false, _) => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Asserts that the delay is the base delay plus a jitter of at most half of it
+    fn assert_delay(delay: Duration, base: Duration) {
+        assert!(
+            delay >= base && delay <= base + base / 2,
+            "{:?} is not within the jitter of {:?}",
+            delay,
+            base
+        );
+    }
+
+    #[test]
+    fn the_delay_doubles_up_to_the_cap() {
+        let mut backoff = Backoff::new();
+        let mut base = MIN_BACKOFF;
+        for _ in 0..6 {
+            assert_delay(backoff.next_delay(), base);
+            base *= 2;
+        }
+        // 64s is capped to 60s
+        for _ in 0..3 {
+            assert_delay(backoff.next_delay(), MAX_BACKOFF);
+        }
+    }
+
+    #[test]
+    fn topics_are_counted_by_filter() {
+        let filters = vec![
+            subscription_topic(Some("backends"), MQTT_TOPIC),
+            "ntnu/+/status/#".to_string(),
+        ];
+        let topic_filter = |topic| topic_filter(&filters, topic);
+        assert_eq!(topic_filter("ntnu/a/b/loudness/group06/sensor"), MQTT_TOPIC);
+        assert_eq!(
+            topic_filter("ntnu/a/status/sensor/online"),
+            "ntnu/+/status/#"
+        );
+        assert_eq!(topic_filter("ntnu/a/status"), "ntnu/+/status/#");
+        assert_eq!(topic_filter("ntnu/a/b/loudness/group06"), "other");
+        assert_eq!(topic_filter("ntnu/a/b/loudness/group07/sensor"), "other");
+        assert_eq!(topic_filter("ntnu/a/b/loudness/group06/sensor/x"), "other");
+    }
+
+    #[test]
+    fn reset_starts_over() {
+        let mut backoff = Backoff::new();
+        for _ in 0..10 {
+            backoff.next_delay();
+        }
+        backoff.reset();
+        assert_delay(backoff.next_delay(), MIN_BACKOFF);
+        assert_delay(backoff.next_delay(), MIN_BACKOFF * 2);
+    }
+
+    #[test]
+    fn acks_that_do_not_fit_are_kept_in_order() {
+        let (client, _eventloop) =
+            AsyncClient::new(MqttOptions::new("acker", "localhost", 1883), 1);
+        let mut acker = Acker::new(client);
+        let publish = |pkid| {
+            let mut publish = Publish::new("topic", QoS::AtLeastOnce, "payload");
+            publish.pkid = pkid;
+            publish
+        };
+        // returns instead of waiting for the event loop to empty the queue
+        for pkid in 1..=3 {
+            acker.ack(&publish(pkid));
+        }
+        assert_eq!(acker.pending(), 2);
+        acker.flush();
+        assert_eq!(
+            acker.pending.iter().map(|p| p.pkid).collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+    }
+}
@@ -0,0 +1,367 @@
+use crate::registration::Admission;
+use async_trait::async_trait;
+use iot_sound_backend::loudness_data::LoudnessData;
+use iot_sound_backend::spectrum::Spectrum;
+use iot_sound_database::Pool;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::time::SystemTime;
+use tracing::{error, warn};
+
+/// Stages run when `INGESTION_PIPELINE` is not set
+pub const DEFAULT_STAGES: &str = "decode,validate,register,store";
+
+/// A reading in a message, with the quality flags stored with it
+pub struct Sample {
+    pub data: LoudnessData,
+    pub flags: Vec<String>,
+    /// The band levels of a spectrum reading, whose level is their sum
+    pub spectrum: Option<Spectrum>,
+}
+
+/// A message received from the broker as it moves through the pipeline
+pub struct Reading {
+    pub topic: String,
+    pub sensor_id: String,
+    pub sensor_type: String,
+    pub payload: Vec<u8>,
+    /// The time the message was received from the broker
+    pub received: SystemTime,
+    /// The readings in the payload, set by the decode stage, batched payloads carry many
+    pub samples: Vec<Sample>,
+    /// Optional fields of the payload, by name, stored with the latest reading
+    pub fields: BTreeMap<String, String>,
+    /// What to do with the reading, set by the register stage. Pipelines without
+    /// it store every reading, which `stages::build` only allows with the auto policy.
+    pub admission: Admission,
+    /// The counter of an authenticated payload, set by the authenticate stage and
+    /// stored with the readings, so the payload is not accepted again
+    pub counter: Option<u64>,
+    /// Whether the message is replayed from the spool after it went through
+    /// the pipeline before, so stages with state do not count it twice
+    pub replayed: bool,
+}
+
+impl Reading {
+    pub fn new(topic: &str, payload: &[u8], received: SystemTime, replayed: bool) -> Self {
+        let topic_split: Vec<&str> = topic.split('/').collect();
+        Reading {
+            topic: topic.to_string(),
+            sensor_id: topic_split
+                .last()
+                .expect("Subscribed topic is valid")
+                .to_string(),
+            sensor_type: topic_split.get(3).unwrap_or(&"").to_string(),
+            payload: payload.to_vec(),
+            received,
+            samples: Vec::new(),
+            fields: BTreeMap::new(),
+            admission: Admission::Store,
+            counter: None,
+            replayed,
+        }
+    }
+
+    /// Returns the decoded readings, for stages that run after the decode stage
+    pub fn samples(&mut self) -> Result<&mut Vec<Sample>, StageError> {
+        if self.samples.is_empty() {
+            return Err(StageError::Failed("Reading has not been decoded".into()));
+        }
+        Ok(&mut self.samples)
+    }
+
+    /// Returns the quality flags of every reading, each flag once
+    fn flags(&self) -> Vec<String> {
+        let mut flags: Vec<String> = Vec::new();
+        for flag in self.samples.iter().flat_map(|sample| &sample.flags) {
+            if !flags.contains(flag) {
+                flags.push(flag.clone());
+            }
+        }
+        flags
+    }
+}
+
+/// Whether the pipeline continues with the next stage
+#[derive(Debug, PartialEq, Eq)]
+pub enum Flow {
+    Continue,
+    /// The reading has been handled, the remaining stages are skipped
+    Stop,
+}
+
+/// Error returned by a stage, the reading is dropped
+#[derive(Debug)]
+pub enum StageError {
+    /// The reading is not valid, logged as a warning
+    Rejected(String),
+    /// The stage could not handle the reading, logged as an error, or retried
+    /// later if the database is unavailable
+    Failed(Box<dyn Error>),
+}
+
+impl fmt::Display for StageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StageError::Rejected(reason) => f.write_str(reason),
+            StageError::Failed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl<E: Into<Box<dyn Error>>> From<E> for StageError {
+    fn from(e: E) -> Self {
+        StageError::Failed(e.into())
+    }
+}
+
+/// What happened to a message in the pipeline
+#[derive(Debug)]
+pub enum Outcome {
+    /// Every stage ran, with the quality flags of the readings
+    Completed { flags: Vec<String> },
+    /// A stage handled the reading and skipped the stages after it
+    Stopped { stage: &'static str },
+    /// A stage rejected the reading
+    Rejected { stage: &'static str, reason: String },
+    /// A stage failed while the database was available
+    Failed { stage: &'static str, error: String },
+}
+
+/// A step of the ingestion pipeline
+#[async_trait(?Send)]
+pub trait Stage {
+    /// The name of the stage, as used in `INGESTION_PIPELINE`
+    fn name(&self) -> &'static str;
+
+    /// Process a reading, updating it for the stages after this one
+    /// # Arguments
+    /// * `reading` - The reading to process
+    async fn process(&mut self, reading: &mut Reading) -> Result<Flow, StageError>;
+}
+
+/// Runs every message through the configured stages in order
+pub struct Pipeline {
+    db_pool: Pool,
+    stages: Vec<Box<dyn Stage>>,
+}
+
+impl Pipeline {
+    /// Create a new Pipeline
+    /// # Arguments
+    /// * `db_pool` - The database pool, used to tell failures apart from database outages
+    /// * `stages` - The stages to run, in order
+    pub fn new(db_pool: Pool, stages: Vec<Box<dyn Stage>>) -> Self {
+        Pipeline { db_pool, stages }
+    }
+
+    /// Returns the names of the stages, in order
+    pub fn stage_names(&self) -> Vec<&'static str> {
+        self.stages.iter().map(|stage| stage.name()).collect()
+    }
+
+    /// Runs a message received from the broker through the pipeline
+    ///
+    /// Returns an error only when the message could not be handled because the
+    /// database is unavailable, it should then be retried later.
+    /// Other failures are logged and the message is dropped.
+    ///
+    /// # Arguments
+    /// * `topic` - The topic the message was published to
+    /// * `payload` - The payload of the message
+    /// * `received` - The time the message was received
+    /// * `replayed` - Whether the message went through the pipeline before and failed
+    /// # Returns
+    /// * `Outcome` - What happened to the message
+    #[tracing::instrument(
+        name = "message",
+        skip_all,
+        fields(topic = %topic, sensor_id = tracing::field::Empty, payload_size = payload.len())
+    )]
+    pub async fn run(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        received: SystemTime,
+        replayed: bool,
+    ) -> Result<Outcome, Box<dyn Error>> {
+        let mut reading = Reading::new(topic, payload, received, replayed);
+        tracing::Span::current().record("sensor_id", tracing::field::display(&reading.sensor_id));
+
+        for stage in self.stages.iter_mut() {
+            let stage_name = stage.name();
+            match stage.process(&mut reading).await {
+                Ok(Flow::Continue) => {}
+                Ok(Flow::Stop) => return Ok(Outcome::Stopped { stage: stage_name }),
+                Err(StageError::Rejected(reason)) => {
+                    warn!(stage = stage_name, "{}", reason);
+                    return Ok(Outcome::Rejected {
+                        stage: stage_name,
+                        reason,
+                    });
+                }
+                Err(StageError::Failed(e)) => {
+                    if !self.db_pool.is_available().await {
+                        return Err(e);
+                    }
+                    error!(stage = stage_name, "{}", e);
+                    return Ok(Outcome::Failed {
+                        stage: stage_name,
+                        error: e.to_string(),
+                    });
+                }
+            }
+        }
+        Ok(Outcome::Completed {
+            flags: reading.flags(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iot_sound_backend::loudness_data::LoudnessData;
+    use iot_sound_wire::Timestamp;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    type Log = Rc<RefCell<Vec<&'static str>>>;
+    type Process = fn(&mut Reading) -> Result<Flow, StageError>;
+
+    /// A stage that records that it ran and then does what it was told
+    struct Fake {
+        name: &'static str,
+        log: Log,
+        process: Process,
+    }
+
+    #[async_trait(?Send)]
+    impl Stage for Fake {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        async fn process(&mut self, reading: &mut Reading) -> Result<Flow, StageError> {
+            self.log.borrow_mut().push(self.name);
+            (self.process)(reading)
+        }
+    }
+
+    fn decode(reading: &mut Reading) -> Result<Flow, StageError> {
+        for flags in [vec!["stuck".to_string()], vec![], vec!["stuck".to_string()]] {
+            reading.samples.push(Sample {
+                data: LoudnessData::new(50.0, Timestamp::UNIX_EPOCH),
+                flags,
+                spectrum: None,
+            });
+        }
+        Ok(Flow::Continue)
+    }
+
+    fn flag(reading: &mut Reading) -> Result<Flow, StageError> {
+        reading.samples()?[1].flags.push("delayed".to_string());
+        Ok(Flow::Continue)
+    }
+
+    fn stop(_: &mut Reading) -> Result<Flow, StageError> {
+        Ok(Flow::Stop)
+    }
+
+    fn reject(_: &mut Reading) -> Result<Flow, StageError> {
+        Err(StageError::Rejected("not plausible".to_string()))
+    }
+
+    fn fail(_: &mut Reading) -> Result<Flow, StageError> {
+        Err("connection refused".into())
+    }
+
+    /// Returns a pipeline of the given stages and the log of the stages that ran
+    async fn pipeline(stages: &[(&'static str, Process)]) -> (Pipeline, Log) {
+        // nothing listens on port 1, so the database is unavailable
+        let db_pool = Pool::new(
+            Some("127.0.0.1".to_string()),
+            Some(1),
+            Some("user".to_string()),
+            Some("password".to_string()),
+            Some("iot_sound".to_string()),
+        )
+        .await
+        .unwrap();
+        let log = Log::default();
+        let stages = stages
+            .iter()
+            .map(|&(name, process)| {
+                Box::new(Fake {
+                    name,
+                    log: log.clone(),
+                    process,
+                }) as Box<dyn Stage>
+            })
+            .collect();
+        (Pipeline::new(db_pool, stages), log)
+    }
+
+    async fn run(pipeline: &mut Pipeline) -> Result<Outcome, Box<dyn Error>> {
+        pipeline
+            .run(
+                "ntnu/a/b/loudness/group06/sensor",
+                b"50,0",
+                SystemTime::now(),
+                false,
+            )
+            .await
+    }
+
+    #[tokio::test]
+    async fn completed_messages_carry_every_flag_once() {
+        let (mut pipeline, log) = pipeline(&[("decode", decode), ("validate", flag)]).await;
+        assert_eq!(pipeline.stage_names(), vec!["decode", "validate"]);
+        match run(&mut pipeline).await.unwrap() {
+            Outcome::Completed { flags } => assert_eq!(flags, vec!["stuck", "delayed"]),
+            other => panic!("unexpected outcome {:?}", other),
+        }
+        assert_eq!(*log.borrow(), vec!["decode", "validate"]);
+    }
+
+    #[tokio::test]
+    async fn stop_skips_the_remaining_stages() {
+        let (mut pipeline, log) =
+            pipeline(&[("decode", decode), ("forward", stop), ("store", decode)]).await;
+        match run(&mut pipeline).await.unwrap() {
+            Outcome::Stopped { stage } => assert_eq!(stage, "forward"),
+            other => panic!("unexpected outcome {:?}", other),
+        }
+        assert_eq!(*log.borrow(), vec!["decode", "forward"]);
+    }
+
+    #[tokio::test]
+    async fn rejected_messages_skip_the_remaining_stages() {
+        let (mut pipeline, log) =
+            pipeline(&[("decode", decode), ("validate", reject), ("store", decode)]).await;
+        match run(&mut pipeline).await.unwrap() {
+            Outcome::Rejected { stage, reason } => {
+                assert_eq!(stage, "validate");
+                assert_eq!(reason, "not plausible");
+            }
+            other => panic!("unexpected outcome {:?}", other),
+        }
+        assert_eq!(*log.borrow(), vec!["decode", "validate"]);
+    }
+
+    #[tokio::test]
+    async fn stages_need_decoded_readings() {
+        let (mut pipeline, _) = pipeline(&[("validate", flag)]).await;
+        let error = run(&mut pipeline).await.unwrap_err();
+        assert_eq!(error.to_string(), "Reading has not been decoded");
+    }
+
+    #[tokio::test]
+    async fn failures_while_the_database_is_down_are_retried() {
+        let (mut pipeline, log) = pipeline(&[("decode", decode), ("store", fail)]).await;
+        let error = run(&mut pipeline).await.unwrap_err();
+        assert_eq!(error.to_string(), "connection refused");
+        assert_eq!(*log.borrow(), vec!["decode", "store"]);
+    }
+}
@@ -0,0 +1,214 @@
+use crate::liveness::Liveness;
+use iot_sound_backend::auth::{Channel, KeysFile};
+use iot_sound_backend::status::{self, Connection, StatusMessage};
+use iot_sound_database::Pool;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tracing::{debug, info, warn};
+
+/// Topic sensors announce their status on, see `status::status_topic`
+pub const STATUS_TOPIC: &str = "ntnu/+/+/+/group06/+/status";
+
+/// Handle a status message from a sensor, its birth message or its last will
+///
+/// The liveness tracker marks the sensor offline as soon as its last will arrives,
+/// instead of waiting for it to miss readings. Status messages from sensors with a key
+/// must be signed. Birth messages are only taken if their counter is higher than the one
+/// of the stored status. A last will is signed when the sensor connects, before its birth
+/// messages, so its counter is not checked. It is only taken if its session is the one
+/// of the stored birth message, and only once, so neither a replayed last will nor a late
+/// one from a connection the sensor has replaced marks a connected sensor offline.
+///
+/// Retained messages the broker delivers on subscribing were handled when they were
+/// published, they would make a sensor seem online now.
+///
+/// # Arguments
+/// * `db_pool` - The database pool
+/// * `keys_file` - The keys to verify the message with
+/// * `liveness` - The liveness tracker
+/// * `topic` - The status topic the message was received on
+/// * `payload` - The status as json
+/// * `retained` - Whether the broker delivered the message as retained
+/// * `received` - The time the message was received
+pub async fn record_status(
+    db_pool: &Pool,
+    keys_file: &KeysFile,
+    liveness: &Arc<Mutex<Liveness>>,
+    topic: &str,
+    payload: &[u8],
+    retained: bool,
+    received: SystemTime,
+) -> Result<(), Box<dyn Error>> {
+    let sensor_id = status::sensor_topic_of_status(topic)
+        .and_then(|sensor_topic| sensor_topic.rsplit('/').next())
+        .ok_or_else(|| format!("Not a status topic: {}", topic))?;
+    if retained {
+        debug!("Ignoring retained status of sensor {}", sensor_id);
+        return Ok(());
+    }
+    let (message, counter) = keys_file
+        .verify(Channel::Status, sensor_id, payload)
+        .map_err(|e| format!("Unauthenticated status from sensor {}: {}", sensor_id, e))?;
+    let message: StatusMessage = serde_json::from_str(message)
+        .map_err(|e| format!("Invalid status from sensor {}: {}", sensor_id, e))?;
+    let session = message
+        .session
+        .map(i64::try_from)
+        .transpose()
+        .map_err(|_| format!("Invalid session in status from sensor {}", sensor_id))?;
+    let stored = match message.state {
+        Connection::Online => {
+            let counter = counter
+                .map(i64::try_from)
+                .transpose()
+                .map_err(|_| format!("Invalid counter in status from sensor {}", sensor_id))?;
+            let booted_at = message
+                .uptime_secs
+                .and_then(|uptime| received.checked_sub(Duration::from_secs(uptime)));
+            db_pool
+                .upsert_sensor_connection(
+                    sensor_id,
+                    message.firmware_version.as_deref(),
+                    booted_at,
+                    message.config_hash.as_deref(),
+                    counter,
+                    session,
+                )
+                .await?
+        }
+        Connection::Offline => match session {
+            Some(session) => db_pool.mark_sensor_offline(sensor_id, session).await?,
+            None => false,
+        },
+    };
+    if !stored {
+        match message.state {
+            Connection::Online => warn!(
+                "Ignoring status from sensor {}: a newer status is stored",
+                sensor_id
+            ),
+            Connection::Offline => warn!(
+                "Ignoring last will of sensor {}: it is not for the current connection",
+                sensor_id
+            ),
+        }
+        return Ok(());
+    }
+
+    liveness
+        .lock()
+        .expect("Liveness lock poisoned")
+        .record_connection(sensor_id, message.state == Connection::Online, received);
+
+    match message.state {
+        Connection::Online => info!(
+            db_log = true,
+            "Sensor {} connected, firmware {}, up {}s, config {}",
+            sensor_id,
+            message.firmware_version.as_deref().unwrap_or("unknown"),
+            message.uptime_secs.unwrap_or(0),
+            message.config_hash.as_deref().unwrap_or("unknown")
+        ),
+        Connection::Offline => info!(
+            db_log = true,
+            "Sensor {} lost its connection to the broker", sensor_id
+        ),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    /// Returns a pool for the database in `TEST_DB_NAME`, `None` if it is not set
+    async fn test_pool() -> Option<Pool> {
+        let dbname = env::var("TEST_DB_NAME").ok()?;
+        let var =
+            |name: &str, default: &str| env::var(name).unwrap_or_else(|_| default.to_string());
+        let pool = Pool::new(
+            Some(var("TEST_DB_HOST", "localhost")),
+            Some(var("TEST_DB_PORT", "5432").parse().unwrap()),
+            Some(var("TEST_DB_USER", "postgres")),
+            Some(var("TEST_DB_PASSWORD", "postgres")),
+            Some(dbname),
+        )
+        .await
+        .unwrap();
+        pool.create_sensor_table().await.unwrap();
+        pool.create_sensor_status_table().await.unwrap();
+        pool.create_sensor_connection_table().await.unwrap();
+        Some(pool)
+    }
+
+    fn status(state: Connection, session: u64) -> Vec<u8> {
+        serde_json::to_vec(&StatusMessage {
+            state,
+            session: Some(session),
+            firmware_version: None,
+            uptime_secs: None,
+            config_hash: None,
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn only_the_last_will_of_the_current_connection_is_taken() {
+        let Some(pool) = test_pool().await else {
+            eprintln!("TEST_DB_NAME is not set, skipping");
+            return;
+        };
+        let sensor_id = format!("presence-{}", std::process::id());
+        let topic = format!("ntnu/a/b/c/group06/{}/status", sensor_id);
+        pool.insert_new_sensor(&sensor_id, "loudness", "test")
+            .await
+            .unwrap();
+        pool.upsert_sensor_status(&sensor_id, "online", SystemTime::now(), 1.0)
+            .await
+            .unwrap();
+        let keys_file = KeysFile {
+            path: None,
+            required: false,
+        };
+        let liveness = Arc::new(Mutex::new(Liveness::new(None)));
+        let connection = || async {
+            pool.get_sensor_statuses()
+                .await
+                .unwrap()
+                .into_iter()
+                .find(|status| status.get_sensor_id() == sensor_id)
+                .and_then(|status| status.get_connection().map(str::to_string))
+        };
+        let record = |payload: Vec<u8>| {
+            let (pool, keys_file, liveness, topic) = (&pool, &keys_file, &liveness, &topic);
+            async move {
+                record_status(
+                    pool,
+                    keys_file,
+                    liveness,
+                    topic,
+                    &payload,
+                    false,
+                    SystemTime::now(),
+                )
+                .await
+                .unwrap()
+            }
+        };
+
+        record(status(Connection::Online, 2)).await;
+        // a late last will of the connection the sensor replaced
+        record(status(Connection::Offline, 1)).await;
+        assert_eq!(connection().await.as_deref(), Some("online"));
+
+        record(status(Connection::Offline, 2)).await;
+        assert_eq!(connection().await.as_deref(), Some("offline"));
+
+        // a replayed last will once the sensor is connected again
+        record(status(Connection::Online, 3)).await;
+        record(status(Connection::Offline, 2)).await;
+        assert_eq!(connection().await.as_deref(), Some("online"));
+    }
+}
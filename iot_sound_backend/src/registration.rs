@@ -0,0 +1,324 @@
+use iot_sound_database::Pool;
+use std::collections::HashSet;
+use std::error::Error;
+use std::str::FromStr;
+use tracing::{error, info};
+
+/// How sensors that are not in the database yet are handled
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegistrationPolicy {
+    /// Unknown sensors are added to the database on their first reading
+    AutoAccept,
+    /// Only sensors on the allowlist are added, readings from others are dropped
+    Allowlist(HashSet<String>),
+    /// Unknown sensors are queued and their readings held until an operator approves them
+    PendingApproval,
+}
+
+impl FromStr for RegistrationPolicy {
+    type Err = Box<dyn Error>;
+
+    /// Parses `auto`, `allowlist` or `approval`.
+    /// The allowlist is read from the `SENSOR_ALLOWLIST` env variable, comma separated.
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy.trim() {
+            "auto" => Ok(RegistrationPolicy::AutoAccept),
+            "allowlist" => {
+                let allowlist = std::env::var("SENSOR_ALLOWLIST")
+                    .map_err(|_| "SENSOR_ALLOWLIST must be set for the allowlist policy")?;
+                Ok(RegistrationPolicy::Allowlist(
+                    allowlist
+                        .split(',')
+                        .map(|id| id.trim().to_string())
+                        .filter(|id| !id.is_empty())
+                        .collect(),
+                ))
+            }
+            "approval" => Ok(RegistrationPolicy::PendingApproval),
+            other => Err(format!(
+                "Invalid sensor registration policy: {}, expected auto, allowlist or approval",
+                other
+            )
+            .into()),
+        }
+    }
+}
+
+/// What to do with a reading from a sensor
+#[derive(Debug, PartialEq, Eq)]
+pub enum Admission {
+    /// The sensor is registered, store the reading
+    Store,
+    /// The sensor is waiting for approval, hold the reading
+    Hold,
+    /// The sensor is not allowed, drop the reading
+    Drop,
+}
+
+/// What to do about a sensor that is not in the database
+#[derive(Debug, PartialEq, Eq)]
+enum Decision {
+    /// Add the sensor to the database and store its readings
+    Register,
+    /// Queue the sensor for approval and hold its readings
+    Queue,
+    /// The sensor is already queued, hold its readings
+    Hold,
+    /// Drop the readings
+    Drop,
+}
+
+impl RegistrationPolicy {
+    /// Decide what to do about a sensor that is not in the database
+    /// # Arguments
+    /// * `sensor_id` - The id of the sensor
+    /// * `pending_status` - The status of the sensor in the approval queue, if it is queued
+    fn decide(&self, sensor_id: &str, pending_status: Option<&str>) -> Decision {
+        match self {
+            RegistrationPolicy::AutoAccept => Decision::Register,
+            RegistrationPolicy::Allowlist(allowlist) if allowlist.contains(sensor_id) => {
+                Decision::Register
+            }
+            RegistrationPolicy::Allowlist(_) => Decision::Drop,
+            RegistrationPolicy::PendingApproval => match pending_status {
+                Some("rejected") => Decision::Drop,
+                Some(_) => Decision::Hold,
+                None => Decision::Queue,
+            },
+        }
+    }
+}
+
+/// Decides whether readings from a sensor are stored, held or dropped,
+/// registering new sensors according to the policy.
+pub struct Registrar {
+    policy: RegistrationPolicy,
+    /// Ids of the sensors in the database, loaded on first use
+    sensors_cache: Option<Vec<String>>,
+    /// Unknown sensors that have already been logged, so they are only logged once
+    reported: HashSet<String>,
+}
+
+impl Registrar {
+    /// Create a new Registrar
+    /// # Arguments
+    /// * `policy` - The registration policy
+    pub fn new(policy: RegistrationPolicy) -> Self {
+        Registrar {
+            policy,
+            sensors_cache: None,
+            reported: HashSet::new(),
+        }
+    }
+
+    /// Returns whether the sensor is in the database, as far as the cache knows
+    async fn is_known(&mut self, db_pool: &Pool, sensor_id: &str) -> Result<bool, Box<dyn Error>> {
+        if self.sensors_cache.is_none() {
+            self.sensors_cache = Some(db_pool.get_sensor_ids().await?);
+        }
+        Ok(self
+            .sensors_cache
+            .iter()
+            .flatten()
+            .any(|id| id == sensor_id))
+    }
+
+    /// Returns whether the sensor is in the database, reloading the cache first.
+    /// Used before registering a sensor, since another backend sharing the
+    /// subscription may have registered it after the cache was loaded.
+    async fn is_known_fresh(
+        &mut self,
+        db_pool: &Pool,
+        sensor_id: &str,
+    ) -> Result<bool, Box<dyn Error>> {
+        self.sensors_cache = None;
+        self.is_known(db_pool, sensor_id).await
+    }
+
+    /// Decide what to do with a reading published to the given topic
+    /// # Arguments
+    /// * `db_pool` - The database pool
+    /// * `topic_split` - The topic the reading was published to, split on '/'
+    pub async fn admit(
+        &mut self,
+        db_pool: &Pool,
+        topic_split: &[&str],
+    ) -> Result<Admission, Box<dyn Error>> {
+        let sensor_id = *topic_split.last().expect("Subscribed topic is valid");
+        if self.is_known(db_pool, sensor_id).await? {
+            return Ok(Admission::Store);
+        }
+
+        let pending_status = match self.policy {
+            RegistrationPolicy::PendingApproval => {
+                db_pool.get_pending_sensor_status(sensor_id).await?
+            }
+            _ => None,
+        };
+        match self.policy.decide(sensor_id, pending_status.as_deref()) {
+            Decision::Register => {
+                // another backend may have registered the sensor since the cache was filled
+                if self.is_known_fresh(db_pool, sensor_id).await? {
+                    return Ok(Admission::Store);
+                }
+                let message = match self.policy {
+                    RegistrationPolicy::Allowlist(_) => {
+                        format!("Sensor {} is on the allowlist, adding...", sensor_id)
+                    }
+                    _ => format!("Sensor {} not found in database, adding...", sensor_id),
+                };
+                self.log_once(sensor_id, &message);
+                self.register(db_pool, topic_split).await?;
+                Ok(Admission::Store)
+            }
+            Decision::Queue => {
+                // the sensor may have been approved since the cache was filled
+                if self.is_known_fresh(db_pool, sensor_id).await? {
+                    return Ok(Admission::Store);
+                }
+                let (sensor_type, sensor_location) = sensor_type_and_location(topic_split)?;
+                db_pool
+                    .insert_pending_sensor(sensor_id, sensor_type, &sensor_location)
+                    .await?;
+                self.log_once(
+                    sensor_id,
+                    &format!("Sensor {} is awaiting approval", sensor_id),
+                );
+                Ok(Admission::Hold)
+            }
+            Decision::Hold => Ok(Admission::Hold),
+            Decision::Drop => {
+                if let RegistrationPolicy::Allowlist(_) = self.policy {
+                    self.log_once(
+                        sensor_id,
+                        &format!(
+                            "Sensor {} is not on the allowlist, dropping readings",
+                            sensor_id
+                        ),
+                    );
+                }
+                Ok(Admission::Drop)
+            }
+        }
+    }
+
+    /// Add a new sensor to the database and refresh the cache
+    /// If another backend adds the same sensor at the same time the insert does nothing.
+    async fn register(
+        &mut self,
+        db_pool: &Pool,
+        topic_split: &[&str],
+    ) -> Result<(), Box<dyn Error>> {
+        add_new_sensor(db_pool, topic_split).await?;
+        self.sensors_cache = Some(db_pool.get_sensor_ids().await?);
+        Ok(())
+    }
+
+    /// Log a message about an unknown sensor, once per sensor
+    fn log_once(&mut self, sensor_id: &str, message: &str) {
+        if self.reported.insert(sensor_id.to_string()) {
+            info!(db_log = true, "{}", message);
+        }
+    }
+}
+
+/// Returns the sensor type and location encoded in the topic
+/// # Arguments
+/// * `topic_split` - The topic split on '/'
+fn sensor_type_and_location<'a>(
+    topic_split: &[&'a str],
+) -> Result<(&'a str, String), Box<dyn Error>> {
+    let sensor_type = topic_split[3];
+    let sensor_location = format!("{}/{}/{}", topic_split[0], topic_split[1], topic_split[2]);
+
+    if sensor_type == "loudness" {
+        Ok((sensor_type, sensor_location))
+    } else {
+        Err((format!("Sensor type {} not supported", sensor_type)).into())
+    }
+}
+
+async fn add_new_sensor(db_pool: &Pool, topic_split: &[&str]) -> Result<(), Box<dyn Error>> {
+    let sensor_id = topic_split.last().unwrap();
+    let (sensor_type, sensor_location) = sensor_type_and_location(topic_split)?;
+
+    if let Err(e) = db_pool
+        .insert_new_sensor(sensor_id, sensor_type, &sensor_location)
+        .await
+    {
+        error!("Error inserting new sensor into database: {}", e);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn policies_decide_about_unknown_sensors() {
+        let allowlist = RegistrationPolicy::Allowlist(HashSet::from(["allowed".to_string()]));
+        let cases = [
+            (
+                RegistrationPolicy::AutoAccept,
+                "sensor",
+                None,
+                Decision::Register,
+            ),
+            (allowlist.clone(), "allowed", None, Decision::Register),
+            (allowlist, "other", None, Decision::Drop),
+            (
+                RegistrationPolicy::PendingApproval,
+                "sensor",
+                None,
+                Decision::Queue,
+            ),
+            (
+                RegistrationPolicy::PendingApproval,
+                "sensor",
+                Some("pending"),
+                Decision::Hold,
+            ),
+            (
+                RegistrationPolicy::PendingApproval,
+                "sensor",
+                Some("rejected"),
+                Decision::Drop,
+            ),
+        ];
+        for (policy, sensor_id, pending_status, expected) in cases {
+            assert_eq!(
+                policy.decide(sensor_id, pending_status),
+                expected,
+                "{:?} {} {:?}",
+                policy,
+                sensor_id,
+                pending_status
+            );
+        }
+    }
+
+    #[test]
+    fn policies_parse() {
+        assert_eq!(
+            "auto".parse::<RegistrationPolicy>().unwrap(),
+            RegistrationPolicy::AutoAccept
+        );
+        assert_eq!(
+            " approval ".parse::<RegistrationPolicy>().unwrap(),
+            RegistrationPolicy::PendingApproval
+        );
+        assert!("open".parse::<RegistrationPolicy>().is_err());
+    }
+
+    #[test]
+    fn only_loudness_sensors_can_register() {
+        let topic = ["ntnu", "ankeret", "c220", "loudness", "group06", "sensor"];
+        assert_eq!(
+            sensor_type_and_location(&topic).unwrap(),
+            ("loudness", "ntnu/ankeret/c220".to_string())
+        );
+        let topic = ["ntnu", "ankeret", "c220", "humidity", "group06", "sensor"];
+        assert!(sensor_type_and_location(&topic).is_err());
+    }
+}
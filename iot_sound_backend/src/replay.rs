@@ -0,0 +1,156 @@
+use crate::capture;
+use crate::pipeline::{Outcome, Pipeline};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::info;
+
+pub const USAGE: &str =
+    "Usage: iot_sound_backend replay <file> [--speed real|max|<factor>] [--dry-run]
+
+Feeds a capture or spool file through the ingestion pipeline.
+--speed     real keeps the original timing, a factor such as 10 replays ten times faster,
+            max replays without waiting (default max)
+--dry-run   only decodes and validates, nothing is written to the database";
+
+/// How fast messages are replayed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Speed {
+    /// With the time between messages divided by the factor, 1 is real time
+    Factor(f64),
+    /// As fast as possible
+    Max,
+}
+
+impl FromStr for Speed {
+    type Err = Box<dyn Error>;
+
+    fn from_str(speed: &str) -> Result<Self, Self::Err> {
+        match speed.trim() {
+            "real" => Ok(Speed::Factor(1.0)),
+            "max" => Ok(Speed::Max),
+            factor => match factor.parse::<f64>() {
+                Ok(factor) if factor > 0.0 => Ok(Speed::Factor(factor)),
+                _ => Err(format!(
+                    "Invalid replay speed: {}, expected real, max or a positive factor",
+                    speed
+                )
+                .into()),
+            },
+        }
+    }
+}
+
+/// Options of the replay command
+pub struct Options {
+    pub path: String,
+    pub speed: Speed,
+    pub dry_run: bool,
+}
+
+impl Options {
+    /// Parse the arguments after `replay`
+    /// # Arguments
+    /// * `args` - The command line arguments
+    pub fn parse(args: &[String]) -> Result<Self, Box<dyn Error>> {
+        let mut path = None;
+        let mut speed = Speed::Max;
+        let mut dry_run = false;
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--speed" => speed = args.next().ok_or(USAGE)?.parse()?,
+                "--dry-run" => dry_run = true,
+                other if path.is_none() && !other.starts_with("--") => {
+                    path = Some(other.to_string())
+                }
+                _ => return Err(USAGE.into()),
+            }
+        }
+        Ok(Options {
+            path: path.ok_or(USAGE)?,
+            speed,
+            dry_run,
+        })
+    }
+
+    /// Returns the stages to replay through, out of the configured ones.
+    /// Readings are never forwarded again, and a dry run does not touch the database.
+    /// # Arguments
+    /// * `stages` - The configured stages, comma separated
+    pub fn stages(&self, stages: &str) -> String {
+        let skipped: &[&str] = if self.dry_run {
+            &["forward", "register", "store"]
+        } else {
+            &["forward"]
+        };
+        stages
+            .split(',')
+            .map(|stage| stage.trim())
+            .filter(|stage| !skipped.contains(stage))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+/// Feed the messages in a capture file through the pipeline and print what happened to them,
+/// every rejected message with its line in the file and a count per outcome at the end
+///
+/// Messages keep their original receive time, so the validation outcome is the same
+/// as when they were received.
+///
+/// # Arguments
+/// * `options` - The options of the replay command
+/// * `pipeline` - The pipeline to replay through
+pub async fn replay(options: &Options, pipeline: &mut Pipeline) -> Result<(), Box<dyn Error>> {
+    let captured = capture::read(&options.path)?;
+    let messages = captured.messages;
+    info!(
+        "Replaying {} messages from {} through {}",
+        messages.len(),
+        options.path,
+        pipeline.stage_names().join(" -> ")
+    );
+
+    let mut outcomes: BTreeMap<String, u64> = BTreeMap::new();
+    let mut previous = None;
+    for (line, message) in &messages {
+        if let (Speed::Factor(factor), Some(previous)) = (options.speed, previous) {
+            let gap = message
+                .received()
+                .duration_since(previous)
+                .unwrap_or_default();
+            tokio::time::sleep(Duration::from_secs_f64(gap.as_secs_f64() / factor)).await;
+        }
+        previous = Some(message.received());
+
+        let outcome = pipeline
+            .run(&message.topic, &message.payload, message.received(), false)
+            .await
+            .map_err(|e| format!("Database unavailable, replay stopped: {}", e))?;
+        let key = match outcome {
+            Outcome::Completed { flags } if flags.is_empty() => "accepted".to_string(),
+            Outcome::Completed { flags } => format!("accepted with flags {}", flags.join(", ")),
+            Outcome::Stopped { stage } => format!("stopped by {}", stage),
+            Outcome::Rejected { stage, reason } => {
+                println!("line {}: rejected by {}: {}", line, stage, reason);
+                format!("rejected by {}", stage)
+            }
+            Outcome::Failed { stage, error } => {
+                println!("line {}: failed in {}: {}", line, stage, error);
+                format!("failed in {}", stage)
+            }
+        };
+        *outcomes.entry(key).or_insert(0) += 1;
+    }
+
+    println!("Replayed {} messages", messages.len());
+    if captured.skipped > 0 {
+        println!("Skipped {} unreadable lines", captured.skipped);
+    }
+    for (outcome, count) in outcomes {
+        println!("{:>8} {}", count, outcome);
+    }
+    Ok(())
+}
@@ -0,0 +1,337 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{error, warn};
+
+/// Number of handled messages after which the spool file is rewritten
+const COMPACT_AFTER: usize = 1000;
+
+/// What to do when the spool is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Drop the oldest message to make room for the new one
+    DropOldest,
+    /// Drop the new message
+    DropNewest,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = Box<dyn Error>;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy.trim() {
+            "drop_oldest" => Ok(OverflowPolicy::DropOldest),
+            "drop_newest" => Ok(OverflowPolicy::DropNewest),
+            other => Err(format!(
+                "Invalid spool overflow policy: {}, expected drop_oldest or drop_newest",
+                other
+            )
+            .into()),
+        }
+    }
+}
+
+/// A message received from the broker, as it is written to disk
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpooledMessage {
+    pub topic: String,
+    #[serde(with = "hex")]
+    pub payload: Vec<u8>,
+    /// Receive time in milliseconds since the unix epoch
+    received_ms: u64,
+    /// Whether the message went through the pipeline before it was spooled.
+    /// Not written to disk, after a restart the pipeline has no state left from the attempt.
+    #[serde(skip)]
+    pub attempted: bool,
+}
+
+impl SpooledMessage {
+    pub fn new(topic: String, payload: Vec<u8>, received: SystemTime) -> Self {
+        SpooledMessage {
+            topic,
+            payload,
+            received_ms: received
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            attempted: false,
+        }
+    }
+
+    /// Returns the time the message was received from the broker
+    pub fn received(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.received_ms)
+    }
+}
+
+/// Durable first in, first out queue of messages that could not be stored
+/// because the database was unavailable.
+///
+/// Messages are appended to a file as json lines and kept in memory.
+/// Handled messages are removed from the file when it is compacted,
+/// a crash before that replays them again, which the database ignores.
+pub struct Spool {
+    path: PathBuf,
+    file: File,
+    queue: VecDeque<SpooledMessage>,
+    max_messages: usize,
+    overflow: OverflowPolicy,
+    /// Messages removed from the queue but still in the file
+    stale: usize,
+    dropped: u64,
+}
+
+impl Spool {
+    /// Open the spool file, loading any messages left from a previous run
+    /// # Arguments
+    /// * `path` - Path to the spool file
+    /// * `max_messages` - The maximum number of messages in the spool
+    /// * `overflow` - What to do when the spool is full
+    pub fn open(
+        path: impl Into<PathBuf>,
+        max_messages: usize,
+        overflow: OverflowPolicy,
+    ) -> io::Result<Self> {
+        let path = path.into();
+        let mut queue = VecDeque::new();
+        if let Ok(file) = File::open(&path) {
+            for line in BufReader::new(file).lines() {
+                match serde_json::from_str::<SpooledMessage>(&line?) {
+                    Ok(message) => queue.push_back(message),
+                    // a partially written last line from a crash
+                    Err(e) => warn!("Skipping unreadable spool entry: {}", e),
+                }
+            }
+        }
+        let mut dropped = 0;
+        while queue.len() > max_messages {
+            match overflow {
+                OverflowPolicy::DropOldest => queue.pop_front(),
+                OverflowPolicy::DropNewest => queue.pop_back(),
+            };
+            dropped += 1;
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let mut spool = Spool {
+            path,
+            file,
+            queue,
+            max_messages,
+            overflow,
+            stale: 0,
+            dropped,
+        };
+        spool.compact()?;
+        Ok(spool)
+    }
+
+    /// Returns the number of messages in the spool
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Returns the number of messages dropped because the spool was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Add a message to the end of the spool, it is on disk when this returns
+    /// # Arguments
+    /// * `message` - The message to add
+    pub fn push(&mut self, message: SpooledMessage) -> io::Result<()> {
+        if self.queue.len() >= self.max_messages {
+            self.dropped += 1;
+            match self.overflow {
+                OverflowPolicy::DropNewest => return Ok(()),
+                OverflowPolicy::DropOldest => self.pop_front(),
+            }
+        }
+        let mut line = serde_json::to_string(&message)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.file.sync_data()?;
+        self.queue.push_back(message);
+        Ok(())
+    }
+
+    /// Returns the oldest message in the spool
+    pub fn front(&self) -> Option<&SpooledMessage> {
+        self.queue.front()
+    }
+
+    /// Remember that the oldest message went through the pipeline before it failed
+    pub fn mark_front_attempted(&mut self) {
+        if let Some(message) = self.queue.front_mut() {
+            message.attempted = true;
+        }
+    }
+
+    /// Remove the oldest message from the spool
+    pub fn pop_front(&mut self) {
+        if self.queue.pop_front().is_some() {
+            self.stale += 1;
+        }
+        if self.stale >= COMPACT_AFTER || (self.queue.is_empty() && self.stale > 0) {
+            if let Err(e) = self.compact() {
+                error!("Error compacting spool file: {}", e);
+            }
+        }
+    }
+
+    /// Rewrite the spool file with only the messages still in the queue
+    fn compact(&mut self) -> io::Result<()> {
+        let tmp_path = self.path.with_extension("tmp");
+        {
+            let mut tmp = File::create(&tmp_path)?;
+            for message in &self.queue {
+                let mut line = serde_json::to_string(message)?;
+                line.push('\n');
+                tmp.write_all(line.as_bytes())?;
+            }
+            tmp.sync_all()?;
+        }
+        fs::rename(&tmp_path, &self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.stale = 0;
+        Ok(())
+    }
+}
+
+/// Hex encoding of binary payloads, so the spool file stays line based
+mod hex {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        serializer.serialize_str(&hex)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        if !hex.is_ascii() || hex.len() % 2 != 0 {
+            return Err(de::Error::custom("invalid hex string"));
+        }
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(de::Error::custom))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a path in a fresh temporary directory
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("spool-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("spool.jsonl")
+    }
+
+    fn message(n: u8) -> SpooledMessage {
+        SpooledMessage::new(
+            format!("topic/{}", n),
+            vec![n, 0xff],
+            UNIX_EPOCH + Duration::from_millis(1_700_000_000_000 + n as u64),
+        )
+    }
+
+    fn topics(spool: &mut Spool) -> Vec<String> {
+        let mut topics = Vec::new();
+        while let Some(message) = spool.front() {
+            topics.push(message.topic.clone());
+            spool.pop_front();
+        }
+        topics
+    }
+
+    #[test]
+    fn messages_survive_a_restart() {
+        let path = temp_path("restart");
+        let mut spool = Spool::open(&path, 10, OverflowPolicy::DropOldest).unwrap();
+        spool.push(message(1)).unwrap();
+        spool.push(message(2)).unwrap();
+        drop(spool);
+
+        let spool = Spool::open(&path, 10, OverflowPolicy::DropOldest).unwrap();
+        assert_eq!(spool.len(), 2);
+        let front = spool.front().unwrap();
+        assert_eq!(front.topic, "topic/1");
+        assert_eq!(front.payload, vec![1, 0xff]);
+        assert_eq!(front.received(), message(1).received());
+        assert!(!front.attempted);
+    }
+
+    #[test]
+    fn unreadable_lines_are_skipped() {
+        let path = temp_path("corrupt");
+        let mut spool = Spool::open(&path, 10, OverflowPolicy::DropOldest).unwrap();
+        spool.push(message(1)).unwrap();
+        spool
+            .file
+            .write_all(b"{\"topic\":\"topic/2\",\"payl")
+            .unwrap();
+        spool.file.write_all(b"\nnot json\n").unwrap();
+        spool.push(message(3)).unwrap();
+        drop(spool);
+
+        let mut spool = Spool::open(&path, 10, OverflowPolicy::DropOldest).unwrap();
+        assert_eq!(topics(&mut spool), vec!["topic/1", "topic/3"]);
+    }
+
+    #[test]
+    fn drained_messages_are_removed_from_the_file() {
+        let path = temp_path("drain");
+        let mut spool = Spool::open(&path, 10, OverflowPolicy::DropOldest).unwrap();
+        for n in 1..=3 {
+            spool.push(message(n)).unwrap();
+        }
+        spool.pop_front();
+        spool.mark_front_attempted();
+        assert!(spool.front().unwrap().attempted);
+        drop(spool);
+
+        // the file is only compacted every so often, so a restart replays the first message
+        let mut spool = Spool::open(&path, 10, OverflowPolicy::DropOldest).unwrap();
+        assert!(!spool.front().unwrap().attempted);
+        assert_eq!(topics(&mut spool), vec!["topic/1", "topic/2", "topic/3"]);
+        assert!(spool.is_empty());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+        drop(spool);
+
+        let spool = Spool::open(&path, 10, OverflowPolicy::DropOldest).unwrap();
+        assert!(spool.is_empty());
+    }
+
+    #[test]
+    fn a_full_spool_drops_by_policy() {
+        let path = temp_path("oldest");
+        let mut spool = Spool::open(&path, 2, OverflowPolicy::DropOldest).unwrap();
+        for n in 1..=3 {
+            spool.push(message(n)).unwrap();
+        }
+        assert_eq!(spool.dropped(), 1);
+        assert_eq!(topics(&mut spool), vec!["topic/2", "topic/3"]);
+
+        let path = temp_path("newest");
+        let mut spool = Spool::open(&path, 2, OverflowPolicy::DropNewest).unwrap();
+        for n in 1..=3 {
+            spool.push(message(n)).unwrap();
+        }
+        drop(spool);
+        let mut spool = Spool::open(&path, 2, OverflowPolicy::DropNewest).unwrap();
+        assert_eq!(topics(&mut spool), vec!["topic/1", "topic/2"]);
+    }
+}
@@ -0,0 +1,907 @@
+use crate::liveness::Liveness;
+use crate::metrics::Metrics;
+use crate::pipeline::{Flow, Reading, Sample, Stage, StageError};
+use crate::registration::{Admission, Registrar, RegistrationPolicy};
+use crate::validation::{Validator, Verdict};
+use async_trait::async_trait;
+use iot_sound_backend::auth::{self, AuthError, KeyStore, SignedPayload};
+use iot_sound_backend::envelope::{self, Sealed};
+use iot_sound_backend::loudness_data::LoudnessData;
+use iot_sound_backend::scale::{Reference, Scale};
+use iot_sound_backend::senml;
+use iot_sound_backend::spectrum::Spectrum;
+use iot_sound_backend::wire::Envelope;
+use iot_sound_database::{NewReading, Pool};
+use rumqttc::{AsyncClient, QoS};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{debug, error, info, warn};
+
+/// How often the authenticate stage checks whether the keys file changed
+const KEYS_RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Everything the stages may need, stages are built from it by name
+pub struct StageContext {
+    pub db_pool: Pool,
+    pub metrics: Metrics,
+    pub liveness: Arc<Mutex<Liveness>>,
+    pub validation_rules_file: Option<String>,
+    pub registration_policy: RegistrationPolicy,
+    pub calibration_file: Option<String>,
+    /// Not available when replaying
+    pub mqtt_client: Option<AsyncClient>,
+    pub forward_topic: Option<String>,
+    pub sensor_keys_file: Option<String>,
+    pub sensor_keys_required: bool,
+    pub encryption_required: bool,
+    /// The highest counter stored per sensor
+    pub sensor_counters: HashMap<String, u64>,
+    /// Firmware versions counted by name in the decode metric, others count as `other`
+    pub known_firmware: Vec<String>,
+}
+
+/// Build the stages with the given names, in order
+/// # Arguments
+/// * `names` - Comma separated stage names: authenticate, decode, validate, register,
+///   calibrate, forward or store
+/// * `context` - What the stages need
+pub fn build(names: &str, context: &StageContext) -> Result<Vec<Box<dyn Stage>>, Box<dyn Error>> {
+    let names: Vec<&str> = names
+        .split(',')
+        .map(|name| name.trim())
+        .filter(|name| !name.is_empty())
+        .collect();
+    check_registration(&names, &context.registration_policy)?;
+    names
+        .into_iter()
+        .map(|name| build_stage(name, context))
+        .collect()
+}
+
+/// Readings are stored unless the register stage decides otherwise, so a pipeline that
+/// stores without registering first would store readings of sensors the policy keeps out
+/// # Arguments
+/// * `names` - The stage names, in order
+/// * `policy` - The registration policy
+fn check_registration(names: &[&str], policy: &RegistrationPolicy) -> Result<(), Box<dyn Error>> {
+    let store = match names.iter().position(|name| *name == "store") {
+        Some(store) => store,
+        None => return Ok(()),
+    };
+    let registered = names[..store].contains(&"register");
+    if !registered && *policy != RegistrationPolicy::AutoAccept {
+        return Err(
+            "The store stage needs the register stage before it unless SENSOR_REGISTRATION_POLICY is auto"
+                .into(),
+        );
+    }
+    Ok(())
+}
+
+fn build_stage(name: &str, context: &StageContext) -> Result<Box<dyn Stage>, Box<dyn Error>> {
+    let stage: Box<dyn Stage> = match name {
+        "authenticate" => {
+            let path = context
+                .sensor_keys_file
+                .clone()
+                .ok_or("SENSOR_KEYS_FILE must be set for the authenticate stage")?;
+            Box::new(Authenticate::new(
+                path,
+                context.sensor_keys_required,
+                context.encryption_required,
+                context.sensor_counters.clone(),
+                context.metrics.clone(),
+            )?)
+        }
+        "decode" => Box::new(Decode {
+            metrics: context.metrics.clone(),
+            known_firmware: context.known_firmware.clone(),
+        }),
+        "validate" => Box::new(Validate {
+            validator: Validator::from_file(context.validation_rules_file.as_deref())?,
+        }),
+        "register" => Box::new(Register {
+            db_pool: context.db_pool.clone(),
+            registrar: Registrar::new(context.registration_policy.clone()),
+        }),
+        "calibrate" => {
+            let path = context
+                .calibration_file
+                .as_deref()
+                .ok_or("CALIBRATION_FILE must be set for the calibrate stage")?;
+            Box::new(Calibrate::from_file(path)?)
+        }
+        "forward" => Box::new(Forward {
+            mqtt_client: context
+                .mqtt_client
+                .clone()
+                .ok_or("The forward stage needs an MQTT client")?,
+            topic: context
+                .forward_topic
+                .clone()
+                .ok_or("FORWARD_TOPIC must be set for the forward stage")?,
+        }),
+        "store" => Box::new(Store {
+            db_pool: context.db_pool.clone(),
+            liveness: context.liveness.clone(),
+            metrics: context.metrics.clone(),
+        }),
+        other => {
+            return Err(format!(
+                "Invalid pipeline stage: {}, expected authenticate, decode, validate, register, calibrate, forward or store",
+                other
+            )
+            .into())
+        }
+    };
+    Ok(stage)
+}
+
+/// Verifies the HMAC of payloads from sensors with a key, or decrypts them if they are
+/// sealed, and strips the signature or envelope from the payload
+struct Authenticate {
+    keys_file: String,
+    keys: KeyStore,
+    /// Modification time of the keys file when it was loaded
+    keys_modified: Option<SystemTime>,
+    keys_checked: Instant,
+    /// Whether payloads from sensors without a key are rejected
+    required: bool,
+    /// Whether payloads that are only signed are rejected
+    encryption_required: bool,
+    /// Highest counter accepted per sensor, starting from the ones stored in the database.
+    /// The store stage checks the counter again in the database, where backends sharing
+    /// the subscription store theirs.
+    counters: HashMap<String, u64>,
+    metrics: Metrics,
+}
+
+impl Authenticate {
+    fn new(
+        keys_file: String,
+        required: bool,
+        encryption_required: bool,
+        counters: HashMap<String, u64>,
+        metrics: Metrics,
+    ) -> Result<Self, Box<dyn Error>> {
+        let keys_modified = modified(&keys_file);
+        Ok(Authenticate {
+            keys: auth::load_keys(&keys_file)?,
+            keys_file,
+            keys_modified,
+            keys_checked: Instant::now(),
+            required,
+            encryption_required,
+            counters,
+            metrics,
+        })
+    }
+
+    /// Reload the keys file if it changed, so keys can be rotated without a restart
+    fn reload_keys(&mut self) {
+        if self.keys_checked.elapsed() < KEYS_RELOAD_INTERVAL {
+            return;
+        }
+        self.keys_checked = Instant::now();
+        let keys_modified = modified(&self.keys_file);
+        if keys_modified == self.keys_modified {
+            return;
+        }
+        match auth::load_keys(&self.keys_file) {
+            Ok(keys) => {
+                info!(
+                    db_log = true,
+                    "Reloaded sensor keys from {}", self.keys_file
+                );
+                self.keys = keys;
+                self.keys_modified = keys_modified;
+            }
+            Err(e) => error!("Error reloading sensor keys: {}", e),
+        }
+    }
+
+    /// Verify or decrypt a payload, returning the payload without the signature or envelope
+    /// and its counter, `None` if the sensor has no key
+    /// # Arguments
+    /// * `sensor_id` - The id of the sensor the payload claims to be from
+    /// * `payload` - The payload as received
+    /// * `replayed` - Whether the payload is replayed from the spool after it was accepted
+    ///   before, its counter is then the last one accepted
+    fn authenticate(
+        &mut self,
+        sensor_id: &str,
+        payload: &[u8],
+        replayed: bool,
+    ) -> Result<(Vec<u8>, Option<u64>), AuthError> {
+        let keys = match self.keys.get(sensor_id) {
+            Some(keys) => keys,
+            None if self.required || envelope::is_sealed(payload) => return Err(AuthError::NoKey),
+            None => return Ok((payload.to_vec(), None)),
+        };
+        let key = |key_id: u32| {
+            keys.iter()
+                .find(|key| key.id == key_id)
+                .and_then(|key| key.bytes().ok())
+                .ok_or(AuthError::UnknownKey(key_id))
+        };
+
+        let (counter, message) = if envelope::is_sealed(payload) {
+            let sealed = Sealed::parse(payload)?;
+            (
+                sealed.counter,
+                sealed.open(sensor_id, &key(sealed.key_id)?)?,
+            )
+        } else {
+            if self.encryption_required {
+                return Err(AuthError::Unencrypted);
+            }
+            let payload = std::str::from_utf8(payload).map_err(|_| AuthError::Malformed)?;
+            let signed = SignedPayload::parse(payload)?;
+            signed.verify(sensor_id, &key(signed.key_id)?)?;
+            (signed.counter, signed.message.as_bytes().to_vec())
+        };
+
+        // counters are stored as a signed 64 bit integer
+        if counter > i64::MAX as u64 {
+            return Err(AuthError::Malformed);
+        }
+        let last = self.counters.entry(sensor_id.to_string()).or_insert(0);
+        if counter < *last || (counter == *last && !replayed) {
+            return Err(AuthError::Replay {
+                counter,
+                last: *last,
+            });
+        }
+        *last = counter;
+        Ok((message, Some(counter)))
+    }
+}
+
+/// Returns the modification time of a file
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[async_trait(?Send)]
+impl Stage for Authenticate {
+    fn name(&self) -> &'static str {
+        "authenticate"
+    }
+
+    async fn process(&mut self, reading: &mut Reading) -> Result<Flow, StageError> {
+        self.reload_keys();
+        match self.authenticate(&reading.sensor_id, &reading.payload, reading.replayed) {
+            Ok((message, counter)) => {
+                reading.payload = message;
+                reading.counter = counter;
+                Ok(Flow::Continue)
+            }
+            Err(e) => {
+                self.metrics
+                    .auth_failures
+                    .with_label_values(&[e.kind()])
+                    .inc();
+                Err(StageError::Rejected(format!(
+                    "Authentication failed for {}: {}",
+                    reading.sensor_id, e
+                )))
+            }
+        }
+    }
+}
+
+/// Decodes the payload, in any supported format version or as a SenML pack,
+/// a batch or pack into its readings
+struct Decode {
+    metrics: Metrics,
+    known_firmware: Vec<String>,
+}
+
+impl Decode {
+    /// The firmware label of the decode metric, unknown versions share one label
+    /// so a sensor cannot add a series per version it claims
+    fn firmware_label<'a>(&'a self, firmware_version: Option<&'a str>) -> &'a str {
+        match firmware_version {
+            None => "unknown",
+            Some(version) if self.known_firmware.iter().any(|known| known == version) => version,
+            Some(_) => "other",
+        }
+    }
+
+    /// Count a payload that could not be decoded and return the error rejecting it
+    fn rejected(&self, kind: &str, e: &dyn Error) -> StageError {
+        self.metrics.parse_failures.with_label_values(&[kind]).inc();
+        StageError::Rejected(format!("Error parsing payload ({}): {}", kind, e))
+    }
+
+    /// Decode a SenML pack, which must name the sensor it was published by, if any
+    fn decode_senml(
+        &self,
+        reading: &mut Reading,
+        encoding: senml::Encoding,
+    ) -> Result<Flow, StageError> {
+        let pack = senml::decode(&reading.payload, reading.received)
+            .map_err(|e| self.rejected(e.kind(), &e))?;
+        if let Some(sensor_id) = pack.sensor_id.filter(|id| *id != reading.sensor_id) {
+            return Err(StageError::Rejected(format!(
+                "SenML pack published by {} names sensor {}",
+                reading.sensor_id, sensor_id
+            )));
+        }
+        if pack.readings.is_empty() {
+            return Err(StageError::Rejected(format!(
+                "SenML pack from {} has no readings in a decibel unit",
+                reading.sensor_id
+            )));
+        }
+        self.metrics
+            .payloads_decoded
+            .with_label_values(&[encoding.as_str(), "unknown"])
+            .inc();
+        reading.samples = samples(pack.readings);
+        reading.fields = pack.fields;
+        Ok(Flow::Continue)
+    }
+}
+
+/// Returns readings without quality flags
+fn samples(readings: Vec<LoudnessData>) -> Vec<Sample> {
+    readings
+        .into_iter()
+        .map(|data| Sample {
+            data,
+            flags: Vec::new(),
+            spectrum: None,
+        })
+        .collect()
+}
+
+/// Returns spectrum readings without quality flags, with the sum of their bands as level
+fn spectrum_samples(spectra: Vec<Spectrum>) -> Vec<Sample> {
+    spectra
+        .into_iter()
+        .map(|spectrum| Sample {
+            data: LoudnessData::new(spectrum.overall_level(), spectrum.timestamp())
+                .with_scale(spectrum.scale()),
+            flags: Vec::new(),
+            spectrum: Some(spectrum),
+        })
+        .collect()
+}
+
+#[async_trait(?Send)]
+impl Stage for Decode {
+    fn name(&self) -> &'static str {
+        "decode"
+    }
+
+    async fn process(&mut self, reading: &mut Reading) -> Result<Flow, StageError> {
+        if let Some(encoding) = senml::Encoding::detect(&reading.payload) {
+            return self.decode_senml(reading, encoding);
+        }
+        let envelope =
+            Envelope::decode(&reading.payload).map_err(|e| self.rejected(e.kind(), &e))?;
+        if envelope.readings.is_empty() && envelope.spectra.is_empty() {
+            return Err(StageError::Rejected(format!(
+                "Batch from {} has no readings",
+                reading.sensor_id
+            )));
+        }
+        self.metrics
+            .payloads_decoded
+            .with_label_values(&[
+                &envelope.version.to_string(),
+                self.firmware_label(envelope.firmware_version.as_deref()),
+            ])
+            .inc();
+        reading.samples = samples(envelope.readings);
+        reading.samples.extend(spectrum_samples(envelope.spectra));
+        reading.fields = envelope.fields;
+        Ok(Flow::Continue)
+    }
+}
+
+/// Rejects implausible readings and flags suspicious ones
+///
+/// A rejected reading in a batch is dropped with a warning, the message is only
+/// rejected when none of its readings are left.
+struct Validate {
+    validator: Validator,
+}
+
+#[async_trait(?Send)]
+impl Stage for Validate {
+    fn name(&self) -> &'static str {
+        "validate"
+    }
+
+    async fn process(&mut self, reading: &mut Reading) -> Result<Flow, StageError> {
+        let samples = std::mem::take(reading.samples()?);
+        // the history already holds the readings of a replayed message, nothing
+        // was validated since it failed because later messages went to the spool
+        if reading.replayed {
+            self.validator.rollback(&reading.sensor_id);
+        }
+        self.validator.checkpoint(&reading.sensor_id);
+        let batched = samples.len() > 1;
+        let mut last_reason = None;
+        for mut sample in samples {
+            let verdict = self.validator.validate(
+                &reading.sensor_id,
+                &reading.sensor_type,
+                &sample.data,
+                reading.received,
+            );
+            if let Verdict::Rejected(reason) = &verdict {
+                let reason = format!("Rejected reading from {}: {}", reading.sensor_id, reason);
+                if batched {
+                    warn!("{}", reason);
+                }
+                last_reason = Some(reason);
+                continue;
+            }
+            sample.flags.extend(verdict.flags());
+            reading.samples.push(sample);
+        }
+        match last_reason {
+            Some(reason) if reading.samples.is_empty() => Err(StageError::Rejected(reason)),
+            _ => Ok(Flow::Continue),
+        }
+    }
+}
+
+/// Decides whether the reading is stored, held or dropped, registering new sensors
+struct Register {
+    db_pool: Pool,
+    registrar: Registrar,
+}
+
+#[async_trait(?Send)]
+impl Stage for Register {
+    fn name(&self) -> &'static str {
+        "register"
+    }
+
+    async fn process(&mut self, reading: &mut Reading) -> Result<Flow, StageError> {
+        let topic_split: Vec<&str> = reading.topic.split('/').collect();
+        reading.admission = self.registrar.admit(&self.db_pool, &topic_split).await?;
+        if reading.admission == Admission::Drop {
+            return Ok(Flow::Stop);
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+/// Calibration of a sensor, in the calibration file as an offset in decibel or as an
+/// object with an offset per reference, e.g. `{"spl": -1.5, "dbfs": 120.0}`
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(untagged, deny_unknown_fields)]
+enum Calibration {
+    /// Added to every reading of the sensor
+    All(f32),
+    /// Added to the readings with that reference, other readings are left as they are
+    PerReference { spl: Option<f32>, dbfs: Option<f32> },
+}
+
+impl Calibration {
+    /// Returns the offset for readings with the reference, if there is one
+    fn offset(&self, reference: Reference) -> Option<f32> {
+        match (self, reference) {
+            (Calibration::All(offset), _) => Some(*offset),
+            (Calibration::PerReference { spl, .. }, Reference::Spl) => *spl,
+            (Calibration::PerReference { dbfs, .. }, Reference::Dbfs) => *dbfs,
+        }
+    }
+}
+
+/// Adds a per sensor offset to the level, for sensors that read too high or too low
+///
+/// The offset for a reading in dBFS is the sensitivity of the sensor, the reading becomes
+/// SPL. A reading in dBFS without an offset stays in dBFS.
+struct Calibrate {
+    /// Calibration per sensor id
+    offsets: HashMap<String, Calibration>,
+}
+
+impl Calibrate {
+    /// Load the offsets from a json file mapping sensor id to its calibration
+    /// # Arguments
+    /// * `path` - Path to the json file
+    fn from_file(path: &str) -> Result<Self, Box<dyn Error>> {
+        let file = std::fs::read_to_string(path)?;
+        Ok(Calibrate {
+            offsets: serde_json::from_str(&file)?,
+        })
+    }
+}
+
+#[async_trait(?Send)]
+impl Stage for Calibrate {
+    fn name(&self) -> &'static str {
+        "calibrate"
+    }
+
+    async fn process(&mut self, reading: &mut Reading) -> Result<Flow, StageError> {
+        if let Some(calibration) = self.offsets.get(&reading.sensor_id) {
+            for sample in reading.samples()? {
+                let Some(offset) = calibration.offset(sample.data.scale().reference) else {
+                    continue;
+                };
+                // the offset of a sensor reporting dBFS is its sensitivity, the result is SPL
+                let scale = Scale {
+                    reference: Reference::Spl,
+                    ..sample.data.scale()
+                };
+                sample.data =
+                    LoudnessData::new(sample.data.db_level() + offset, sample.data.timestamp())
+                        .with_scale(scale);
+                sample.spectrum = sample
+                    .spectrum
+                    .map(|spectrum| spectrum.with_offset(offset).with_scale(scale));
+            }
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+/// Republishes every reading to `<FORWARD_TOPIC>/<sensor id>`, for other systems
+struct Forward {
+    mqtt_client: AsyncClient,
+    topic: String,
+}
+
+impl Forward {
+    /// Returns the payloads the readings are republished as, so their scale is kept
+    ///
+    /// A level is republished as a version 1 payload, the latest with the fields of the
+    /// payload it came in, and a spectrum in the binary spectrum format with its bands.
+    ///
+    /// # Arguments
+    /// * `samples` - The decoded readings
+    /// * `fields` - The optional fields of the payload
+    fn payloads(samples: &[Sample], fields: &BTreeMap<String, String>) -> Vec<Vec<u8>> {
+        let latest = samples
+            .iter()
+            .enumerate()
+            .filter(|(_, sample)| sample.spectrum.is_none())
+            .max_by_key(|(_, sample)| sample.data.timestamp())
+            .map(|(index, _)| index);
+        samples
+            .iter()
+            .enumerate()
+            .map(|(index, sample)| match sample.spectrum {
+                Some(spectrum) => Envelope::spectrum(spectrum).encode(),
+                None => {
+                    let mut envelope = Envelope::new(sample.data, None);
+                    if Some(index) == latest {
+                        envelope.fields = fields.clone();
+                    }
+                    envelope.encode()
+                }
+            })
+            .collect()
+    }
+}
+
+#[async_trait(?Send)]
+impl Stage for Forward {
+    fn name(&self) -> &'static str {
+        "forward"
+    }
+
+    async fn process(&mut self, reading: &mut Reading) -> Result<Flow, StageError> {
+        let topic = format!("{}/{}", self.topic, reading.sensor_id);
+        let fields = reading.fields.clone();
+        for payload in Forward::payloads(reading.samples()?, &fields) {
+            // waiting for room in the request queue could block the event loop, which is
+            // itself waiting for room in the channel to this pipeline
+            self.mqtt_client
+                .try_publish(&topic, QoS::AtLeastOnce, false, payload)?;
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+/// Stores the readings, or holds them while their sensor is waiting for approval
+///
+/// The readings of a batch are stored in one transaction.
+struct Store {
+    db_pool: Pool,
+    liveness: Arc<Mutex<Liveness>>,
+    metrics: Metrics,
+}
+
+#[async_trait(?Send)]
+impl Stage for Store {
+    fn name(&self) -> &'static str {
+        "store"
+    }
+
+    async fn process(&mut self, reading: &mut Reading) -> Result<Flow, StageError> {
+        let mut rows: Vec<_> = reading
+            .samples()?
+            .iter()
+            .map(|sample| NewReading {
+                level: format!("{}", sample.data.db_level()),
+                time: SystemTime::from(sample.data.timestamp()),
+                flags: sample.flags.clone(),
+                scale: sample.data.scale(),
+                spectrum: sample.spectrum,
+                fields: BTreeMap::new(),
+            })
+            .collect();
+        // the fields describe the sensor when it sent the payload, so they go with its latest reading
+        if let Some(latest) = rows.iter_mut().max_by_key(|row| row.time) {
+            latest.fields = reading.fields.clone();
+        }
+        let counter = reading.counter.map(|counter| counter as i64);
+        let stored = match reading.admission {
+            Admission::Store => {
+                let timer = self.metrics.db_insert_seconds.start_timer();
+                let result = self
+                    .db_pool
+                    .insert_loudness_data_batch(&reading.sensor_id, &rows, counter)
+                    .await;
+                timer.observe_duration();
+                let stored = result?;
+                if stored {
+                    let mut liveness = self.liveness.lock().expect("Liveness lock poisoned");
+                    for row in &rows {
+                        liveness.record(&reading.sensor_id, row.time, reading.received);
+                    }
+                }
+                stored
+            }
+            Admission::Hold => {
+                self.db_pool
+                    .insert_pending_loudness_data_batch(&reading.sensor_id, &rows, counter)
+                    .await?
+            }
+            Admission::Drop => return Ok(Flow::Stop),
+        };
+        // another backend, or this one before a restart, stored a payload with a higher counter
+        if !stored {
+            let error = AuthError::Replay {
+                counter: reading.counter.unwrap_or_default(),
+                last: reading.counter.unwrap_or_default(),
+            };
+            self.metrics
+                .auth_failures
+                .with_label_values(&[error.kind()])
+                .inc();
+            return Err(StageError::Rejected(format!(
+                "Authentication failed for {}: counter {} was stored already",
+                reading.sensor_id,
+                reading.counter.unwrap_or_default()
+            )));
+        }
+        debug!(readings = rows.len(), admission = ?reading.admission, "Readings stored");
+        Ok(Flow::Continue)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iot_sound_backend::auth::SensorKey;
+    use iot_sound_backend::scale::{TimeWeighting, Weighting};
+    use iot_sound_backend::spectrum::Bandwidth;
+    use iot_sound_wire::Timestamp;
+
+    const KEY: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+    /// An authenticate stage with a key with id 1 for sensor-1
+    fn authenticate(counters: HashMap<String, u64>) -> Authenticate {
+        let path = std::env::temp_dir().join(format!("keys-{}.json", std::process::id()));
+        let keys = KeyStore::from([(
+            "sensor-1".to_string(),
+            vec![SensorKey {
+                id: 1,
+                key: hex::encode(KEY),
+            }],
+        )]);
+        std::fs::write(&path, serde_json::to_string(&keys).unwrap()).unwrap();
+        let stage = Authenticate::new(
+            path.to_string_lossy().into_owned(),
+            false,
+            false,
+            counters,
+            Metrics::new(),
+        )
+        .unwrap();
+        std::fs::remove_file(path).unwrap();
+        stage
+    }
+
+    #[test]
+    fn authenticate_strips_the_signature() {
+        let mut stage = authenticate(HashMap::new());
+        let payload = auth::sign("sensor-1", KEY, 1, 10, "1700000000,45.5");
+        assert_eq!(
+            stage.authenticate("sensor-1", payload.as_bytes(), false),
+            Ok((b"1700000000,45.5".to_vec(), Some(10)))
+        );
+        assert_eq!(
+            stage.authenticate("sensor-2", b"1700000000,45.5", false),
+            Ok((b"1700000000,45.5".to_vec(), None))
+        );
+    }
+
+    #[test]
+    fn authenticate_rejects_unknown_key_ids() {
+        let mut stage = authenticate(HashMap::new());
+        let payload = auth::sign("sensor-1", KEY, 2, 10, "1700000000,45.5");
+        assert_eq!(
+            stage.authenticate("sensor-1", payload.as_bytes(), false),
+            Err(AuthError::UnknownKey(2))
+        );
+    }
+
+    #[test]
+    fn authenticate_rejects_replayed_counters() {
+        let counters = HashMap::from([("sensor-1".to_string(), 10)]);
+        let mut stage = authenticate(counters);
+        let sign = |counter| auth::sign("sensor-1", KEY, 1, counter, "1700000000,45.5");
+
+        // the counter loaded from the database
+        assert_eq!(
+            stage.authenticate("sensor-1", sign(10).as_bytes(), false),
+            Err(AuthError::Replay {
+                counter: 10,
+                last: 10
+            })
+        );
+        assert!(stage
+            .authenticate("sensor-1", sign(11).as_bytes(), false)
+            .is_ok());
+        assert_eq!(
+            stage.authenticate("sensor-1", sign(11).as_bytes(), false),
+            Err(AuthError::Replay {
+                counter: 11,
+                last: 11
+            })
+        );
+        // the spool replays the last accepted payload after a failure further on
+        assert!(stage
+            .authenticate("sensor-1", sign(11).as_bytes(), true)
+            .is_ok());
+        assert_eq!(
+            stage.authenticate("sensor-1", sign(9).as_bytes(), true),
+            Err(AuthError::Replay {
+                counter: 9,
+                last: 11
+            })
+        );
+        assert_eq!(
+            stage.authenticate("sensor-1", sign(u64::MAX).as_bytes(), false),
+            Err(AuthError::Malformed)
+        );
+    }
+
+    #[test]
+    fn storing_needs_registering_first_unless_the_policy_is_auto() {
+        let cases: [(&[&str], bool); 5] = [
+            (&["decode", "validate", "register", "store"], true),
+            (&["decode", "validate", "store"], false),
+            (&["decode", "store", "register"], false),
+            (&["decode", "validate", "forward"], true),
+            (&[], true),
+        ];
+        for (names, allowed) in cases {
+            let policy = RegistrationPolicy::PendingApproval;
+            assert_eq!(
+                check_registration(names, &policy).is_ok(),
+                allowed,
+                "{:?}",
+                names
+            );
+            assert!(check_registration(names, &RegistrationPolicy::AutoAccept).is_ok());
+        }
+    }
+
+    #[test]
+    fn unknown_firmware_versions_share_a_label() {
+        let stage = Decode {
+            metrics: Metrics::new(),
+            known_firmware: vec!["0.1.0".to_string()],
+        };
+        assert_eq!(stage.firmware_label(Some("0.1.0")), "0.1.0");
+        assert_eq!(stage.firmware_label(Some("0.2.0")), "other");
+        assert_eq!(stage.firmware_label(Some("")), "other");
+        assert_eq!(stage.firmware_label(None), "unknown");
+    }
+
+    #[tokio::test]
+    async fn calibrate_only_makes_readings_spl_that_have_an_offset_for_their_reference() {
+        let mut stage = Calibrate {
+            offsets: serde_json::from_str(
+                r#"{"sensor-1": {"spl": -1.5}, "sensor-2": {"dbfs": 120.0}, "sensor-3": 2.0}"#,
+            )
+            .unwrap(),
+        };
+        let dbfs = Scale {
+            reference: Reference::Dbfs,
+            ..Scale::default()
+        };
+        let calibrated = |sensor_id: &str, scale: Scale| {
+            let mut reading = Reading::new(
+                &format!("ntnu/a/b/loudness/group06/{}", sensor_id),
+                b"",
+                SystemTime::now(),
+                false,
+            );
+            reading.samples.push(Sample {
+                data: LoudnessData::new(-40.0, Timestamp::UNIX_EPOCH).with_scale(scale),
+                flags: Vec::new(),
+                spectrum: None,
+            });
+            reading
+        };
+
+        // no offset for dBFS, the reading is not calibrated
+        let mut reading = calibrated("sensor-1", dbfs);
+        stage.process(&mut reading).await.unwrap();
+        assert_eq!(reading.samples[0].data.db_level(), -40.0);
+        assert_eq!(reading.samples[0].data.scale(), dbfs);
+
+        let mut reading = calibrated("sensor-1", Scale::default());
+        stage.process(&mut reading).await.unwrap();
+        assert_eq!(reading.samples[0].data.db_level(), -41.5);
+        assert_eq!(reading.samples[0].data.scale(), Scale::default());
+
+        let mut reading = calibrated("sensor-2", dbfs);
+        stage.process(&mut reading).await.unwrap();
+        assert_eq!(reading.samples[0].data.db_level(), 80.0);
+        assert_eq!(reading.samples[0].data.scale(), Scale::default());
+
+        let mut reading = calibrated("sensor-3", dbfs);
+        stage.process(&mut reading).await.unwrap();
+        assert_eq!(reading.samples[0].data.db_level(), -38.0);
+        assert_eq!(reading.samples[0].data.scale(), Scale::default());
+
+        assert!(serde_json::from_str::<Calibration>(r#"{"dBFS": 120.0}"#).is_err());
+    }
+
+    #[test]
+    fn forwarding_keeps_the_scale_fields_and_bands() {
+        let scale = Scale {
+            weighting: Weighting::C,
+            time_weighting: TimeWeighting::Leq,
+            reference: Reference::Dbfs,
+        };
+        let level = |secs| Sample {
+            data: LoudnessData::new(-40.0, Timestamp::from_unix_secs(secs).unwrap())
+                .with_scale(scale),
+            flags: Vec::new(),
+            spectrum: None,
+        };
+        let spectrum = Spectrum::new(
+            Bandwidth::Octave,
+            1000.0,
+            &[-50.0, -45.0],
+            Timestamp::from_unix_secs(20).unwrap(),
+        )
+        .unwrap()
+        .with_scale(scale);
+        let samples = [level(20), level(10)]
+            .into_iter()
+            .chain(spectrum_samples(vec![spectrum]))
+            .collect::<Vec<_>>();
+        let fields = BTreeMap::from([("lmax".to_string(), "-35.00".to_string())]);
+
+        let forwarded: Vec<Envelope> = Forward::payloads(&samples, &fields)
+            .iter()
+            .map(|payload| Envelope::decode(payload).unwrap())
+            .collect();
+        assert_eq!(forwarded.len(), 3);
+        assert_eq!(forwarded[0].readings, vec![samples[0].data]);
+        assert_eq!(forwarded[0].readings[0].scale(), scale);
+        assert_eq!(forwarded[0].fields, fields);
+        assert_eq!(forwarded[1].readings, vec![samples[1].data]);
+        assert!(forwarded[1].fields.is_empty());
+        assert_eq!(forwarded[2].spectra, vec![spectrum]);
+    }
+}
@@ -0,0 +1,216 @@
+use iot_sound_database::Pool;
+use std::fmt::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::sync::mpsc::{channel, error::TrySendError, Receiver, Sender};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+
+/// Target of the events about the database log itself, these are never written to it
+const SINK_TARGET: &str = "db_log_sink";
+/// Messages waiting for the database log, later ones are dropped until there is room
+const DB_LOG_CAPACITY: usize = 1000;
+
+/// Messages for the database log, and how many were dropped because it fell behind
+pub struct DbLog {
+    receiver: Receiver<String>,
+    dropped: Arc<AtomicU64>,
+}
+
+/// Setup tracing for the backend
+///
+/// Events are written to stdout like the other executables do, see `iot_sound_telemetry`.
+/// Warnings, errors and events with `db_log = true` are also sent to the returned channel,
+/// which `write_db_log` writes to the log table once the database is available.
+/// # Returns
+/// * `DbLog` - The messages to write to the database log
+pub fn init() -> DbLog {
+    let (sender, receiver) = channel(DB_LOG_CAPACITY);
+    let dropped = Arc::new(AtomicU64::new(0));
+    tracing_subscriber::registry()
+        .with(iot_sound_telemetry::env_filter())
+        .with(iot_sound_telemetry::stdout_layer())
+        .with(DbLogLayer {
+            sender,
+            dropped: dropped.clone(),
+        })
+        .init();
+    DbLog { receiver, dropped }
+}
+
+/// Writes the messages collected by the tracing layer to the log table
+/// # Arguments
+/// * `db_pool` - The database pool
+/// * `db_log` - The messages returned by `init`
+pub async fn write_db_log(db_pool: Pool, mut db_log: DbLog) {
+    while let Some(message) = db_log.receiver.recv().await {
+        let dropped = db_log.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            let notice = format!(
+                "Dropped {} log messages, the database log could not keep up",
+                dropped
+            );
+            insert_log(&db_pool, &notice).await;
+        }
+        insert_log(&db_pool, &message).await;
+    }
+}
+
+async fn insert_log(db_pool: &Pool, message: &str) {
+    if let Err(e) = db_pool.insert_log(message, SystemTime::now()).await {
+        tracing::error!(target: SINK_TARGET, "Error inserting log into database: {}", e);
+    }
+}
+
+/// Tracing layer that forwards warnings, errors and events marked with
+/// `db_log = true` to the database log, with the fields of the spans they are in
+struct DbLogLayer {
+    sender: Sender<String>,
+    dropped: Arc<AtomicU64>,
+}
+
+/// The fields of a span, formatted for the database log
+struct SpanFields(String);
+
+impl<S> Layer<S> for DbLogLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut visitor = MessageVisitor::default();
+        attrs.record(&mut visitor);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(SpanFields(visitor.fields));
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let mut visitor = MessageVisitor::default();
+        values.record(&mut visitor);
+        if let Some(span) = ctx.span(id) {
+            if let Some(fields) = span.extensions_mut().get_mut::<SpanFields>() {
+                fields.0.push_str(&visitor.fields);
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        if metadata.target() == SINK_TARGET {
+            return;
+        }
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+        if *metadata.level() > Level::WARN && !visitor.db_log {
+            return;
+        }
+        let mut message = visitor.message;
+        for span in ctx
+            .event_scope(event)
+            .into_iter()
+            .flat_map(|scope| scope.from_root())
+        {
+            if let Some(fields) = span.extensions().get::<SpanFields>() {
+                message.push_str(&fields.0);
+            }
+        }
+        match self.sender.try_send(message) {
+            Err(TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            // the receiver only goes away when the backend shuts down
+            Err(TrySendError::Closed(_)) | Ok(()) => {}
+        }
+    }
+}
+
+/// Collects the message of an event, followed by its other fields
+#[derive(Default)]
+struct MessageVisitor {
+    message: String,
+    fields: String,
+    db_log: bool,
+}
+
+impl Visit for MessageVisitor {
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        if field.name() == "db_log" {
+            self.db_log = value;
+        } else {
+            self.record_debug(field, &value);
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{:?}{}", value, self.fields);
+            self.fields.clear();
+        } else if self.message.is_empty() {
+            let _ = write!(self.fields, " {}={:?}", field.name(), value);
+        } else {
+            let _ = write!(self.message, " {}={:?}", field.name(), value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing::{info, info_span, warn};
+
+    /// Runs `f` with a DbLogLayer of the given capacity, returning what it sent
+    fn capture(capacity: usize, f: impl FnOnce()) -> (Vec<String>, u64) {
+        let (sender, mut receiver) = channel(capacity);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let subscriber = tracing_subscriber::registry().with(DbLogLayer {
+            sender,
+            dropped: dropped.clone(),
+        });
+        tracing::subscriber::with_default(subscriber, f);
+        let mut messages = Vec::new();
+        while let Ok(message) = receiver.try_recv() {
+            messages.push(message);
+        }
+        (messages, dropped.load(Ordering::Relaxed))
+    }
+
+    #[test]
+    fn messages_carry_the_fields_of_their_spans() {
+        let (messages, _) = capture(10, || {
+            let span = info_span!(
+                "message",
+                topic = "ntnu/a/b/loudness/group06/sensor",
+                sensor_id = tracing::field::Empty
+            );
+            let _guard = span.enter();
+            span.record("sensor_id", "sensor");
+            warn!(stage = "validate", "Rejected reading");
+            info!("Not for the database");
+            info!(db_log = true, "For the database");
+        });
+        assert_eq!(
+            messages,
+            vec![
+                "Rejected reading stage=\"validate\" topic=\"ntnu/a/b/loudness/group06/sensor\" sensor_id=\"sensor\"",
+                "For the database topic=\"ntnu/a/b/loudness/group06/sensor\" sensor_id=\"sensor\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn messages_beyond_the_capacity_are_counted() {
+        let (messages, dropped) = capture(2, || {
+            for n in 0..5 {
+                warn!("Warning {}", n);
+            }
+        });
+        assert_eq!(messages, vec!["Warning 0", "Warning 1"]);
+        assert_eq!(dropped, 3);
+    }
+}
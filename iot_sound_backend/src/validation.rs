@@ -0,0 +1,507 @@
+use iot_sound_backend::loudness_data::LoudnessData;
+use iot_sound_backend::scale::Reference;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+/// Rules a reading from a given sensor type has to follow.
+/// Readings breaking the range or clock skew rules are rejected,
+/// the rest of the rules only flag the reading.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ValidationRules {
+    /// Lowest physically possible level, of readings in dB SPL
+    pub min_level: f32,
+    /// Highest physically possible level, of readings in dB SPL
+    pub max_level: f32,
+    /// How far the sensor clock may be ahead of or behind the receive time, in seconds
+    pub max_clock_skew_secs: u64,
+    /// Largest plausible change between two readings, in level units per second
+    pub max_rate_of_change: f32,
+    /// Number of identical readings in a row before the sensor is considered stuck
+    pub stuck_after: u32,
+}
+
+impl ValidationRules {
+    /// Default rules for loudness sensors, levels in dB SPL
+    pub fn loudness() -> Self {
+        ValidationRules {
+            min_level: 0.0,
+            max_level: 194.0,
+            max_clock_skew_secs: 60,
+            max_rate_of_change: 30.0,
+            stuck_after: 30,
+        }
+    }
+}
+
+/// Flag stored alongside a reading that was accepted but looks suspicious
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QualityFlag {
+    /// The reading is older than the allowed clock skew when it was received
+    Delayed,
+    /// The level changed faster than the sensor type allows
+    RateOfChange,
+    /// The sensor has reported the same level too many times in a row
+    Stuck,
+}
+
+impl QualityFlag {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            QualityFlag::Delayed => "delayed",
+            QualityFlag::RateOfChange => "rate_of_change",
+            QualityFlag::Stuck => "stuck",
+        }
+    }
+}
+
+impl fmt::Display for QualityFlag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Reason a reading was rejected
+#[derive(Debug, Clone, PartialEq)]
+pub enum RejectReason {
+    /// The level is NaN or infinite
+    NotFinite,
+    /// The level is outside the physical range of the sensor type
+    OutOfRange(f32),
+    /// The timestamp is further in the future than the allowed clock skew
+    InFuture(Duration),
+    /// There are no rules for the sensor type
+    UnknownSensorType(String),
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RejectReason::NotFinite => write!(f, "level is not a finite number"),
+            RejectReason::OutOfRange(level) => write!(f, "level {} is out of range", level),
+            RejectReason::InFuture(ahead) => {
+                write!(f, "timestamp is {}s in the future", ahead.as_secs())
+            }
+            RejectReason::UnknownSensorType(type_) => {
+                write!(f, "no validation rules for sensor type {}", type_)
+            }
+        }
+    }
+}
+
+/// The outcome of validating a reading
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    Accepted,
+    Flagged(Vec<QualityFlag>),
+    Rejected(RejectReason),
+}
+
+impl Verdict {
+    /// Returns the quality flags of the verdict as strings for storage
+    pub fn flags(&self) -> Vec<String> {
+        match self {
+            Verdict::Flagged(flags) => flags.iter().map(|f| f.to_string()).collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Last accepted reading of a sensor, used for the rate of change and stuck rules
+#[derive(Clone)]
+struct History {
+    level: f32,
+    timestamp: SystemTime,
+    repeats: u32,
+}
+
+/// Validates readings against per sensor type rules
+/// and keeps the per sensor history the rules need.
+pub struct Validator {
+    rules: HashMap<String, ValidationRules>,
+    history: HashMap<String, History>,
+    /// A sensor and its history before the last message, see `checkpoint`
+    checkpoint: Option<(String, Option<History>)>,
+}
+
+impl Validator {
+    /// Create a new Validator
+    /// # Arguments
+    /// * `rules` - Rules by sensor type
+    pub fn new(rules: HashMap<String, ValidationRules>) -> Self {
+        Validator {
+            rules,
+            history: HashMap::new(),
+            checkpoint: None,
+        }
+    }
+
+    /// Create a Validator with rules loaded from a json file, mapping sensor type to rules.
+    /// Sensor types not in the file fall back to the defaults.
+    /// # Arguments
+    /// * `path` - Path to the json file, defaults are used if `None`
+    pub fn from_file(path: Option<&str>) -> Result<Self, Box<dyn Error>> {
+        let mut rules = HashMap::new();
+        rules.insert("loudness".to_string(), ValidationRules::loudness());
+        if let Some(path) = path {
+            let file = std::fs::read_to_string(path)?;
+            let configured: HashMap<String, ValidationRules> = serde_json::from_str(&file)?;
+            rules.extend(configured);
+        }
+        Ok(Validator::new(rules))
+    }
+
+    /// Remember the history of a sensor before the readings of a message are validated
+    /// # Arguments
+    /// * `sensor_id` - The id of the sensor that sent the message
+    pub fn checkpoint(&mut self, sensor_id: &str) {
+        self.checkpoint = Some((sensor_id.to_string(), self.history.get(sensor_id).cloned()));
+    }
+
+    /// Restore the history of a sensor to the last checkpoint, so a message validated
+    /// again gets the same verdicts and is only recorded once.
+    /// Does nothing if the last checkpoint is of another sensor.
+    /// # Arguments
+    /// * `sensor_id` - The id of the sensor that sent the message
+    pub fn rollback(&mut self, sensor_id: &str) {
+        match self.checkpoint.take() {
+            Some((id, Some(history))) if id == sensor_id => {
+                self.history.insert(id, history);
+            }
+            Some((id, None)) if id == sensor_id => {
+                self.history.remove(&id);
+            }
+            _ => {}
+        }
+    }
+
+    /// Validate a reading
+    /// # Arguments
+    /// * `sensor_id` - The id of the sensor that sent the reading
+    /// * `sensor_type` - The type of the sensor
+    /// * `data` - The reading
+    /// * `received` - The time the reading was received
+    pub fn validate(
+        &mut self,
+        sensor_id: &str,
+        sensor_type: &str,
+        data: &LoudnessData,
+        received: SystemTime,
+    ) -> Verdict {
+        let rules = match self.rules.get(sensor_type) {
+            Some(rules) => rules,
+            None => return Verdict::Rejected(RejectReason::UnknownSensorType(sensor_type.into())),
+        };
+        let level = data.db_level();
+        let timestamp = SystemTime::from(data.timestamp());
+        let max_skew = Duration::from_secs(rules.max_clock_skew_secs);
+
+        if !level.is_finite() {
+            return Verdict::Rejected(RejectReason::NotFinite);
+        }
+        let in_range = match data.scale().reference {
+            Reference::Spl => (rules.min_level..=rules.max_level).contains(&level),
+            // full scale is the loudest level a converter can report, uncalibrated
+            // levels can not be held against the physical range of the sensor type
+            Reference::Dbfs => level <= 0.0,
+        };
+        if !in_range {
+            return Verdict::Rejected(RejectReason::OutOfRange(level));
+        }
+        if let Ok(ahead) = timestamp.duration_since(received) {
+            if ahead > max_skew {
+                return Verdict::Rejected(RejectReason::InFuture(ahead));
+            }
+        }
+
+        let mut flags = Vec::new();
+        if let Ok(behind) = received.duration_since(timestamp) {
+            if behind > max_skew {
+                flags.push(QualityFlag::Delayed);
+            }
+        }
+
+        let repeats = match self.history.get(sensor_id) {
+            Some(last) => {
+                let elapsed = match timestamp.duration_since(last.timestamp) {
+                    Ok(elapsed) => elapsed,
+                    Err(e) => e.duration(),
+                };
+                // timestamps have second resolution, readings within the same second count as one
+                let elapsed = elapsed.as_secs_f32().max(1.0);
+                if (level - last.level).abs() / elapsed > rules.max_rate_of_change {
+                    flags.push(QualityFlag::RateOfChange);
+                }
+                if level == last.level {
+                    last.repeats + 1
+                } else {
+                    1
+                }
+            }
+            None => 1,
+        };
+        if repeats >= rules.stuck_after {
+            flags.push(QualityFlag::Stuck);
+        }
+
+        self.history.insert(
+            sensor_id.to_string(),
+            History {
+                level,
+                timestamp,
+                repeats,
+            },
+        );
+
+        if flags.is_empty() {
+            Verdict::Accepted
+        } else {
+            Verdict::Flagged(flags)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iot_sound_backend::scale::Scale;
+    use iot_sound_wire::Timestamp;
+
+    const START: u64 = 1_700_000_000;
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::from(Timestamp::from_unix_secs(START + secs).unwrap())
+    }
+
+    fn reading(level: f32, secs: u64) -> LoudnessData {
+        LoudnessData::new(level, Timestamp::from_unix_secs(START + secs).unwrap())
+    }
+
+    fn dbfs() -> Scale {
+        Scale {
+            reference: Reference::Dbfs,
+            ..Scale::default()
+        }
+    }
+
+    struct Case {
+        name: &'static str,
+        /// Readings accepted before, level and seconds after the start
+        history: Vec<(f32, u64)>,
+        reading: LoudnessData,
+        received: u64,
+        expected: Verdict,
+    }
+
+    #[test]
+    fn validate() {
+        let cases = [
+            Case {
+                name: "plausible reading",
+                history: vec![],
+                reading: reading(50.0, 0),
+                received: 0,
+                expected: Verdict::Accepted,
+            },
+            Case {
+                name: "NaN",
+                history: vec![],
+                reading: reading(f32::NAN, 0),
+                received: 0,
+                expected: Verdict::Rejected(RejectReason::NotFinite),
+            },
+            Case {
+                name: "infinite",
+                history: vec![],
+                reading: reading(f32::INFINITY, 0),
+                received: 0,
+                expected: Verdict::Rejected(RejectReason::NotFinite),
+            },
+            Case {
+                name: "below the range",
+                history: vec![],
+                reading: reading(-1.0, 0),
+                received: 0,
+                expected: Verdict::Rejected(RejectReason::OutOfRange(-1.0)),
+            },
+            Case {
+                name: "above the range",
+                history: vec![],
+                reading: reading(195.0, 0),
+                received: 0,
+                expected: Verdict::Rejected(RejectReason::OutOfRange(195.0)),
+            },
+            Case {
+                name: "edges of the range",
+                history: vec![(0.0, 0)],
+                reading: reading(194.0, 10),
+                received: 10,
+                expected: Verdict::Accepted,
+            },
+            Case {
+                name: "below full scale",
+                history: vec![],
+                reading: reading(-20.0, 0).with_scale(dbfs()),
+                received: 0,
+                expected: Verdict::Accepted,
+            },
+            Case {
+                name: "above full scale",
+                history: vec![],
+                reading: reading(1.0, 0).with_scale(dbfs()),
+                received: 0,
+                expected: Verdict::Rejected(RejectReason::OutOfRange(1.0)),
+            },
+            Case {
+                name: "in the future",
+                history: vec![],
+                reading: reading(50.0, 61),
+                received: 0,
+                expected: Verdict::Rejected(RejectReason::InFuture(Duration::from_secs(61))),
+            },
+            Case {
+                name: "ahead within the clock skew",
+                history: vec![],
+                reading: reading(50.0, 60),
+                received: 0,
+                expected: Verdict::Accepted,
+            },
+            Case {
+                name: "behind within the clock skew",
+                history: vec![],
+                reading: reading(50.0, 0),
+                received: 60,
+                expected: Verdict::Accepted,
+            },
+            Case {
+                name: "delayed",
+                history: vec![],
+                reading: reading(50.0, 0),
+                received: 61,
+                expected: Verdict::Flagged(vec![QualityFlag::Delayed]),
+            },
+            Case {
+                name: "plausible change",
+                history: vec![(50.0, 0)],
+                reading: reading(80.0, 1),
+                received: 1,
+                expected: Verdict::Accepted,
+            },
+            Case {
+                name: "implausible change",
+                history: vec![(50.0, 0)],
+                reading: reading(81.0, 1),
+                received: 1,
+                expected: Verdict::Flagged(vec![QualityFlag::RateOfChange]),
+            },
+            Case {
+                name: "change spread over seconds",
+                history: vec![(50.0, 0)],
+                reading: reading(100.0, 2),
+                received: 2,
+                expected: Verdict::Accepted,
+            },
+            Case {
+                name: "change within a second",
+                history: vec![(50.0, 10)],
+                reading: reading(90.0, 10),
+                received: 10,
+                expected: Verdict::Flagged(vec![QualityFlag::RateOfChange]),
+            },
+            Case {
+                name: "change from a later reading",
+                history: vec![(50.0, 10)],
+                reading: reading(90.0, 9),
+                received: 10,
+                expected: Verdict::Flagged(vec![QualityFlag::RateOfChange]),
+            },
+            Case {
+                name: "repeating below the limit",
+                history: (0..28).map(|secs| (50.0, secs)).collect(),
+                reading: reading(50.0, 28),
+                received: 28,
+                expected: Verdict::Accepted,
+            },
+            Case {
+                name: "stuck",
+                history: (0..29).map(|secs| (50.0, secs)).collect(),
+                reading: reading(50.0, 29),
+                received: 29,
+                expected: Verdict::Flagged(vec![QualityFlag::Stuck]),
+            },
+            Case {
+                name: "a change resets stuck",
+                history: (0..29)
+                    .map(|secs| (50.0, secs))
+                    .chain([(51.0, 29)])
+                    .collect(),
+                reading: reading(51.0, 30),
+                received: 30,
+                expected: Verdict::Accepted,
+            },
+            Case {
+                name: "delayed and implausible change",
+                history: vec![(50.0, 0)],
+                reading: reading(90.0, 1),
+                received: 100,
+                expected: Verdict::Flagged(vec![QualityFlag::Delayed, QualityFlag::RateOfChange]),
+            },
+        ];
+
+        for case in cases {
+            let mut validator = Validator::from_file(None).unwrap();
+            for (level, secs) in &case.history {
+                validator.validate("sensor", "loudness", &reading(*level, *secs), at(*secs));
+            }
+            let verdict =
+                validator.validate("sensor", "loudness", &case.reading, at(case.received));
+            assert_eq!(verdict, case.expected, "{}", case.name);
+        }
+    }
+
+    #[test]
+    fn unknown_sensor_types_are_rejected() {
+        let mut validator = Validator::from_file(None).unwrap();
+        assert_eq!(
+            validator.validate("sensor", "humidity", &reading(50.0, 0), at(0)),
+            Verdict::Rejected(RejectReason::UnknownSensorType("humidity".to_string()))
+        );
+    }
+
+    #[test]
+    fn rejected_readings_are_not_history() {
+        let mut validator = Validator::from_file(None).unwrap();
+        validator.validate("sensor", "loudness", &reading(50.0, 0), at(0));
+        validator.validate("sensor", "loudness", &reading(500.0, 1), at(1));
+        assert_eq!(
+            validator.validate("sensor", "loudness", &reading(60.0, 2), at(2)),
+            Verdict::Accepted
+        );
+    }
+
+    #[test]
+    fn rolled_back_messages_get_the_same_verdict() {
+        let mut validator = Validator::from_file(None).unwrap();
+        validator.validate("sensor", "loudness", &reading(50.0, 0), at(0));
+        validator.checkpoint("sensor");
+        let first = validator.validate("sensor", "loudness", &reading(90.0, 1), at(1));
+        assert_eq!(first, Verdict::Flagged(vec![QualityFlag::RateOfChange]));
+
+        validator.rollback("sensor");
+        let again = validator.validate("sensor", "loudness", &reading(90.0, 1), at(1));
+        assert_eq!(again, first);
+        assert_eq!(validator.history["sensor"].repeats, 1);
+    }
+
+    #[test]
+    fn sensors_have_their_own_history() {
+        let mut validator = Validator::from_file(None).unwrap();
+        validator.validate("quiet", "loudness", &reading(20.0, 0), at(0));
+        assert_eq!(
+            validator.validate("loud", "loudness", &reading(100.0, 1), at(1)),
+            Verdict::Accepted
+        );
+    }
+}
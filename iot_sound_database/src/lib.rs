@@ -1,7 +1,11 @@
 use chrono::{DateTime, Local, Utc};
 use deadpool_postgres::{self, CreatePoolError};
+// sensors apply their config without the database, so it is defined with the messages
+pub use iot_sound_protocol::sensor_config::{ReportingMode, SensorConfig};
+use iot_sound_wire::{Scale, Spectrum};
 use serde::{Deserialize, Serialize};
 use serde_json::{self, json};
+use std::collections::BTreeMap;
 
 /// Struct that contains a pool of postgres connections
 #[derive(Clone)]
@@ -23,6 +27,15 @@ impl Sensor {
             location,
         }
     }
+    pub fn get_id(&self) -> &str {
+        &self.id
+    }
+    pub fn get_type(&self) -> &str {
+        &self.type_
+    }
+    pub fn get_location(&self) -> &str {
+        &self.location
+    }
 }
 
 /// Struct for data from the database that can be converted to json
@@ -32,6 +45,13 @@ pub struct Data {
     sensor_name: String,
     sound: String,
     time: std::time::SystemTime,
+    flags: Vec<String>,
+    weighting: String,
+    time_weighting: String,
+    reference: String,
+    /// Optional fields the sensor sent with the reading, by name
+    #[serde(default)]
+    fields: BTreeMap<String, String>,
 }
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DataWithDateTimeString {
@@ -40,6 +60,11 @@ pub struct DataWithDateTimeString {
     sound: String,
     time: std::time::SystemTime,
     time_string: String,
+    flags: Vec<String>,
+    weighting: String,
+    time_weighting: String,
+    reference: String,
+    fields: BTreeMap<String, String>,
 }
 
 // implement a trait for vec of data
@@ -50,14 +75,28 @@ impl Data {
     /// * `sensor_name` - The name of the sensor
     /// * `sound` - The sound level
     /// * `time` - The time the data was created
+    /// * `flags` - Quality flags set when the data was validated
+    /// * `scale` - The scale the sound level was measured on
     /// # Returns
     /// `Data` - The new Data struct
-    pub fn new(id: i32, sound: String, sensor_name: String, time: std::time::SystemTime) -> Data {
+    pub fn new(
+        id: i32,
+        sound: String,
+        sensor_name: String,
+        time: std::time::SystemTime,
+        flags: Vec<String>,
+        scale: Scale,
+    ) -> Data {
         Data {
             id,
             sensor_name,
             sound,
             time,
+            flags,
+            weighting: scale.weighting.as_str().to_string(),
+            time_weighting: scale.time_weighting.as_str().to_string(),
+            reference: scale.reference.as_str().to_string(),
+            fields: BTreeMap::new(),
         }
     }
 
@@ -67,6 +106,24 @@ impl Data {
     pub fn get_sensor_name(&self) -> String {
         self.sensor_name.clone()
     }
+    pub fn get_flags(&self) -> &[String] {
+        &self.flags
+    }
+    pub fn get_fields(&self) -> &BTreeMap<String, String> {
+        &self.fields
+    }
+    /// Returns the sound level, `None` if it is not a number
+    pub fn get_level(&self) -> Option<f32> {
+        self.sound.parse().ok()
+    }
+    /// Returns the scale the sound level was measured on, `None` if it is not known
+    pub fn get_scale(&self) -> Option<Scale> {
+        Some(Scale {
+            weighting: self.weighting.parse().ok()?,
+            time_weighting: self.time_weighting.parse().ok()?,
+            reference: self.reference.parse().ok()?,
+        })
+    }
     pub fn get_date_time_string(&self) -> DataWithDateTimeString {
         let time_string = self.time;
         let datetime = DateTime::<Utc>::from(time_string);
@@ -78,6 +135,11 @@ impl Data {
             sound: self.sound.clone(),
             time: self.time,
             time_string: datetime_string,
+            flags: self.flags.clone(),
+            weighting: self.weighting.clone(),
+            time_weighting: self.time_weighting.clone(),
+            reference: self.reference.clone(),
+            fields: self.fields.clone(),
         }
     }
 }
@@ -90,10 +152,107 @@ impl From<Data> for serde_json::Value {
             "sensor_name": data.sensor_name,
             "sound": data.sound,
             "time": data.time,
+            "flags": data.flags,
+            "weighting": data.weighting,
+            "time_weighting": data.time_weighting,
+            "reference": data.reference,
+            "fields": data.fields,
         })
     }
 }
 
+/// A reading to store, see `Pool::insert_loudness_data_batch`
+#[derive(Debug, Clone)]
+pub struct NewReading {
+    /// The sound level
+    pub level: String,
+    /// The time the reading was measured
+    pub time: std::time::SystemTime,
+    /// Quality flags set when the reading was validated
+    pub flags: Vec<String>,
+    /// The scale the sound level was measured on
+    pub scale: Scale,
+    /// The band levels of a spectrum reading, the sound level is their sum
+    pub spectrum: Option<Spectrum>,
+    /// Optional fields the sensor sent with the reading, by name
+    pub fields: BTreeMap<String, String>,
+}
+
+/// Band levels of a spectrum reading from the database that can be converted to json
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SpectrumData {
+    sensor_name: String,
+    time: std::time::SystemTime,
+    /// `octave` or `third_octave`
+    bandwidth: String,
+    /// Nominal center frequency of every band in Hz
+    frequencies: Vec<f32>,
+    levels: Vec<f32>,
+    weighting: String,
+    time_weighting: String,
+    reference: String,
+}
+
+/// A sensor that is waiting for an operator to approve or reject it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PendingSensor {
+    id: String,
+    type_: String,
+    location: String,
+    status: String,
+    first_seen: std::time::SystemTime,
+    held_readings: i64,
+}
+
+/// Liveness status of a sensor, maintained by the backend
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SensorStatus {
+    sensor_id: String,
+    state: String,
+    last_seen: std::time::SystemTime,
+    expected_interval_secs: f64,
+    changed_at: std::time::SystemTime,
+    /// "online" or "offline" as last announced by the sensor, `None` if it never did
+    connection: Option<String>,
+    /// The time the sensor last announced its connection status
+    connection_changed_at: Option<std::time::SystemTime>,
+    firmware_version: Option<String>,
+    booted_at: Option<std::time::SystemTime>,
+    config_hash: Option<String>,
+}
+
+impl SensorStatus {
+    pub fn get_sensor_id(&self) -> &str {
+        &self.sensor_id
+    }
+    pub fn get_state(&self) -> &str {
+        &self.state
+    }
+    pub fn get_last_seen(&self) -> std::time::SystemTime {
+        self.last_seen
+    }
+    pub fn get_expected_interval_secs(&self) -> f64 {
+        self.expected_interval_secs
+    }
+    pub fn get_connection(&self) -> Option<&str> {
+        self.connection.as_deref()
+    }
+    pub fn get_connection_changed_at(&self) -> Option<std::time::SystemTime> {
+        self.connection_changed_at
+    }
+}
+
+/// The config an operator wants a sensor to have and the config the sensor reported it applied
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SensorConfigState {
+    sensor_id: String,
+    desired: Option<SensorConfig>,
+    reported: Option<SensorConfig>,
+    reported_at: Option<std::time::SystemTime>,
+    /// Whether the sensor has applied the desired config
+    in_sync: bool,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Log {
     id: i32,
@@ -136,13 +295,26 @@ impl Pool {
         let pool = match pool {
             Ok(pool) => pool,
             Err(e) => {
-                println!("Error: {}", e);
+                tracing::error!("Error creating database pool: {}", e);
                 return Err(e);
             }
         };
         Ok(Pool { pool })
     }
 
+    /// Check if the database can be reached
+    /// # Arguments
+    /// * `self` - The Pool struct
+    ///
+    /// # Returns
+    /// `bool` - true if a query could be run
+    pub async fn is_available(&self) -> bool {
+        match self.pool.get().await {
+            Ok(client) => client.simple_query("SELECT 1").await.is_ok(),
+            Err(_) => false,
+        }
+    }
+
     /// Create the sensor table if it does not exist
     /// # Arguments
     /// * `self` - The Pool struct
@@ -165,13 +337,20 @@ impl Pool {
     }
 
     /// Create the table containing the data if it does not exist
+    ///
+    /// A sensor can only have one reading of each kind per timestamp, so messages
+    /// redelivered by the MQTT broker are not stored twice. The kind is the bandwidth
+    /// of a spectrum, so a level and a spectrum measured in the same second are both kept.
+    /// Timestamps are whole seconds, so inserts drop every reading after the first one
+    /// of a kind of a sensor in a second. Duplicates stored before the unique index
+    /// existed are deleted when it is created, keeping the reading that was stored first.
     /// # Arguments
     /// * `self` - The Pool struct
     ///
     /// # Returns
     /// `Result<(), tokio_postgres::Error>` - The result of the query
     pub async fn create_loudness_table(&self) -> Result<(), deadpool_postgres::PoolError> {
-        let client = self.pool.get().await?;
+        let mut client = self.pool.get().await?;
         client
             .execute(
                 "CREATE TABLE IF NOT EXISTS loudness (
@@ -182,10 +361,54 @@ impl Pool {
                 &[],
             )
             .await?;
+        client
+            .execute(
+                "ALTER TABLE loudness ADD COLUMN IF NOT EXISTS flags text[] NOT NULL DEFAULT '{}';",
+                &[],
+            )
+            .await?;
+        client
+            .execute(add_scale_columns("loudness").as_str(), &[])
+            .await?;
+        client
+            .execute(add_spectrum_columns("loudness").as_str(), &[])
+            .await?;
+        client
+            .execute(
+                "ALTER TABLE loudness ADD COLUMN IF NOT EXISTS fields text",
+                &[],
+            )
+            .await?;
+        let index = client
+            .query_one(
+                "SELECT to_regclass('loudness_sensor_time_kind_idx') IS NOT NULL",
+                &[],
+            )
+            .await?;
+        if index.get(0) {
+            return Ok(());
+        }
+
+        // The index cannot be created while a sensor has two readings of a kind at the same
+        // time. It replaces the index without the kind, which dropped spectra of the same second.
+        let transaction = client.transaction().await?;
+        transaction
+            .batch_execute(
+                "LOCK TABLE loudness IN SHARE ROW EXCLUSIVE MODE;
+                DELETE FROM loudness a USING loudness b
+                    WHERE a.sensor_id = b.sensor_id AND a.time = b.time
+                        AND COALESCE(a.bandwidth, '') = COALESCE(b.bandwidth, '') AND a.id > b.id;
+                CREATE UNIQUE INDEX IF NOT EXISTS loudness_sensor_time_kind_idx
+                    ON loudness (sensor_id, time, (COALESCE(bandwidth, '')));
+                DROP INDEX IF EXISTS loudness_sensor_time_idx;",
+            )
+            .await?;
+        transaction.commit().await?;
         Ok(())
     }
 
-    /// Return all the data from the database
+    /// Return all the readings of a single level from the database, spectra are
+    /// returned by `get_spectrum_limited`
     /// # Arguments
     /// * `self` - The Pool struct
     ///
@@ -193,7 +416,13 @@ impl Pool {
     /// `Result<Vec<Data>, tokio_postgres::Error>` - The result of the query
     pub async fn get_loudness(&self) -> Result<Vec<Data>, deadpool_postgres::PoolError> {
         let client = self.pool.get().await?;
-        let statement = client.prepare("SELECT * FROM loudness").await?;
+        let statement = client
+            .prepare(
+                "SELECT id, sensor_id, level, time, flags, weighting, time_weighting, reference,
+                    fields
+                FROM loudness WHERE bandwidth IS NULL",
+            )
+            .await?;
         let rows = client.query(&statement, &[]).await?;
         let mut data = Vec::new();
 
@@ -203,12 +432,17 @@ impl Pool {
                 sensor_name: row.get(1),
                 sound: row.get(2),
                 time: row.get(3),
+                flags: row.get(4),
+                weighting: row.get(5),
+                time_weighting: row.get(6),
+                reference: row.get(7),
+                fields: parse_fields(row.get(8)),
             });
         }
         Ok(data)
     }
 
-    /// Return *n* records of given sensor data from the database
+    /// Return *n* records of given sensor data from the database, without spectra
     /// # Arguments
     /// * `self` - The Pool struct
     /// * `sensor_name` - The name of the sensor
@@ -226,7 +460,9 @@ impl Pool {
             .prepare(
                 "
         WITH latest_n AS
-        (SELECT * FROM loudness WHERE sensor_id = $1 ORDER BY time DESC LIMIT $2)
+        (SELECT id, sensor_id, level, time, flags, weighting, time_weighting, reference, fields
+            FROM loudness WHERE sensor_id = $1 AND bandwidth IS NULL
+            ORDER BY time DESC LIMIT $2)
         SELECT * FROM latest_n ORDER BY time ASC
         ",
             )
@@ -240,6 +476,11 @@ impl Pool {
                 sensor_name: row.get(1),
                 sound: row.get(2),
                 time: row.get(3),
+                flags: row.get(4),
+                weighting: row.get(5),
+                time_weighting: row.get(6),
+                reference: row.get(7),
+                fields: parse_fields(row.get(8)),
             });
         }
         Ok(data)
@@ -285,32 +526,81 @@ impl Pool {
         Ok(data)
     }
 
-    /// Insert loudness data into the database
+    /// Return the *n* latest spectrum readings of a sensor from the database
     /// # Arguments
     /// * `self` - The Pool struct
-    /// * `sensor_id` - The id of the sensor
-    /// * `level` - The sound level
-    /// * `time` - The time the data was created
+    /// * `sensor_name` - The name of the sensor
+    /// * `n` - The number of records to return
     ///
     /// # Returns
-    /// `Result<(), tokio_postgres::Error>` - The result of the query
-    pub async fn insert_loudness_data(
+    /// `Result<Vec<SpectrumData>, tokio_postgres::Error>` - The result of the query, oldest first
+    pub async fn get_spectrum_limited(
         &self,
-        sensor_id: &str,
-        level: &str,
-        time: std::time::SystemTime,
-    ) -> Result<(), deadpool_postgres::PoolError> {
+        sensor_name: &str,
+        n: i64,
+    ) -> Result<Vec<SpectrumData>, deadpool_postgres::PoolError> {
         let client = self.pool.get().await?;
         let statement = client
-            .prepare("INSERT INTO loudness (sensor_id, level, time) VALUES ($1, $2, $3)")
-            .await?;
-        client
-            .execute(&statement, &[&sensor_id, &level, &time])
+            .prepare(
+                "
+        WITH latest_n AS
+        (SELECT sensor_id, time, bandwidth, band_frequencies, band_levels,
+            weighting, time_weighting, reference
+            FROM loudness WHERE sensor_id = $1 AND bandwidth IS NOT NULL
+            ORDER BY time DESC LIMIT $2)
+        SELECT * FROM latest_n ORDER BY time ASC
+        ",
+            )
             .await?;
-        Ok(())
+        let rows = client.query(&statement, &[&sensor_name, &n]).await?;
+        let mut data = Vec::new();
+
+        for row in rows {
+            data.push(SpectrumData {
+                sensor_name: row.get(0),
+                time: row.get(1),
+                bandwidth: row.get(2),
+                frequencies: row.get(3),
+                levels: row.get(4),
+                weighting: row.get(5),
+                time_weighting: row.get(6),
+                reference: row.get(7),
+            });
+        }
+        Ok(data)
+    }
+
+    /// Insert many readings of a sensor in one transaction, for batched payloads
+    /// Readings that are already stored for the sensor, time and kind are ignored.
+    /// # Arguments
+    /// * `self` - The Pool struct
+    /// * `sensor_id` - The id of the sensor
+    /// * `readings` - The readings
+    /// * `counter` - The counter of the authenticated payload the readings came in, if any
+    ///
+    /// # Returns
+    /// `Result<bool, tokio_postgres::Error>` - The result of the query, nothing is stored if it
+    /// fails, or if it is false because the counter is not higher than the last one stored
+    pub async fn insert_loudness_data_batch(
+        &self,
+        sensor_id: &str,
+        readings: &[NewReading],
+        counter: Option<i64>,
+    ) -> Result<bool, deadpool_postgres::PoolError> {
+        self.insert_batch(
+            "INSERT INTO loudness (sensor_id, level, time, flags, weighting, time_weighting, reference,
+                bandwidth, band_frequencies, band_levels, fields)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+            ON CONFLICT (sensor_id, time, (COALESCE(bandwidth, ''))) DO NOTHING",
+            sensor_id,
+            readings,
+            counter,
+        )
+        .await
     }
 
     /// Insert sensor data into the database
+    /// Does nothing if the sensor already exists, another backend may have added it first.
     /// # Arguments
     /// * `self` - The Pool struct
     /// * `sensor_id` - The id of the sensor
@@ -327,7 +617,10 @@ impl Pool {
     ) -> Result<(), deadpool_postgres::PoolError> {
         let client = self.pool.get().await?;
         let statement = client
-            .prepare("INSERT INTO sensor (id, type, location) VALUES ($1, $2, $3)")
+            .prepare(
+                "INSERT INTO sensor (id, type, location) VALUES ($1, $2, $3)
+                ON CONFLICT (id) DO NOTHING",
+            )
             .await?;
         client
             .execute(&statement, &[&sensor_id, &sensor_type, &sensor_location])
@@ -335,81 +628,1019 @@ impl Pool {
         Ok(())
     }
 
-    pub async fn create_log_table(&self) -> Result<(), deadpool_postgres::PoolError> {
+    /// Create the tables for sensors waiting for approval and their held readings
+    /// # Arguments
+    /// * `self` - The Pool struct
+    ///
+    /// # Returns
+    /// `Result<(), tokio_postgres::Error>` - The result of the query
+    pub async fn create_pending_sensor_tables(&self) -> Result<(), deadpool_postgres::PoolError> {
         let client = self.pool.get().await?;
         client
             .execute(
-                "CREATE TABLE IF NOT EXISTS log (
+                "CREATE TABLE IF NOT EXISTS pending_sensor (
+                    id text PRIMARY KEY,
+                    type text NOT NULL,
+                    location text NOT NULL,
+                    status text NOT NULL CHECK (status IN ('pending', 'rejected')),
+                    first_seen timestamp NOT NULL);",
+                &[],
+            )
+            .await?;
+        client
+            .execute(
+                "CREATE TABLE IF NOT EXISTS pending_loudness (
                     id SERIAL PRIMARY KEY,
-                    message TEXT NOT NULL,
-                    time timestamp NOT NULL);",
+                    sensor_id text REFERENCES pending_sensor(id) ON DELETE CASCADE,
+                    level text NOT NULL,
+                    time timestamp NOT NULL,
+                    flags text[] NOT NULL DEFAULT '{}');",
+                &[],
+            )
+            .await?;
+        client
+            .execute(add_scale_columns("pending_loudness").as_str(), &[])
+            .await?;
+        client
+            .execute(add_spectrum_columns("pending_loudness").as_str(), &[])
+            .await?;
+        client
+            .execute(
+                "ALTER TABLE pending_loudness ADD COLUMN IF NOT EXISTS fields text",
                 &[],
             )
             .await?;
+        // like the loudness table, one reading of each kind per sensor and timestamp
+        client
+            .batch_execute(
+                "CREATE UNIQUE INDEX IF NOT EXISTS pending_loudness_sensor_time_kind_idx
+                    ON pending_loudness (sensor_id, time, (COALESCE(bandwidth, '')));
+                ALTER TABLE pending_loudness DROP CONSTRAINT IF EXISTS pending_loudness_sensor_id_time_key;",
+            )
+            .await?;
         Ok(())
     }
 
-    /// Return all log entries from the database, ordered by time DESC
+    /// Add a sensor to the approval queue, does nothing if it is already queued
+    /// # Arguments
+    /// * `self` - The Pool struct
+    /// * `sensor_id` - The id of the sensor
+    /// * `sensor_type` - The type of the sensor
+    /// * `location` - The location of the sensor
+    ///
     /// # Returns
-    /// `Result<Vec<Log>, tokio_postgres::Error>` - The result of the query
-    pub async fn get_logs(&self) -> Result<Vec<Log>, deadpool_postgres::PoolError> {
+    /// `Result<(), tokio_postgres::Error>` - The result of the query
+    pub async fn insert_pending_sensor(
+        &self,
+        sensor_id: &str,
+        sensor_type: &str,
+        sensor_location: &str,
+    ) -> Result<(), deadpool_postgres::PoolError> {
         let client = self.pool.get().await?;
         let statement = client
-            .prepare("SELECT * FROM log ORDER BY time DESC")
+            .prepare(
+                "INSERT INTO pending_sensor (id, type, location, status, first_seen)
+                VALUES ($1, $2, $3, 'pending', $4) ON CONFLICT (id) DO NOTHING",
+            )
             .await?;
-        let rows = client.query(&statement, &[]).await?;
-        let mut data = Vec::new();
-
-        for row in rows {
-            data.push(Log {
-                id: row.get(0),
-                message: row.get(1),
-                time: row.get(2),
-            });
-        }
+        client
+            .execute(
+                &statement,
+                &[
+                    &sensor_id,
+                    &sensor_type,
+                    &sensor_location,
+                    &std::time::SystemTime::now(),
+                ],
+            )
+            .await?;
+        Ok(())
+    }
 
-        Ok(data)
+    /// Return the approval status of a sensor in the queue
+    /// # Arguments
+    /// * `self` - The Pool struct
+    /// * `sensor_id` - The id of the sensor
+    ///
+    /// # Returns
+    /// `Result<Option<String>, tokio_postgres::Error>` - "pending" or "rejected", `None` if not queued
+    pub async fn get_pending_sensor_status(
+        &self,
+        sensor_id: &str,
+    ) -> Result<Option<String>, deadpool_postgres::PoolError> {
+        let client = self.pool.get().await?;
+        let statement = client
+            .prepare("SELECT status FROM pending_sensor WHERE id = $1")
+            .await?;
+        let row = client.query_opt(&statement, &[&sensor_id]).await?;
+        Ok(row.map(|row| row.get(0)))
     }
 
-    /// Return n most recent log entries from the database, ordered by time DESC
+    /// Return all sensors in the approval queue, with the number of readings held for each
     /// # Arguments
-    /// * `n` - The number of log entries to return
+    /// * `self` - The Pool struct
+    ///
     /// # Returns
-    /// `Result<Vec<Log>, tokio_postgres::Error>` - The result of the query
-    pub async fn get_logs_limited(&self, n: i64) -> Result<Vec<Log>, deadpool_postgres::PoolError> {
+    /// `Result<Vec<PendingSensor>, tokio_postgres::Error>` - The result of the query
+    pub async fn get_pending_sensors(
+        &self,
+    ) -> Result<Vec<PendingSensor>, deadpool_postgres::PoolError> {
         let client = self.pool.get().await?;
         let statement = client
-            .prepare("SELECT * FROM log ORDER BY time DESC LIMIT $1")
+            .prepare(
+                "SELECT s.id, s.type, s.location, s.status, s.first_seen, COUNT(l.id)
+                FROM pending_sensor s LEFT JOIN pending_loudness l ON l.sensor_id = s.id
+                GROUP BY s.id ORDER BY s.first_seen",
+            )
             .await?;
-        let rows = client.query(&statement, &[&n]).await?;
+        let rows = client.query(&statement, &[]).await?;
         let mut data = Vec::new();
 
         for row in rows {
-            data.push(Log {
+            data.push(PendingSensor {
                 id: row.get(0),
-                message: row.get(1),
-                time: row.get(2),
+                type_: row.get(1),
+                location: row.get(2),
+                status: row.get(3),
+                first_seen: row.get(4),
+                held_readings: row.get(5),
             });
         }
         Ok(data)
     }
 
-    /// Insert log entry into the database
+    /// Hold many readings of a sensor that is waiting for approval in one transaction
     /// # Arguments
-    /// * `message` - The message to log
-    /// * `time` - The time the log was created
+    /// * `self` - The Pool struct
+    /// * `sensor_id` - The id of the sensor
+    /// * `readings` - The readings
+    /// * `counter` - The counter of the authenticated payload the readings came in, if any
+    ///
     /// # Returns
-    /// `Result<(), tokio_postgres::Error>` - The result of the query
-    pub async fn insert_log(
+    /// `Result<bool, tokio_postgres::Error>` - The result of the query, nothing is held if it
+    /// fails, or if it is false because the counter is not higher than the last one stored
+    pub async fn insert_pending_loudness_data_batch(
         &self,
-        message: &str,
-        time: std::time::SystemTime,
-    ) -> Result<(), deadpool_postgres::PoolError> {
+        sensor_id: &str,
+        readings: &[NewReading],
+        counter: Option<i64>,
+    ) -> Result<bool, deadpool_postgres::PoolError> {
+        self.insert_batch(
+            "INSERT INTO pending_loudness (sensor_id, level, time, flags, weighting, time_weighting,
+                reference, bandwidth, band_frequencies, band_levels, fields)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+            ON CONFLICT (sensor_id, time, (COALESCE(bandwidth, ''))) DO NOTHING",
+            sensor_id,
+            readings,
+            counter,
+        )
+        .await
+    }
+
+    /// Run an insert statement for every reading of a batch in one transaction,
+    /// together with moving the counter of the sensor forward, see `create_sensor_counter_table`
+    async fn insert_batch(
+        &self,
+        query: &str,
+        sensor_id: &str,
+        readings: &[NewReading],
+        counter: Option<i64>,
+    ) -> Result<bool, deadpool_postgres::PoolError> {
+        let mut client = self.pool.get().await?;
+        let transaction = client.transaction().await?;
+        if let Some(counter) = counter {
+            let moved = transaction
+                .execute(
+                    "INSERT INTO sensor_counter (sensor_id, counter) VALUES ($1, $2)
+                    ON CONFLICT (sensor_id) DO UPDATE SET counter = EXCLUDED.counter
+                    WHERE sensor_counter.counter < EXCLUDED.counter",
+                    &[&sensor_id, &counter],
+                )
+                .await?;
+            if moved == 0 {
+                return Ok(false);
+            }
+        }
+        let statement = transaction.prepare(query).await?;
+        for reading in readings {
+            let spectrum = reading.spectrum.as_ref();
+            let fields = Some(&reading.fields)
+                .filter(|fields| !fields.is_empty())
+                .map(|fields| serde_json::to_string(fields).expect("Fields can be serialized"));
+            transaction
+                .execute(
+                    &statement,
+                    &[
+                        &sensor_id,
+                        &reading.level,
+                        &reading.time,
+                        &reading.flags,
+                        &reading.scale.weighting.as_str(),
+                        &reading.scale.time_weighting.as_str(),
+                        &reading.scale.reference.as_str(),
+                        &spectrum.map(|spectrum| spectrum.bandwidth().as_str()),
+                        &spectrum.map(|spectrum| spectrum.frequencies().collect::<Vec<_>>()),
+                        &spectrum.map(|spectrum| spectrum.levels().to_vec()),
+                        &fields,
+                    ],
+                )
+                .await?;
+        }
+        transaction.commit().await?;
+        Ok(true)
+    }
+
+    /// Create the table with the highest counter of an authenticated payload stored per sensor,
+    /// so a payload can not be replayed after a restart or to another backend
+    /// # Arguments
+    /// * `self` - The Pool struct
+    ///
+    /// # Returns
+    /// `Result<(), tokio_postgres::Error>` - The result of the query
+    pub async fn create_sensor_counter_table(&self) -> Result<(), deadpool_postgres::PoolError> {
         let client = self.pool.get().await?;
-        let statement = client
-            .prepare("INSERT INTO log (message, time) VALUES ($1, $2)")
+        client
+            .execute(
+                "CREATE TABLE IF NOT EXISTS sensor_counter (
+                    sensor_id text PRIMARY KEY,
+                    counter bigint NOT NULL);",
+                &[],
+            )
             .await?;
-        client.execute(&statement, &[&message, &time]).await?;
         Ok(())
     }
+
+    /// Return the highest counter stored per sensor
+    /// # Arguments
+    /// * `self` - The Pool struct
+    ///
+    /// # Returns
+    /// `Result<Vec<(String, i64)>, tokio_postgres::Error>` - Sensor ids and their counters
+    pub async fn get_sensor_counters(
+        &self,
+    ) -> Result<Vec<(String, i64)>, deadpool_postgres::PoolError> {
+        let client = self.pool.get().await?;
+        let rows = client
+            .query("SELECT sensor_id, counter FROM sensor_counter", &[])
+            .await?;
+        Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+    }
+
+    /// Approve a sensor in the queue.
+    /// The sensor is added to the sensor table and its held readings are moved to the loudness table.
+    /// # Arguments
+    /// * `self` - The Pool struct
+    /// * `sensor_id` - The id of the sensor
+    ///
+    /// # Returns
+    /// `Result<bool, tokio_postgres::Error>` - false if the sensor was not waiting for approval
+    pub async fn approve_pending_sensor(
+        &self,
+        sensor_id: &str,
+    ) -> Result<bool, deadpool_postgres::PoolError> {
+        let mut client = self.pool.get().await?;
+        let transaction = client.transaction().await?;
+        let pending = transaction
+            .execute(
+                "SELECT id FROM pending_sensor WHERE id = $1 AND status = 'pending' FOR UPDATE",
+                &[&sensor_id],
+            )
+            .await?;
+        if pending == 0 {
+            return Ok(false);
+        }
+        // a backend with another registration policy may have added the sensor already
+        transaction
+            .execute(
+                "INSERT INTO sensor (id, type, location)
+                SELECT id, type, location FROM pending_sensor WHERE id = $1
+                ON CONFLICT (id) DO NOTHING",
+                &[&sensor_id],
+            )
+            .await?;
+        transaction
+            .execute(
+                "INSERT INTO loudness (sensor_id, level, time, flags, weighting, time_weighting, reference,
+                    bandwidth, band_frequencies, band_levels, fields)
+                SELECT sensor_id, level, time, flags, weighting, time_weighting, reference,
+                    bandwidth, band_frequencies, band_levels, fields
+                FROM pending_loudness WHERE sensor_id = $1
+                ON CONFLICT (sensor_id, time, (COALESCE(bandwidth, ''))) DO NOTHING",
+                &[&sensor_id],
+            )
+            .await?;
+        transaction
+            .execute("DELETE FROM pending_sensor WHERE id = $1", &[&sensor_id])
+            .await?;
+        transaction.commit().await?;
+        Ok(true)
+    }
+
+    /// Reject a sensor in the queue.
+    /// Its held readings are deleted and later readings from it are dropped.
+    /// # Arguments
+    /// * `self` - The Pool struct
+    /// * `sensor_id` - The id of the sensor
+    ///
+    /// # Returns
+    /// `Result<bool, tokio_postgres::Error>` - false if the sensor was not waiting for approval
+    pub async fn reject_pending_sensor(
+        &self,
+        sensor_id: &str,
+    ) -> Result<bool, deadpool_postgres::PoolError> {
+        let mut client = self.pool.get().await?;
+        let transaction = client.transaction().await?;
+        let updated = transaction
+            .execute(
+                "UPDATE pending_sensor SET status = 'rejected' WHERE id = $1 AND status = 'pending'",
+                &[&sensor_id],
+            )
+            .await?;
+        transaction
+            .execute(
+                "DELETE FROM pending_loudness WHERE sensor_id = $1",
+                &[&sensor_id],
+            )
+            .await?;
+        transaction.commit().await?;
+        Ok(updated > 0)
+    }
+
+    /// Create the tables containing the liveness status of each sensor, and the
+    /// backend that decides it, if they do not exist
+    /// # Arguments
+    /// * `self` - The Pool struct
+    ///
+    /// # Returns
+    /// `Result<(), tokio_postgres::Error>` - The result of the query
+    pub async fn create_sensor_status_table(&self) -> Result<(), deadpool_postgres::PoolError> {
+        let client = self.pool.get().await?;
+        client
+            .execute(
+                "CREATE TABLE IF NOT EXISTS sensor_status (
+                    sensor_id text PRIMARY KEY REFERENCES sensor(id) ON DELETE CASCADE,
+                    state text NOT NULL CHECK (state IN ('online', 'late', 'offline')),
+                    last_seen timestamp NOT NULL,
+                    expected_interval_secs double precision NOT NULL,
+                    changed_at timestamp NOT NULL);",
+                &[],
+            )
+            .await?;
+        client
+            .execute(
+                "CREATE TABLE IF NOT EXISTS liveness_lease (
+                    id integer PRIMARY KEY CHECK (id = 1),
+                    backend_id text NOT NULL,
+                    expires_at timestamptz NOT NULL);",
+                &[],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Claim or renew the lease of the backend that decides the liveness state of the sensors,
+    /// so backends sharing a subscription do not overwrite each other's states
+    /// # Arguments
+    /// * `self` - The Pool struct
+    /// * `backend_id` - Unique id of the backend
+    /// * `lease_secs` - How long the lease lasts if it is not renewed
+    ///
+    /// # Returns
+    /// `Result<bool, tokio_postgres::Error>` - true if the backend holds the lease
+    pub async fn claim_liveness_lease(
+        &self,
+        backend_id: &str,
+        lease_secs: f64,
+    ) -> Result<bool, deadpool_postgres::PoolError> {
+        let client = self.pool.get().await?;
+        let claimed = client
+            .execute(
+                "INSERT INTO liveness_lease (id, backend_id, expires_at)
+                VALUES (1, $1, now() + make_interval(secs => $2))
+                ON CONFLICT (id) DO UPDATE SET
+                    backend_id = EXCLUDED.backend_id,
+                    expires_at = EXCLUDED.expires_at
+                WHERE liveness_lease.backend_id = EXCLUDED.backend_id
+                    OR liveness_lease.expires_at < now()",
+                &[&backend_id, &lease_secs],
+            )
+            .await?;
+        Ok(claimed > 0)
+    }
+
+    /// Insert or update the liveness status of a sensor.
+    /// `changed_at` is only moved when the state changes, and `last_seen`
+    /// never moves back, another backend may have seen the sensor since.
+    /// # Arguments
+    /// * `self` - The Pool struct
+    /// * `sensor_id` - The id of the sensor
+    /// * `state` - "online", "late" or "offline"
+    /// * `last_seen` - The time the last reading was received
+    /// * `expected_interval_secs` - How often the sensor is expected to report
+    ///
+    /// # Returns
+    /// `Result<(), tokio_postgres::Error>` - The result of the query
+    pub async fn upsert_sensor_status(
+        &self,
+        sensor_id: &str,
+        state: &str,
+        last_seen: std::time::SystemTime,
+        expected_interval_secs: f64,
+    ) -> Result<(), deadpool_postgres::PoolError> {
+        let client = self.pool.get().await?;
+        let statement = client
+            .prepare(
+                "INSERT INTO sensor_status
+                    (sensor_id, state, last_seen, expected_interval_secs, changed_at)
+                VALUES ($1, $2, $3, $4, $5)
+                ON CONFLICT (sensor_id) DO UPDATE SET
+                    changed_at = CASE WHEN sensor_status.state = EXCLUDED.state
+                        THEN sensor_status.changed_at ELSE EXCLUDED.changed_at END,
+                    state = EXCLUDED.state,
+                    last_seen = GREATEST(sensor_status.last_seen, EXCLUDED.last_seen),
+                    expected_interval_secs = EXCLUDED.expected_interval_secs",
+            )
+            .await?;
+        client
+            .execute(
+                &statement,
+                &[
+                    &sensor_id,
+                    &state,
+                    &last_seen,
+                    &expected_interval_secs,
+                    &std::time::SystemTime::now(),
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Insert or update the time a sensor was last seen, for backends that do not decide
+    /// its state. A new sensor is inserted as online, the state of a known one is kept.
+    /// # Arguments
+    /// * `self` - The Pool struct
+    /// * `sensor_id` - The id of the sensor
+    /// * `last_seen` - The time the last reading was received
+    /// * `expected_interval_secs` - How often the sensor is expected to report, for a new sensor
+    ///
+    /// # Returns
+    /// `Result<(), tokio_postgres::Error>` - The result of the query
+    pub async fn upsert_sensor_last_seen(
+        &self,
+        sensor_id: &str,
+        last_seen: std::time::SystemTime,
+        expected_interval_secs: f64,
+    ) -> Result<(), deadpool_postgres::PoolError> {
+        let client = self.pool.get().await?;
+        let statement = client
+            .prepare(
+                "INSERT INTO sensor_status
+                    (sensor_id, state, last_seen, expected_interval_secs, changed_at)
+                VALUES ($1, 'online', $2, $3, $4)
+                ON CONFLICT (sensor_id) DO UPDATE SET
+                    last_seen = GREATEST(sensor_status.last_seen, EXCLUDED.last_seen)",
+            )
+            .await?;
+        client
+            .execute(
+                &statement,
+                &[
+                    &sensor_id,
+                    &last_seen,
+                    &expected_interval_secs,
+                    &std::time::SystemTime::now(),
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Create the table with the connection status sensors announce over MQTT
+    /// # Arguments
+    /// * `self` - The Pool struct
+    ///
+    /// # Returns
+    /// `Result<(), tokio_postgres::Error>` - The result of the query
+    pub async fn create_sensor_connection_table(&self) -> Result<(), deadpool_postgres::PoolError> {
+        let client = self.pool.get().await?;
+        client
+            .execute(
+                "CREATE TABLE IF NOT EXISTS sensor_connection (
+                    sensor_id text PRIMARY KEY REFERENCES sensor(id) ON DELETE CASCADE,
+                    state text NOT NULL CHECK (state IN ('online', 'offline')),
+                    firmware_version text,
+                    booted_at timestamp,
+                    config_hash text,
+                    changed_at timestamp NOT NULL);",
+                &[],
+            )
+            .await?;
+        client
+            .execute(
+                "ALTER TABLE sensor_connection ADD COLUMN IF NOT EXISTS counter bigint",
+                &[],
+            )
+            .await?;
+        client
+            .execute(
+                "ALTER TABLE sensor_connection ADD COLUMN IF NOT EXISTS session bigint",
+                &[],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Insert or update the `online` status a sensor announced when it connected.
+    /// Details that are not given keep their previous value.
+    /// # Arguments
+    /// * `self` - The Pool struct
+    /// * `sensor_id` - The id of the sensor
+    /// * `firmware_version` - The firmware version of the sensor
+    /// * `booted_at` - The time the sensor started
+    /// * `config_hash` - Hash of the config the sensor is running with
+    /// * `counter` - The counter the status was signed with, it is only stored if
+    ///   the counter is higher than the one of the stored status
+    /// * `session` - The session of the connection the status was sent on, see
+    ///   `mark_sensor_offline`
+    ///
+    /// # Returns
+    /// `Result<bool, tokio_postgres::Error>` - Whether the status was stored
+    pub async fn upsert_sensor_connection(
+        &self,
+        sensor_id: &str,
+        firmware_version: Option<&str>,
+        booted_at: Option<std::time::SystemTime>,
+        config_hash: Option<&str>,
+        counter: Option<i64>,
+        session: Option<i64>,
+    ) -> Result<bool, deadpool_postgres::PoolError> {
+        let client = self.pool.get().await?;
+        let statement = client
+            .prepare(
+                "INSERT INTO sensor_connection
+                    (sensor_id, state, firmware_version, booted_at, config_hash, changed_at,
+                    counter, session)
+                VALUES ($1, 'online', $2, $3, $4, $5, $6, $7)
+                ON CONFLICT (sensor_id) DO UPDATE SET
+                    state = EXCLUDED.state,
+                    firmware_version = COALESCE(EXCLUDED.firmware_version,
+                        sensor_connection.firmware_version),
+                    booted_at = COALESCE(EXCLUDED.booted_at, sensor_connection.booted_at),
+                    config_hash = COALESCE(EXCLUDED.config_hash, sensor_connection.config_hash),
+                    changed_at = EXCLUDED.changed_at,
+                    counter = COALESCE(EXCLUDED.counter, sensor_connection.counter),
+                    session = EXCLUDED.session
+                WHERE EXCLUDED.counter IS NULL
+                    OR sensor_connection.counter IS NULL
+                    OR sensor_connection.counter < EXCLUDED.counter",
+            )
+            .await?;
+        let rows = client
+            .execute(
+                &statement,
+                &[
+                    &sensor_id,
+                    &firmware_version,
+                    &booted_at,
+                    &config_hash,
+                    &std::time::SystemTime::now(),
+                    &counter,
+                    &session,
+                ],
+            )
+            .await?;
+        Ok(rows > 0)
+    }
+
+    /// Mark a sensor offline after its last will arrived.
+    /// The last will is only taken if the sensor is online in the session it was
+    /// registered for, the session is then cleared so the last will is taken once.
+    /// # Arguments
+    /// * `self` - The Pool struct
+    /// * `sensor_id` - The id of the sensor
+    /// * `session` - The session in the last will
+    ///
+    /// # Returns
+    /// `Result<bool, tokio_postgres::Error>` - Whether the sensor was marked offline
+    pub async fn mark_sensor_offline(
+        &self,
+        sensor_id: &str,
+        session: i64,
+    ) -> Result<bool, deadpool_postgres::PoolError> {
+        let client = self.pool.get().await?;
+        let statement = client
+            .prepare(
+                "UPDATE sensor_connection
+                SET state = 'offline', changed_at = $3, session = NULL
+                WHERE sensor_id = $1 AND state = 'online' AND session = $2",
+            )
+            .await?;
+        let rows = client
+            .execute(
+                &statement,
+                &[&sensor_id, &session, &std::time::SystemTime::now()],
+            )
+            .await?;
+        Ok(rows > 0)
+    }
+
+    /// Return the liveness status of all sensors, with the connection status they announced
+    /// # Arguments
+    /// * `self` - The Pool struct
+    ///
+    /// # Returns
+    /// `Result<Vec<SensorStatus>, tokio_postgres::Error>` - The result of the query
+    pub async fn get_sensor_statuses(
+        &self,
+    ) -> Result<Vec<SensorStatus>, deadpool_postgres::PoolError> {
+        let client = self.pool.get().await?;
+        let statement = client
+            .prepare(
+                "SELECT sensor_status.sensor_id, sensor_status.state, last_seen,
+                    expected_interval_secs, sensor_status.changed_at, sensor_connection.state,
+                    firmware_version, booted_at, config_hash, sensor_connection.changed_at
+                FROM sensor_status
+                LEFT JOIN sensor_connection ON sensor_connection.sensor_id = sensor_status.sensor_id
+                ORDER BY sensor_status.sensor_id",
+            )
+            .await?;
+        let rows = client.query(&statement, &[]).await?;
+        let mut data = Vec::new();
+
+        for row in rows {
+            data.push(SensorStatus {
+                sensor_id: row.get(0),
+                state: row.get(1),
+                last_seen: row.get(2),
+                expected_interval_secs: row.get(3),
+                changed_at: row.get(4),
+                connection: row.get(5),
+                firmware_version: row.get(6),
+                booted_at: row.get(7),
+                config_hash: row.get(8),
+                connection_changed_at: row.get(9),
+            });
+        }
+        Ok(data)
+    }
+
+    /// Create the table with the desired and reported config of each sensor
+    ///
+    /// `published_version` is the last desired version the backend published,
+    /// so a config is published once after every change.
+    /// # Arguments
+    /// * `self` - The Pool struct
+    ///
+    /// # Returns
+    /// `Result<(), tokio_postgres::Error>` - The result of the query
+    pub async fn create_sensor_config_table(&self) -> Result<(), deadpool_postgres::PoolError> {
+        let client = self.pool.get().await?;
+        client
+            .execute(
+                "CREATE TABLE IF NOT EXISTS sensor_config (
+                    sensor_id text PRIMARY KEY REFERENCES sensor(id) ON DELETE CASCADE,
+                    desired text,
+                    desired_version integer NOT NULL DEFAULT 0,
+                    published_version integer NOT NULL DEFAULT 0,
+                    updated_at timestamp,
+                    reported text,
+                    reported_version integer,
+                    reported_at timestamp);",
+                &[],
+            )
+            .await?;
+        client
+            .execute(
+                "ALTER TABLE sensor_config ADD COLUMN IF NOT EXISTS reported_counter bigint",
+                &[],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Set the config a sensor should have, it is published to the sensor by the backend
+    /// # Arguments
+    /// * `self` - The Pool struct
+    /// * `sensor_id` - The id of the sensor
+    /// * `config` - The desired config, its version is ignored
+    ///
+    /// # Returns
+    /// `Result<i32, tokio_postgres::Error>` - The version of the new desired config
+    pub async fn set_desired_sensor_config(
+        &self,
+        sensor_id: &str,
+        config: &SensorConfig,
+    ) -> Result<i32, deadpool_postgres::PoolError> {
+        let desired = serde_json::to_string(config).expect("SensorConfig can be serialized");
+        let client = self.pool.get().await?;
+        let statement = client
+            .prepare(
+                "INSERT INTO sensor_config (sensor_id, desired, desired_version, updated_at)
+                VALUES ($1, $2, 1, $3)
+                ON CONFLICT (sensor_id) DO UPDATE SET
+                    desired = EXCLUDED.desired,
+                    desired_version = sensor_config.desired_version + 1,
+                    updated_at = EXCLUDED.updated_at
+                RETURNING desired_version",
+            )
+            .await?;
+        let row = client
+            .query_one(
+                &statement,
+                &[&sensor_id, &desired, &std::time::SystemTime::now()],
+            )
+            .await?;
+        Ok(row.get(0))
+    }
+
+    /// Return the desired and reported config of a sensor
+    /// # Arguments
+    /// * `self` - The Pool struct
+    /// * `sensor_id` - The id of the sensor
+    ///
+    /// # Returns
+    /// `Result<Option<SensorConfigState>, tokio_postgres::Error>` - `None` if the sensor has neither
+    pub async fn get_sensor_config_state(
+        &self,
+        sensor_id: &str,
+    ) -> Result<Option<SensorConfigState>, deadpool_postgres::PoolError> {
+        let client = self.pool.get().await?;
+        let statement = client
+            .prepare(
+                "SELECT desired, desired_version, reported, reported_version, reported_at
+                FROM sensor_config WHERE sensor_id = $1",
+            )
+            .await?;
+        let row = match client.query_opt(&statement, &[&sensor_id]).await? {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        let desired_version: i32 = row.get(1);
+        let desired = parse_config(row.get(0), desired_version);
+        let reported_version: Option<i32> = row.get(3);
+        let reported = parse_config(row.get(2), reported_version.unwrap_or(0));
+        Ok(Some(SensorConfigState {
+            sensor_id: sensor_id.to_string(),
+            in_sync: desired.is_none() || reported_version == Some(desired_version),
+            desired,
+            reported,
+            reported_at: row.get(4),
+        }))
+    }
+
+    /// Return the desired configs that have changed since they were last published,
+    /// with the sensor they are for
+    /// # Arguments
+    /// * `self` - The Pool struct
+    ///
+    /// # Returns
+    /// `Result<Vec<(Sensor, SensorConfig)>, tokio_postgres::Error>` - The result of the query
+    pub async fn get_unpublished_sensor_configs(
+        &self,
+    ) -> Result<Vec<(Sensor, SensorConfig)>, deadpool_postgres::PoolError> {
+        let client = self.pool.get().await?;
+        let statement = client
+            .prepare(
+                "SELECT sensor.id, sensor.type, sensor.location,
+                    sensor_config.desired, sensor_config.desired_version
+                FROM sensor_config JOIN sensor ON sensor.id = sensor_config.sensor_id
+                WHERE sensor_config.desired IS NOT NULL
+                    AND sensor_config.desired_version > sensor_config.published_version",
+            )
+            .await?;
+        let rows = client.query(&statement, &[]).await?;
+        let mut data = Vec::new();
+
+        for row in rows {
+            let sensor = Sensor {
+                id: row.get(0),
+                type_: row.get(1),
+                location: row.get(2),
+            };
+            if let Some(config) = parse_config(row.get(3), row.get(4)) {
+                data.push((sensor, config));
+            }
+        }
+        Ok(data)
+    }
+
+    /// Record that a desired config has been published to the sensor
+    /// # Arguments
+    /// * `self` - The Pool struct
+    /// * `sensor_id` - The id of the sensor
+    /// * `version` - The version that was published
+    ///
+    /// # Returns
+    /// `Result<(), tokio_postgres::Error>` - The result of the query
+    pub async fn mark_sensor_config_published(
+        &self,
+        sensor_id: &str,
+        version: i32,
+    ) -> Result<(), deadpool_postgres::PoolError> {
+        let client = self.pool.get().await?;
+        let statement = client
+            .prepare(
+                "UPDATE sensor_config SET published_version = GREATEST(published_version, $2)
+                WHERE sensor_id = $1",
+            )
+            .await?;
+        client.execute(&statement, &[&sensor_id, &version]).await?;
+        Ok(())
+    }
+
+    /// Store the config a sensor reported it applied
+    /// # Arguments
+    /// * `self` - The Pool struct
+    /// * `sensor_id` - The id of the sensor
+    /// * `config` - The config the sensor is running with
+    /// * `counter` - The counter the report was signed with, it is only stored if
+    ///   the counter is higher than the one of the stored report
+    ///
+    /// # Returns
+    /// `Result<bool, tokio_postgres::Error>` - Whether the report was stored
+    pub async fn update_reported_sensor_config(
+        &self,
+        sensor_id: &str,
+        config: &SensorConfig,
+        counter: Option<i64>,
+    ) -> Result<bool, deadpool_postgres::PoolError> {
+        let reported = serde_json::to_string(config).expect("SensorConfig can be serialized");
+        let client = self.pool.get().await?;
+        let statement = client
+            .prepare(
+                "INSERT INTO sensor_config
+                    (sensor_id, reported, reported_version, reported_at, reported_counter)
+                VALUES ($1, $2, $3, $4, $5)
+                ON CONFLICT (sensor_id) DO UPDATE SET
+                    reported = EXCLUDED.reported,
+                    reported_version = EXCLUDED.reported_version,
+                    reported_at = EXCLUDED.reported_at,
+                    reported_counter = EXCLUDED.reported_counter
+                WHERE EXCLUDED.reported_counter IS NULL
+                    OR sensor_config.reported_counter IS NULL
+                    OR sensor_config.reported_counter < EXCLUDED.reported_counter",
+            )
+            .await?;
+        let rows = client
+            .execute(
+                &statement,
+                &[
+                    &sensor_id,
+                    &reported,
+                    &config.version,
+                    &std::time::SystemTime::now(),
+                    &counter,
+                ],
+            )
+            .await?;
+        Ok(rows > 0)
+    }
+
+    /// Return the config every sensor reported it applied
+    /// # Arguments
+    /// * `self` - The Pool struct
+    ///
+    /// # Returns
+    /// `Result<Vec<(String, SensorConfig)>, tokio_postgres::Error>` - The sensor ids with their config
+    pub async fn get_reported_sensor_configs(
+        &self,
+    ) -> Result<Vec<(String, SensorConfig)>, deadpool_postgres::PoolError> {
+        let client = self.pool.get().await?;
+        let statement = client
+            .prepare(
+                "SELECT sensor_id, reported, reported_version FROM sensor_config
+                WHERE reported IS NOT NULL",
+            )
+            .await?;
+        let rows = client.query(&statement, &[]).await?;
+        let mut data = Vec::new();
+
+        for row in rows {
+            let version: Option<i32> = row.get(2);
+            if let Some(config) = parse_config(row.get(1), version.unwrap_or(0)) {
+                data.push((row.get(0), config));
+            }
+        }
+        Ok(data)
+    }
+
+    pub async fn create_log_table(&self) -> Result<(), deadpool_postgres::PoolError> {
+        let client = self.pool.get().await?;
+        client
+            .execute(
+                "CREATE TABLE IF NOT EXISTS log (
+                    id SERIAL PRIMARY KEY,
+                    message TEXT NOT NULL,
+                    time timestamp NOT NULL);",
+                &[],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Return all log entries from the database, ordered by time DESC
+    /// # Returns
+    /// `Result<Vec<Log>, tokio_postgres::Error>` - The result of the query
+    pub async fn get_logs(&self) -> Result<Vec<Log>, deadpool_postgres::PoolError> {
+        let client = self.pool.get().await?;
+        let statement = client
+            .prepare("SELECT * FROM log ORDER BY time DESC")
+            .await?;
+        let rows = client.query(&statement, &[]).await?;
+        let mut data = Vec::new();
+
+        for row in rows {
+            data.push(Log {
+                id: row.get(0),
+                message: row.get(1),
+                time: row.get(2),
+            });
+        }
+
+        Ok(data)
+    }
+
+    /// Return n most recent log entries from the database, ordered by time DESC
+    /// # Arguments
+    /// * `n` - The number of log entries to return
+    /// # Returns
+    /// `Result<Vec<Log>, tokio_postgres::Error>` - The result of the query
+    pub async fn get_logs_limited(&self, n: i64) -> Result<Vec<Log>, deadpool_postgres::PoolError> {
+        let client = self.pool.get().await?;
+        let statement = client
+            .prepare("SELECT * FROM log ORDER BY time DESC LIMIT $1")
+            .await?;
+        let rows = client.query(&statement, &[&n]).await?;
+        let mut data = Vec::new();
+
+        for row in rows {
+            data.push(Log {
+                id: row.get(0),
+                message: row.get(1),
+                time: row.get(2),
+            });
+        }
+        Ok(data)
+    }
+
+    /// Insert log entry into the database
+    /// # Arguments
+    /// * `message` - The message to log
+    /// * `time` - The time the log was created
+    /// # Returns
+    /// `Result<(), tokio_postgres::Error>` - The result of the query
+    pub async fn insert_log(
+        &self,
+        message: &str,
+        time: std::time::SystemTime,
+    ) -> Result<(), deadpool_postgres::PoolError> {
+        let client = self.pool.get().await?;
+        let statement = client
+            .prepare("INSERT INTO log (message, time) VALUES ($1, $2)")
+            .await?;
+        client.execute(&statement, &[&message, &time]).await?;
+        Ok(())
+    }
+}
+
+/// Parse the optional fields of a reading, stored as a json object
+fn parse_fields(fields: Option<String>) -> BTreeMap<String, String> {
+    fields
+        .and_then(|fields| serde_json::from_str(&fields).ok())
+        .unwrap_or_default()
+}
+
+/// Parse a config stored as json, with the version kept in its own column
+fn parse_config(config: Option<String>, version: i32) -> Option<SensorConfig> {
+    let mut config: SensorConfig = serde_json::from_str(&config?).ok()?;
+    config.version = version;
+    Some(config)
+}
+
+/// Returns the statement adding the scale columns to a table of readings,
+/// readings stored before the columns existed are on the default scale
+fn add_scale_columns(table: &str) -> String {
+    format!(
+        "ALTER TABLE {}
+            ADD COLUMN IF NOT EXISTS weighting text NOT NULL DEFAULT 'A'
+                CHECK (weighting IN ('A', 'C', 'Z')),
+            ADD COLUMN IF NOT EXISTS time_weighting text NOT NULL DEFAULT 'fast'
+                CHECK (time_weighting IN ('fast', 'slow', 'leq')),
+            ADD COLUMN IF NOT EXISTS reference text NOT NULL DEFAULT 'spl'
+                CHECK (reference IN ('spl', 'dbfs'));",
+        table
+    )
+}
+
+/// Returns the statement adding the columns of spectrum readings to a table of readings,
+/// they are null for readings of a single level
+fn add_spectrum_columns(table: &str) -> String {
+    format!(
+        "ALTER TABLE {}
+            ADD COLUMN IF NOT EXISTS bandwidth text CHECK (bandwidth IN ('octave', 'third_octave')),
+            ADD COLUMN IF NOT EXISTS band_frequencies real[],
+            ADD COLUMN IF NOT EXISTS band_levels real[];",
+        table
+    )
 }
@@ -0,0 +1,497 @@
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Length of generated keys in bytes
+pub const KEY_LENGTH: usize = 32;
+
+/// Label of the subkey that encrypts payloads in an envelope
+pub const AEAD_LABEL: &[u8] = b"iot-sound aead v1";
+
+/// What a signed message is for, each is signed with its own subkey so a message
+/// can not be passed off as one of another kind
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    /// Readings published by a sensor
+    Readings,
+    /// Configs the backend sends to a sensor
+    Config,
+    /// Configs a sensor reports it applied
+    ReportedConfig,
+    /// Status messages a sensor announces
+    Status,
+}
+
+impl Channel {
+    /// Returns the label of the subkey that signs messages on this channel
+    pub fn label(&self) -> &'static [u8] {
+        match self {
+            Channel::Readings => b"iot-sound mac v1",
+            Channel::Config => b"iot-sound config mac v1",
+            Channel::ReportedConfig => b"iot-sound reported config mac v1",
+            Channel::Status => b"iot-sound status mac v1",
+        }
+    }
+}
+
+/// Derive a subkey from the shared key of a sensor, so the same key is never used
+/// for two purposes. This is the expand step of HKDF-SHA256 with the label as info,
+/// the shared key is random already so it needs no extract step.
+/// # Arguments
+/// * `key` - The shared key of the sensor
+/// * `label` - What the subkey is used for
+/// # Returns
+/// * `[u8; KEY_LENGTH]` - The subkey
+pub fn derive_key(key: &[u8], label: &[u8]) -> [u8; KEY_LENGTH] {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(label);
+    mac.update(&[1]);
+    mac.finalize().into_bytes().into()
+}
+
+/// A shared key of a sensor, sensors can have several keys while one is being rotated
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SensorKey {
+    pub id: u32,
+    /// The key, hex encoded
+    pub key: String,
+}
+
+impl SensorKey {
+    /// Generate a new random key
+    /// # Arguments
+    /// * `id` - The id of the key
+    pub fn generate(id: u32) -> Self {
+        let key: [u8; KEY_LENGTH] = rand::random();
+        SensorKey {
+            id,
+            key: hex::encode(key),
+        }
+    }
+
+    /// Returns the decoded key
+    pub fn bytes(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        Ok(hex::decode(&self.key)?)
+    }
+}
+
+/// The keys of every sensor, as stored in the keys file
+pub type KeyStore = HashMap<String, Vec<SensorKey>>;
+
+/// Load the keys file
+/// # Arguments
+/// * `path` - Path to the json keys file
+pub fn load_keys(path: &str) -> Result<KeyStore, Box<dyn Error>> {
+    let file = std::fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&file)?)
+}
+
+/// The keys file of the backend, for messages to and from sensors other than readings.
+/// They are rare, so the file is read every time one is signed or verified.
+#[derive(Debug, Clone, Default)]
+pub struct KeysFile {
+    /// Path to the keys file, no sensor has a key if `None`
+    pub path: Option<String>,
+    /// Whether messages from sensors without a key are rejected
+    pub required: bool,
+}
+
+impl KeysFile {
+    /// Returns the keys of a sensor, empty if it has none
+    /// # Arguments
+    /// * `sensor_id` - The id of the sensor
+    pub fn keys_of(&self, sensor_id: &str) -> Result<Vec<SensorKey>, Box<dyn Error>> {
+        match &self.path {
+            Some(path) => Ok(load_keys(path)?.remove(sensor_id).unwrap_or_default()),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Verify a message a sensor signed, messages from sensors without a key
+    /// are let through unsigned unless keys are required
+    /// # Arguments
+    /// * `channel` - What the message is for
+    /// * `sensor_id` - The id of the sensor the message claims to be from
+    /// * `payload` - The message as received
+    /// # Returns
+    /// * `(&str, Option<u64>)` - The message without the signature, and its counter if it is signed
+    pub fn verify<'a>(
+        &self,
+        channel: Channel,
+        sensor_id: &str,
+        payload: &'a [u8],
+    ) -> Result<(&'a str, Option<u64>), Box<dyn Error>> {
+        let payload = std::str::from_utf8(payload).map_err(|_| AuthError::Malformed)?;
+        let keys = self.keys_of(sensor_id)?;
+        if keys.is_empty() {
+            if self.required {
+                return Err(AuthError::NoKey.into());
+            }
+            return Ok((payload, None));
+        }
+        let signed = SignedPayload::parse_on(channel, payload)?;
+        let key = keys
+            .iter()
+            .find(|key| key.id == signed.key_id)
+            .ok_or(AuthError::UnknownKey(signed.key_id))?;
+        signed.verify(sensor_id, &key.bytes()?)?;
+        Ok((signed.message, Some(signed.counter)))
+    }
+
+    /// Sign a message to a sensor with its newest key, so a sensor configured with
+    /// the key printed by `iot_sound_keys rotate` can verify it. Messages to sensors
+    /// without a key are sent unsigned unless keys are required.
+    /// # Arguments
+    /// * `channel` - What the message is for
+    /// * `sensor_id` - The id of the sensor the message is for
+    /// * `counter` - Must be higher than the counter of every earlier message on the channel
+    /// * `message` - The message to sign
+    pub fn sign(
+        &self,
+        channel: Channel,
+        sensor_id: &str,
+        counter: u64,
+        message: &str,
+    ) -> Result<String, Box<dyn Error>> {
+        let keys = self.keys_of(sensor_id)?;
+        match keys.iter().max_by_key(|key| key.id) {
+            Some(key) => Ok(sign_on(
+                channel,
+                sensor_id,
+                &key.bytes()?,
+                key.id,
+                counter,
+                message,
+            )),
+            None if self.required => Err(AuthError::NoKey.into()),
+            None => Ok(message.to_string()),
+        }
+    }
+}
+
+/// Why a payload could not be authenticated
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthError {
+    /// The sensor has no key but keys are required
+    NoKey,
+    /// The payload has no signature but the sensor has a key
+    Unsigned,
+    /// The signature is not in the expected format
+    Malformed,
+    /// The key id is not one of the sensor's keys
+    UnknownKey(u32),
+    /// The HMAC does not match
+    BadSignature,
+    /// The envelope version is not supported
+    UnsupportedVersion(u8),
+    /// The payload could not be decrypted with the key
+    DecryptionFailed,
+    /// The payload is only signed but encryption is required
+    Unencrypted,
+    /// The counter is not higher than the last one accepted
+    Replay { counter: u64, last: u64 },
+}
+
+impl AuthError {
+    /// Short name of the error, used as metric label
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AuthError::NoKey => "no_key",
+            AuthError::Unsigned => "unsigned",
+            AuthError::Malformed => "malformed",
+            AuthError::UnknownKey(_) => "unknown_key",
+            AuthError::BadSignature => "bad_signature",
+            AuthError::UnsupportedVersion(_) => "unsupported_version",
+            AuthError::DecryptionFailed => "decryption_failed",
+            AuthError::Unencrypted => "unencrypted",
+            AuthError::Replay { .. } => "replay",
+        }
+    }
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::NoKey => write!(f, "sensor has no key"),
+            AuthError::Unsigned => write!(f, "payload is not signed"),
+            AuthError::Malformed => write!(f, "signature is malformed"),
+            AuthError::UnknownKey(id) => write!(f, "unknown key id {}", id),
+            AuthError::BadSignature => write!(f, "signature does not match"),
+            AuthError::UnsupportedVersion(version) => {
+                write!(f, "envelope version {} is not supported", version)
+            }
+            AuthError::DecryptionFailed => write!(f, "payload could not be decrypted"),
+            AuthError::Unencrypted => write!(f, "payload is not encrypted"),
+            AuthError::Replay { counter, last } => {
+                write!(f, "counter {} is not higher than {}", counter, last)
+            }
+        }
+    }
+}
+
+impl Error for AuthError {}
+
+/// A payload split into its parts
+#[derive(Debug, PartialEq, Eq)]
+pub struct SignedPayload<'a> {
+    /// The original payload
+    pub message: &'a str,
+    pub counter: u64,
+    pub key_id: u32,
+    channel: Channel,
+    /// The part of the payload covered by the HMAC
+    signed: &'a str,
+    mac: &'a str,
+}
+
+impl<'a> SignedPayload<'a> {
+    /// Split a signed reading payload, fails if it is not signed
+    /// # Arguments
+    /// * `payload` - The payload as received
+    pub fn parse(payload: &'a str) -> Result<Self, AuthError> {
+        Self::parse_on(Channel::Readings, payload)
+    }
+
+    /// Split a signed payload, fails if it is not signed
+    /// # Arguments
+    /// * `channel` - What the payload is for
+    /// * `payload` - The payload as received
+    pub fn parse_on(channel: Channel, payload: &'a str) -> Result<Self, AuthError> {
+        let (signed, mac) = payload.rsplit_once(',').ok_or(AuthError::Unsigned)?;
+        let mut fields = signed.rsplitn(3, ',');
+        let key_id = fields.next().ok_or(AuthError::Unsigned)?;
+        let counter = fields.next().ok_or(AuthError::Unsigned)?;
+        let message = fields.next().ok_or(AuthError::Unsigned)?;
+        // an unsigned csv payload has only two fields
+        if channel == Channel::Readings && !message.contains(',') {
+            return Err(AuthError::Unsigned);
+        }
+        Ok(SignedPayload {
+            message,
+            counter: counter.parse().map_err(|_| AuthError::Malformed)?,
+            key_id: key_id.parse().map_err(|_| AuthError::Malformed)?,
+            channel,
+            signed,
+            mac,
+        })
+    }
+
+    /// Check the HMAC of the payload
+    /// # Arguments
+    /// * `sensor_id` - The id of the sensor the payload claims to be from
+    /// * `key` - The key with the id in the payload
+    pub fn verify(&self, sensor_id: &str, key: &[u8]) -> Result<(), AuthError> {
+        let mac = hex::decode(self.mac).map_err(|_| AuthError::Malformed)?;
+        mac_for(self.channel, sensor_id, key, self.signed)
+            .verify_slice(&mac)
+            .map_err(|_| AuthError::BadSignature)
+    }
+}
+
+/// Sign a reading payload with the shared key of a sensor
+///
+/// The signed payload is the payload followed by `,<counter>,<key id>,<hmac>`.
+/// The HMAC-SHA256 is keyed with the subkey of the channel and
+/// covers the sensor id and everything before the last comma,
+/// so a payload can neither be changed nor replayed to the topic of another sensor.
+/// # Arguments
+/// * `sensor_id` - The id of the sensor sending the payload
+/// * `key` - The shared key of the sensor
+/// * `key_id` - The id of the key
+/// * `counter` - Must be higher than the counter of every earlier payload
+/// * `message` - The payload to sign
+/// # Returns
+/// * `String` - The signed payload
+pub fn sign(sensor_id: &str, key: &[u8], key_id: u32, counter: u64, message: &str) -> String {
+    sign_on(Channel::Readings, sensor_id, key, key_id, counter, message)
+}
+
+/// Sign a message with the shared key of a sensor, in the same format as `sign`
+/// but with the subkey of the channel
+/// # Arguments
+/// * `channel` - What the message is for
+/// * `sensor_id` - The id of the sensor sending or receiving the message
+/// * `key` - The shared key of the sensor
+/// * `key_id` - The id of the key
+/// * `counter` - Must be higher than the counter of every earlier message on the channel
+/// * `message` - The message to sign
+pub fn sign_on(
+    channel: Channel,
+    sensor_id: &str,
+    key: &[u8],
+    key_id: u32,
+    counter: u64,
+    message: &str,
+) -> String {
+    let signed = format!("{},{},{}", message, counter, key_id);
+    let mac = mac_for(channel, sensor_id, key, &signed)
+        .finalize()
+        .into_bytes();
+    format!("{},{}", signed, hex::encode(mac))
+}
+
+fn mac_for(channel: Channel, sensor_id: &str, key: &[u8], signed: &str) -> HmacSha256 {
+    let key = derive_key(key, channel.label());
+    let mut mac = HmacSha256::new_from_slice(&key).expect("HMAC accepts keys of any length");
+    mac.update(sensor_id.as_bytes());
+    mac.update(b"\n");
+    mac.update(signed.as_bytes());
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn subkeys_differ_per_label_and_key() {
+        let mac = derive_key(KEY, Channel::Readings.label());
+        assert_eq!(mac, derive_key(KEY, Channel::Readings.label()));
+        assert_ne!(mac, derive_key(KEY, AEAD_LABEL));
+        assert_ne!(mac, derive_key(KEY, Channel::Config.label()));
+        assert_ne!(mac, derive_key(b"another key", Channel::Readings.label()));
+        assert_ne!(&mac[..], KEY);
+    }
+
+    #[test]
+    fn signed_payloads_parse_and_verify() {
+        let payload = sign("sensor-1", KEY, 2, 42, "1700000000,45.5");
+        let signed = SignedPayload::parse(&payload).unwrap();
+        assert_eq!(signed.message, "1700000000,45.5");
+        assert_eq!(signed.counter, 42);
+        assert_eq!(signed.key_id, 2);
+        assert_eq!(signed.verify("sensor-1", KEY), Ok(()));
+    }
+
+    #[test]
+    fn unsigned_and_malformed_payloads_are_rejected() {
+        assert_eq!(
+            SignedPayload::parse("1700000000,45.5"),
+            Err(AuthError::Unsigned)
+        );
+        assert_eq!(SignedPayload::parse("45.5"), Err(AuthError::Unsigned));
+        assert_eq!(
+            SignedPayload::parse("1700000000,45.5,x,1,00"),
+            Err(AuthError::Malformed)
+        );
+        assert_eq!(
+            SignedPayload::parse("1700000000,45.5,1,-1,00"),
+            Err(AuthError::Malformed)
+        );
+        let signed = SignedPayload::parse("1700000000,45.5,1,1,not hex").unwrap();
+        assert_eq!(signed.verify("sensor-1", KEY), Err(AuthError::Malformed));
+    }
+
+    #[test]
+    fn tampered_payloads_are_rejected() {
+        let payload = sign("sensor-1", KEY, 1, 42, "1700000000,45.5");
+        let cases = [
+            payload.replace("45.5", "45.6"),
+            payload.replace(",42,", ",43,"),
+            payload.replace(",1,", ",2,"),
+        ];
+        for tampered in cases {
+            let signed = SignedPayload::parse(&tampered).unwrap();
+            assert_eq!(
+                signed.verify("sensor-1", KEY),
+                Err(AuthError::BadSignature),
+                "{}",
+                tampered
+            );
+        }
+    }
+
+    #[test]
+    fn messages_only_verify_on_their_channel() {
+        let payload = sign_on(Channel::Config, "sensor-1", KEY, 1, 3, r#"{"version":3}"#);
+        let signed = SignedPayload::parse_on(Channel::Config, &payload).unwrap();
+        assert_eq!(signed.message, r#"{"version":3}"#);
+        assert_eq!(signed.verify("sensor-1", KEY), Ok(()));
+        let signed = SignedPayload::parse_on(Channel::ReportedConfig, &payload).unwrap();
+        assert_eq!(signed.verify("sensor-1", KEY), Err(AuthError::BadSignature));
+    }
+
+    #[test]
+    fn keys_file_signs_with_the_newest_key_and_verifies() {
+        let path = std::env::temp_dir().join(format!("keys-file-{}.json", std::process::id()));
+        let keys = KeyStore::from([(
+            "sensor-1".to_string(),
+            vec![
+                SensorKey {
+                    id: 1,
+                    key: hex::encode(b"old key"),
+                },
+                SensorKey {
+                    id: 2,
+                    key: hex::encode(KEY),
+                },
+            ],
+        )]);
+        std::fs::write(&path, serde_json::to_string(&keys).unwrap()).unwrap();
+        let keys_file = KeysFile {
+            path: Some(path.to_string_lossy().into_owned()),
+            required: false,
+        };
+
+        let signed = keys_file
+            .sign(Channel::Config, "sensor-1", 3, "config")
+            .unwrap();
+        assert_eq!(
+            signed,
+            sign_on(Channel::Config, "sensor-1", KEY, 2, 3, "config")
+        );
+        let (message, counter) = keys_file
+            .verify(Channel::Config, "sensor-1", signed.as_bytes())
+            .unwrap();
+        assert_eq!((message, counter), ("config", Some(3)));
+        assert!(keys_file
+            .verify(Channel::Status, "sensor-1", signed.as_bytes())
+            .is_err());
+        assert!(keys_file
+            .verify(Channel::Config, "sensor-1", b"config")
+            .is_err());
+
+        // sensors without a key
+        assert_eq!(
+            keys_file
+                .sign(Channel::Config, "sensor-2", 3, "config")
+                .unwrap(),
+            "config"
+        );
+        assert_eq!(
+            keys_file
+                .verify(Channel::Config, "sensor-2", b"config")
+                .unwrap(),
+            ("config", None)
+        );
+        let required = KeysFile {
+            required: true,
+            ..keys_file
+        };
+        assert!(required
+            .sign(Channel::Config, "sensor-2", 3, "config")
+            .is_err());
+        assert!(required
+            .verify(Channel::Config, "sensor-2", b"config")
+            .is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn payloads_only_verify_for_their_sensor_and_key() {
+        let payload = sign("sensor-1", KEY, 1, 42, "1700000000,45.5");
+        let signed = SignedPayload::parse(&payload).unwrap();
+        assert_eq!(signed.verify("sensor-2", KEY), Err(AuthError::BadSignature));
+        assert_eq!(
+            signed.verify("sensor-1", b"another key"),
+            Err(AuthError::BadSignature)
+        );
+    }
+}
@@ -0,0 +1,199 @@
+use crate::auth::{self, AuthError};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use std::error::Error;
+
+/// First byte of a sealed payload, followed by the version. The byte never appears in
+/// valid UTF-8, so sealed payloads can not be mistaken for csv or json, and it is not
+/// one of the markers of the binary formats.
+pub const MAGIC: u8 = 0xf5;
+/// Envelope version written by `seal`
+pub const VERSION: u8 = 1;
+
+const NONCE_LENGTH: usize = 12;
+/// magic, version, key id, counter and nonce
+const HEADER_LENGTH: usize = 2 + 4 + 8 + NONCE_LENGTH;
+
+/// Returns whether the payload is a sealed envelope
+/// # Arguments
+/// * `payload` - The payload as received
+pub fn is_sealed(payload: &[u8]) -> bool {
+    payload.first() == Some(&MAGIC)
+}
+
+/// Encrypt a payload with the shared key of a sensor
+///
+/// The envelope is `magic (1 byte) | version (1) | key id (4) | counter (8) | nonce (12) | ciphertext`,
+/// with integers in big endian. The payload is encrypted with ChaCha20-Poly1305 under
+/// a random nonce, keyed with the `AEAD_LABEL` subkey of the shared key. The header and the sensor id are authenticated as associated data,
+/// so nobody without the key, including the broker, can read or change the payload,
+/// change the counter or move the payload to the topic of another sensor.
+/// # Arguments
+/// * `sensor_id` - The id of the sensor sending the payload
+/// * `key` - The shared key of the sensor
+/// * `key_id` - The id of the key
+/// * `counter` - Must be higher than the counter of every earlier payload
+/// * `payload` - The payload to encrypt
+/// # Returns
+/// * `Vec<u8>` - The sealed payload
+pub fn seal(
+    sensor_id: &str,
+    key: &[u8],
+    key_id: u32,
+    counter: u64,
+    payload: &[u8],
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let cipher = cipher(key);
+    let nonce: [u8; NONCE_LENGTH] = rand::random();
+
+    let mut envelope = Vec::with_capacity(HEADER_LENGTH + payload.len() + 16);
+    envelope.push(MAGIC);
+    envelope.push(VERSION);
+    envelope.extend_from_slice(&key_id.to_be_bytes());
+    envelope.extend_from_slice(&counter.to_be_bytes());
+    envelope.extend_from_slice(&nonce);
+
+    let aad = associated_data(sensor_id, &envelope);
+    let ciphertext = cipher
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: payload,
+                aad: &aad,
+            },
+        )
+        .map_err(|_| "Encryption failed")?;
+    envelope.extend_from_slice(&ciphertext);
+    Ok(envelope)
+}
+
+/// A sealed payload split into its parts
+#[derive(Debug, PartialEq, Eq)]
+pub struct Sealed<'a> {
+    pub version: u8,
+    pub key_id: u32,
+    pub counter: u64,
+    header: &'a [u8],
+    ciphertext: &'a [u8],
+}
+
+impl<'a> Sealed<'a> {
+    /// Split a sealed payload
+    /// # Arguments
+    /// * `payload` - The payload as received
+    pub fn parse(payload: &'a [u8]) -> Result<Self, AuthError> {
+        if !is_sealed(payload) || payload.len() < 2 {
+            return Err(AuthError::Malformed);
+        }
+        if payload[1] != VERSION {
+            return Err(AuthError::UnsupportedVersion(payload[1]));
+        }
+        if payload.len() < HEADER_LENGTH {
+            return Err(AuthError::Malformed);
+        }
+        let (header, ciphertext) = payload.split_at(HEADER_LENGTH);
+        Ok(Sealed {
+            version: header[1],
+            key_id: u32::from_be_bytes(header[2..6].try_into().expect("Slice is 4 bytes")),
+            counter: u64::from_be_bytes(header[6..14].try_into().expect("Slice is 8 bytes")),
+            header,
+            ciphertext,
+        })
+    }
+
+    /// Decrypt the payload, fails if it was not sealed with the key for this sensor
+    /// # Arguments
+    /// * `sensor_id` - The id of the sensor the payload claims to be from
+    /// * `key` - The key with the id in the envelope
+    pub fn open(&self, sensor_id: &str, key: &[u8]) -> Result<Vec<u8>, AuthError> {
+        let aad = associated_data(sensor_id, self.header);
+        cipher(key)
+            .decrypt(
+                Nonce::from_slice(&self.header[14..]),
+                Payload {
+                    msg: self.ciphertext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| AuthError::DecryptionFailed)
+    }
+}
+
+fn cipher(key: &[u8]) -> ChaCha20Poly1305 {
+    ChaCha20Poly1305::new(&auth::derive_key(key, auth::AEAD_LABEL).into())
+}
+
+fn associated_data(sensor_id: &str, header: &[u8]) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(sensor_id.len() + 1 + header.len());
+    aad.extend_from_slice(sensor_id.as_bytes());
+    aad.push(b'\n');
+    aad.extend_from_slice(header);
+    aad
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn sealed_payloads_open() {
+        let envelope = seal("sensor-1", KEY, 2, 42, b"1700000000,45.5").unwrap();
+        assert!(is_sealed(&envelope));
+        assert!(std::str::from_utf8(&envelope).is_err());
+        let sealed = Sealed::parse(&envelope).unwrap();
+        assert_eq!(sealed.version, VERSION);
+        assert_eq!(sealed.key_id, 2);
+        assert_eq!(sealed.counter, 42);
+        assert_eq!(
+            sealed.open("sensor-1", KEY),
+            Ok(b"1700000000,45.5".to_vec())
+        );
+    }
+
+    #[test]
+    fn tampered_envelopes_do_not_open() {
+        let envelope = seal("sensor-1", KEY, 2, 42, b"1700000000,45.5").unwrap();
+        // the last byte of the ciphertext, and the counter in the header
+        for index in [envelope.len() - 1, 13] {
+            let mut tampered = envelope.clone();
+            tampered[index] ^= 1;
+            let sealed = Sealed::parse(&tampered).unwrap();
+            assert_eq!(
+                sealed.open("sensor-1", KEY),
+                Err(AuthError::DecryptionFailed),
+                "byte {}",
+                index
+            );
+        }
+    }
+
+    #[test]
+    fn envelopes_only_open_for_their_sensor_and_key() {
+        let envelope = seal("sensor-1", KEY, 2, 42, b"1700000000,45.5").unwrap();
+        let sealed = Sealed::parse(&envelope).unwrap();
+        assert_eq!(
+            sealed.open("sensor-2", KEY),
+            Err(AuthError::DecryptionFailed)
+        );
+        assert_eq!(
+            sealed.open("sensor-1", b"another key"),
+            Err(AuthError::DecryptionFailed)
+        );
+    }
+
+    #[test]
+    fn malformed_envelopes_are_rejected() {
+        let envelope = seal("sensor-1", KEY, 2, 42, b"1700000000,45.5").unwrap();
+        assert_eq!(
+            Sealed::parse(&envelope[..HEADER_LENGTH - 1]),
+            Err(AuthError::Malformed)
+        );
+        assert_eq!(Sealed::parse(b"1700000000,45.5"), Err(AuthError::Malformed));
+        assert_eq!(
+            Sealed::parse(&[MAGIC, 9]),
+            Err(AuthError::UnsupportedVersion(9))
+        );
+    }
+}
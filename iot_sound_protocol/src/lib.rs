@@ -0,0 +1,7 @@
+// the messages between sensors and the backend besides the readings, shared by both
+// without the async runtime and the database the backend needs
+pub mod auth;
+pub mod envelope;
+pub mod senml;
+pub mod sensor_config;
+pub mod status;
@@ -0,0 +1,500 @@
+use ciborium::value::Value;
+use iot_sound_wire::scale::{Reference, TimeWeighting, Weighting};
+use iot_sound_wire::{LoudnessData, Scale, Timestamp};
+use serde::de::IgnoredAny;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The decibel of SenML, a level without a frequency weighting
+const DECIBEL: &str = "dB";
+/// Units of loudness readings and their scales, SenML has no time weighting so every one is fast
+///
+/// Units without a weighting are unweighted, a weighted level always names its weighting.
+const DECIBEL_UNITS: &[(&str, Scale)] = &[
+    (DECIBEL, fast(Weighting::Z, Reference::Spl)),
+    ("dBA", fast(Weighting::A, Reference::Spl)),
+    ("dBC", fast(Weighting::C, Reference::Spl)),
+    ("dBZ", fast(Weighting::Z, Reference::Spl)),
+    ("dBFS", fast(Weighting::Z, Reference::Dbfs)),
+    ("dBFSA", fast(Weighting::A, Reference::Dbfs)),
+    ("dBFSC", fast(Weighting::C, Reference::Dbfs)),
+];
+/// Name of the records written by `encode`
+const MEASUREMENT: &str = "loudness";
+/// Newest SenML version understood, the one of RFC 8428
+const VERSION: i64 = 10;
+/// Times below 2**28 seconds are relative to when the pack is received
+const RELATIVE_TIME_LIMIT: f64 = 268_435_456.0;
+/// Labels of the fields in CBOR, which uses integers instead of the JSON names
+const CBOR_LABELS: &[(&str, i8)] = &[
+    ("bver", -1),
+    ("bn", -2),
+    ("bt", -3),
+    ("bu", -4),
+    ("bv", -5),
+    ("bs", -6),
+    ("n", 0),
+    ("u", 1),
+    ("v", 2),
+    ("vs", 3),
+    ("vb", 4),
+    ("s", 5),
+    ("t", 6),
+    ("ut", 7),
+    ("vd", 8),
+];
+
+/// Encoding of a SenML pack (RFC 8428)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Json,
+    Cbor,
+}
+
+impl Encoding {
+    /// Returns the encoding of a payload that is a SenML pack, a JSON or CBOR array
+    ///
+    /// Neither starts like any other payload format.
+    ///
+    /// # Arguments
+    /// * `payload` - The payload as received
+    pub fn detect(payload: &[u8]) -> Option<Self> {
+        match payload.iter().find(|byte| !byte.is_ascii_whitespace())? {
+            b'[' => Some(Encoding::Json),
+            // CBOR major type 4, an array
+            0x80..=0x9f => Some(Encoding::Cbor),
+            _ => None,
+        }
+    }
+
+    /// Returns the name of the encoding as in its media type, e.g. `senml+json`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Json => "senml+json",
+            Encoding::Cbor => "senml+cbor",
+        }
+    }
+}
+
+/// A SenML record, the base fields apply to it and the records after it
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Record {
+    #[serde(rename = "bver", default, skip_serializing_if = "Option::is_none")]
+    pub base_version: Option<i64>,
+    #[serde(rename = "bn", default, skip_serializing_if = "Option::is_none")]
+    pub base_name: Option<String>,
+    #[serde(rename = "bt", default, skip_serializing_if = "Option::is_none")]
+    pub base_time: Option<f64>,
+    #[serde(rename = "bu", default, skip_serializing_if = "Option::is_none")]
+    pub base_unit: Option<String>,
+    #[serde(rename = "bv", default, skip_serializing_if = "Option::is_none")]
+    pub base_value: Option<f64>,
+    #[serde(rename = "n", default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(rename = "u", default, skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+    #[serde(rename = "v", default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<f64>,
+    #[serde(rename = "vs", default, skip_serializing_if = "Option::is_none")]
+    pub string_value: Option<String>,
+    #[serde(rename = "vb", default, skip_serializing_if = "Option::is_none")]
+    pub bool_value: Option<bool>,
+    #[serde(rename = "t", default, skip_serializing_if = "Option::is_none")]
+    pub time: Option<f64>,
+    /// Fields not used by the backend, only their names are kept
+    #[serde(flatten, skip_serializing)]
+    other: BTreeMap<String, IgnoredAny>,
+}
+
+/// The readings in a SenML pack
+#[derive(Debug)]
+pub struct Pack {
+    /// The sensor the records are named after, `None` if their names do not say
+    pub sensor_id: Option<String>,
+    /// The records in decibel, in order
+    pub readings: Vec<LoudnessData>,
+    /// The latest value of every other measurement, by name
+    pub fields: BTreeMap<String, String>,
+}
+
+/// Why a payload is not a SenML pack the backend can use
+#[derive(Debug)]
+pub enum SenmlError {
+    /// The payload is not a SenML pack in JSON or CBOR
+    Malformed(String),
+    /// The pack is in a newer SenML version
+    UnsupportedVersion(i64),
+    /// A record has a field that must be understood, one ending in `_`, which is not
+    MustUnderstand(String),
+    /// A record has no name or its time is out of range
+    BadRecord { name: String, reason: &'static str },
+    /// The records are named after more than one sensor
+    ManySensors(String, String),
+}
+
+impl SenmlError {
+    /// Short name of the error, used as metric label
+    pub fn kind(&self) -> &'static str {
+        match self {
+            SenmlError::Malformed(_) => "senml_malformed",
+            SenmlError::UnsupportedVersion(_) => "unsupported_version",
+            SenmlError::MustUnderstand(_) => "senml_must_understand",
+            SenmlError::BadRecord { .. } => "senml_bad_record",
+            SenmlError::ManySensors(..) => "senml_many_sensors",
+        }
+    }
+}
+
+impl fmt::Display for SenmlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SenmlError::Malformed(e) => write!(f, "not a SenML pack: {}", e),
+            SenmlError::UnsupportedVersion(version) => {
+                write!(f, "unsupported SenML version {}", version)
+            }
+            SenmlError::MustUnderstand(field) => write!(f, "unknown field {:?}", field),
+            SenmlError::BadRecord { name, reason } => write!(f, "record {:?} {}", name, reason),
+            SenmlError::ManySensors(first, second) => {
+                write!(f, "records of sensors {} and {} in one pack", first, second)
+            }
+        }
+    }
+}
+
+impl Error for SenmlError {}
+
+/// Decode a SenML pack in JSON or CBOR
+///
+/// The base fields are resolved: names are the base name followed by the name,
+/// times the base time plus the time and values the base value plus the value.
+/// Records in `dB` are readings, the others are kept as fields.
+///
+/// # Arguments
+/// * `payload` - The payload as received
+/// * `received` - The time the payload was received, for records with relative times
+pub fn decode(payload: &[u8], received: SystemTime) -> Result<Pack, SenmlError> {
+    let malformed = |e: &dyn fmt::Display| SenmlError::Malformed(e.to_string());
+    let records: Vec<Record> = match Encoding::detect(payload) {
+        Some(Encoding::Json) => serde_json::from_slice(payload).map_err(|e| malformed(&e))?,
+        Some(Encoding::Cbor) => {
+            let value: Value = ciborium::de::from_reader(payload).map_err(|e| malformed(&e))?;
+            relabel(value, &|label| match label {
+                Value::Integer(label) => CBOR_LABELS
+                    .iter()
+                    .find(|(_, cbor)| i128::from(*cbor) == i128::from(label))
+                    .map_or_else(
+                        || Value::Text(i128::from(label).to_string()),
+                        |(json, _)| Value::Text(json.to_string()),
+                    ),
+                label => label,
+            })
+            .deserialized()
+            .map_err(|e| malformed(&e))?
+        }
+        None => return Err(malformed(&"expected an array")),
+    };
+    resolve(records, received)
+}
+
+/// Resolve the base fields of the records and sort them into readings and fields
+fn resolve(records: Vec<Record>, received: SystemTime) -> Result<Pack, SenmlError> {
+    let now = received
+        .duration_since(UNIX_EPOCH)
+        .map_or(0.0, |d| d.as_secs_f64());
+    let mut base_name = String::new();
+    let mut base_time = 0.0;
+    let mut base_unit = None;
+    let mut base_value = 0.0;
+    let mut pack = Pack {
+        sensor_id: None,
+        readings: Vec::new(),
+        fields: BTreeMap::new(),
+    };
+
+    for record in records {
+        if let Some(field) = record.other.keys().find(|field| field.ends_with('_')) {
+            return Err(SenmlError::MustUnderstand(field.clone()));
+        }
+        if let Some(version) = record.base_version.filter(|version| *version > VERSION) {
+            return Err(SenmlError::UnsupportedVersion(version));
+        }
+        base_name = record.base_name.unwrap_or(base_name);
+        base_time = record.base_time.unwrap_or(base_time);
+        base_unit = record.base_unit.or(base_unit);
+        base_value = record.base_value.unwrap_or(base_value);
+
+        let name = format!("{}{}", base_name, record.name.unwrap_or_default());
+        let bad_record = |reason| SenmlError::BadRecord {
+            name: name.clone(),
+            reason,
+        };
+        if name.is_empty() {
+            return Err(bad_record("has no name"));
+        }
+        let (sensor_id, measurement) = split_name(&name);
+        if let Some(sensor_id) = sensor_id {
+            match &pack.sensor_id {
+                Some(first) if first != sensor_id => {
+                    return Err(SenmlError::ManySensors(
+                        first.clone(),
+                        sensor_id.to_string(),
+                    ))
+                }
+                _ => pack.sensor_id = Some(sensor_id.to_string()),
+            }
+        }
+
+        let time = base_time + record.time.unwrap_or(0.0);
+        let time = if time < RELATIVE_TIME_LIMIT {
+            now + time
+        } else {
+            time
+        };
+        let unit = record.unit.as_ref().or(base_unit.as_ref());
+        let scale = DECIBEL_UNITS
+            .iter()
+            .find(|(name, _)| Some(*name) == unit.map(String::as_str))
+            .map(|(_, scale)| *scale);
+        let value = match (record.value, record.string_value, record.bool_value, scale) {
+            (Some(value), _, _, Some(scale)) => {
+                let timestamp = (time.is_finite() && time >= 0.0)
+                    .then(|| Timestamp::from_unix_secs(time as u64))
+                    .flatten()
+                    .ok_or_else(|| bad_record("has a time out of range"))?;
+                let db_level = (base_value + value) as f32;
+                pack.readings
+                    .push(LoudnessData::new(db_level, timestamp).with_scale(scale));
+                continue;
+            }
+            (Some(value), _, _, None) => (base_value + value).to_string(),
+            (None, Some(value), _, _) => value,
+            (None, None, Some(value), _) => value.to_string(),
+            (None, None, None, _) => continue,
+        };
+        pack.fields.insert(measurement.to_string(), value);
+    }
+    Ok(pack)
+}
+
+const fn fast(weighting: Weighting, reference: Reference) -> Scale {
+    Scale {
+        weighting,
+        time_weighting: TimeWeighting::Fast,
+        reference,
+    }
+}
+
+/// Returns the unit of readings on a scale, the time weighting is lost
+fn unit(scale: Scale) -> &'static str {
+    match (scale.reference, scale.weighting) {
+        (Reference::Spl, Weighting::A) => "dBA",
+        (Reference::Spl, Weighting::C) => "dBC",
+        (Reference::Spl, Weighting::Z) => DECIBEL,
+        (Reference::Dbfs, Weighting::A) => "dBFSA",
+        (Reference::Dbfs, Weighting::C) => "dBFSC",
+        (Reference::Dbfs, Weighting::Z) => "dBFS",
+    }
+}
+
+/// Split a resolved name into the id of the sensor and the name of the measurement
+///
+/// The sensor id is the last part of the name before the measurement, so
+/// `sensor-1:loudness` and `urn:dev:mac:0024befffe804ff1:loudness` name the sensors
+/// `sensor-1` and `0024befffe804ff1`. A name without `:` or `/` names no sensor.
+fn split_name(name: &str) -> (Option<&str>, &str) {
+    let separator = |c| c == ':' || c == '/';
+    match name.rsplit_once(separator) {
+        Some((device, measurement)) => {
+            let sensor_id = device.rsplit(separator).next().filter(|id| !id.is_empty());
+            (
+                sensor_id,
+                if measurement.is_empty() {
+                    name
+                } else {
+                    measurement
+                },
+            )
+        }
+        None => (None, name),
+    }
+}
+
+/// Encode readings of a sensor as a SenML pack
+///
+/// The first record has the base name `<sensor id>:`, the base time of the first reading
+/// and the base unit of the scale of the readings, `dBA` for the default scale, every reading
+/// is a `loudness` record with its time relative to the base time.
+///
+/// # Arguments
+/// * `encoding` - JSON or CBOR
+/// * `sensor_id` - The id of the sensor
+/// * `readings` - The readings, at least one, all on the same scale
+/// # Returns
+/// * `Vec<u8>` - The pack
+pub fn encode(encoding: Encoding, sensor_id: &str, readings: &[LoudnessData]) -> Vec<u8> {
+    let base_time = readings
+        .first()
+        .map_or(0, |data| data.timestamp().unix_secs());
+    let records: Vec<Record> = readings
+        .iter()
+        .enumerate()
+        .map(|(i, data)| {
+            let first = i == 0;
+            Record {
+                base_name: first.then(|| format!("{}:", sensor_id)),
+                base_time: first.then_some(base_time as f64),
+                base_unit: first.then(|| unit(data.scale()).to_string()),
+                name: Some(MEASUREMENT.to_string()),
+                value: Some(f64::from(data.db_level())),
+                time: Some(data.timestamp().unix_secs() as f64 - base_time as f64)
+                    .filter(|time| *time != 0.0),
+                ..Record::default()
+            }
+        })
+        .collect();
+
+    match encoding {
+        Encoding::Json => serde_json::to_vec(&records).expect("Records can be serialized"),
+        Encoding::Cbor => {
+            let value = Value::serialized(&records).expect("Records can be serialized");
+            let value = relabel(value, &|label| match label {
+                Value::Text(label) => CBOR_LABELS
+                    .iter()
+                    .find(|(json, _)| *json == label)
+                    .map_or(Value::Text(label), |(_, cbor)| {
+                        Value::Integer((*cbor).into())
+                    }),
+                label => label,
+            });
+            let mut payload = Vec::new();
+            ciborium::ser::into_writer(&value, &mut payload).expect("Writing to a Vec succeeds");
+            payload
+        }
+    }
+}
+
+/// Replace the labels of the fields of every record in a CBOR pack
+fn relabel(pack: Value, label: &impl Fn(Value) -> Value) -> Value {
+    match pack {
+        Value::Array(records) => Value::Array(
+            records
+                .into_iter()
+                .map(|record| match record {
+                    Value::Map(fields) => Value::Map(
+                        fields
+                            .into_iter()
+                            .map(|(name, value)| (label(name), value))
+                            .collect(),
+                    ),
+                    record => record,
+                })
+                .collect(),
+        ),
+        pack => pack,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn reading(db_level: f32, secs: u64) -> LoudnessData {
+        LoudnessData::new(db_level, Timestamp::from_unix_secs(secs).unwrap())
+    }
+
+    #[test]
+    fn packs_round_trip() {
+        let readings = [reading(40.5, 1669026612), reading(41.25, 1669026614)];
+        for encoding in [Encoding::Json, Encoding::Cbor] {
+            let payload = encode(encoding, "sensor-1", &readings);
+            assert_eq!(Encoding::detect(&payload), Some(encoding));
+            let pack = decode(&payload, SystemTime::now()).unwrap();
+            assert_eq!(pack.sensor_id.as_deref(), Some("sensor-1"));
+            assert_eq!(pack.readings, readings);
+        }
+    }
+
+    #[test]
+    fn base_fields_are_resolved() {
+        let payload = br#"[
+            {"bn":"urn:dev:mac:0024befffe804ff1:","bt":1669026600,"bu":"dBA","bv":40,"n":"loudness","v":1.5},
+            {"n":"loudness","t":10,"v":2.5},
+            {"bv":0,"n":"battery","u":"V","v":3.7},
+            {"n":"state","vs":"ok"}
+        ]"#;
+        let pack = decode(payload, SystemTime::now()).unwrap();
+        assert_eq!(pack.sensor_id.as_deref(), Some("0024befffe804ff1"));
+        assert_eq!(
+            pack.readings,
+            [reading(41.5, 1669026600), reading(42.5, 1669026610)]
+        );
+        assert_eq!(pack.fields["battery"], "3.7");
+        assert_eq!(pack.fields["state"], "ok");
+    }
+
+    #[test]
+    fn the_unit_is_the_scale() {
+        let cases = [
+            ("dB", Weighting::Z, Reference::Spl),
+            ("dBA", Weighting::A, Reference::Spl),
+            ("dBC", Weighting::C, Reference::Spl),
+            ("dBZ", Weighting::Z, Reference::Spl),
+            ("dBFS", Weighting::Z, Reference::Dbfs),
+            ("dBFSA", Weighting::A, Reference::Dbfs),
+            ("dBFSC", Weighting::C, Reference::Dbfs),
+        ];
+        for (unit, weighting, reference) in cases {
+            let payload = format!(r#"[{{"n":"a:loudness","u":"{}","v":-30}}]"#, unit);
+            let pack = decode(payload.as_bytes(), SystemTime::now()).unwrap();
+            assert_eq!(
+                pack.readings[0].scale(),
+                fast(weighting, reference),
+                "{}",
+                unit
+            );
+
+            let data = reading(-30.0, 1669026612).with_scale(fast(weighting, reference));
+            let pack = decode(&encode(Encoding::Json, "a", &[data]), SystemTime::now()).unwrap();
+            assert_eq!(pack.readings, [data], "{}", unit);
+        }
+    }
+
+    #[test]
+    fn relative_times_are_from_when_the_pack_was_received() {
+        let received = UNIX_EPOCH + Duration::from_secs(1669026612);
+        let payload = br#"[{"n":"loudness","u":"dBA","v":50,"t":-2}]"#;
+        let pack = decode(payload, received).unwrap();
+        assert_eq!(pack.sensor_id, None);
+        assert_eq!(pack.readings, [reading(50.0, 1669026610)]);
+    }
+
+    #[test]
+    fn invalid_packs_are_rejected() {
+        let now = SystemTime::now();
+        for (payload, kind) in [
+            (
+                &br#"[{"n":"a:loudness","u":"dB","v":1"#[..],
+                "senml_malformed",
+            ),
+            (
+                br#"[{"bver":11,"n":"loudness","u":"dB","v":1}]"#,
+                "unsupported_version",
+            ),
+            (
+                br#"[{"n":"loudness","u":"dB","v":1,"x_":1}]"#,
+                "senml_must_understand",
+            ),
+            (br#"[{"u":"dB","v":1}]"#, "senml_bad_record"),
+            (
+                br#"[{"n":"a:loudness","u":"dB","v":1},{"n":"b:loudness","u":"dB","v":1}]"#,
+                "senml_many_sensors",
+            ),
+        ] {
+            assert_eq!(decode(payload, now).unwrap_err().kind(), kind);
+        }
+    }
+}
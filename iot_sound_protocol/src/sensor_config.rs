@@ -0,0 +1,109 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Suffix of the topic a sensor receives its config on, after the topic it publishes readings to
+const CONFIG_SUFFIX: &str = "/config";
+/// Suffix of the topic a sensor reports the config it applied on
+const REPORTED_SUFFIX: &str = "/config/reported";
+
+/// How a sensor decides which samples to publish
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportingMode {
+    /// Every sample is published
+    Periodic,
+    /// A sample is published when it differs from the last published one by `change_threshold_db` or more
+    OnChange,
+    /// A sample is published when it is at or above `alert_threshold_db`
+    Threshold,
+}
+
+/// Configuration a sensor applies at runtime, sent to it by the backend
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SensorConfig {
+    /// Increased every time the desired config is changed, 0 for the defaults of the sensor
+    #[serde(default)]
+    pub version: i32,
+    pub sample_interval_secs: u64,
+    pub reporting_mode: ReportingMode,
+    pub change_threshold_db: f32,
+    pub alert_threshold_db: f32,
+    /// Longest time without publishing, so the sensor is not taken for offline
+    /// while its level does not change or stays below the threshold
+    pub max_report_interval_secs: u64,
+}
+
+impl Default for SensorConfig {
+    fn default() -> Self {
+        SensorConfig {
+            version: 0,
+            sample_interval_secs: 2,
+            reporting_mode: ReportingMode::Periodic,
+            change_threshold_db: 3.0,
+            alert_threshold_db: 70.0,
+            max_report_interval_secs: 60,
+        }
+    }
+}
+
+impl SensorConfig {
+    /// Longest interval between samples or reports a sensor accepts, one hour,
+    /// so a config can not silence a sensor for good
+    pub const MAX_INTERVAL_SECS: u64 = 3600;
+
+    /// Check that a sensor can apply the config
+    /// # Returns
+    /// `Result<(), String>` - What is wrong with the config
+    pub fn validate(&self) -> Result<(), String> {
+        if self.sample_interval_secs == 0 {
+            return Err("sample_interval_secs must be at least 1".to_string());
+        }
+        if self.max_report_interval_secs < self.sample_interval_secs {
+            return Err(
+                "max_report_interval_secs must be at least sample_interval_secs".to_string(),
+            );
+        }
+        if self.max_report_interval_secs > Self::MAX_INTERVAL_SECS {
+            return Err(format!(
+                "max_report_interval_secs must be at most {}",
+                Self::MAX_INTERVAL_SECS
+            ));
+        }
+        if !self.change_threshold_db.is_finite() || self.change_threshold_db < 0.0 {
+            return Err("change_threshold_db must be a positive number".to_string());
+        }
+        if !self.alert_threshold_db.is_finite() {
+            return Err("alert_threshold_db must be a number".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Returns the retained topic the desired config of a sensor is published to
+/// # Arguments
+/// * `sensor_topic` - The topic the sensor publishes readings to
+pub fn config_topic(sensor_topic: &str) -> String {
+    format!("{}{}", sensor_topic, CONFIG_SUFFIX)
+}
+
+/// Returns the retained topic a sensor reports the config it applied on
+/// # Arguments
+/// * `sensor_topic` - The topic the sensor publishes readings to
+pub fn reported_topic(sensor_topic: &str) -> String {
+    format!("{}{}", sensor_topic, REPORTED_SUFFIX)
+}
+
+/// Returns the topic the sensor publishes readings to, if the topic is a reported config topic
+/// # Arguments
+/// * `topic` - The topic a message was received on
+pub fn sensor_topic_of_report(topic: &str) -> Option<&str> {
+    topic.strip_suffix(REPORTED_SUFFIX)
+}
+
+/// Returns a short hash of a config, so a sensor can tell which config it runs with in few bytes
+/// # Arguments
+/// * `config` - The config to hash
+pub fn config_hash(config: &SensorConfig) -> String {
+    let json = serde_json::to_vec(config).expect("SensorConfig can be serialized");
+    hex::encode(&Sha256::digest(json)[..8])
+}
@@ -0,0 +1,74 @@
+use serde::{Deserialize, Serialize};
+
+/// Suffix of the topic a sensor announces its status on, after the topic it publishes readings to
+const STATUS_SUFFIX: &str = "/status";
+
+/// Whether a sensor is connected to the broker
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Connection {
+    Online,
+    Offline,
+}
+
+impl Connection {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Connection::Online => "online",
+            Connection::Offline => "offline",
+        }
+    }
+}
+
+/// Retained message on the status topic of a sensor
+///
+/// The sensor publishes it as `online`, with its details, when it connects. The broker
+/// publishes it as `offline`, without details, as the last will of the sensor when the
+/// connection is lost without the sensor disconnecting.
+///
+/// Every connection of the sensor has its own session, a new last will is registered
+/// with it on each connect. A last will is only taken if its session is the one of the
+/// last `online` status, so a last will of an older connection can not mark a sensor
+/// offline that connected again.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatusMessage {
+    pub state: Connection,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub firmware_version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uptime_secs: Option<u64>,
+    /// See `sensor_config::config_hash`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config_hash: Option<String>,
+}
+
+impl StatusMessage {
+    /// Returns the last will of a sensor
+    /// # Arguments
+    /// * `session` - The session of the connection the last will is registered for
+    pub fn offline(session: u64) -> Self {
+        StatusMessage {
+            state: Connection::Offline,
+            session: Some(session),
+            firmware_version: None,
+            uptime_secs: None,
+            config_hash: None,
+        }
+    }
+}
+
+/// Returns the retained topic a sensor announces its status on
+/// # Arguments
+/// * `sensor_topic` - The topic the sensor publishes readings to
+pub fn status_topic(sensor_topic: &str) -> String {
+    format!("{}{}", sensor_topic, STATUS_SUFFIX)
+}
+
+/// Returns the topic the sensor publishes readings to, if the topic is a status topic
+/// # Arguments
+/// * `topic` - The topic a message was received on
+pub fn sensor_topic_of_status(topic: &str) -> Option<&str> {
+    topic.strip_suffix(STATUS_SUFFIX)
+}
@@ -0,0 +1,317 @@
+use crate::dsp::{SoundLevelMeter, MIN_SAMPLE_RATE};
+use crate::Sample;
+use iot_sound_wire::scale::{TimeWeighting, Weighting};
+use iot_sound_wire::{LoudnessData, Timestamp};
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tracing::{debug, error, info};
+
+/// Samples of an audio input, full scale is ±1.0
+type Samples = Box<dyn Iterator<Item = io::Result<f32>> + Send>;
+
+/// Where the sensor reads audio from
+#[derive(Debug, Clone)]
+pub enum AudioInput {
+    /// A WAV file, played back in real time
+    Wav(String),
+    /// Raw signed 16 bit little endian PCM on stdin, e.g. from `arecord -f S16_LE`
+    Stdin { sample_rate: u32, channels: u16 },
+}
+
+/// How the sensor measures the level of its audio input
+#[derive(Debug, Clone)]
+pub struct AudioConfig {
+    pub input: AudioInput,
+    /// Length of the window every reading is measured over
+    pub window: Duration,
+    /// Frequency weighting of the readings
+    pub weighting: Weighting,
+    /// Time weighting of the readings, the Leq over the window or the level at its end
+    pub time_weighting: TimeWeighting,
+    /// Level in dB SPL of a signal at 0 dBFS, the levels are in dBFS if not set
+    pub calibration_db: Option<f32>,
+}
+
+/// Opens the audio input and measures it on its own thread
+///
+/// A WAV file is played back in real time, so the readings are spaced as if the
+/// sound was measured live. Frames with several channels are mixed down to one.
+///
+/// # Arguments
+/// * `config` - The input and how to measure it
+/// # Returns
+/// * `Receiver<Sample>` - The level of every window with its Lmax and Lmin, closed when
+///   the input ends
+pub fn spawn(config: AudioConfig) -> Result<Receiver<Sample>, Box<dyn Error>> {
+    let (samples, sample_rate, channels, real_time): (Samples, u32, u16, bool) = match &config.input
+    {
+        AudioInput::Wav(path) => {
+            let reader = hound::WavReader::open(path)?;
+            let spec = reader.spec();
+            info!(
+                "Reading {} at {} Hz, {} channels, {} bit",
+                path, spec.sample_rate, spec.channels, spec.bits_per_sample
+            );
+            (wav_samples(reader), spec.sample_rate, spec.channels, true)
+        }
+        AudioInput::Stdin {
+            sample_rate,
+            channels,
+        } => (stdin_samples(), *sample_rate, *channels, false),
+    };
+    if sample_rate < MIN_SAMPLE_RATE {
+        return Err(format!("sample rate must be at least {} Hz", MIN_SAMPLE_RATE).into());
+    }
+    let meter = SoundLevelMeter::new(
+        config.weighting,
+        config.time_weighting,
+        sample_rate,
+        config.window,
+        config.calibration_db,
+    );
+    let (tx, rx) = channel(100);
+    std::thread::Builder::new()
+        .name("audio-input".to_string())
+        .spawn(move || {
+            let pace = real_time.then_some(sample_rate);
+            match measure(samples, channels, meter, pace, &tx) {
+                Ok(()) => info!("Audio input ended"),
+                Err(e) => error!("Failed to read audio input: {}", e),
+            }
+        })?;
+    Ok(rx)
+}
+
+/// Returns the samples of a WAV file in any integer or float sample format
+fn wav_samples(reader: hound::WavReader<BufReader<File>>) -> Samples {
+    let spec = reader.spec();
+    let invalid = |e| io::Error::new(io::ErrorKind::InvalidData, e);
+    match spec.sample_format {
+        hound::SampleFormat::Float => Box::new(
+            reader
+                .into_samples::<f32>()
+                .map(move |s| s.map_err(invalid)),
+        ),
+        hound::SampleFormat::Int => {
+            let full_scale = (1u64 << (spec.bits_per_sample - 1)) as f32;
+            Box::new(
+                reader
+                    .into_samples::<i32>()
+                    .map(move |s| s.map(|s| s as f32 / full_scale).map_err(invalid)),
+            )
+        }
+    }
+}
+
+/// Returns the samples of raw signed 16 bit little endian PCM on stdin
+fn stdin_samples() -> Samples {
+    pcm_samples(io::stdin())
+}
+
+/// Returns the samples of raw signed 16 bit little endian PCM, until the input ends
+///
+/// A sample cut off by the end of the input is dropped.
+fn pcm_samples(input: impl Read + Send + 'static) -> Samples {
+    let mut input = BufReader::new(input);
+    Box::new(std::iter::from_fn(move || {
+        let mut sample = [0; 2];
+        match input.read_exact(&mut sample) {
+            Ok(()) => Some(Ok(f32::from(i16::from_le_bytes(sample)) / 32768.0)),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => None,
+            Err(e) => Some(Err(e)),
+        }
+    }))
+}
+
+/// Measures the samples until they end or the receiver is dropped
+///
+/// * `samples` - The samples, `channels` per frame
+/// * `channels` - Number of channels
+/// * `meter` - Measures the levels
+/// * `pace` - The sample rate to play the samples back at, as fast as they come if not set
+/// * `tx` - Where to send the levels
+fn measure(
+    mut samples: Samples,
+    channels: u16,
+    mut meter: SoundLevelMeter,
+    pace: Option<u32>,
+    tx: &Sender<Sample>,
+) -> io::Result<()> {
+    let start = Instant::now();
+    let mut frames = 0u64;
+    loop {
+        let mut sum = 0.0;
+        for _ in 0..channels {
+            match samples.next() {
+                Some(sample) => sum += sample?,
+                None => return Ok(()),
+            }
+        }
+        frames += 1;
+        if let Some(levels) = meter.push(sum / f32::from(channels)) {
+            if let Some(sample_rate) = pace {
+                let played = Duration::from_secs_f64(frames as f64 / f64::from(sample_rate));
+                if let Some(ahead) = played.checked_sub(start.elapsed()) {
+                    std::thread::sleep(ahead);
+                }
+            }
+            debug!(
+                "Leq {:.1} dB, max {:.1} dB, min {:.1} dB",
+                levels.leq, levels.max, levels.min
+            );
+            let scale = meter.scale();
+            let level = levels.level(scale.time_weighting);
+            let sample = Sample {
+                data: LoudnessData::new(level, Timestamp::now()).with_scale(scale),
+                lmax: Some(levels.max),
+                lmin: Some(levels.min),
+            };
+            if tx.blocking_send(sample).is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iot_sound_wire::scale::Reference;
+    use iot_sound_wire::Scale;
+    use std::f64::consts::PI;
+    use std::io::Cursor;
+
+    const SAMPLE_RATE: u32 = 48_000;
+
+    /// Samples of a full scale 1 kHz sine, `seconds` long
+    fn tone(seconds: f64) -> Vec<f32> {
+        let len = (seconds * f64::from(SAMPLE_RATE)) as usize;
+        (0..len)
+            .map(|n| (2.0 * PI * 1000.0 * n as f64 / f64::from(SAMPLE_RATE)).sin() as f32)
+            .collect()
+    }
+
+    fn meter(time_weighting: TimeWeighting) -> SoundLevelMeter {
+        SoundLevelMeter::new(
+            Weighting::Z,
+            time_weighting,
+            SAMPLE_RATE,
+            Duration::from_secs(1),
+            None,
+        )
+    }
+
+    /// Measures interleaved frames as fast as they come, returns every reading
+    fn measure_frames(frames: Vec<f32>, channels: u16, meter: SoundLevelMeter) -> Vec<Sample> {
+        let (tx, mut rx) = channel(100);
+        let samples: Samples = Box::new(frames.into_iter().map(Ok));
+        measure(samples, channels, meter, None, &tx).unwrap();
+        drop(tx);
+        std::iter::from_fn(|| rx.blocking_recv()).collect()
+    }
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() <= 0.05,
+            "{} is not close to {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn the_meter_reports_a_reading_per_window() {
+        let readings = measure_frames(tone(2.5), 1, meter(TimeWeighting::Leq));
+        // the last half window is not reported
+        assert_eq!(readings.len(), 2);
+        for sample in &readings {
+            assert_close(sample.data.db_level(), -3.01);
+            assert_eq!(
+                sample.data.scale(),
+                Scale {
+                    weighting: Weighting::Z,
+                    time_weighting: TimeWeighting::Leq,
+                    reference: Reference::Dbfs,
+                }
+            );
+        }
+
+        // Lmax and Lmin of the window, the tone is steady once the detector has settled
+        let levels = (readings[1].lmax.unwrap(), readings[1].lmin.unwrap());
+        assert_close(levels.0, -3.01);
+        assert_close(levels.1, -3.01);
+
+        // the fast level at the end of the window
+        let fast = measure_frames(tone(1.0), 1, meter(TimeWeighting::Fast));
+        assert_close(fast[0].data.db_level(), -3.01);
+        assert_eq!(fast[0].data.scale().time_weighting, TimeWeighting::Fast);
+    }
+
+    #[test]
+    fn channels_are_mixed_down() {
+        // the same sine on both channels reads like the sine alone
+        let stereo: Vec<f32> = tone(1.0).into_iter().flat_map(|s| [s, s]).collect();
+        let readings = measure_frames(stereo, 2, meter(TimeWeighting::Leq));
+        assert_eq!(readings.len(), 1);
+        assert_close(readings[0].data.db_level(), -3.01);
+
+        // a sine on one channel is halved, 6 dB lower
+        let left: Vec<f32> = tone(1.0).into_iter().flat_map(|s| [s, 0.0]).collect();
+        let readings = measure_frames(left, 2, meter(TimeWeighting::Leq));
+        assert_close(readings[0].data.db_level(), -3.01 - 6.02);
+
+        // opposite phases cancel out
+        let opposite: Vec<f32> = tone(1.0).into_iter().flat_map(|s| [s, -s]).collect();
+        let readings = measure_frames(opposite, 2, meter(TimeWeighting::Leq));
+        assert_eq!(readings[0].data.db_level(), -120.0);
+    }
+
+    #[test]
+    fn an_incomplete_frame_ends_the_input() {
+        let mut frames = tone(1.0)
+            .into_iter()
+            .flat_map(|s| [s, s])
+            .collect::<Vec<_>>();
+        frames.push(1.0);
+        assert_eq!(
+            measure_frames(frames, 2, meter(TimeWeighting::Leq)).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn pcm_ends_at_the_end_of_the_input() {
+        let pcm: Vec<u8> = [i16::MAX, i16::MIN, 0, -16384]
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes())
+            .collect();
+        let samples: Vec<f32> = pcm_samples(Cursor::new(pcm.clone()))
+            .collect::<io::Result<_>>()
+            .unwrap();
+        assert_eq!(samples, [32767.0 / 32768.0, -1.0, 0.0, -0.5]);
+
+        // a sample cut off by the end of the input is dropped
+        let cut = pcm[..7].to_vec();
+        let samples: Vec<f32> = pcm_samples(Cursor::new(cut))
+            .collect::<io::Result<_>>()
+            .unwrap();
+        assert_eq!(samples, [32767.0 / 32768.0, -1.0, 0.0]);
+        assert_eq!(pcm_samples(Cursor::new(Vec::new())).count(), 0);
+    }
+
+    #[test]
+    fn pcm_read_errors_are_returned() {
+        struct Failing;
+        impl Read for Failing {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed"))
+            }
+        }
+        let mut samples = pcm_samples(Failing);
+        let error = samples.next().unwrap().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::BrokenPipe);
+    }
+}
@@ -0,0 +1,454 @@
+use iot_sound_wire::scale::{Reference, TimeWeighting, Weighting};
+use iot_sound_wire::Scale;
+use std::f64::consts::PI;
+use std::time::Duration;
+
+/// Level of a signal without any energy, as 10 * log10 of zero is minus infinity
+const SILENCE_DBFS: f64 = -120.0;
+
+/// Lowest sample rate the weighting filters work at, 1 kHz has to be below the Nyquist frequency
+pub const MIN_SAMPLE_RATE: u32 = 8_000;
+
+/// Pole frequencies of the A- and C-weighting in Hz, from IEC 61672-1
+const POLE_1: f64 = 20.598_997;
+const POLE_2: f64 = 107.652_65;
+const POLE_3: f64 = 737.862_23;
+const POLE_4: f64 = 12_194.217;
+
+/// Frequency the weightings are normalized to 0 dB at
+const REFERENCE_FREQUENCY: f64 = 1_000.0;
+
+/// Time constants of the fast and slow exponential time weighting
+const FAST: Duration = Duration::from_millis(125);
+const SLOW: Duration = Duration::from_secs(1);
+
+/// Second order IIR section in transposed direct form II
+#[derive(Debug, Clone)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    state: [f64; 2],
+}
+
+impl Biquad {
+    /// Create the digital section of an analog section with the bilinear transform
+    /// # Arguments
+    /// * `numerator` - Coefficients of s², s and 1 of the analog numerator
+    /// * `denominator` - Coefficients of s², s and 1 of the analog denominator
+    /// * `sample_rate` - Samples per second
+    fn bilinear(numerator: [f64; 3], denominator: [f64; 3], sample_rate: f64) -> Self {
+        let k = 2.0 * sample_rate;
+        let transform = |[s2, s1, s0]: [f64; 3]| {
+            [
+                s2 * k * k + s1 * k + s0,
+                2.0 * (s0 - s2 * k * k),
+                s2 * k * k - s1 * k + s0,
+            ]
+        };
+        let b = transform(numerator);
+        let a = transform(denominator);
+        Biquad {
+            b: [b[0] / a[0], b[1] / a[0], b[2] / a[0]],
+            a: [a[1] / a[0], a[2] / a[0]],
+            state: [0.0; 2],
+        }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.state[0];
+        self.state[0] = self.b[1] * x - self.a[0] * y + self.state[1];
+        self.state[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+
+    /// Returns the gain of the section at a frequency
+    /// # Arguments
+    /// * `omega` - The frequency in radians per sample
+    fn gain(&self, omega: f64) -> f64 {
+        let magnitude = |[c0, c1, c2]: [f64; 3]| {
+            let re = c0 + c1 * omega.cos() + c2 * (2.0 * omega).cos();
+            let im = c1 * omega.sin() + c2 * (2.0 * omega).sin();
+            re.hypot(im)
+        };
+        magnitude(self.b) / magnitude([1.0, self.a[0], self.a[1]])
+    }
+}
+
+/// A-, C- or Z-weighting filter
+///
+/// The analog filters of IEC 61672-1 are mapped to the sample rate with the
+/// bilinear transform and normalized to 0 dB at 1 kHz. The transform compresses
+/// the frequencies towards the Nyquist frequency, so at 48 kHz the response is
+/// within a few tenths of a dB up to about 5 kHz and falls off too fast above.
+#[derive(Debug, Clone)]
+pub struct WeightingFilter {
+    sections: Vec<Biquad>,
+    gain: f64,
+}
+
+impl WeightingFilter {
+    /// Create a new WeightingFilter
+    /// # Arguments
+    /// * `weighting` - The frequency weighting
+    /// * `sample_rate` - Samples per second, at least `MIN_SAMPLE_RATE`
+    pub fn new(weighting: Weighting, sample_rate: u32) -> Self {
+        let fs = f64::from(sample_rate);
+        let w = |f: f64| 2.0 * PI * f;
+        // s² / (s + ω)² for the double poles at 20.6 Hz, 1 / (s + ω)² for those at 12.2 kHz
+        let high_pass = Biquad::bilinear(
+            [1.0, 0.0, 0.0],
+            [1.0, 2.0 * w(POLE_1), w(POLE_1) * w(POLE_1)],
+            fs,
+        );
+        let low_pass = Biquad::bilinear(
+            [0.0, 0.0, 1.0],
+            [1.0, 2.0 * w(POLE_4), w(POLE_4) * w(POLE_4)],
+            fs,
+        );
+        let sections = match weighting {
+            Weighting::A => vec![
+                high_pass,
+                Biquad::bilinear(
+                    [1.0, 0.0, 0.0],
+                    [1.0, w(POLE_2) + w(POLE_3), w(POLE_2) * w(POLE_3)],
+                    fs,
+                ),
+                low_pass,
+            ],
+            Weighting::C => vec![high_pass, low_pass],
+            Weighting::Z => Vec::new(),
+        };
+        let omega = w(REFERENCE_FREQUENCY) / fs;
+        let gain = 1.0 / sections.iter().map(|s| s.gain(omega)).product::<f64>();
+        WeightingFilter { sections, gain }
+    }
+
+    /// Filter a sample
+    pub fn process(&mut self, sample: f64) -> f64 {
+        self.sections
+            .iter_mut()
+            .fold(sample * self.gain, |x, section| section.process(x))
+    }
+}
+
+/// Exponential average of the squared samples, the fast or slow time weighting
+#[derive(Debug, Clone)]
+pub struct ExponentialAverage {
+    factor: f64,
+    /// Not set until the first sample
+    mean_square: Option<f64>,
+}
+
+impl ExponentialAverage {
+    /// Create a new ExponentialAverage, starting from the first squared sample,
+    /// so the level does not rise from silence when the input starts
+    /// # Arguments
+    /// * `time_constant` - 125 ms for fast, 1 s for slow
+    /// * `sample_rate` - Samples per second
+    pub fn new(time_constant: Duration, sample_rate: u32) -> Self {
+        ExponentialAverage {
+            factor: 1.0 - (-1.0 / (time_constant.as_secs_f64() * f64::from(sample_rate))).exp(),
+            mean_square: None,
+        }
+    }
+
+    /// Add a squared sample, returns the mean square after it
+    pub fn push(&mut self, square: f64) -> f64 {
+        let mean_square = match self.mean_square {
+            Some(mean_square) => mean_square + (square - mean_square) * self.factor,
+            None => square,
+        };
+        self.mean_square = Some(mean_square);
+        mean_square
+    }
+}
+
+/// Levels of one reporting interval in dB
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Levels {
+    /// Energy average over the interval
+    pub leq: f32,
+    /// Highest time weighted level during the interval
+    pub max: f32,
+    /// Lowest time weighted level during the interval
+    pub min: f32,
+    /// Time weighted level at the end of the interval
+    pub last: f32,
+}
+
+impl Levels {
+    /// Returns the level reported for a time weighting, the Leq or the time weighted level
+    pub fn level(&self, time_weighting: TimeWeighting) -> f32 {
+        match time_weighting {
+            TimeWeighting::Leq => self.leq,
+            TimeWeighting::Fast | TimeWeighting::Slow => self.last,
+        }
+    }
+}
+
+/// Sound level meter, measures weighted levels over reporting intervals
+///
+/// Every sample is weighted and squared, then summed for the Leq and averaged
+/// exponentially for the time weighted levels. The maximum and minimum follow the
+/// slow time weighting if it is selected and the fast one otherwise. The levels are
+/// in dBFS, where a square wave at full scale is 0 dBFS and a sine at full scale
+/// -3.01 dBFS, plus the calibration.
+#[derive(Debug, Clone)]
+pub struct SoundLevelMeter {
+    filter: WeightingFilter,
+    detector: ExponentialAverage,
+    scale: Scale,
+    calibration_db: f32,
+    interval_len: usize,
+    count: usize,
+    sum_of_squares: f64,
+    max: f64,
+    min: f64,
+}
+
+impl SoundLevelMeter {
+    /// Create a new SoundLevelMeter
+    /// # Arguments
+    /// * `weighting` - The frequency weighting
+    /// * `time_weighting` - The time weighting of the reported level
+    /// * `sample_rate` - Samples per second, at least `MIN_SAMPLE_RATE`
+    /// * `interval` - Length of the reporting interval
+    /// * `calibration_db` - Level in dB SPL of a signal at 0 dBFS, the levels are in dBFS if not set
+    pub fn new(
+        weighting: Weighting,
+        time_weighting: TimeWeighting,
+        sample_rate: u32,
+        interval: Duration,
+        calibration_db: Option<f32>,
+    ) -> Self {
+        let time_constant = match time_weighting {
+            TimeWeighting::Slow => SLOW,
+            TimeWeighting::Fast | TimeWeighting::Leq => FAST,
+        };
+        SoundLevelMeter {
+            filter: WeightingFilter::new(weighting, sample_rate),
+            detector: ExponentialAverage::new(time_constant, sample_rate),
+            scale: Scale {
+                weighting,
+                time_weighting,
+                reference: match calibration_db {
+                    Some(_) => Reference::Spl,
+                    None => Reference::Dbfs,
+                },
+            },
+            calibration_db: calibration_db.unwrap_or(0.0),
+            interval_len: ((f64::from(sample_rate) * interval.as_secs_f64()) as usize).max(1),
+            count: 0,
+            sum_of_squares: 0.0,
+            max: 0.0,
+            min: f64::INFINITY,
+        }
+    }
+
+    /// Returns the scale of the reported levels
+    pub fn scale(&self) -> Scale {
+        self.scale
+    }
+
+    /// Add a sample, returns the levels of the interval when the sample completes it
+    /// # Arguments
+    /// * `sample` - The sample, full scale is ±1.0
+    pub fn push(&mut self, sample: f32) -> Option<Levels> {
+        let weighted = self.filter.process(f64::from(sample));
+        let square = weighted * weighted;
+        let mean_square = self.detector.push(square);
+        self.sum_of_squares += square;
+        self.max = self.max.max(mean_square);
+        self.min = self.min.min(mean_square);
+        self.count += 1;
+        if self.count < self.interval_len {
+            return None;
+        }
+        let levels = Levels {
+            leq: self.decibels(self.sum_of_squares / self.count as f64),
+            max: self.decibels(self.max),
+            min: self.decibels(self.min),
+            last: self.decibels(mean_square),
+        };
+        self.count = 0;
+        self.sum_of_squares = 0.0;
+        self.max = 0.0;
+        self.min = f64::INFINITY;
+        Some(levels)
+    }
+
+    /// Returns a mean square as a calibrated level
+    fn decibels(&self, mean_square: f64) -> f32 {
+        (10.0 * mean_square.log10()).max(SILENCE_DBFS) as f32 + self.calibration_db
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48_000;
+
+    /// Samples of a sine, `seconds` long
+    fn tone(frequency: f64, amplitude: f64, seconds: f64) -> Vec<f32> {
+        let len = (seconds * f64::from(SAMPLE_RATE)) as usize;
+        (0..len)
+            .map(|n| {
+                let t = n as f64 / f64::from(SAMPLE_RATE);
+                (amplitude * (2.0 * PI * frequency * t).sin()) as f32
+            })
+            .collect()
+    }
+
+    fn silence(seconds: f64) -> Vec<f32> {
+        vec![0.0; (seconds * f64::from(SAMPLE_RATE)) as usize]
+    }
+
+    /// Levels of every interval of the samples
+    fn measure(meter: &mut SoundLevelMeter, samples: &[f32]) -> Vec<Levels> {
+        samples.iter().filter_map(|&s| meter.push(s)).collect()
+    }
+
+    fn meter(weighting: Weighting, time_weighting: TimeWeighting) -> SoundLevelMeter {
+        SoundLevelMeter::new(
+            weighting,
+            time_weighting,
+            SAMPLE_RATE,
+            Duration::from_secs(1),
+            None,
+        )
+    }
+
+    /// Leq of a full scale sine after the filter has settled
+    fn weighted_leq(weighting: Weighting, frequency: f64) -> f32 {
+        let levels = measure(
+            &mut meter(weighting, TimeWeighting::Leq),
+            &tone(frequency, 1.0, 2.0),
+        );
+        levels[1].leq
+    }
+
+    fn assert_close(actual: f32, expected: f32, tolerance: f32) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{} is not within {} of {}",
+            actual,
+            tolerance,
+            expected
+        );
+    }
+
+    #[test]
+    fn z_weighting_measures_the_rms() {
+        assert_close(weighted_leq(Weighting::Z, 1000.0), -3.01, 0.01);
+        let levels = measure(
+            &mut meter(Weighting::Z, TimeWeighting::Leq),
+            &tone(250.0, 0.5, 1.0),
+        );
+        assert_close(levels[0].leq, -9.03, 0.01);
+    }
+
+    #[test]
+    fn a_weighting_follows_iec_61672() {
+        // full scale sine is -3.01 dBFS, plus the weighting from the standard's table
+        assert_close(weighted_leq(Weighting::A, 1000.0), -3.01, 0.05);
+        assert_close(weighted_leq(Weighting::A, 31.5), -3.01 - 39.4, 0.2);
+        assert_close(weighted_leq(Weighting::A, 100.0), -3.01 - 19.1, 0.1);
+        assert_close(weighted_leq(Weighting::A, 250.0), -3.01 - 8.6, 0.1);
+        assert_close(weighted_leq(Weighting::A, 4000.0), -3.01 + 1.0, 0.1);
+    }
+
+    #[test]
+    fn c_weighting_follows_iec_61672() {
+        assert_close(weighted_leq(Weighting::C, 1000.0), -3.01, 0.05);
+        assert_close(weighted_leq(Weighting::C, 31.5), -3.01 - 3.0, 0.1);
+        assert_close(weighted_leq(Weighting::C, 100.0), -3.01 - 0.3, 0.1);
+        assert_close(weighted_leq(Weighting::C, 4000.0), -3.01 - 0.8, 0.1);
+    }
+
+    #[test]
+    fn leq_is_the_energy_average() {
+        // half the interval at the level of the tone and half silent is 3 dB below it
+        let samples = [tone(1000.0, 1.0, 0.5), silence(0.5)].concat();
+        let levels = measure(&mut meter(Weighting::Z, TimeWeighting::Leq), &samples);
+        assert_close(levels[0].leq, -3.01 - 3.01, 0.05);
+    }
+
+    #[test]
+    fn tone_bursts_follow_the_time_constants() {
+        // the maximum of a burst of length t is 10 * log10(1 - e^(-t / τ)) below the tone
+        let burst = |time_weighting, seconds| {
+            let samples = [tone(1000.0, 1.0, seconds), silence(1.0 - seconds)].concat();
+            measure(&mut meter(Weighting::Z, time_weighting), &samples)[0]
+        };
+        assert_close(burst(TimeWeighting::Fast, 0.2).max, -3.01 - 1.0, 0.1);
+        assert_close(burst(TimeWeighting::Fast, 0.125).max, -3.01 - 2.0, 0.1);
+        assert_close(burst(TimeWeighting::Slow, 0.5).max, -3.01 - 4.1, 0.1);
+        // the Leq takes the maximum and minimum from the fast time weighting
+        assert_close(burst(TimeWeighting::Leq, 0.2).max, -3.01 - 1.0, 0.1);
+    }
+
+    #[test]
+    fn levels_decay_with_the_time_constants() {
+        // after the tone stops the level falls by 10 * log10(e) / τ dB per second
+        let decay = |time_weighting| {
+            let samples = [tone(1000.0, 1.0, 5.0), silence(0.5)].concat();
+            let mut meter = SoundLevelMeter::new(
+                Weighting::Z,
+                time_weighting,
+                SAMPLE_RATE,
+                Duration::from_millis(500),
+                None,
+            );
+            let levels = measure(&mut meter, &samples);
+            levels[9].last - levels[10].last
+        };
+        assert_close(decay(TimeWeighting::Fast), 34.7 * 0.5, 0.2);
+        assert_close(decay(TimeWeighting::Slow), 4.34 * 0.5, 0.05);
+    }
+
+    #[test]
+    fn min_and_max_cover_the_interval() {
+        let samples = [
+            tone(1000.0, 1.0, 2.0),
+            tone(1000.0, 0.1, 2.0),
+            tone(1000.0, 1.0, 1.0),
+        ]
+        .concat();
+        let levels = measure(&mut meter(Weighting::Z, TimeWeighting::Fast), &samples);
+        // the quieter tone is 20 dB below the loud one, fast settles within its first second
+        assert_close(levels[2].max, -3.01, 0.05);
+        assert_close(levels[3].last, -23.01, 0.05);
+        assert_close(levels[3].min, -23.01, 0.05);
+        assert_close(levels[4].min, -23.01, 0.05);
+        assert_close(levels[4].last, -3.01, 0.05);
+        assert_eq!(levels[4].level(TimeWeighting::Fast), levels[4].last);
+        assert_eq!(levels[4].level(TimeWeighting::Leq), levels[4].leq);
+    }
+
+    #[test]
+    fn calibration_offsets_the_levels() {
+        let mut meter = SoundLevelMeter::new(
+            Weighting::A,
+            TimeWeighting::Leq,
+            SAMPLE_RATE,
+            Duration::from_secs(1),
+            Some(94.0),
+        );
+        assert_eq!(meter.scale().reference, Reference::Spl);
+        let samples = [silence(1.0), tone(1000.0, 1.0, 2.0)].concat();
+        let levels = measure(&mut meter, &samples);
+        assert_eq!(levels[0].leq, 94.0 - 120.0);
+        assert_close(levels[2].leq, 94.0 - 3.01, 0.05);
+    }
+
+    #[test]
+    fn the_detector_starts_from_the_first_sample() {
+        // a constant signal at half scale is -6.02 dBFS from its first sample on
+        let samples = vec![0.5; SAMPLE_RATE as usize];
+        let levels = measure(&mut meter(Weighting::Z, TimeWeighting::Slow), &samples);
+        assert_close(levels[0].min, -6.02, 0.01);
+        assert_close(levels[0].max, -6.02, 0.01);
+        assert_close(levels[0].last, -6.02, 0.01);
+    }
+}
@@ -1,4 +1,4 @@
-use iot_sound_backend::loudness_data::LoudnessData;
+use iot_sound_wire::{LoudnessData, Timestamp};
 use rand::Rng;
 use std::time::SystemTime;
 
@@ -36,8 +36,7 @@ impl LoudnessSensorSimulator {
     pub fn get_loudness_data(&mut self) -> LoudnessData {
         self.latest_loudness = self.next_loudness();
 
-        let timestamp = SystemTime::now();
-        LoudnessData::new(self.latest_loudness, timestamp)
+        LoudnessData::new(self.latest_loudness, Timestamp::now())
     }
 
     /// Generates next random loudness value
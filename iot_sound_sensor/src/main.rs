@@ -1,39 +1,84 @@
+mod audio_input;
+mod dsp;
 mod loudness_sensor_simulator;
 
-use rumqttc::{AsyncClient, EventLoop, MqttOptions, QoS};
-use std::{env, error::Error, time::Duration};
+use audio_input::{AudioConfig, AudioInput};
+use iot_sound_protocol::auth::{self, Channel, SignedPayload};
+use iot_sound_protocol::envelope;
+use iot_sound_protocol::senml;
+use iot_sound_protocol::sensor_config::{self, ReportingMode, SensorConfig};
+use iot_sound_protocol::status::{self, Connection, StatusMessage};
+use iot_sound_wire::scale::{TimeWeighting, Weighting};
+use iot_sound_wire::wire::Envelope;
+use iot_sound_wire::{LoudnessData, Scale};
+use rumqttc::{AsyncClient, Event, EventLoop, Incoming, LastWill, MqttOptions, Publish, QoS};
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{env, error::Error};
 use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::watch;
+use tracing::{debug, error, info, warn, Instrument};
+
+/// Most readings kept while the sensor is disconnected, the oldest are dropped first
+const BACKLOG_LIMIT: usize = 10_000;
+/// Most readings in one batch or SenML pack when the backlog is uploaded
+const BATCH_SIZE: usize = 100;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    println!("Sensor node started");
-
     // get env variables
     let env_vars = match get_env_variables() {
         Ok(env_vars) => env_vars,
         Err(e) => panic!("Error getting env variables: {}", e),
     };
+    iot_sound_telemetry::init();
+    info!("Sensor node started");
 
+    let sensor_topic = format!("{}{}", env_vars.mqtt_publish_topic, env_vars.mqtt_client_id);
     let (client, eventloop) = setup_mqtt_client(
         &env_vars.mqtt_address,
         env_vars.mqtt_port,
         &env_vars.mqtt_client_id,
     );
 
+    let audio = match env_vars.audio {
+        Some(audio) => Some(audio_input::spawn(audio)?),
+        None => None,
+    };
+    let (config_tx, config_rx) = watch::channel(SensorConfig::default());
+    let (connected_tx, connected_rx) = watch::channel(false);
     let (tx, rx) = channel::<Message>(100);
     let err = tokio::try_join!(
-        keep_mqtt_client_alive(eventloop),
+        keep_mqtt_client_alive(
+            eventloop,
+            client.clone(),
+            &sensor_topic,
+            &env_vars.mqtt_client_id,
+            env_vars.key.as_ref(),
+            config_tx,
+            connected_tx
+        ),
         send_mqtt_messages(
             client,
             &env_vars.mqtt_client_id,
             &env_vars.mqtt_publish_topic,
+            env_vars.key.as_ref(),
+            env_vars.encrypt,
             rx
         ),
-        message_generator(tx),
+        message_generator(
+            tx,
+            config_rx,
+            connected_rx,
+            &env_vars.mqtt_client_id,
+            env_vars.payload_format,
+            audio
+        ),
     );
 
     if let Err(e) = err {
-        println!("Error: {}", e);
+        error!("Error: {}", e);
         return Err(e);
     }
 
@@ -45,11 +90,74 @@ struct EnvVars {
     mqtt_port: u16,
     mqtt_client_id: String,
     mqtt_publish_topic: String,
+    /// Key id and shared key used to sign payloads
+    key: Option<(u32, Vec<u8>)>,
+    /// Whether payloads are encrypted instead of only signed
+    encrypt: bool,
+    payload_format: PayloadFormat,
+    /// Audio to measure, the simulator is used if not set
+    audio: Option<AudioConfig>,
+}
+
+/// Format of the payloads the sensor publishes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PayloadFormat {
+    /// The original `db_level,timestamp`, understood by every backend
+    Csv,
+    /// Format version 1, with the firmware version of the sensor
+    V1,
+    /// The compact binary format, for slow links, the backlog is uploaded in batches.
+    /// Readings not on the default scale are sent as batches of one, which carry the scale
+    Binary,
+    /// SenML (RFC 8428) in JSON, the backlog is uploaded in multi-record packs
+    SenmlJson,
+    /// SenML in CBOR, the backlog is uploaded in multi-record packs
+    SenmlCbor,
+}
+
+impl PayloadFormat {
+    /// Returns whether a payload in this format can hold many readings
+    fn batches(self) -> bool {
+        !matches!(self, PayloadFormat::Csv | PayloadFormat::V1)
+    }
+
+    /// Returns whether payloads in this format carry every part of the scale of a reading
+    fn carries_scale(self) -> bool {
+        matches!(self, PayloadFormat::V1 | PayloadFormat::Binary)
+    }
+
+    /// Returns whether payloads in this format are binary, which can not be signed
+    fn is_binary(self) -> bool {
+        matches!(self, PayloadFormat::Binary | PayloadFormat::SenmlCbor)
+    }
+}
+
+impl FromStr for PayloadFormat {
+    type Err = Box<dyn Error>;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format.trim() {
+            "csv" => Ok(PayloadFormat::Csv),
+            "v1" => Ok(PayloadFormat::V1),
+            "binary" => Ok(PayloadFormat::Binary),
+            "senml-json" => Ok(PayloadFormat::SenmlJson),
+            "senml-cbor" => Ok(PayloadFormat::SenmlCbor),
+            other => Err(format!(
+                "Invalid payload format: {}, expected csv, v1, binary, senml-json or senml-cbor",
+                other
+            )
+            .into()),
+        }
+    }
 }
 
 /// Get env variables
 ///
 /// `MQTT_ADDRESS`, `MQTT_PORT`, `MQTT_CLIENT_ID`, `MQTT_PUBLISH_TOPIC`
+///
+/// Optional: `SENSOR_KEY_ID`, `SENSOR_KEY`, `SENSOR_ENCRYPT`, `SENSOR_PAYLOAD_FORMAT`,
+/// `SENSOR_AUDIO_INPUT`, `SENSOR_AUDIO_SAMPLE_RATE`, `SENSOR_AUDIO_CHANNELS`,
+/// `SENSOR_AUDIO_WINDOW_MS`, `SENSOR_CALIBRATION_DB`
 fn get_env_variables() -> Result<EnvVars, Box<dyn Error>> {
     if env::var("MQTT_ADDRESS").is_err()
         || env::var("MQTT_PORT").is_err()
@@ -63,11 +171,98 @@ fn get_env_variables() -> Result<EnvVars, Box<dyn Error>> {
     let mqtt_port = mqtt_port.parse::<u16>()?;
     let mqtt_client_id = env::var("MQTT_CLIENT_ID")?;
     let mqtt_publish_topic = env::var("MQTT_PUBLISH_TOPIC")?;
+    // optional, payloads are signed when the sensor has a key
+    let key = match env::var("SENSOR_KEY") {
+        Ok(key) => {
+            let key_id = env::var("SENSOR_KEY_ID")
+                .map_err(|_| "SENSOR_KEY_ID must be set with SENSOR_KEY")?
+                .parse::<u32>()?;
+            Some((key_id, hex::decode(key.trim())?))
+        }
+        Err(_) => None,
+    };
+    let encrypt = match env::var("SENSOR_ENCRYPT") {
+        Ok(encrypt) => encrypt.parse::<bool>()?,
+        Err(_) => false,
+    };
+    if encrypt && key.is_none() {
+        return Err("SENSOR_KEY must be set with SENSOR_ENCRYPT".into());
+    }
+    // csv until every backend understands the newer formats
+    let payload_format = match env::var("SENSOR_PAYLOAD_FORMAT") {
+        Ok(format) => format.parse::<PayloadFormat>()?,
+        Err(_) => PayloadFormat::Csv,
+    };
+    // the signature is appended as text, binary payloads are protected by encrypting them
+    if payload_format.is_binary() && key.is_some() && !encrypt {
+        return Err("SENSOR_ENCRYPT must be set to send binary payloads with SENSOR_KEY".into());
+    }
+    // optional, a WAV file or `-` for raw PCM on stdin instead of the simulator
+    let audio = match env::var("SENSOR_AUDIO_INPUT") {
+        Ok(input) => {
+            let sample_rate = match env::var("SENSOR_AUDIO_SAMPLE_RATE") {
+                Ok(rate) => rate.parse::<u32>()?,
+                Err(_) => 48_000,
+            };
+            let channels = match env::var("SENSOR_AUDIO_CHANNELS") {
+                Ok(channels) => channels.parse::<u16>()?,
+                Err(_) => 1,
+            };
+            if sample_rate == 0 || channels == 0 {
+                return Err(
+                    "SENSOR_AUDIO_SAMPLE_RATE and SENSOR_AUDIO_CHANNELS must not be 0".into(),
+                );
+            }
+            let input = match input.trim() {
+                "-" => AudioInput::Stdin {
+                    sample_rate,
+                    channels,
+                },
+                path => AudioInput::Wav(path.to_string()),
+            };
+            let window = match env::var("SENSOR_AUDIO_WINDOW_MS") {
+                Ok(window) => Duration::from_millis(window.parse::<u64>()?),
+                Err(_) => Duration::from_secs(1),
+            };
+            // timestamps are whole seconds and a sensor has one reading per second
+            if window < Duration::from_secs(1) {
+                return Err("SENSOR_AUDIO_WINDOW_MS must be at least 1000".into());
+            }
+            let weighting = match env::var("SENSOR_AUDIO_WEIGHTING") {
+                Ok(weighting) => weighting.trim().parse::<Weighting>()?,
+                Err(_) => Weighting::A,
+            };
+            let time_weighting = match env::var("SENSOR_AUDIO_TIME_WEIGHTING") {
+                Ok(time_weighting) => time_weighting.trim().parse::<TimeWeighting>()?,
+                Err(_) => TimeWeighting::Leq,
+            };
+            let calibration_db = match env::var("SENSOR_CALIBRATION_DB") {
+                Ok(calibration) => Some(calibration.parse::<f32>()?),
+                Err(_) => None,
+            };
+            Some(AudioConfig {
+                input,
+                window,
+                weighting,
+                time_weighting,
+                calibration_db,
+            })
+        }
+        Err(_) => None,
+    };
+    // measured levels can be on any scale, which the csv and SenML payloads can not tell
+    if audio.is_some() && !payload_format.carries_scale() {
+        return Err("SENSOR_PAYLOAD_FORMAT must be v1 or binary with SENSOR_AUDIO_INPUT".into());
+    }
     Ok(EnvVars {
         mqtt_address,
         mqtt_port,
         mqtt_client_id,
         mqtt_publish_topic,
+        key,
+        encrypt,
+        payload_format,
+        audio,
     })
 }
 #[derive(Debug)]
@@ -82,56 +277,318 @@ impl Message {
     }
 }
 
+/// A reading, with the highest and lowest time weighted level of its window
+/// when it was measured from the audio input
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sample {
+    pub data: LoudnessData,
+    pub lmax: Option<f32>,
+    pub lmin: Option<f32>,
+}
+
+impl From<LoudnessData> for Sample {
+    fn from(data: LoudnessData) -> Self {
+        Sample {
+            data,
+            lmax: None,
+            lmin: None,
+        }
+    }
+}
+
 /// Generates messages and sends them to the mqtt client
 ///
+/// Without an audio input samples are simulated every `sample_interval_secs` of the
+/// current config, with one every measured window is a sample. The config decides
+/// which samples are published, a new config takes effect immediately.
+/// While the sensor is disconnected samples are kept in a backlog, which is uploaded
+/// when it connects again, in batches or packs with the formats that have them.
+/// When the audio input ends the backlog is uploaded and no more samples are taken.
+///
 /// * `channel` - The channel to send the messages to
-async fn message_generator(channel: Sender<Message>) -> Result<(), Box<dyn Error>> {
+/// * `config` - The config the sensor is running with
+/// * `connected` - Whether the mqtt client is connected to the broker
+/// * `client_id` - Mqtt client id for this device, the base name of SenML packs
+/// * `format` - The format of the payloads
+/// * `audio` - The levels measured from the audio input
+async fn message_generator(
+    channel: Sender<Message>,
+    mut config: watch::Receiver<SensorConfig>,
+    mut connected: watch::Receiver<bool>,
+    client_id: &str,
+    format: PayloadFormat,
+    mut audio: Option<Receiver<Sample>>,
+) -> Result<(), Box<dyn Error>> {
     let mut loudness_sensor_simulator = loudness_sensor_simulator::LoudnessSensorSimulator::new();
+    let mut last_reported: Option<(f32, Instant)> = None;
+    let mut backlog: VecDeque<Sample> = VecDeque::new();
+    let mut next_sample = tokio::time::Instant::now();
+    let mut measured = None;
     loop {
-        let loudness = loudness_sensor_simulator.get_loudness_data();
-        let message = loudness.to_csv();
+        if *connected.borrow() {
+            send_backlog(&channel, &mut backlog, client_id, format).await?;
+        }
 
-        match channel
-            .send(Message::_payload_from_str_slice(&message))
-            .await
-        {
-            Ok(_) => {
-                println!("Message sent to mqtt publisher: {}", &message);
-                tokio::time::sleep(Duration::from_secs(2)).await;
-            }
-            Err(e) => {
-                println!("Failed to send message to publisher: {}", e);
-                return Err(Box::new(e));
+        let current = config.borrow().clone();
+        let sample = match audio {
+            Some(_) => measured.take(),
+            None if tokio::time::Instant::now() >= next_sample => {
+                next_sample =
+                    tokio::time::Instant::now() + Duration::from_secs(current.sample_interval_secs);
+                Some(Sample::from(loudness_sensor_simulator.get_loudness_data()))
             }
+            None => None,
         };
+        if let Some(sample) = sample {
+            if should_report(&current, sample.data.db_level(), last_reported) {
+                last_reported = Some((sample.data.db_level(), Instant::now()));
+                if *connected.borrow() {
+                    send(&channel, encode(format, client_id, &[sample])).await?;
+                } else {
+                    if backlog.len() == BACKLOG_LIMIT {
+                        backlog.pop_front();
+                        warn!("Backlog is full, dropping the oldest reading");
+                    }
+                    backlog.push_back(sample);
+                }
+            }
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep_until(next_sample), if audio.is_none() => {}
+            sample = next_level(&mut audio), if audio.is_some() => match sample {
+                Some(sample) => measured = Some(sample),
+                None => {
+                    while !*connected.borrow() {
+                        connected.changed().await?;
+                    }
+                    return send_backlog(&channel, &mut backlog, client_id, format).await;
+                }
+            },
+            changed = config.changed() => {
+                changed?;
+                next_sample = tokio::time::Instant::now();
+            }
+            changed = connected.changed() => changed?,
+        }
+    }
+}
+
+/// Returns the next level measured from the audio input, `None` when it has ended
+async fn next_level(audio: &mut Option<Receiver<Sample>>) -> Option<Sample> {
+    audio.as_mut()?.recv().await
+}
+
+/// Encode readings in the payload format
+///
+/// * `format` - The format of the payload
+/// * `client_id` - Mqtt client id for this device
+/// * `samples` - The samples, one unless the format has batches, only v1 carries
+///   their `lmax` and `lmin`
+fn encode(format: PayloadFormat, client_id: &str, samples: &[Sample]) -> Vec<u8> {
+    let readings: Vec<LoudnessData> = samples.iter().map(|sample| sample.data).collect();
+    let envelope = match (format, readings.as_slice()) {
+        (PayloadFormat::SenmlJson, _) => {
+            return senml::encode(senml::Encoding::Json, client_id, &readings)
+        }
+        (PayloadFormat::SenmlCbor, _) => {
+            return senml::encode(senml::Encoding::Cbor, client_id, &readings)
+        }
+        (PayloadFormat::Csv, [loudness]) => Envelope::csv(*loudness),
+        (PayloadFormat::V1, [loudness]) => {
+            let mut envelope =
+                Envelope::new(*loudness, Some(env!("CARGO_PKG_VERSION").to_string()));
+            let extremes = [("lmax", samples[0].lmax), ("lmin", samples[0].lmin)];
+            for (name, level) in extremes {
+                if let Some(level) = level {
+                    envelope
+                        .fields
+                        .insert(name.to_string(), format!("{:.2}", level));
+                }
+            }
+            envelope
+        }
+        (PayloadFormat::Binary, [loudness]) if loudness.scale() == Scale::default() => {
+            Envelope::binary(*loudness)
+        }
+        (PayloadFormat::Binary, _) => Envelope::batch(readings),
+        (PayloadFormat::Csv | PayloadFormat::V1, _) => {
+            unreachable!("Text formats have one reading per payload")
+        }
+    };
+    envelope.encode()
+}
+
+/// Send the readings kept while the sensor was disconnected
+///
+/// Formats with batches send up to `BATCH_SIZE` readings in one message,
+/// the text formats send them one at a time as backends may not understand batches.
+///
+/// * `channel` - The channel to send the messages to
+/// * `backlog` - The readings to send, oldest first
+/// * `client_id` - Mqtt client id for this device
+/// * `format` - The format of the payloads
+async fn send_backlog(
+    channel: &Sender<Message>,
+    backlog: &mut VecDeque<Sample>,
+    client_id: &str,
+    format: PayloadFormat,
+) -> Result<(), Box<dyn Error>> {
+    if backlog.is_empty() {
+        return Ok(());
+    }
+    info!(
+        "Uploading {} readings taken while disconnected",
+        backlog.len()
+    );
+    let batch_size = if format.batches() { BATCH_SIZE } else { 1 };
+    while !backlog.is_empty() {
+        let count = backlog.len().min(batch_size);
+        let samples: Vec<Sample> = backlog.drain(..count).collect();
+        send(channel, encode(format, client_id, &samples)).await?;
+    }
+    Ok(())
+}
+
+/// Send a payload to the mqtt publisher
+async fn send(channel: &Sender<Message>, payload: Vec<u8>) -> Result<(), Box<dyn Error>> {
+    let message = Message { payload };
+    debug!(
+        "Message sent to mqtt publisher: {}",
+        String::from_utf8_lossy(&message.payload).escape_debug()
+    );
+    if let Err(e) = channel.send(message).await {
+        error!("Failed to send message to publisher: {}", e);
+        return Err(Box::new(e));
+    }
+    Ok(())
+}
+
+/// Returns whether a sample is published under the reporting mode of the config
+///
+/// * `config` - The config the sensor is running with
+/// * `level` - The level of the sample
+/// * `last_reported` - The level and time of the last published sample
+fn should_report(config: &SensorConfig, level: f32, last_reported: Option<(f32, Instant)>) -> bool {
+    let (last_level, last_time) = match last_reported {
+        Some(last_reported) => last_reported,
+        None => return true,
+    };
+    if last_time.elapsed() >= Duration::from_secs(config.max_report_interval_secs) {
+        return true;
+    }
+    match config.reporting_mode {
+        ReportingMode::Periodic => true,
+        ReportingMode::OnChange => (level - last_level).abs() >= config.change_threshold_db,
+        ReportingMode::Threshold => level >= config.alert_threshold_db,
     }
 }
 
 /// Listens for messages on the channel and publishes them to the mqtt client
 ///
+/// With a key every payload is signed, or encrypted if `encrypt` is set. The counter starts at the current time in
+/// milliseconds, so it keeps increasing across restarts of the sensor.
+///
 /// * `client` - The mqtt client
 /// * `client_id` - Mqtt client id for this device
 /// * `publish_topic` - The topic to publish messages to
+/// * `key` - Key id and shared key to sign payloads with
+/// * `encrypt` - Whether to encrypt payloads instead of only signing them
 /// * `channel` - The channel to listen for messages on
 async fn send_mqtt_messages(
     client: AsyncClient,
     client_id: &str,
     publish_topic: &str,
+    key: Option<&(u32, Vec<u8>)>,
+    encrypt: bool,
     mut channel: Receiver<Message>,
 ) -> Result<(), Box<dyn Error>> {
     let topic = format!("{publish_topic}{client_id}");
-    while let Some(message) = channel.recv().await {
+    let mut counter = Counter::new();
+    while let Some(mut message) = channel.recv().await {
+        if let Some((key_id, key)) = key {
+            let counter = counter.next();
+            message.payload = if encrypt {
+                envelope::seal(client_id, key, *key_id, counter, &message.payload)?
+            } else {
+                let payload = String::from_utf8(message.payload)?;
+                auth::sign(client_id, key, *key_id, counter, &payload).into_bytes()
+            };
+        }
+        let span = tracing::info_span!(
+            "message",
+            topic = %topic,
+            payload_size = message.payload.len()
+        );
         if let Err(e) = client
             .publish(&topic, QoS::ExactlyOnce, false, message.payload)
+            .instrument(span.clone())
             .await
         {
-            println!("Failed to publish: {}", e);
+            span.in_scope(|| error!("Failed to publish: {}", e));
             return Err(Box::new(e));
         }
     }
     Ok(())
 }
 
+/// Counter for signed messages, it starts at the current time in milliseconds
+/// so it keeps increasing across restarts of the sensor
+struct Counter(u64);
+
+impl Counter {
+    fn new() -> Self {
+        Counter(0)
+    }
+
+    /// Returns a counter higher than every earlier one
+    fn next(&mut self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        self.0 = now.max(self.0 + 1);
+        self.0
+    }
+}
+
+/// Signs the messages the sensor sends besides readings, if it has a key
+struct Signer<'a> {
+    client_id: &'a str,
+    key: Option<&'a (u32, Vec<u8>)>,
+    counter: Counter,
+}
+
+impl<'a> Signer<'a> {
+    /// * `client_id` - Mqtt client id for this device
+    /// * `key` - Key id and shared key to sign messages with
+    fn new(client_id: &'a str, key: Option<&'a (u32, Vec<u8>)>) -> Self {
+        Signer {
+            client_id,
+            key,
+            counter: Counter::new(),
+        }
+    }
+
+    /// Sign a message, returns it unchanged without a key
+    ///
+    /// * `channel` - What the message is for
+    /// * `message` - The message to sign
+    fn sign(&mut self, channel: Channel, message: String) -> String {
+        match self.key {
+            Some((key_id, key)) => auth::sign_on(
+                channel,
+                self.client_id,
+                key,
+                *key_id,
+                self.counter.next(),
+                &message,
+            ),
+            None => message,
+        }
+    }
+}
+
 /// Sets up the mqtt client
 ///
 /// * `address` - The address of the mqtt broker
@@ -148,19 +605,340 @@ fn setup_mqtt_client(
     (client, eventloop)
 }
 
+/// Returns the last will for a new connection to the broker, with the session of the
+/// connection
+///
+/// The broker publishes an `offline` status, retained, as the last will of the sensor
+/// when the connection is lost without the sensor disconnecting. Every connection has
+/// its own session, which the `online` statuses sent on it announce, and the backend
+/// only takes a last will with the session of the last `online` status. With a key the
+/// last will is signed, its counter is older than the ones of the `online` statuses
+/// that follow it.
+///
+/// * `status_topic` - The status topic of the sensor
+/// * `signer` - Signs the last will if the sensor has a key
+fn last_will(status_topic: &str, signer: &mut Signer) -> (u64, LastWill) {
+    let session = signer.counter.next();
+    let message = serde_json::to_string(&StatusMessage::offline(session))
+        .expect("StatusMessage can be serialized");
+    let message = signer.sign(Channel::Status, message);
+    (
+        session,
+        LastWill::new(status_topic, message, QoS::AtLeastOnce, true),
+    )
+}
+
+/// Registers a new last will for the next connection to the broker, see `last_will`
+/// Returns the session of the connection
+///
+/// * `eventloop` - The eventloop of the mqtt client, it connects with the last will
+/// * `status_topic` - The status topic of the sensor
+/// * `signer` - Signs the last will if the sensor has a key
+fn register_last_will(eventloop: &mut EventLoop, status_topic: &str, signer: &mut Signer) -> u64 {
+    let (session, will) = last_will(status_topic, signer);
+    eventloop.options.set_last_will(will);
+    session
+}
+
 /// Keeps the mqtt client running
 ///
+/// Subscribes to the config topic of the sensor on every connect and applies the
+/// configs received on it. The config the sensor runs with is reported back, retained,
+/// on connect and after every change, so the backend knows which version it applied.
+/// With a key, only configs signed by the backend are applied, and reports and
+/// statuses are signed.
+/// The `online` status is published on connect and after every change as well. A new
+/// last will is registered before every connect, see `last_will`.
+/// Whether the client is connected is sent to the message generator, which keeps
+/// a backlog while it is not.
+///
 /// * `eventloop` - The eventloop of the mqtt client
-async fn keep_mqtt_client_alive(mut eventloop: EventLoop) -> Result<(), Box<dyn Error>> {
+/// * `client` - The mqtt client
+/// * `sensor_topic` - The topic this sensor publishes readings to
+/// * `client_id` - Mqtt client id for this device
+/// * `key` - Key id and shared key to verify configs and sign reports with
+/// * `config` - Sends applied configs to the message generator
+/// * `connected` - Sends whether the client is connected to the message generator
+async fn keep_mqtt_client_alive(
+    mut eventloop: EventLoop,
+    client: AsyncClient,
+    sensor_topic: &str,
+    client_id: &str,
+    key: Option<&(u32, Vec<u8>)>,
+    config: watch::Sender<SensorConfig>,
+    connected: watch::Sender<bool>,
+) -> Result<(), Box<dyn Error>> {
+    let started = Instant::now();
+    let config_topic = sensor_config::config_topic(sensor_topic);
+    let reported_topic = sensor_config::reported_topic(sensor_topic);
+    let status_topic = status::status_topic(sensor_topic);
+    let mut signer = Signer::new(client_id, key);
+    let mut session = register_last_will(&mut eventloop, &status_topic, &mut signer);
     loop {
         match eventloop.poll().await {
+            Ok(Event::Incoming(Incoming::ConnAck(_))) => {
+                if let Err(e) = client.try_subscribe(&config_topic, QoS::AtLeastOnce) {
+                    warn!("Error subscribing to {}: {}", config_topic, e);
+                }
+                report_config(&client, &reported_topic, &mut signer, &config.borrow());
+                announce_online(
+                    &client,
+                    &status_topic,
+                    &mut signer,
+                    session,
+                    started,
+                    &config.borrow(),
+                );
+                connected.send_replace(true);
+            }
+            Ok(Event::Incoming(Incoming::Publish(publish))) if publish.topic == config_topic => {
+                if apply_config(&publish, client_id, key, &config) {
+                    report_config(&client, &reported_topic, &mut signer, &config.borrow());
+                    announce_online(
+                        &client,
+                        &status_topic,
+                        &mut signer,
+                        session,
+                        started,
+                        &config.borrow(),
+                    );
+                }
+            }
             Ok(notification) => {
-                println!("Notification: = {:?}", notification);
+                debug!("Notification: = {:?}", notification);
             }
             Err(e) => {
-                println!("Failed to poll: {}", e);
+                warn!("Failed to poll: {}", e);
+                connected.send_replace(false);
+                session = register_last_will(&mut eventloop, &status_topic, &mut signer);
                 tokio::time::sleep(Duration::from_secs(2)).await;
             }
         }
     }
 }
+
+/// Apply a config received from the backend, configs older than the one
+/// the sensor runs with are ignored
+///
+/// * `publish` - The message with the config
+/// * `client_id` - Mqtt client id for this device
+/// * `key` - Key id and shared key the config must be signed with
+/// * `config` - Sends the config to the message generator
+/// # Returns
+/// * `bool` - Whether the config was applied
+fn apply_config(
+    publish: &Publish,
+    client_id: &str,
+    key: Option<&(u32, Vec<u8>)>,
+    config: &watch::Sender<SensorConfig>,
+) -> bool {
+    let received = match read_config(&publish.payload, client_id, key) {
+        Ok(received) => received,
+        Err(e) => {
+            warn!("Ignoring config: {}", e);
+            return false;
+        }
+    };
+    if received.version <= config.borrow().version {
+        if *config.borrow() != received {
+            warn!(
+                "Ignoring config version {}, running version {}",
+                received.version,
+                config.borrow().version
+            );
+        }
+        return false;
+    }
+    info!(
+        "Applying config version {}: sample every {}s, reporting {:?}",
+        received.version, received.sample_interval_secs, received.reporting_mode
+    );
+    config.send_replace(received);
+    true
+}
+
+/// Read a config received from the backend, with a key it must be signed
+/// with that key and the version as counter
+///
+/// * `payload` - The config as received
+/// * `client_id` - Mqtt client id for this device
+/// * `key` - Key id and shared key the config must be signed with
+fn read_config(
+    payload: &[u8],
+    client_id: &str,
+    key: Option<&(u32, Vec<u8>)>,
+) -> Result<SensorConfig, String> {
+    let payload = std::str::from_utf8(payload).map_err(|e| e.to_string())?;
+    let (message, counter) = match key {
+        Some((key_id, key)) => {
+            let signed =
+                SignedPayload::parse_on(Channel::Config, payload).map_err(|e| e.to_string())?;
+            if signed.key_id != *key_id {
+                return Err(format!("signed with key {}", signed.key_id));
+            }
+            signed.verify(client_id, key).map_err(|e| e.to_string())?;
+            (signed.message, Some(signed.counter))
+        }
+        None => (payload, None),
+    };
+    let config: SensorConfig = serde_json::from_str(message).map_err(|e| e.to_string())?;
+    if counter.is_some() && counter != u64::try_from(config.version).ok() {
+        return Err(format!("version {} was not signed", config.version));
+    }
+    config
+        .validate()
+        .map_err(|e| format!("version {}: {}", config.version, e))?;
+    Ok(config)
+}
+
+/// Publish the config the sensor is running with, retained
+///
+/// Published without waiting, as this runs on the task that polls the eventloop.
+///
+/// * `client` - The mqtt client
+/// * `reported_topic` - The topic to report on
+/// * `signer` - Signs the report if the sensor has a key
+/// * `config` - The config the sensor is running with
+fn report_config(
+    client: &AsyncClient,
+    reported_topic: &str,
+    signer: &mut Signer,
+    config: &SensorConfig,
+) {
+    let payload = serde_json::to_string(config).expect("SensorConfig can be serialized");
+    let payload = signer.sign(Channel::ReportedConfig, payload);
+    if let Err(e) = client.try_publish(reported_topic, QoS::AtLeastOnce, true, payload) {
+        warn!("Failed to report config: {}", e);
+    }
+}
+
+/// Publish the `online` status of the sensor, retained, replacing its last will
+///
+/// * `client` - The mqtt client
+/// * `status_topic` - The status topic of the sensor
+/// * `signer` - Signs the status if the sensor has a key
+/// * `session` - The session of the connection, see `last_will`
+/// * `started` - The time the sensor started
+/// * `config` - The config the sensor is running with
+fn announce_online(
+    client: &AsyncClient,
+    status_topic: &str,
+    signer: &mut Signer,
+    session: u64,
+    started: Instant,
+    config: &SensorConfig,
+) {
+    let message = StatusMessage {
+        state: Connection::Online,
+        session: Some(session),
+        firmware_version: Some(env!("CARGO_PKG_VERSION").to_string()),
+        uptime_secs: Some(started.elapsed().as_secs()),
+        config_hash: Some(sensor_config::config_hash(config)),
+    };
+    let payload = serde_json::to_string(&message).expect("StatusMessage can be serialized");
+    let payload = signer.sign(Channel::Status, payload);
+    if let Err(e) = client.try_publish(status_topic, QoS::AtLeastOnce, true, payload) {
+        warn!("Failed to publish status: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+    fn config(version: i32) -> String {
+        serde_json::to_string(&SensorConfig {
+            version,
+            ..SensorConfig::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn configs_must_be_signed_by_the_backend_with_the_version() {
+        let key = (1, KEY.to_vec());
+        let signed = auth::sign_on(Channel::Config, "sensor-1", KEY, 1, 3, &config(3));
+        assert_eq!(
+            read_config(signed.as_bytes(), "sensor-1", Some(&key))
+                .unwrap()
+                .version,
+            3
+        );
+
+        let cases = [
+            ("unsigned", config(3)),
+            (
+                "other version",
+                auth::sign_on(Channel::Config, "sensor-1", KEY, 1, 4, &config(3)),
+            ),
+            (
+                "other key id",
+                auth::sign_on(Channel::Config, "sensor-1", KEY, 2, 3, &config(3)),
+            ),
+            (
+                "other sensor",
+                auth::sign_on(Channel::Config, "sensor-2", KEY, 1, 3, &config(3)),
+            ),
+            (
+                "other channel",
+                auth::sign_on(Channel::ReportedConfig, "sensor-1", KEY, 1, 3, &config(3)),
+            ),
+            ("tampered", signed.replace("\"version\":3", "\"version\":4")),
+        ];
+        for (name, payload) in cases {
+            assert!(
+                read_config(payload.as_bytes(), "sensor-1", Some(&key)).is_err(),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn sensors_without_a_key_read_unsigned_configs() {
+        assert_eq!(
+            read_config(config(3).as_bytes(), "sensor-1", None)
+                .unwrap()
+                .version,
+            3
+        );
+        let invalid = SensorConfig {
+            max_report_interval_secs: SensorConfig::MAX_INTERVAL_SECS + 1,
+            ..SensorConfig::default()
+        };
+        let invalid = serde_json::to_string(&invalid).unwrap();
+        assert!(read_config(invalid.as_bytes(), "sensor-1", None).is_err());
+    }
+
+    #[test]
+    fn v1_payloads_carry_lmax_and_lmin() {
+        let data = LoudnessData::new(52.3, iot_sound_wire::Timestamp::UNIX_EPOCH);
+        let sample = Sample {
+            data,
+            lmax: Some(60.456),
+            lmin: Some(41.0),
+        };
+        let decoded = Envelope::decode(&encode(PayloadFormat::V1, "sensor-1", &[sample])).unwrap();
+        assert_eq!(decoded.fields["lmax"], "60.46");
+        assert_eq!(decoded.fields["lmin"], "41.00");
+
+        let simulated = encode(PayloadFormat::V1, "sensor-1", &[Sample::from(data)]);
+        assert!(Envelope::decode(&simulated).unwrap().fields.is_empty());
+    }
+
+    #[test]
+    fn every_connection_registers_a_last_will_with_its_own_session() {
+        let mut signer = Signer::new("sensor-1", None);
+        let (first, will) = last_will("sensors/sensor-1/status", &mut signer);
+        let (second, _) = last_will("sensors/sensor-1/status", &mut signer);
+        assert!(second > first);
+        assert_eq!(will.topic, "sensors/sensor-1/status");
+        assert!(will.retain);
+        assert_eq!(
+            serde_json::from_slice::<StatusMessage>(&will.message).unwrap(),
+            StatusMessage::offline(first)
+        );
+    }
+}
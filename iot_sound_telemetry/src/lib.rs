@@ -0,0 +1,34 @@
+use tracing::Subscriber;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+/// Returns the filter set by `RUST_LOG`, `info` if it is not set
+pub fn env_filter() -> EnvFilter {
+    EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"))
+}
+
+/// Returns the layer writing events to stdout, as json when `LOG_FORMAT=json`
+/// and human readable otherwise
+pub fn stdout_layer<S>() -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    if matches!(std::env::var("LOG_FORMAT").as_deref(), Ok("json")) {
+        tracing_subscriber::fmt::layer()
+            .json()
+            .with_current_span(true)
+            .boxed()
+    } else {
+        tracing_subscriber::fmt::layer().boxed()
+    }
+}
+
+/// Setup tracing to stdout, filtered by `RUST_LOG`, see `env_filter` and `stdout_layer`
+pub fn init() {
+    tracing_subscriber::registry()
+        .with(env_filter())
+        .with(stdout_layer())
+        .init();
+}
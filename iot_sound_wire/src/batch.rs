@@ -0,0 +1,402 @@
+use crate::codec::{self, BufferTooSmall, Writer, MAX_VARINT_LEN};
+use crate::loudness_data::{LoudnessData, ParseError};
+use crate::scale::Scale;
+use crate::timestamp::Timestamp;
+use core::fmt;
+
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+
+/// First byte of a batch payload, like `BINARY_MARKER` a byte that never starts a UTF-8 text
+pub const BATCH_MARKER: u8 = 0xb3;
+/// Most readings in a batch, a longer backlog is sent as several batches
+pub const MAX_READINGS: usize = 1000;
+
+/// Every reading has its own timestamp
+const ABSOLUTE: u8 = 0;
+/// The readings are evenly spaced from a start time
+const INTERVAL: u8 = 1;
+/// Bits of the layout byte holding the layout, the others hold the scale of the readings
+const LAYOUT_BITS: u8 = 0b11;
+
+/// Why readings could not be encoded as a batch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncodeError {
+    /// More than `MAX_READINGS` readings, they have to be sent as several batches
+    TooManyReadings,
+    /// The readings are not all on the same scale, a batch has room for one
+    MixedScales,
+    /// The buffer given to `encode_into` is too small for the payload
+    BufferTooSmall,
+}
+
+impl From<BufferTooSmall> for EncodeError {
+    fn from(_: BufferTooSmall) -> Self {
+        EncodeError::BufferTooSmall
+    }
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncodeError::TooManyReadings => {
+                write!(f, "a batch has at most {} readings", MAX_READINGS)
+            }
+            EncodeError::MixedScales => f.write_str("the readings of a batch share a scale"),
+            EncodeError::BufferTooSmall => BufferTooSmall.fmt(f),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for EncodeError {}
+
+/// Returns the most bytes `encode_into` writes for `count` readings
+pub const fn max_len(count: usize) -> usize {
+    2 + 3 * MAX_VARINT_LEN + count * (2 + MAX_VARINT_LEN)
+}
+
+/// Encode several readings in one payload, for sensors uploading a backlog
+///
+/// `BATCH_MARKER | layout (1 byte) | count (varint) | ...`, then with the absolute layout
+/// `count * (level (i16, 0.01 dB) | timestamp (varint))`, and with the interval layout
+/// `start (varint) | interval in seconds (varint) | count * level (i16, 0.01 dB)`.
+/// The interval layout is used when the readings are evenly spaced and in order.
+/// The upper 6 bits of the layout byte are the scale of the readings, 0 for the default scale,
+/// so batches of readings on the default scale read the same as before scales were added.
+///
+/// # Arguments
+/// * `readings` - The readings, at most `MAX_READINGS`, all on the same scale
+/// * `buf` - Where to write the payload, `max_len` bytes are always enough
+/// # Returns
+/// * `usize` - The length of the payload
+/// # Errors
+/// `EncodeError::TooManyReadings` or `EncodeError::MixedScales` if the readings do not fit
+/// in one batch, `EncodeError::BufferTooSmall` if the payload does not fit in `buf`
+pub fn encode_into(readings: &[LoudnessData], buf: &mut [u8]) -> Result<usize, EncodeError> {
+    if readings.len() > MAX_READINGS {
+        return Err(EncodeError::TooManyReadings);
+    }
+    let scale = readings
+        .first()
+        .map_or(Scale::default(), LoudnessData::scale);
+    if readings.iter().any(|data| data.scale() != scale) {
+        return Err(EncodeError::MixedScales);
+    }
+    let mut writer = Writer::new(buf);
+    writer.push(BATCH_MARKER)?;
+    match evenly_spaced(readings) {
+        Some(interval) => {
+            writer.push(INTERVAL | scale.code())?;
+            writer.varint(readings.len() as u64)?;
+            writer.varint(readings.first().map_or(0, unix_secs))?;
+            writer.varint(interval)?;
+            for data in readings {
+                writer.level(data.db_level())?;
+            }
+        }
+        None => {
+            writer.push(ABSOLUTE | scale.code())?;
+            writer.varint(readings.len() as u64)?;
+            for data in readings {
+                writer.level(data.db_level())?;
+                writer.varint(unix_secs(data))?;
+            }
+        }
+    }
+    Ok(writer.len())
+}
+
+/// Encode several readings in one payload, see `encode_into`
+/// # Arguments
+/// * `readings` - The readings, at most `MAX_READINGS`, all on the same scale
+/// # Returns
+/// * `Vec<u8>` - The batch payload
+/// # Errors
+/// `EncodeError::TooManyReadings` or `EncodeError::MixedScales` if the readings do not fit
+/// in one batch
+#[cfg(feature = "alloc")]
+pub fn encode(readings: &[LoudnessData]) -> Result<Vec<u8>, EncodeError> {
+    let mut payload = alloc::vec![0; max_len(readings.len().min(MAX_READINGS))];
+    let len = encode_into(readings, &mut payload)?;
+    payload.truncate(len);
+    Ok(payload)
+}
+
+fn unix_secs(data: &LoudnessData) -> u64 {
+    data.timestamp().unix_secs()
+}
+
+/// Returns the time between the readings if they are evenly spaced and in order
+fn evenly_spaced(readings: &[LoudnessData]) -> Option<u64> {
+    let interval = match readings {
+        [first, second, ..] => unix_secs(second).checked_sub(unix_secs(first))?,
+        _ => 0,
+    };
+    let even = readings
+        .windows(2)
+        .all(|pair| unix_secs(&pair[1]).checked_sub(unix_secs(&pair[0])) == Some(interval));
+    even.then_some(interval)
+}
+
+/// Decode a payload written by `encode`
+/// # Arguments
+/// * `payload` - The payload as received
+#[cfg(feature = "alloc")]
+pub fn decode(payload: &[u8]) -> Result<Vec<LoudnessData>, ParseError> {
+    readings(payload)?.collect()
+}
+
+/// Returns the readings in a payload written by `encode` one at a time, without an allocator
+///
+/// The header is checked first, every reading when it is reached, and the iterator
+/// ends with an error if bytes are left after the last reading.
+///
+/// # Arguments
+/// * `payload` - The payload as received
+pub fn readings(payload: &[u8]) -> Result<Readings<'_>, ParseError> {
+    if payload.first() != Some(&BATCH_MARKER) {
+        return Err(ParseError::UnsupportedVersion {
+            offset: 0,
+            token: codec::hex_token(&payload[..payload.len().min(1)]),
+        });
+    }
+    let layout = *payload.get(1).ok_or(ParseError::MissingField {
+        offset: 1,
+        field: "layout",
+    })?;
+    let (count, offset) = codec::read_varint(payload, 2, "count")?;
+    if count as usize > MAX_READINGS {
+        return Err(ParseError::BadNumber {
+            offset: 2,
+            field: "count",
+            token: codec::hex_token(&payload[2..offset]),
+        });
+    }
+
+    let unsupported_layout = || ParseError::UnsupportedVersion {
+        offset: 1,
+        token: codec::hex_token(&payload[1..2]),
+    };
+    let scale = Scale::from_code(layout).ok_or_else(unsupported_layout)?;
+    let layout = match layout & LAYOUT_BITS {
+        ABSOLUTE => Layout::Absolute,
+        INTERVAL => {
+            let (start, end) = codec::read_varint(payload, offset, "timestamp")?;
+            let (interval, end) = codec::read_varint(payload, end, "interval")?;
+            Layout::Interval {
+                start,
+                interval,
+                start_offset: offset,
+                levels_offset: end,
+            }
+        }
+        _ => return Err(unsupported_layout()),
+    };
+    let offset = match layout {
+        Layout::Interval { levels_offset, .. } => levels_offset,
+        Layout::Absolute => offset,
+    };
+    Ok(Readings {
+        payload,
+        layout,
+        scale,
+        count,
+        index: 0,
+        offset,
+        done: false,
+    })
+}
+
+enum Layout {
+    Absolute,
+    Interval {
+        start: u64,
+        interval: u64,
+        /// Offset of the start timestamp, where errors in computed timestamps are reported
+        start_offset: usize,
+        levels_offset: usize,
+    },
+}
+
+/// Iterator over the readings of a batch, see `readings`
+pub struct Readings<'a> {
+    payload: &'a [u8],
+    layout: Layout,
+    scale: Scale,
+    count: u64,
+    index: u64,
+    offset: usize,
+    done: bool,
+}
+
+impl Readings<'_> {
+    fn read(&mut self) -> Result<LoudnessData, ParseError> {
+        let (db_level, end) = codec::read_level(self.payload, self.offset)?;
+        let (timestamp, end) = match self.layout {
+            Layout::Absolute => {
+                let (secs, timestamp_end) = codec::read_varint(self.payload, end, "timestamp")?;
+                let timestamp = codec::timestamp_from_secs(self.payload, end, timestamp_end, secs)?;
+                (timestamp, timestamp_end)
+            }
+            Layout::Interval {
+                start,
+                interval,
+                start_offset,
+                levels_offset,
+            } => {
+                let secs = interval
+                    .checked_mul(self.index)
+                    .and_then(|elapsed| start.checked_add(elapsed))
+                    .and_then(Timestamp::from_unix_secs);
+                let timestamp = secs.ok_or_else(|| ParseError::TimestampOutOfRange {
+                    offset: start_offset,
+                    token: codec::hex_token(&self.payload[start_offset..levels_offset]),
+                })?;
+                (timestamp, end)
+            }
+        };
+        self.offset = end;
+        Ok(LoudnessData::new(db_level, timestamp).with_scale(self.scale))
+    }
+}
+
+impl Iterator for Readings<'_> {
+    type Item = Result<LoudnessData, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        if self.index == self.count {
+            self.done = true;
+            if self.offset < self.payload.len() {
+                return Some(Err(ParseError::TrailingFields {
+                    offset: self.offset,
+                    token: codec::hex_token(&self.payload[self.offset..]),
+                }));
+            }
+            return None;
+        }
+        let reading = self.read();
+        self.index += 1;
+        self.done = reading.is_err();
+        Some(reading)
+    }
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    fn reading(db_level: f32, secs: u64) -> LoudnessData {
+        LoudnessData::new(db_level, Timestamp::from_unix_secs(secs).unwrap())
+    }
+
+    fn assert_round_trips(readings: &[LoudnessData]) -> Vec<u8> {
+        let payload = encode(readings).unwrap();
+        let decoded = decode(&payload).unwrap();
+        assert_eq!(decoded.len(), readings.len());
+        for (decoded, reading) in decoded.iter().zip(readings) {
+            assert!((decoded.db_level() - reading.db_level()).abs() < 0.005);
+            assert_eq!(decoded.timestamp(), reading.timestamp());
+            assert_eq!(decoded.scale(), reading.scale());
+        }
+        payload
+    }
+
+    #[test]
+    fn evenly_spaced_readings_use_the_interval_layout() {
+        let readings: Vec<_> = (0..100)
+            .map(|i| reading(40.0 + i as f32 / 10.0, 1669026612 + 2 * i))
+            .collect();
+        let payload = assert_round_trips(&readings);
+        assert_eq!(payload[1], INTERVAL);
+        assert!(payload.len() < 2 * readings.len() + 16);
+    }
+
+    #[test]
+    fn irregular_readings_use_the_absolute_layout() {
+        let readings = [
+            reading(40.0, 1669026612),
+            reading(41.0, 1669026614),
+            reading(42.0, 1669026700),
+            reading(43.0, 1669026650),
+        ];
+        assert_eq!(assert_round_trips(&readings)[1], ABSOLUTE);
+    }
+
+    #[test]
+    fn small_batches_round_trip() {
+        assert_round_trips(&[]);
+        assert_round_trips(&[reading(55.5, 1669026612)]);
+    }
+
+    #[test]
+    fn the_scale_is_kept() {
+        let scale = Scale {
+            weighting: crate::scale::Weighting::Z,
+            time_weighting: crate::scale::TimeWeighting::Leq,
+            reference: crate::scale::Reference::Dbfs,
+        };
+        let readings = [
+            reading(-40.0, 10).with_scale(scale),
+            reading(-41.0, 20).with_scale(scale),
+        ];
+        assert_eq!(assert_round_trips(&readings)[1] & LAYOUT_BITS, INTERVAL);
+        assert_eq!(
+            assert_round_trips(&[reading(40.0, 10), reading(41.0, 20)])[1],
+            INTERVAL
+        );
+    }
+
+    #[test]
+    fn batches_encode_into_a_fixed_buffer() {
+        let readings = [reading(40.0, 10), reading(41.0, 20), reading(42.0, 35)];
+        let mut buf = [0; max_len(3)];
+        let len = encode_into(&readings, &mut buf).unwrap();
+        assert_eq!(&buf[..len], encode(&readings).unwrap().as_slice());
+        assert_eq!(
+            encode_into(&readings, &mut buf[..len - 1]),
+            Err(EncodeError::BufferTooSmall)
+        );
+    }
+
+    #[test]
+    fn readings_that_do_not_fit_one_batch_are_refused() {
+        let readings = vec![reading(40.0, 10); MAX_READINGS + 1];
+        let mut buf = vec![0; max_len(readings.len())];
+        assert_eq!(
+            encode_into(&readings, &mut buf),
+            Err(EncodeError::TooManyReadings)
+        );
+        assert_eq!(encode(&readings), Err(EncodeError::TooManyReadings));
+        assert!(encode(&readings[1..]).is_ok());
+
+        let dbfs = Scale {
+            reference: crate::scale::Reference::Dbfs,
+            ..Scale::default()
+        };
+        let mixed = [reading(40.0, 10), reading(-40.0, 20).with_scale(dbfs)];
+        assert_eq!(encode_into(&mixed, &mut buf), Err(EncodeError::MixedScales));
+        assert_eq!(encode(&mixed), Err(EncodeError::MixedScales));
+    }
+
+    #[test]
+    fn truncated_and_oversized_batches_are_rejected() {
+        let payload = encode(&[reading(40.0, 10), reading(41.0, 20), reading(42.0, 35)]).unwrap();
+        for end in 1..payload.len() {
+            assert!(decode(&payload[..end]).is_err());
+        }
+        let mut trailing = payload.clone();
+        trailing.push(0);
+        assert_eq!(decode(&trailing).unwrap_err().kind(), "trailing_fields");
+
+        let mut too_many = vec![BATCH_MARKER, ABSOLUTE, 0, 0];
+        let mut writer = Writer::new(&mut too_many[2..]);
+        writer.varint(MAX_READINGS as u64 + 1).unwrap();
+        assert_eq!(decode(&too_many).unwrap_err().kind(), "bad_number");
+    }
+}
@@ -0,0 +1,240 @@
+use crate::loudness_data::{ParseError, Token};
+use crate::timestamp::Timestamp;
+use core::fmt::{self, Write};
+
+/// The buffer given to an encoder is too small for the payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferTooSmall;
+
+impl fmt::Display for BufferTooSmall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("buffer is too small for the payload")
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for BufferTooSmall {}
+
+/// Most bytes a varint of a u64 takes
+pub(crate) const MAX_VARINT_LEN: usize = 10;
+
+/// Writes a payload into a fixed buffer, so encoders work without an allocator
+pub(crate) struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Writer<'a> {
+    pub(crate) fn new(buf: &'a mut [u8]) -> Self {
+        Writer { buf, len: 0 }
+    }
+
+    /// Returns the number of bytes written
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn push(&mut self, byte: u8) -> Result<(), BufferTooSmall> {
+        *self.buf.get_mut(self.len).ok_or(BufferTooSmall)? = byte;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Append a level as a little endian i16 in 0.01 dB
+    pub(crate) fn level(&mut self, db_level: f32) -> Result<(), BufferTooSmall> {
+        // `f32::round` needs std, adding a half away from zero and truncating is the same
+        let centi_db = db_level * 100.0;
+        let half = if centi_db < 0.0 { -0.5 } else { 0.5 };
+        let centi_db = (centi_db + half) as i16;
+        for byte in centi_db.to_le_bytes() {
+            self.push(byte)?;
+        }
+        Ok(())
+    }
+
+    /// Append an unsigned LEB128 varint, 7 bits per byte with the high bit set on all but the last
+    pub(crate) fn varint(&mut self, mut value: u64) -> Result<(), BufferTooSmall> {
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                return self.push(byte);
+            }
+            self.push(byte | 0x80)?;
+        }
+    }
+}
+
+/// Read a level written by `Writer::level`
+/// # Returns
+/// * `(f32, usize)` - The level and the offset after it
+pub(crate) fn read_level(payload: &[u8], offset: usize) -> Result<(f32, usize), ParseError> {
+    let level = payload
+        .get(offset..offset + 2)
+        .ok_or(ParseError::MissingField {
+            offset: payload.len().min(offset),
+            field: "level",
+        })?;
+    let centi_db = i16::from_le_bytes([level[0], level[1]]);
+    Ok((f32::from(centi_db) / 100.0, offset + 2))
+}
+
+/// Read a varint written by `Writer::varint`
+/// # Returns
+/// * `(u64, usize)` - The value and the offset after it
+pub(crate) fn read_varint(
+    payload: &[u8],
+    offset: usize,
+    field: &'static str,
+) -> Result<(u64, usize), ParseError> {
+    let mut value = 0u64;
+    for (i, &byte) in payload.iter().enumerate().skip(offset) {
+        let shift = 7 * (i - offset);
+        let bits = u64::from(byte & 0x7f);
+        if shift >= 64 || (shift > 0 && bits >> (64 - shift) != 0) {
+            let token = hex_token(&payload[offset..=i]);
+            return Err(match field {
+                "timestamp" => ParseError::TimestampOutOfRange { offset, token },
+                _ => ParseError::BadNumber {
+                    offset,
+                    field,
+                    token,
+                },
+            });
+        }
+        value |= bits << shift;
+        if byte & 0x80 == 0 {
+            return Ok((value, i + 1));
+        }
+    }
+    Err(ParseError::MissingField {
+        offset: payload.len(),
+        field,
+    })
+}
+
+/// Returns the time `secs` seconds after the Unix epoch, for a timestamp
+/// at `offset..end` of a binary payload
+pub(crate) fn timestamp_from_secs(
+    payload: &[u8],
+    offset: usize,
+    end: usize,
+    secs: u64,
+) -> Result<Timestamp, ParseError> {
+    Timestamp::from_unix_secs(secs).ok_or_else(|| ParseError::TimestampOutOfRange {
+        offset,
+        token: hex_token(&payload[offset..end]),
+    })
+}
+
+/// Returns bytes as `\x..` escapes, used as the token of errors in binary data
+pub(crate) fn hex_token(bytes: &[u8]) -> Token {
+    let mut token = Token::default();
+    for byte in bytes {
+        // a full token is truncated instead of failing
+        let _ = write!(token, "\\x{:02x}", byte);
+    }
+    token
+}
+
+// these run without the alloc feature, so they cover what firmware builds use
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batch;
+    use crate::loudness_data::{LoudnessData, MAX_BINARY_LEN};
+    use crate::spectrum::{Bandwidth, Spectrum, MAX_SPECTRUM_LEN};
+
+    fn data() -> LoudnessData {
+        LoudnessData::new(42.5, Timestamp::from_unix_secs(1669026612).unwrap())
+    }
+
+    #[test]
+    fn writer_encodes_levels_and_varints() {
+        let mut buf = [0; 16];
+        let mut writer = Writer::new(&mut buf);
+        writer.level(42.5).unwrap();
+        writer.level(-12.34).unwrap();
+        writer.varint(300).unwrap();
+        writer.varint(u64::MAX).unwrap();
+        let len = writer.len();
+        assert_eq!(len, 16);
+        assert_eq!(buf[..6], [0x9a, 0x10, 0x2e, 0xfb, 0xac, 0x02]);
+        assert_eq!(
+            buf[6..],
+            [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]
+        );
+
+        assert_eq!(read_level(&buf, 0), Ok((42.5, 2)));
+        assert_eq!(read_level(&buf, 2), Ok((-12.34, 4)));
+        assert_eq!(read_varint(&buf, 4, "count"), Ok((300, 6)));
+        assert_eq!(read_varint(&buf, 6, "count"), Ok((u64::MAX, 16)));
+    }
+
+    #[test]
+    fn writer_stops_at_the_end_of_the_buffer() {
+        let mut buf = [0; 3];
+        let mut writer = Writer::new(&mut buf);
+        writer.level(42.5).unwrap();
+        assert_eq!(writer.level(42.5), Err(BufferTooSmall));
+        assert_eq!(writer.varint(300), Err(BufferTooSmall));
+        assert_eq!(writer.len(), 3);
+        assert_eq!(Writer::new(&mut []).push(0), Err(BufferTooSmall));
+    }
+
+    #[test]
+    fn encode_binary_round_trips() {
+        let mut buf = [0; MAX_BINARY_LEN];
+        let len = data().encode_binary(&mut buf).unwrap();
+        assert_eq!(buf[..len], [0xb2, 0x9a, 0x10, 0xb4, 0xa6, 0xed, 0x9b, 0x06]);
+        assert_eq!(LoudnessData::parse_binary(&buf[..len]), Ok(data()));
+        for short in 0..len {
+            assert_eq!(
+                data().encode_binary(&mut buf[..short]),
+                Err(BufferTooSmall),
+                "{}",
+                short
+            );
+        }
+    }
+
+    #[test]
+    fn spectrum_encode_binary_round_trips() {
+        let spectrum = Spectrum::new(Bandwidth::Octave, 63.0, &[42.5, 40.0], data().timestamp());
+        let spectrum = spectrum.unwrap();
+        let mut buf = [0; MAX_SPECTRUM_LEN];
+        let len = spectrum.encode_binary(&mut buf).unwrap();
+        assert_eq!(Spectrum::parse_binary(&buf[..len]), Ok(spectrum));
+        for short in 0..len {
+            assert_eq!(
+                spectrum.encode_binary(&mut buf[..short]),
+                Err(BufferTooSmall),
+                "{}",
+                short
+            );
+        }
+    }
+
+    #[test]
+    fn batch_encode_into_round_trips() {
+        let later = LoudnessData::new(40.0, Timestamp::from_unix_secs(1669026700).unwrap());
+        let mut buf = [0; batch::max_len(3)];
+        // evenly spaced and not
+        for readings in [[data(), data(), data()], [data(), later, data()]] {
+            let len = batch::encode_into(&readings, &mut buf).unwrap();
+            let mut decoded = batch::readings(&buf[..len]).unwrap();
+            for data in readings {
+                assert_eq!(decoded.next(), Some(Ok(data)));
+            }
+            assert_eq!(decoded.next(), None);
+            for short in 0..len {
+                assert_eq!(
+                    batch::encode_into(&readings, &mut buf[..short]),
+                    Err(batch::EncodeError::BufferTooSmall),
+                    "{}",
+                    short
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,21 @@
+#![no_std]
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
+
+pub mod batch;
+mod codec;
+pub mod loudness_data;
+pub mod scale;
+pub mod spectrum;
+pub mod timestamp;
+#[cfg(feature = "alloc")]
+pub mod wire;
+
+pub use codec::BufferTooSmall;
+pub use loudness_data::{LoudnessData, ParseError, Token};
+pub use scale::Scale;
+pub use spectrum::Spectrum;
+pub use timestamp::Timestamp;
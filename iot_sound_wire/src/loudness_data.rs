@@ -0,0 +1,704 @@
+use crate::codec::{self, BufferTooSmall, Writer, MAX_VARINT_LEN};
+use crate::scale::Scale;
+use crate::timestamp::Timestamp;
+use core::fmt::{self, Write};
+use core::num::IntErrorKind;
+use core::ops::Deref;
+use core::str::Utf8Error;
+
+#[cfg(feature = "alloc")]
+use alloc::{string::String, vec::Vec};
+
+/// First byte of a binary payload, a byte that never starts a UTF-8 text,
+/// so binary payloads can not be mistaken for csv
+pub const BINARY_MARKER: u8 = 0xb2;
+/// Most bytes `LoudnessData::encode_binary` writes
+pub const MAX_BINARY_LEN: usize = 3 + MAX_VARINT_LEN;
+
+/// Struct for loudness data
+/// Represents a single measurement of loudness in decibel
+/// with a timestamp of when the measurement was taken,
+/// and the scale the level was measured on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LoudnessData {
+    db_level: f32,
+    timestamp: Timestamp,
+    scale: Scale,
+}
+/// Create a new LoudnessData on the default scale, see `with_scale`
+///
+/// # Arguments
+///
+/// * `db_level` - The loudness in decibel
+/// * `timestamp` - The time the loudness was measured
+impl LoudnessData {
+    pub fn new(db_level: f32, timestamp: Timestamp) -> Self {
+        LoudnessData {
+            db_level,
+            timestamp,
+            scale: Scale::default(),
+        }
+    }
+    /// Returns the LoudnessData with the level on another scale
+    ///
+    /// # Arguments
+    ///
+    /// * `scale` - The scale the level was measured on
+    pub fn with_scale(self, scale: Scale) -> Self {
+        LoudnessData { scale, ..self }
+    }
+    /// Returns db_level of the LoudnessData
+    pub fn db_level(&self) -> f32 {
+        self.db_level
+    }
+    /// Returns timestamp of the LoudnessData
+    pub fn timestamp(&self) -> Timestamp {
+        self.timestamp
+    }
+    /// Returns the scale the level of the LoudnessData was measured on
+    pub fn scale(&self) -> Scale {
+        self.scale
+    }
+    /// Parses a payload into a LoudnessData, the payload must be a UTF-8 csv string.
+    ///
+    /// # Arguments
+    ///
+    /// * `payload` - The payload as received
+    pub fn parse(payload: &[u8]) -> Result<Self, ParseError> {
+        match core::str::from_utf8(payload) {
+            Ok(csv) => LoudnessData::parse_csv(csv),
+            Err(e) => Err(ParseError::bad_encoding(payload, &e)),
+        }
+    }
+
+    /// Parses a csv string into a LoudnessData.
+    /// Returns a LoudnessData with the values from the csv string.
+    ///
+    /// The csv string is `db_level,timestamp`, with the timestamp in seconds since the Unix epoch.
+    /// It has no scale, the reading is on the default scale.
+    ///
+    /// # Arguments
+    ///
+    /// * `csv` - The csv string to parse
+    pub fn parse_csv(csv: &str) -> Result<Self, ParseError> {
+        // every field with its byte offset in the csv string
+        let mut fields = csv.split(',').scan(0, |offset, field| {
+            let start = *offset;
+            *offset += field.len() + 1;
+            Some((start, field))
+        });
+
+        let (offset, token) = next_field(&mut fields, "level", csv.len())?;
+        let db_level = token.parse::<f32>().map_err(|_| ParseError::BadNumber {
+            offset,
+            field: "level",
+            token: token.into(),
+        })?;
+        if !db_level.is_finite() {
+            return Err(ParseError::NonFiniteLevel {
+                offset,
+                token: token.into(),
+            });
+        }
+
+        let (offset, token) = next_field(&mut fields, "timestamp", csv.len())?;
+        let out_of_range = || ParseError::TimestampOutOfRange {
+            offset,
+            token: token.into(),
+        };
+        let secs = token.parse::<u64>().map_err(|e| match e.kind() {
+            IntErrorKind::PosOverflow => out_of_range(),
+            _ => ParseError::BadNumber {
+                offset,
+                field: "timestamp",
+                token: token.into(),
+            },
+        })?;
+        let timestamp = Timestamp::from_unix_secs(secs).ok_or_else(out_of_range)?;
+
+        if let Some((offset, _)) = fields.next() {
+            return Err(ParseError::TrailingFields {
+                offset,
+                token: csv[offset..].into(),
+            });
+        }
+        Ok(LoudnessData::new(db_level, timestamp))
+    }
+
+    /// Writes the csv representation of the LoudnessData, `db_level,timestamp`
+    ///
+    /// # Arguments
+    ///
+    /// * `out` - Where to write the csv string
+    pub fn write_csv(&self, out: &mut impl Write) -> fmt::Result {
+        write!(out, "{},{}", self.db_level, self.timestamp.unix_secs())
+    }
+
+    /// Returns a csv string representation of the LoudnessData.
+    /// db_level,timestamp
+    #[cfg(feature = "alloc")]
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::new();
+        self.write_csv(&mut csv)
+            .expect("Writing to a String does not fail");
+        csv
+    }
+
+    /// Writes the compact binary representation of the LoudnessData,
+    /// for sensors on slow links.
+    ///
+    /// `BINARY_MARKER (1 byte) | level (i16, little endian, in 0.01 dB) | timestamp (LEB128 varint)`,
+    /// with the timestamp in seconds since the Unix epoch. A reading is 8 bytes until 2106.
+    /// The level is rounded to 0.01 dB, levels beyond ±327.67 dB saturate.
+    /// The scale is not written, a parsed reading is on the default scale.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf` - Where to write the payload, `MAX_BINARY_LEN` bytes are always enough
+    /// # Returns
+    /// * `usize` - The length of the payload
+    pub fn encode_binary(&self, buf: &mut [u8]) -> Result<usize, BufferTooSmall> {
+        let mut writer = Writer::new(buf);
+        writer.push(BINARY_MARKER)?;
+        writer.level(self.db_level)?;
+        writer.varint(self.timestamp.unix_secs())?;
+        Ok(writer.len())
+    }
+
+    /// Returns the compact binary representation of the LoudnessData, see `encode_binary`
+    #[cfg(feature = "alloc")]
+    pub fn to_binary(&self) -> Vec<u8> {
+        let mut buf = [0; MAX_BINARY_LEN];
+        let len = self
+            .encode_binary(&mut buf)
+            .expect("MAX_BINARY_LEN fits every reading");
+        buf[..len].to_vec()
+    }
+
+    /// Parses a payload written by `to_binary`
+    ///
+    /// # Arguments
+    ///
+    /// * `payload` - The payload as received
+    pub fn parse_binary(payload: &[u8]) -> Result<Self, ParseError> {
+        match payload.first() {
+            Some(&BINARY_MARKER) => {}
+            Some(_) => {
+                return Err(ParseError::UnsupportedVersion {
+                    offset: 0,
+                    token: codec::hex_token(&payload[..1]),
+                })
+            }
+            None => {
+                return Err(ParseError::MissingField {
+                    offset: 0,
+                    field: "marker",
+                })
+            }
+        }
+        let (db_level, offset) = codec::read_level(payload, 1)?;
+        let (secs, end) = codec::read_varint(payload, offset, "timestamp")?;
+        let timestamp = codec::timestamp_from_secs(payload, offset, end, secs)?;
+
+        if end < payload.len() {
+            return Err(ParseError::TrailingFields {
+                offset: end,
+                token: codec::hex_token(&payload[end..]),
+            });
+        }
+        Ok(LoudnessData::new(db_level, timestamp))
+    }
+}
+
+/// Returns the next field of a csv string, an empty field counts as missing
+fn next_field<'a>(
+    fields: &mut impl Iterator<Item = (usize, &'a str)>,
+    field: &'static str,
+    end: usize,
+) -> Result<(usize, &'a str), ParseError> {
+    match fields.next() {
+        Some((offset, token)) if !token.is_empty() => Ok((offset, token)),
+        Some((offset, _)) => Err(ParseError::MissingField { offset, field }),
+        None => Err(ParseError::MissingField { offset: end, field }),
+    }
+}
+
+/// The offending text of a parse error
+///
+/// Kept inline so errors need no allocator, text beyond `Token::CAPACITY` bytes
+/// is cut off and ends with `...`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Token {
+    bytes: [u8; Token::CAPACITY],
+    len: u8,
+    truncated: bool,
+}
+
+impl Token {
+    /// Most bytes a token holds
+    pub const CAPACITY: usize = 64;
+
+    /// Returns the token as a string
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[..usize::from(self.len)])
+            .expect("Token holds whole characters")
+    }
+}
+
+impl Default for Token {
+    fn default() -> Self {
+        Token {
+            bytes: [0; Token::CAPACITY],
+            len: 0,
+            truncated: false,
+        }
+    }
+}
+
+impl Write for Token {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        for c in text.chars() {
+            if self.truncated {
+                return Ok(());
+            }
+            let len = usize::from(self.len);
+            if len + c.len_utf8() > Token::CAPACITY {
+                let mut end = len.min(Token::CAPACITY - 3);
+                while !self.as_str().is_char_boundary(end) {
+                    end -= 1;
+                }
+                self.bytes[end..end + 3].copy_from_slice(b"...");
+                self.len = (end + 3) as u8;
+                self.truncated = true;
+                return Ok(());
+            }
+            c.encode_utf8(&mut self.bytes[len..]);
+            self.len += c.len_utf8() as u8;
+        }
+        Ok(())
+    }
+}
+
+impl From<&str> for Token {
+    fn from(text: &str) -> Self {
+        let mut token = Token::default();
+        token
+            .write_str(text)
+            .expect("Writing to a Token does not fail");
+        token
+    }
+}
+
+impl Deref for Token {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl fmt::Debug for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Why a payload could not be parsed into a LoudnessData
+///
+/// Every variant has the byte offset in the payload where the problem starts
+/// and, except for a missing field, the offending token.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// A field is empty or the payload ends before it
+    MissingField { offset: usize, field: &'static str },
+    /// A field is not a number
+    BadNumber {
+        offset: usize,
+        field: &'static str,
+        token: Token,
+    },
+    /// The level is infinite or NaN
+    NonFiniteLevel { offset: usize, token: Token },
+    /// The timestamp is too large to be represented
+    TimestampOutOfRange { offset: usize, token: Token },
+    /// There are more fields after the timestamp
+    TrailingFields { offset: usize, token: Token },
+    /// The payload is not valid UTF-8, the token is the invalid bytes in hex.
+    /// The tokens of errors in binary payloads are in hex as well
+    BadEncoding { offset: usize, token: Token },
+    /// The payload is in a format version the backend can not decode
+    UnsupportedVersion { offset: usize, token: Token },
+    /// An optional field is not `name=value`
+    MalformedField { offset: usize, token: Token },
+    /// The weighting, time weighting or reference of the level is not known
+    UnknownUnit { offset: usize, token: Token },
+}
+
+impl ParseError {
+    /// Returns the error for a payload that is not valid UTF-8
+    /// # Arguments
+    /// * `payload` - The payload as received
+    /// * `error` - The error from `core::str::from_utf8`
+    pub fn bad_encoding(payload: &[u8], error: &Utf8Error) -> Self {
+        let offset = error.valid_up_to();
+        let end = offset + error.error_len().unwrap_or(payload.len() - offset);
+        ParseError::BadEncoding {
+            offset,
+            token: codec::hex_token(&payload[offset..end]),
+        }
+    }
+
+    /// Returns the error with its offset moved by `by` bytes, for an error in a
+    /// part of a larger payload
+    pub fn shifted(mut self, by: usize) -> Self {
+        match &mut self {
+            ParseError::MissingField { offset, .. }
+            | ParseError::BadNumber { offset, .. }
+            | ParseError::NonFiniteLevel { offset, .. }
+            | ParseError::TimestampOutOfRange { offset, .. }
+            | ParseError::TrailingFields { offset, .. }
+            | ParseError::BadEncoding { offset, .. }
+            | ParseError::UnsupportedVersion { offset, .. }
+            | ParseError::MalformedField { offset, .. }
+            | ParseError::UnknownUnit { offset, .. } => *offset += by,
+        }
+        self
+    }
+
+    /// Short name of the error, used as metric label
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ParseError::MissingField { .. } => "missing_field",
+            ParseError::BadNumber { .. } => "bad_number",
+            ParseError::NonFiniteLevel { .. } => "non_finite_level",
+            ParseError::TimestampOutOfRange { .. } => "timestamp_out_of_range",
+            ParseError::TrailingFields { .. } => "trailing_fields",
+            ParseError::BadEncoding { .. } => "bad_encoding",
+            ParseError::UnsupportedVersion { .. } => "unsupported_version",
+            ParseError::MalformedField { .. } => "malformed_field",
+            ParseError::UnknownUnit { .. } => "unknown_unit",
+        }
+    }
+
+    /// Byte offset in the payload where the problem starts
+    pub fn offset(&self) -> usize {
+        match self {
+            ParseError::MissingField { offset, .. }
+            | ParseError::BadNumber { offset, .. }
+            | ParseError::NonFiniteLevel { offset, .. }
+            | ParseError::TimestampOutOfRange { offset, .. }
+            | ParseError::TrailingFields { offset, .. }
+            | ParseError::BadEncoding { offset, .. }
+            | ParseError::UnsupportedVersion { offset, .. }
+            | ParseError::MalformedField { offset, .. }
+            | ParseError::UnknownUnit { offset, .. } => *offset,
+        }
+    }
+
+    /// The offending token, empty for a missing field
+    pub fn token(&self) -> &str {
+        match self {
+            ParseError::MissingField { .. } => "",
+            ParseError::BadNumber { token, .. }
+            | ParseError::NonFiniteLevel { token, .. }
+            | ParseError::TimestampOutOfRange { token, .. }
+            | ParseError::TrailingFields { token, .. }
+            | ParseError::BadEncoding { token, .. }
+            | ParseError::UnsupportedVersion { token, .. }
+            | ParseError::MalformedField { token, .. }
+            | ParseError::UnknownUnit { token, .. } => token,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::MissingField { offset, field } => {
+                write!(f, "missing {} at byte {}", field, offset)
+            }
+            ParseError::BadNumber {
+                offset,
+                field,
+                token,
+            } => write!(f, "invalid {} {:?} at byte {}", field, token, offset),
+            ParseError::NonFiniteLevel { offset, token } => {
+                write!(f, "level {:?} at byte {} is not finite", token, offset)
+            }
+            ParseError::TimestampOutOfRange { offset, token } => {
+                write!(
+                    f,
+                    "timestamp {:?} at byte {} is out of range",
+                    token, offset
+                )
+            }
+            ParseError::TrailingFields { offset, token } => {
+                write!(f, "unexpected fields {:?} at byte {}", token, offset)
+            }
+            ParseError::BadEncoding { offset, token } => {
+                write!(f, "invalid UTF-8 {} at byte {}", token, offset)
+            }
+            ParseError::UnsupportedVersion { offset, token } => {
+                write!(
+                    f,
+                    "unsupported format version {:?} at byte {}",
+                    token, offset
+                )
+            }
+            ParseError::MalformedField { offset, token } => {
+                write!(f, "field {:?} at byte {} is not name=value", token, offset)
+            }
+            ParseError::UnknownUnit { offset, token } => {
+                write!(f, "unknown unit {:?} at byte {}", token, offset)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ParseError {}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn reading(db_level: f32, secs: u64) -> LoudnessData {
+        LoudnessData::new(db_level, Timestamp::from_unix_secs(secs).unwrap())
+    }
+
+    #[test]
+    fn csv_round_trip() {
+        for (db_level, secs) in [(30.205029, 1669026612), (0.0, 0), (194.0, u32::MAX as u64)] {
+            let data = reading(db_level, secs);
+            let parsed = LoudnessData::parse_csv(&data.to_csv()).unwrap();
+            assert_eq!(parsed.db_level(), db_level);
+            assert_eq!(parsed.timestamp(), data.timestamp());
+        }
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        for (db_level, secs) in [
+            (30.2, 1669026612),
+            (0.0, 0),
+            (-12.34, 127),
+            (194.0, 128),
+            (327.67, u32::MAX as u64),
+            (55.55, i64::MAX as u64),
+        ] {
+            let data = reading(db_level, secs);
+            let parsed = LoudnessData::parse_binary(&data.to_binary()).unwrap();
+            assert!((parsed.db_level() - db_level).abs() < 0.005);
+            assert_eq!(parsed.timestamp(), data.timestamp());
+        }
+    }
+
+    #[test]
+    fn binary_is_compact() {
+        let data = reading(30.205029, 1669026612);
+        assert_eq!(data.to_binary().len(), 8);
+        assert_eq!(data.to_binary()[0], BINARY_MARKER);
+        assert!(data.to_binary().len() < data.to_csv().len());
+    }
+
+    #[test]
+    fn binary_varint_boundaries() {
+        assert_eq!(&reading(0.0, 0).to_binary()[3..], &[0x00]);
+        assert_eq!(&reading(0.0, 127).to_binary()[3..], &[0x7f]);
+        assert_eq!(&reading(0.0, 128).to_binary()[3..], &[0x80, 0x01]);
+        assert_eq!(&reading(0.0, 300).to_binary()[3..], &[0xac, 0x02]);
+    }
+
+    #[test]
+    fn binary_level_is_rounded_to_fixed_point() {
+        let parsed = LoudnessData::parse_binary(&reading(30.205029, 0).to_binary()).unwrap();
+        assert_eq!(parsed.db_level(), 30.21);
+        let parsed = LoudnessData::parse_binary(&reading(-30.205029, 0).to_binary()).unwrap();
+        assert_eq!(parsed.db_level(), -30.21);
+    }
+
+    #[test]
+    fn binary_encodes_into_a_fixed_buffer() {
+        let data = reading(30.2, u64::MAX >> 1);
+        let mut buf = [0; MAX_BINARY_LEN];
+        let len = data.encode_binary(&mut buf).unwrap();
+        assert_eq!(&buf[..len], data.to_binary().as_slice());
+        assert_eq!(data.encode_binary(&mut buf[..len - 1]), Err(BufferTooSmall));
+    }
+
+    #[test]
+    fn binary_errors() {
+        let payload = reading(50.0, 1669026612).to_binary();
+        assert_eq!(
+            LoudnessData::parse_binary(&payload[..2])
+                .unwrap_err()
+                .kind(),
+            "missing_field"
+        );
+        assert_eq!(
+            LoudnessData::parse_binary(&payload[..payload.len() - 1])
+                .unwrap_err()
+                .kind(),
+            "missing_field"
+        );
+        let mut trailing = payload.clone();
+        trailing.push(0);
+        let error = LoudnessData::parse_binary(&trailing).unwrap_err();
+        assert_eq!(error.kind(), "trailing_fields");
+        assert_eq!(error.offset(), payload.len());
+
+        let mut too_long = vec![BINARY_MARKER, 0, 0];
+        too_long.extend_from_slice(&[0xff; 10]);
+        too_long.push(0x01);
+        assert_eq!(
+            LoudnessData::parse_binary(&too_long).unwrap_err().kind(),
+            "timestamp_out_of_range"
+        );
+        assert_eq!(
+            LoudnessData::parse_binary(b"50,1").unwrap_err().kind(),
+            "unsupported_version"
+        );
+    }
+
+    #[test]
+    fn csv_errors() {
+        let error = LoudnessData::parse_csv("50.5,abc").unwrap_err();
+        assert_eq!(error.kind(), "bad_number");
+        assert_eq!(error.offset(), 5);
+        assert_eq!(error.token(), "abc");
+        assert_eq!(
+            LoudnessData::parse_csv("50.5").unwrap_err().kind(),
+            "missing_field"
+        );
+        assert_eq!(
+            LoudnessData::parse_csv("NaN,1").unwrap_err().kind(),
+            "non_finite_level"
+        );
+        assert_eq!(
+            LoudnessData::parse_csv("50,1,2").unwrap_err().kind(),
+            "trailing_fields"
+        );
+        assert_eq!(
+            LoudnessData::parse(b"50,\xff").unwrap_err().kind(),
+            "bad_encoding"
+        );
+        assert_eq!(
+            LoudnessData::parse_csv("50,9223372036854775808")
+                .unwrap_err()
+                .kind(),
+            "timestamp_out_of_range"
+        );
+    }
+
+    #[test]
+    fn every_error_has_its_offset_and_token() {
+        // payload, kind, offset, token
+        let cases: [(&[u8], &str, usize, &str); 21] = [
+            (b"", "missing_field", 0, ""),
+            (b",1", "missing_field", 0, ""),
+            (b"50.5", "missing_field", 4, ""),
+            (b"50.5,", "missing_field", 5, ""),
+            (b"abc,1", "bad_number", 0, "abc"),
+            (b"50.5,abc", "bad_number", 5, "abc"),
+            (b"50.5,-1", "bad_number", 5, "-1"),
+            (b"50.5,1.5", "bad_number", 5, "1.5"),
+            (b"inf,1", "non_finite_level", 0, "inf"),
+            (b"-inf,1", "non_finite_level", 0, "-inf"),
+            (b"NaN,1", "non_finite_level", 0, "NaN"),
+            // one more than i64::MAX, and one more than u64::MAX
+            (
+                b"50,9223372036854775808",
+                "timestamp_out_of_range",
+                3,
+                "9223372036854775808",
+            ),
+            (
+                b"50,18446744073709551616",
+                "timestamp_out_of_range",
+                3,
+                "18446744073709551616",
+            ),
+            (b"50,1,2", "trailing_fields", 5, "2"),
+            (b"50,1,2,3", "trailing_fields", 5, "2,3"),
+            (b"50,1,", "trailing_fields", 5, ""),
+            (b"50,\xff", "bad_encoding", 3, "\\xff"),
+            // a sequence cut off at the end, and one cut off before a comma
+            (b"50,1\xc3", "bad_encoding", 4, "\\xc3"),
+            (b"\xe2\x82,1", "bad_encoding", 0, "\\xe2\\x82"),
+            (b"\xc0\xaf,1", "bad_encoding", 0, "\\xc0"),
+            (b"50,1\xed\xa0\x80", "bad_encoding", 4, "\\xed"),
+        ];
+        for (payload, kind, offset, token) in cases {
+            let error = LoudnessData::parse(payload).unwrap_err();
+            assert_eq!(
+                (error.kind(), error.offset(), error.token()),
+                (kind, offset, token),
+                "{:?}",
+                payload
+            );
+        }
+    }
+
+    #[test]
+    fn the_largest_timestamp_parses() {
+        let parsed = LoudnessData::parse_csv("50,9223372036854775807").unwrap();
+        assert_eq!(parsed.timestamp(), Timestamp::MAX);
+    }
+
+    #[test]
+    fn binary_errors_have_their_offset_and_token() {
+        // payload, kind, offset, token
+        let cases: [(&[u8], &str, usize, &str); 6] = [
+            (b"", "missing_field", 0, ""),
+            (b"5", "unsupported_version", 0, "\\x35"),
+            (&[BINARY_MARKER, 0], "missing_field", 1, ""),
+            (&[BINARY_MARKER, 0, 0], "missing_field", 3, ""),
+            (&[BINARY_MARKER, 0, 0, 0x80], "missing_field", 4, ""),
+            (&[BINARY_MARKER, 0, 0, 1, 2], "trailing_fields", 4, "\\x02"),
+        ];
+        for (payload, kind, offset, token) in cases {
+            let error = LoudnessData::parse_binary(payload).unwrap_err();
+            assert_eq!(
+                (error.kind(), error.offset(), error.token()),
+                (kind, offset, token),
+                "{:?}",
+                payload
+            );
+        }
+
+        // i64::MAX + 1, which fits in a varint but not in a timestamp
+        let mut payload = vec![BINARY_MARKER, 0, 0];
+        payload.extend_from_slice(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x01]);
+        let error = LoudnessData::parse_binary(&payload).unwrap_err();
+        assert_eq!(
+            (error.kind(), error.offset()),
+            ("timestamp_out_of_range", 3)
+        );
+    }
+
+    #[test]
+    fn shifted_errors_keep_their_token() {
+        let error = LoudnessData::parse_csv("50.5,abc").unwrap_err().shifted(10);
+        assert_eq!(error.offset(), 15);
+        assert_eq!(error.token(), "abc");
+        assert_eq!(
+            alloc::format!("{}", error),
+            "invalid timestamp \"abc\" at byte 15"
+        );
+    }
+
+    #[test]
+    fn long_tokens_are_truncated() {
+        let error =
+            LoudnessData::parse_csv(&alloc::format!("50,1,{}", "é".repeat(40))).unwrap_err();
+        assert_eq!(error.token().len(), Token::CAPACITY - 1);
+        assert!(error.token().ends_with("é..."));
+    }
+}
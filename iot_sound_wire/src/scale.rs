@@ -0,0 +1,245 @@
+use core::fmt;
+use core::str::FromStr;
+
+/// Frequency weighting, the filter applied to the sound before its level was measured
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Weighting {
+    /// dBA, follows the sensitivity of the ear at moderate levels
+    #[default]
+    A,
+    /// dBC, nearly flat, keeps most of the low frequencies
+    C,
+    /// dBZ, no weighting at all
+    Z,
+}
+
+/// Time weighting, how the level follows changes of the sound
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum TimeWeighting {
+    /// Exponential average with a 125 ms time constant
+    #[default]
+    Fast,
+    /// Exponential average with a 1 s time constant
+    Slow,
+    /// Equivalent continuous level, the energy average over the reporting interval
+    Leq,
+}
+
+/// What 0 dB of a level refers to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Reference {
+    /// Sound pressure level, relative to 20 µPa
+    #[default]
+    Spl,
+    /// Relative to the full scale of the analog to digital converter, before calibration
+    Dbfs,
+}
+
+/// How the level of a reading was measured
+///
+/// Levels on different scales can not be compared. Payloads without scale
+/// metadata are on the default scale, dBA fast SPL, which is what the sensors
+/// report that existed before the metadata.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Scale {
+    pub weighting: Weighting,
+    pub time_weighting: TimeWeighting,
+    pub reference: Reference,
+}
+
+/// A weighting, time weighting or reference name that is not known
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnknownUnit;
+
+impl fmt::Display for UnknownUnit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("unknown weighting, time weighting or reference")
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for UnknownUnit {}
+
+impl Weighting {
+    /// Returns the name of the weighting, `A`, `C` or `Z`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Weighting::A => "A",
+            Weighting::C => "C",
+            Weighting::Z => "Z",
+        }
+    }
+}
+
+impl TimeWeighting {
+    /// Returns the name of the time weighting, `fast`, `slow` or `leq`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TimeWeighting::Fast => "fast",
+            TimeWeighting::Slow => "slow",
+            TimeWeighting::Leq => "leq",
+        }
+    }
+}
+
+impl Reference {
+    /// Returns the name of the reference, `spl` or `dbfs`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Reference::Spl => "spl",
+            Reference::Dbfs => "dbfs",
+        }
+    }
+}
+
+impl Scale {
+    /// Returns whether levels on this scale can be aggregated as levels on `target`
+    ///
+    /// Only the time weighting converts: an energy average over fast or slow levels
+    /// is an Leq. Levels with another frequency weighting or reference never convert,
+    /// the weighting would need a spectrum and dBFS a calibration.
+    ///
+    /// # Arguments
+    /// * `target` - The scale of the aggregate
+    pub fn converts_to(&self, target: &Scale) -> bool {
+        self.weighting == target.weighting
+            && self.reference == target.reference
+            && (self.time_weighting == target.time_weighting
+                || target.time_weighting == TimeWeighting::Leq)
+    }
+
+    /// Returns the scale packed into the upper 6 bits of a byte, the default scale is 0
+    pub(crate) fn code(&self) -> u8 {
+        let weighting = match self.weighting {
+            Weighting::A => 0,
+            Weighting::C => 1,
+            Weighting::Z => 2,
+        };
+        let time_weighting = match self.time_weighting {
+            TimeWeighting::Fast => 0,
+            TimeWeighting::Slow => 1,
+            TimeWeighting::Leq => 2,
+        };
+        let reference = match self.reference {
+            Reference::Spl => 0,
+            Reference::Dbfs => 1,
+        };
+        weighting << 2 | time_weighting << 4 | reference << 6
+    }
+
+    /// Returns the scale of a byte written by `code`, the lower 2 bits are ignored
+    pub(crate) fn from_code(code: u8) -> Option<Self> {
+        let weighting = match (code >> 2) & 0b11 {
+            0 => Weighting::A,
+            1 => Weighting::C,
+            2 => Weighting::Z,
+            _ => return None,
+        };
+        let time_weighting = match (code >> 4) & 0b11 {
+            0 => TimeWeighting::Fast,
+            1 => TimeWeighting::Slow,
+            2 => TimeWeighting::Leq,
+            _ => return None,
+        };
+        let reference = match code >> 6 {
+            0 => Reference::Spl,
+            1 => Reference::Dbfs,
+            _ => return None,
+        };
+        Some(Scale {
+            weighting,
+            time_weighting,
+            reference,
+        })
+    }
+}
+
+impl FromStr for Weighting {
+    type Err = UnknownUnit;
+
+    fn from_str(name: &str) -> Result<Self, UnknownUnit> {
+        [Weighting::A, Weighting::C, Weighting::Z]
+            .into_iter()
+            .find(|weighting| weighting.as_str().eq_ignore_ascii_case(name))
+            .ok_or(UnknownUnit)
+    }
+}
+
+impl FromStr for TimeWeighting {
+    type Err = UnknownUnit;
+
+    fn from_str(name: &str) -> Result<Self, UnknownUnit> {
+        [TimeWeighting::Fast, TimeWeighting::Slow, TimeWeighting::Leq]
+            .into_iter()
+            .find(|time_weighting| time_weighting.as_str().eq_ignore_ascii_case(name))
+            .ok_or(UnknownUnit)
+    }
+}
+
+impl FromStr for Reference {
+    type Err = UnknownUnit;
+
+    fn from_str(name: &str) -> Result<Self, UnknownUnit> {
+        [Reference::Spl, Reference::Dbfs]
+            .into_iter()
+            .find(|reference| reference.as_str().eq_ignore_ascii_case(name))
+            .ok_or(UnknownUnit)
+    }
+}
+
+/// `dBA fast spl`, or `dBZ leq dbfs`
+impl fmt::Display for Scale {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "dB{} {} {}",
+            self.weighting.as_str(),
+            self.time_weighting.as_str(),
+            self.reference.as_str()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_scale_has_a_code() {
+        for weighting in [Weighting::A, Weighting::C, Weighting::Z] {
+            for time_weighting in [TimeWeighting::Fast, TimeWeighting::Slow, TimeWeighting::Leq] {
+                for reference in [Reference::Spl, Reference::Dbfs] {
+                    let scale = Scale {
+                        weighting,
+                        time_weighting,
+                        reference,
+                    };
+                    assert_eq!(Scale::from_code(scale.code()), Some(scale));
+                }
+            }
+        }
+        assert_eq!(Scale::default().code(), 0);
+        assert_eq!(Scale::from_code(0b0000_1100), None);
+    }
+
+    #[test]
+    fn only_the_time_weighting_converts() {
+        let fast = Scale::default();
+        let leq = Scale {
+            time_weighting: TimeWeighting::Leq,
+            ..fast
+        };
+        assert!(fast.converts_to(&leq));
+        assert!(!leq.converts_to(&fast));
+        let dbc = Scale {
+            weighting: Weighting::C,
+            ..leq
+        };
+        assert!(!fast.converts_to(&dbc));
+        let dbfs = Scale {
+            reference: Reference::Dbfs,
+            ..leq
+        };
+        assert!(!fast.converts_to(&dbfs));
+    }
+}
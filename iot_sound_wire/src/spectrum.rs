@@ -0,0 +1,345 @@
+use crate::codec::{self, BufferTooSmall, Writer, MAX_VARINT_LEN};
+use crate::loudness_data::ParseError;
+use crate::scale::Scale;
+use crate::timestamp::Timestamp;
+
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+
+/// First byte of a spectrum payload, like `BINARY_MARKER` a byte that never starts a UTF-8 text
+pub const SPECTRUM_MARKER: u8 = 0xb4;
+/// Most bytes `Spectrum::encode_binary` writes
+pub const MAX_SPECTRUM_LEN: usize = 4 + MAX_VARINT_LEN + 2 * Spectrum::MAX_BANDS;
+
+/// Nominal center frequencies in Hz of the one-third octave bands from 10 Hz to 20 kHz,
+/// IEC 61260. Every third band, starting at 16 Hz, is also an octave band.
+const NOMINAL_FREQUENCIES: [f32; 34] = [
+    10.0, 12.5, 16.0, 20.0, 25.0, 31.5, 40.0, 50.0, 63.0, 80.0, 100.0, 125.0, 160.0, 200.0, 250.0,
+    315.0, 400.0, 500.0, 630.0, 800.0, 1000.0, 1250.0, 1600.0, 2000.0, 2500.0, 3150.0, 4000.0,
+    5000.0, 6300.0, 8000.0, 10000.0, 12500.0, 16000.0, 20000.0,
+];
+/// Index of 1 kHz in `NOMINAL_FREQUENCIES`, band number 0 of IEC 61260
+const KILOHERTZ: usize = 20;
+
+/// Width of the bands of a spectrum
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Bandwidth {
+    /// 1/1 octave bands, 16 Hz to 16 kHz
+    Octave,
+    /// 1/3 octave bands, 10 Hz to 20 kHz
+    ThirdOctave,
+}
+
+impl Bandwidth {
+    /// Returns the name of the bandwidth, `octave` or `third_octave`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Bandwidth::Octave => "octave",
+            Bandwidth::ThirdOctave => "third_octave",
+        }
+    }
+
+    /// Returns the number of one-third octave bands from one band to the next
+    fn step(&self) -> usize {
+        match self {
+            Bandwidth::Octave => 3,
+            Bandwidth::ThirdOctave => 1,
+        }
+    }
+}
+
+/// Levels of adjacent octave or one-third octave bands, measured at one time
+///
+/// A spectrum tells sounds apart that have the same level, such as the hum of
+/// a ventilation system and people talking. Like `LoudnessData` it needs no allocator,
+/// the levels are kept inline.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Spectrum {
+    bandwidth: Bandwidth,
+    /// Index of the lowest band in `NOMINAL_FREQUENCIES`
+    first_band: usize,
+    levels: [f32; Spectrum::MAX_BANDS],
+    len: usize,
+    timestamp: Timestamp,
+    scale: Scale,
+}
+
+impl Spectrum {
+    /// Most bands in a spectrum, all one-third octave bands from 10 Hz to 20 kHz
+    pub const MAX_BANDS: usize = NOMINAL_FREQUENCIES.len();
+
+    /// Create a new Spectrum on the default scale
+    ///
+    /// Returns `None` if `first_frequency` is not the nominal center frequency of a band
+    /// of the bandwidth, or if the bands do not fit between 10 Hz and 20 kHz.
+    ///
+    /// # Arguments
+    /// * `bandwidth` - The width of the bands
+    /// * `first_frequency` - The nominal center frequency of the lowest band in Hz, e.g. `31.5`
+    /// * `levels` - The level of every band in decibel, from the lowest band up
+    /// * `timestamp` - The time the spectrum was measured
+    pub fn new(
+        bandwidth: Bandwidth,
+        first_frequency: f32,
+        levels: &[f32],
+        timestamp: Timestamp,
+    ) -> Option<Self> {
+        let first_band = NOMINAL_FREQUENCIES
+            .iter()
+            .position(|frequency| *frequency == first_frequency)?;
+        Spectrum::from_band(bandwidth, first_band, levels, timestamp)
+    }
+
+    fn from_band(
+        bandwidth: Bandwidth,
+        first_band: usize,
+        levels: &[f32],
+        timestamp: Timestamp,
+    ) -> Option<Self> {
+        let octave_aligned = first_band % 3 == KILOHERTZ % 3;
+        if bandwidth == Bandwidth::Octave && !octave_aligned {
+            return None;
+        }
+        let last_band = first_band + bandwidth.step() * levels.len().saturating_sub(1);
+        if levels.is_empty() || last_band >= Spectrum::MAX_BANDS {
+            return None;
+        }
+        let mut spectrum = Spectrum {
+            bandwidth,
+            first_band,
+            levels: [0.0; Spectrum::MAX_BANDS],
+            len: levels.len(),
+            timestamp,
+            scale: Scale::default(),
+        };
+        spectrum.levels[..levels.len()].copy_from_slice(levels);
+        Some(spectrum)
+    }
+
+    /// Returns the Spectrum with the levels on another scale
+    /// # Arguments
+    /// * `scale` - The scale the levels were measured on
+    pub fn with_scale(self, scale: Scale) -> Self {
+        Spectrum { scale, ..self }
+    }
+
+    /// Returns the Spectrum with `offset` decibel added to the level of every band
+    /// # Arguments
+    /// * `offset` - The offset in decibel
+    pub fn with_offset(mut self, offset: f32) -> Self {
+        for level in &mut self.levels[..self.len] {
+            *level += offset;
+        }
+        self
+    }
+
+    /// Returns the bandwidth of the Spectrum
+    pub fn bandwidth(&self) -> Bandwidth {
+        self.bandwidth
+    }
+    /// Returns timestamp of the Spectrum
+    pub fn timestamp(&self) -> Timestamp {
+        self.timestamp
+    }
+    /// Returns the scale the levels of the Spectrum were measured on
+    pub fn scale(&self) -> Scale {
+        self.scale
+    }
+    /// Returns the level of every band in decibel, from the lowest band up
+    pub fn levels(&self) -> &[f32] {
+        &self.levels[..self.len]
+    }
+    /// Returns the nominal center frequency of every band in Hz, from the lowest band up
+    pub fn frequencies(&self) -> impl Iterator<Item = f32> {
+        NOMINAL_FREQUENCIES
+            .into_iter()
+            .skip(self.first_band)
+            .step_by(self.bandwidth.step())
+            .take(self.len)
+    }
+
+    /// Returns the level of the whole spectrum, the energy sum of its bands
+    #[cfg(feature = "std")]
+    pub fn overall_level(&self) -> f32 {
+        let energy: f64 = self
+            .levels()
+            .iter()
+            .map(|level| 10f64.powf(f64::from(*level) / 10.0))
+            .sum();
+        (10.0 * energy.log10()) as f32
+    }
+
+    /// Writes the binary representation of the Spectrum
+    ///
+    /// `SPECTRUM_MARKER | bandwidth (1 byte) | first band (i8) | count (1 byte) | timestamp (varint) |
+    /// count * level (i16, 0.01 dB)`. The bandwidth byte is 0 for octave and 1 for one-third
+    /// octave bands, its upper 6 bits are the scale like in a batch. The first band is the
+    /// band number of IEC 61260, 0 is 1 kHz and every step is a one-third octave.
+    ///
+    /// # Arguments
+    /// * `buf` - Where to write the payload, `MAX_SPECTRUM_LEN` bytes are always enough
+    /// # Returns
+    /// * `usize` - The length of the payload
+    pub fn encode_binary(&self, buf: &mut [u8]) -> Result<usize, BufferTooSmall> {
+        let bandwidth = match self.bandwidth {
+            Bandwidth::Octave => 0,
+            Bandwidth::ThirdOctave => 1,
+        };
+        let mut writer = Writer::new(buf);
+        writer.push(SPECTRUM_MARKER)?;
+        writer.push(bandwidth | self.scale.code())?;
+        writer.push((self.first_band as i8 - KILOHERTZ as i8) as u8)?;
+        writer.push(self.len as u8)?;
+        writer.varint(self.timestamp.unix_secs())?;
+        for level in self.levels() {
+            writer.level(*level)?;
+        }
+        Ok(writer.len())
+    }
+
+    /// Returns the binary representation of the Spectrum, see `encode_binary`
+    #[cfg(feature = "alloc")]
+    pub fn to_binary(&self) -> Vec<u8> {
+        let mut buf = [0; MAX_SPECTRUM_LEN];
+        let len = self
+            .encode_binary(&mut buf)
+            .expect("MAX_SPECTRUM_LEN fits every spectrum");
+        buf[..len].to_vec()
+    }
+
+    /// Parses a payload written by `to_binary`
+    ///
+    /// # Arguments
+    ///
+    /// * `payload` - The payload as received
+    pub fn parse_binary(payload: &[u8]) -> Result<Self, ParseError> {
+        if payload.first() != Some(&SPECTRUM_MARKER) {
+            return Err(ParseError::UnsupportedVersion {
+                offset: 0,
+                token: codec::hex_token(&payload[..payload.len().min(1)]),
+            });
+        }
+        let header = payload.get(1..4).ok_or(ParseError::MissingField {
+            offset: payload.len(),
+            field: "bands",
+        })?;
+        let unsupported_bandwidth = || ParseError::UnsupportedVersion {
+            offset: 1,
+            token: codec::hex_token(&header[..1]),
+        };
+        let scale = Scale::from_code(header[0]).ok_or_else(unsupported_bandwidth)?;
+        let bandwidth = match header[0] & 0b11 {
+            0 => Bandwidth::Octave,
+            1 => Bandwidth::ThirdOctave,
+            _ => return Err(unsupported_bandwidth()),
+        };
+        let (secs, mut offset) = codec::read_varint(payload, 4, "timestamp")?;
+        let timestamp = codec::timestamp_from_secs(payload, 4, offset, secs)?;
+
+        let count = usize::from(header[2]);
+        let mut levels = [0.0; Spectrum::MAX_BANDS];
+        for level in levels.iter_mut().take(count) {
+            (*level, offset) = codec::read_level(payload, offset)?;
+        }
+        let first_band = usize::try_from(header[1] as i8 as isize + KILOHERTZ as isize).ok();
+        let spectrum = first_band
+            .zip(levels.get(..count))
+            .and_then(|(first_band, levels)| {
+                Spectrum::from_band(bandwidth, first_band, levels, timestamp)
+            })
+            .ok_or_else(|| ParseError::BadNumber {
+                offset: 2,
+                field: "bands",
+                token: codec::hex_token(&header[1..]),
+            })?;
+
+        if offset < payload.len() {
+            return Err(ParseError::TrailingFields {
+                offset,
+                token: codec::hex_token(&payload[offset..]),
+            });
+        }
+        Ok(spectrum.with_scale(scale))
+    }
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use crate::scale::Weighting;
+    use alloc::vec::Vec;
+
+    fn time() -> Timestamp {
+        Timestamp::from_unix_secs(1669026612).unwrap()
+    }
+
+    #[test]
+    fn bands_have_their_nominal_frequencies() {
+        let octaves = Spectrum::new(Bandwidth::Octave, 63.0, &[50.0, 45.0, 40.0], time()).unwrap();
+        assert_eq!(
+            octaves.frequencies().collect::<Vec<_>>(),
+            [63.0, 125.0, 250.0]
+        );
+        let thirds = Spectrum::new(Bandwidth::ThirdOctave, 800.0, &[1.0, 2.0], time()).unwrap();
+        assert_eq!(thirds.frequencies().collect::<Vec<_>>(), [800.0, 1000.0]);
+
+        assert!(Spectrum::new(Bandwidth::Octave, 80.0, &[50.0], time()).is_none());
+        assert!(Spectrum::new(Bandwidth::ThirdOctave, 50.5, &[50.0], time()).is_none());
+        assert!(Spectrum::new(Bandwidth::Octave, 16000.0, &[50.0, 50.0], time()).is_none());
+        assert!(Spectrum::new(Bandwidth::ThirdOctave, 1000.0, &[], time()).is_none());
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        let levels: Vec<f32> = (0..Spectrum::MAX_BANDS).map(|i| 20.0 + i as f32).collect();
+        let scale = Scale {
+            weighting: Weighting::Z,
+            ..Scale::default()
+        };
+        for spectrum in [
+            Spectrum::new(Bandwidth::ThirdOctave, 10.0, &levels, time()).unwrap(),
+            Spectrum::new(Bandwidth::Octave, 16.0, &levels[..11], time())
+                .unwrap()
+                .with_scale(scale),
+            Spectrum::new(Bandwidth::Octave, 1000.0, &[-12.5], Timestamp::UNIX_EPOCH).unwrap(),
+        ] {
+            let payload = spectrum.to_binary();
+            assert_eq!(payload[0], SPECTRUM_MARKER);
+            assert_eq!(Spectrum::parse_binary(&payload), Ok(spectrum));
+        }
+    }
+
+    #[test]
+    fn binary_errors() {
+        let payload = Spectrum::new(Bandwidth::Octave, 125.0, &[50.0, 40.0], time())
+            .unwrap()
+            .to_binary();
+        for end in 0..payload.len() {
+            assert!(Spectrum::parse_binary(&payload[..end]).is_err());
+        }
+        let mut trailing = payload.clone();
+        trailing.push(0);
+        assert_eq!(
+            Spectrum::parse_binary(&trailing).unwrap_err().kind(),
+            "trailing_fields"
+        );
+        let mut below = payload.clone();
+        below[2] = (-21i8) as u8;
+        assert_eq!(
+            Spectrum::parse_binary(&below).unwrap_err().kind(),
+            "bad_number"
+        );
+        let mut misaligned = payload.clone();
+        misaligned[2] = 1;
+        assert_eq!(
+            Spectrum::parse_binary(&misaligned).unwrap_err().kind(),
+            "bad_number"
+        );
+        let mut bandwidth = payload;
+        bandwidth[1] = 2;
+        assert_eq!(
+            Spectrum::parse_binary(&bandwidth).unwrap_err().kind(),
+            "unsupported_version"
+        );
+    }
+}
@@ -0,0 +1,54 @@
+#[cfg(feature = "std")]
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A point in time in whole seconds since the Unix epoch
+///
+/// Firmware without `std` has no `SystemTime`, so readings carry a Timestamp,
+/// which converts to and from `SystemTime` with the `std` feature.
+/// The range is that of `SystemTime` on Unix, up to `i64::MAX` seconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp(u64);
+
+impl Timestamp {
+    /// The Unix epoch, 1970-01-01T00:00:00Z
+    pub const UNIX_EPOCH: Timestamp = Timestamp(0);
+    /// The latest time a Timestamp can hold
+    pub const MAX: Timestamp = Timestamp(i64::MAX as u64);
+
+    /// Returns the time `secs` seconds after the Unix epoch, `None` if it is after `Timestamp::MAX`
+    /// # Arguments
+    /// * `secs` - Seconds since the Unix epoch
+    pub const fn from_unix_secs(secs: u64) -> Option<Self> {
+        if secs > Timestamp::MAX.0 {
+            return None;
+        }
+        Some(Timestamp(secs))
+    }
+
+    /// Returns the seconds since the Unix epoch
+    pub const fn unix_secs(&self) -> u64 {
+        self.0
+    }
+
+    /// Returns the current time
+    #[cfg(feature = "std")]
+    pub fn now() -> Self {
+        Timestamp::from(SystemTime::now())
+    }
+}
+
+/// Times before the Unix epoch become the epoch, the fraction of a second is dropped
+#[cfg(feature = "std")]
+impl From<SystemTime> for Timestamp {
+    fn from(time: SystemTime) -> Self {
+        let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        Timestamp(secs.min(Timestamp::MAX.0))
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<Timestamp> for SystemTime {
+    fn from(timestamp: Timestamp) -> Self {
+        UNIX_EPOCH + Duration::from_secs(timestamp.0)
+    }
+}
@@ -0,0 +1,423 @@
+use crate::batch::{self, BATCH_MARKER};
+use crate::loudness_data::{LoudnessData, ParseError, BINARY_MARKER};
+use crate::scale::Scale;
+use crate::spectrum::{Spectrum, SPECTRUM_MARKER};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use alloc::{format, vec};
+
+/// Format versions the backend can decode
+///
+/// * 0 - the original csv payload, `db_level,timestamp`
+/// * 1 - `v1;<firmware version>;<db_level>,<timestamp>[;<name>=<value>]...`,
+///   the fields `weighting`, `time_weighting` and `reference` are the scale of the level,
+///   `%`, `;` and `=` in the firmware version and the fields are escaped as `%25`, `%3B` and `%3D`
+/// * 2 - the compact binary payload of `LoudnessData::to_binary`
+/// * 3 - many readings in one binary payload, see `batch::encode`
+/// * 4 - the octave band levels of a spectrum, see `Spectrum::to_binary`
+pub const SUPPORTED_VERSIONS: &[u8] = &[0, 1, 2, 3, 4];
+/// Format version written by `Envelope::new`
+pub const CURRENT_VERSION: u8 = 1;
+
+/// Readings as they are sent over MQTT, with the format version of the payload
+///
+/// Version 1 carries the firmware version of the sensor and optional named fields,
+/// version 2 is binary and carries only the reading on the default scale.
+/// Version 3 carries many readings on one scale, every other version exactly one.
+/// Version 0 has no scale either, the scale of the readings is that of `LoudnessData`.
+/// Version 4 carries a spectrum instead of readings.
+/// Fields the backend does not know are kept, so sensors can send new fields
+/// before every backend understands them.
+#[derive(Debug)]
+pub struct Envelope {
+    pub version: u8,
+    pub firmware_version: Option<String>,
+    pub readings: Vec<LoudnessData>,
+    pub spectra: Vec<Spectrum>,
+    pub fields: BTreeMap<String, String>,
+}
+
+impl Envelope {
+    /// Create an envelope in the current format version
+    /// # Arguments
+    /// * `data` - The reading
+    /// * `firmware_version` - The firmware version of the sensor
+    pub fn new(data: LoudnessData, firmware_version: Option<String>) -> Self {
+        Envelope {
+            version: CURRENT_VERSION,
+            firmware_version,
+            readings: vec![data],
+            spectra: Vec::new(),
+            fields: BTreeMap::new(),
+        }
+    }
+
+    /// Create an envelope in the original csv format, which has no firmware version or fields
+    /// # Arguments
+    /// * `data` - The reading
+    pub fn csv(data: LoudnessData) -> Self {
+        Envelope {
+            version: 0,
+            firmware_version: None,
+            readings: vec![data],
+            spectra: Vec::new(),
+            fields: BTreeMap::new(),
+        }
+    }
+
+    /// Create an envelope in the compact binary format, which has no firmware version or fields
+    /// # Arguments
+    /// * `data` - The reading
+    pub fn binary(data: LoudnessData) -> Self {
+        Envelope {
+            version: 2,
+            firmware_version: None,
+            readings: vec![data],
+            spectra: Vec::new(),
+            fields: BTreeMap::new(),
+        }
+    }
+
+    /// Create an envelope with many readings, which has no firmware version or fields
+    /// # Arguments
+    /// * `readings` - The readings, at most `batch::MAX_READINGS`, all on the same scale
+    pub fn batch(readings: Vec<LoudnessData>) -> Self {
+        Envelope {
+            version: 3,
+            firmware_version: None,
+            readings,
+            spectra: Vec::new(),
+            fields: BTreeMap::new(),
+        }
+    }
+
+    /// Create an envelope with the band levels of a spectrum, which has no firmware version or fields
+    /// # Arguments
+    /// * `spectrum` - The spectrum
+    pub fn spectrum(spectrum: Spectrum) -> Self {
+        Envelope {
+            version: 4,
+            firmware_version: None,
+            readings: Vec::new(),
+            spectra: vec![spectrum],
+            fields: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the payload in the format version of the envelope
+    ///
+    /// # Panics
+    /// If a batch envelope has readings that do not fit in one batch, see `batch::encode`
+    pub fn encode(&self) -> Vec<u8> {
+        match self.version {
+            3 => {
+                return batch::encode(&self.readings)
+                    .expect("Batch envelopes fit in one batch, see Envelope::batch")
+            }
+            4 => {
+                let spectrum = self.spectra.first().expect("Envelope has a spectrum");
+                return spectrum.to_binary();
+            }
+            _ => {}
+        }
+        let data = self.readings.first().expect("Envelope has a reading");
+        match self.version {
+            0 => data.to_csv().into_bytes(),
+            2 => data.to_binary(),
+            _ => {
+                let mut payload = format!(
+                    "v{};{};{}",
+                    self.version,
+                    escape(self.firmware_version.as_deref().unwrap_or("")),
+                    data.to_csv()
+                );
+                let scale = data.scale();
+                if scale != Scale::default() {
+                    payload.push_str(&format!(
+                        ";{}={};{}={};{}={}",
+                        WEIGHTING,
+                        scale.weighting.as_str(),
+                        TIME_WEIGHTING,
+                        scale.time_weighting.as_str(),
+                        REFERENCE,
+                        scale.reference.as_str()
+                    ));
+                }
+                for (name, value) in &self.fields {
+                    payload.push_str(&format!(";{}={}", escape(name), escape(value)));
+                }
+                payload.into_bytes()
+            }
+        }
+    }
+
+    /// Decode a payload in any supported format version
+    /// # Arguments
+    /// * `payload` - The payload as received
+    pub fn decode(payload: &[u8]) -> Result<Self, ParseError> {
+        match payload.first() {
+            Some(&BINARY_MARKER) => {
+                return Ok(Envelope::binary(LoudnessData::parse_binary(payload)?))
+            }
+            Some(&BATCH_MARKER) => return Ok(Envelope::batch(batch::decode(payload)?)),
+            Some(&SPECTRUM_MARKER) => {
+                return Ok(Envelope::spectrum(Spectrum::parse_binary(payload)?))
+            }
+            Some(b'v') => {}
+            _ => return Ok(Envelope::csv(LoudnessData::parse(payload)?)),
+        }
+        let text =
+            core::str::from_utf8(payload).map_err(|e| ParseError::bad_encoding(payload, &e))?;
+        let mut segments = text.split(';').scan(0, |offset, segment| {
+            let start = *offset;
+            *offset += segment.len() + 1;
+            Some((start, segment))
+        });
+
+        let (_, version) = segments.next().expect("Split returns at least one segment");
+        match version[1..].parse::<u8>() {
+            Ok(1) => decode_v1(text.len(), &mut segments),
+            _ => Err(ParseError::UnsupportedVersion {
+                offset: 0,
+                token: version.into(),
+            }),
+        }
+    }
+}
+
+/// Fields of a version 1 payload holding the scale of the level
+const WEIGHTING: &str = "weighting";
+const TIME_WEIGHTING: &str = "time_weighting";
+const REFERENCE: &str = "reference";
+
+/// Escape the characters that separate the segments and fields of a version 1 payload
+fn escape(text: &str) -> String {
+    text.replace('%', "%25")
+        .replace(';', "%3B")
+        .replace('=', "%3D")
+}
+
+/// Undo `escape`, returns `None` for a `%` that is not one of its escapes
+fn unescape(text: &str) -> Option<String> {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('%') {
+        unescaped.push_str(&rest[..start]);
+        let escaped = match rest.get(start..start + 3)? {
+            "%25" => '%',
+            "%3B" => ';',
+            "%3D" => '=',
+            _ => return None,
+        };
+        unescaped.push(escaped);
+        rest = &rest[start + 3..];
+    }
+    unescaped.push_str(rest);
+    Some(unescaped)
+}
+
+/// Decode the segments of a version 1 payload after the version
+fn decode_v1<'a>(
+    end: usize,
+    segments: &mut impl Iterator<Item = (usize, &'a str)>,
+) -> Result<Envelope, ParseError> {
+    let (offset, firmware_version) = segments.next().ok_or(ParseError::MissingField {
+        offset: end,
+        field: "firmware version",
+    })?;
+    let firmware_version = unescape(firmware_version).ok_or(ParseError::MalformedField {
+        offset,
+        token: firmware_version.into(),
+    })?;
+    let (offset, csv) = segments.next().ok_or(ParseError::MissingField {
+        offset: end,
+        field: "level",
+    })?;
+    let data = LoudnessData::parse_csv(csv).map_err(|e| e.shifted(offset))?;
+
+    let mut scale = Scale::default();
+    let mut fields = BTreeMap::new();
+    for (offset, field) in segments {
+        match field.split_once('=') {
+            Some((name, value)) if !name.is_empty() => {
+                let unknown_unit = || ParseError::UnknownUnit {
+                    offset: offset + name.len() + 1,
+                    token: value.into(),
+                };
+                match name {
+                    WEIGHTING => scale.weighting = value.parse().map_err(|_| unknown_unit())?,
+                    TIME_WEIGHTING => {
+                        scale.time_weighting = value.parse().map_err(|_| unknown_unit())?
+                    }
+                    REFERENCE => scale.reference = value.parse().map_err(|_| unknown_unit())?,
+                    _ => match (unescape(name), unescape(value)) {
+                        (Some(name), Some(value)) => {
+                            fields.insert(name, value);
+                        }
+                        _ => {
+                            return Err(ParseError::MalformedField {
+                                offset,
+                                token: field.into(),
+                            })
+                        }
+                    },
+                }
+            }
+            _ => {
+                return Err(ParseError::MalformedField {
+                    offset,
+                    token: field.into(),
+                })
+            }
+        }
+    }
+    Ok(Envelope {
+        version: 1,
+        firmware_version: Some(firmware_version)
+            .filter(|firmware_version| !firmware_version.is_empty()),
+        readings: vec![data.with_scale(scale)],
+        spectra: Vec::new(),
+        fields,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scale::{Reference, TimeWeighting, Weighting};
+    use crate::spectrum::Bandwidth;
+    use crate::timestamp::Timestamp;
+    use alloc::string::ToString;
+
+    #[test]
+    fn every_version_round_trips() {
+        let data = || LoudnessData::new(42.5, Timestamp::from_unix_secs(1669026612).unwrap());
+        let mut v1 = Envelope::new(data(), Some("0.2.0".to_string()));
+        v1.fields.insert("battery".to_string(), "3.71".to_string());
+        let batch = Envelope::batch(vec![data(), data()]);
+        let spectrum = Spectrum::new(Bandwidth::Octave, 63.0, &[42.5, 40.0], data().timestamp());
+        let spectrum = Envelope::spectrum(spectrum.unwrap());
+        for envelope in [
+            Envelope::csv(data()),
+            v1,
+            Envelope::binary(data()),
+            batch,
+            spectrum,
+        ] {
+            let decoded = Envelope::decode(&envelope.encode()).unwrap();
+            assert_eq!(decoded.version, envelope.version);
+            assert_eq!(decoded.firmware_version, envelope.firmware_version);
+            assert_eq!(decoded.fields, envelope.fields);
+            assert_eq!(decoded.readings.len(), envelope.readings.len());
+            assert_eq!(decoded.spectra, envelope.spectra);
+            for (decoded, data) in decoded.readings.iter().zip(&envelope.readings) {
+                assert_eq!(decoded.db_level(), 42.5);
+                assert_eq!(decoded.timestamp(), data.timestamp());
+            }
+        }
+    }
+
+    #[test]
+    fn v1_carries_the_scale() {
+        let scale = Scale {
+            weighting: Weighting::C,
+            time_weighting: TimeWeighting::Leq,
+            reference: Reference::Spl,
+        };
+        let data = LoudnessData::new(42.5, Timestamp::UNIX_EPOCH).with_scale(scale);
+        let envelope = Envelope::new(data, None);
+        let payload = envelope.encode();
+        assert_eq!(
+            payload,
+            b"v1;;42.5,0;weighting=C;time_weighting=leq;reference=spl"
+        );
+        let decoded = Envelope::decode(&payload).unwrap();
+        assert_eq!(decoded.readings[0].scale(), scale);
+        assert!(decoded.fields.is_empty());
+
+        let decoded = Envelope::decode(b"v1;;42.5,0").unwrap();
+        assert_eq!(decoded.readings[0].scale(), Scale::default());
+        let error = Envelope::decode(b"v1;;42.5,0;weighting=B").unwrap_err();
+        assert_eq!(error.kind(), "unknown_unit");
+        assert_eq!(error.offset(), 21);
+    }
+
+    #[test]
+    fn v1_errors_have_their_offset_and_token() {
+        // payload, kind, offset, token
+        let cases: [(&[u8], &str, usize, &str); 11] = [
+            (b"v2;;42.5,0", "unsupported_version", 0, "v2"),
+            (b"vx;;42.5,0", "unsupported_version", 0, "vx"),
+            (b"v1", "missing_field", 2, ""),
+            (b"v1;0.2.0", "missing_field", 8, ""),
+            (b"v1;0.2.0;abc,0", "bad_number", 9, "abc"),
+            (b"v1;0.2.0;42.5,0,1", "trailing_fields", 16, "1"),
+            (b"v1;;42.5,0;battery", "malformed_field", 11, "battery"),
+            (b"v1;;42.5,0;=3.7", "malformed_field", 11, "=3.7"),
+            (b"v1;0.2%;42.5,0", "malformed_field", 3, "0.2%"),
+            (
+                b"v1;;42.5,0;note=a%3Xb",
+                "malformed_field",
+                11,
+                "note=a%3Xb",
+            ),
+            (
+                b"v1;;42.5,0;reference=spl;weighting=B",
+                "unknown_unit",
+                35,
+                "B",
+            ),
+        ];
+        for (payload, kind, offset, token) in cases {
+            let error = Envelope::decode(payload).unwrap_err();
+            assert_eq!(
+                (error.kind(), error.offset(), error.token()),
+                (kind, offset, token),
+                "{:?}",
+                payload
+            );
+        }
+    }
+
+    #[test]
+    fn v1_escapes_separators() {
+        let data = LoudnessData::new(42.5, Timestamp::UNIX_EPOCH);
+        let mut envelope = Envelope::new(data, Some("0.2;rc=1".to_string()));
+        envelope
+            .fields
+            .insert("note".to_string(), "a=b;100%".to_string());
+        let payload = envelope.encode();
+        assert_eq!(payload, b"v1;0.2%3Brc%3D1;42.5,0;note=a%3Db%3B100%25");
+        let decoded = Envelope::decode(&payload).unwrap();
+        assert_eq!(decoded.firmware_version.as_deref(), Some("0.2;rc=1"));
+        assert_eq!(decoded.fields, envelope.fields);
+    }
+
+    #[test]
+    fn decode_detects_the_format() {
+        let data = LoudnessData::new(42.5, Timestamp::from_unix_secs(1669026612).unwrap());
+        let csv = Envelope::decode(b"42.5,1669026612").unwrap();
+        assert_eq!((csv.version, csv.firmware_version), (0, None));
+        let binary = Envelope::decode(&data.to_binary()).unwrap();
+        assert_eq!(binary.version, 2);
+        assert_eq!(binary.readings[0].timestamp(), data.timestamp());
+        let batch = Envelope::decode(&batch::encode(&[data]).unwrap()).unwrap();
+        assert_eq!((batch.version, batch.readings.len()), (3, 1));
+
+        // fields the backend does not know are kept
+        let v1 = Envelope::decode(b"v1;0.2.0;42.5,1669026612;battery=3.71;rssi=-70").unwrap();
+        assert_eq!(v1.version, 1);
+        assert_eq!(v1.firmware_version.as_deref(), Some("0.2.0"));
+        assert_eq!(v1.readings[0].db_level(), 42.5);
+        assert_eq!(
+            v1.fields,
+            BTreeMap::from([
+                ("battery".to_string(), "3.71".to_string()),
+                ("rssi".to_string(), "-70".to_string()),
+            ])
+        );
+        let empty = Envelope::decode(b"").unwrap_err();
+        assert_eq!(empty.kind(), "missing_field");
+    }
+}